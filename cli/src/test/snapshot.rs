@@ -142,12 +142,18 @@ impl SnapshotGenerator {
                                         "output": output,
                                     }));
                                 }
-                                DataMessage::Invalidate { cursor } => {
+                                DataMessage::Invalidate { cursor, .. } => {
                                     debug!("Ignoring invalidate: {:?}", cursor);
                                 }
-                                DataMessage::Heartbeat => {
+                                DataMessage::Finalized { cursor } => {
+                                    debug!("Ignoring finalized head: {:?}", cursor);
+                                }
+                                DataMessage::Heartbeat { .. } => {
                                     debug!("Ignoring heartbeat");
                                 }
+                                DataMessage::Summary { .. } => {
+                                    debug!("Ignoring stream summary");
+                                }
                             }
                             num_handled_blocks += 1;
                         }
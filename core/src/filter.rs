@@ -4,4 +4,9 @@ use serde::{de::DeserializeOwned, ser::Serialize};
 pub trait Filter: Default + Message + Clone + DeserializeOwned + Serialize {
     /// Merges the given filter into this filter.
     fn merge_filter(&mut self, other: Self);
+
+    /// Checks that the filter is valid, returning a description of the problem if not.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
@@ -1,6 +1,7 @@
 pub mod v1alpha2 {
     use std::fmt::{self, Display};
 
+    use prost::Message;
     use serde::{
         de::{self, Deserialize, Deserializer, Visitor},
         ser::{Serialize, SerializeStruct, Serializer},
@@ -16,6 +17,12 @@ pub mod v1alpha2 {
         NODE_DESCRIPTOR_SET
     }
 
+    /// Version of the stream protocol and data schema.
+    ///
+    /// Bump this value when a change to the stream messages or data makes
+    /// older clients misinterpret the data.
+    pub const STREAM_PROTOCOL_VERSION: u32 = 1;
+
     impl Serialize for Cursor {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
@@ -121,6 +128,46 @@ pub mod v1alpha2 {
         }
     }
 
+    /// The `google.rpc.Status` message, the standard content of the
+    /// `grpc-status-details-bin` trailer.
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    pub struct RpcStatus {
+        #[prost(int32, tag = "1")]
+        pub code: i32,
+        #[prost(string, tag = "2")]
+        pub message: ::prost::alloc::string::String,
+        #[prost(message, repeated, tag = "3")]
+        pub details: ::prost::alloc::vec::Vec<::pbjson_types::Any>,
+    }
+
+    impl ErrorDetail {
+        pub const TYPE_URL: &'static str = "type.googleapis.com/apibara.node.v1alpha2.ErrorDetail";
+
+        /// Returns the `grpc-status-details-bin` value for a status with this detail.
+        pub fn to_status_details(&self, code: i32, message: &str) -> Vec<u8> {
+            let detail = ::pbjson_types::Any {
+                type_url: Self::TYPE_URL.to_string(),
+                value: self.encode_to_vec().into(),
+            };
+            let status = RpcStatus {
+                code,
+                message: message.to_string(),
+                details: vec![detail],
+            };
+            status.encode_to_vec()
+        }
+
+        /// Returns the error detail in the `grpc-status-details-bin` value, if any.
+        pub fn from_status_details(details: &[u8]) -> Option<Self> {
+            let status = RpcStatus::decode(details).ok()?;
+            let detail = status
+                .details
+                .iter()
+                .find(|detail| detail.type_url == Self::TYPE_URL)?;
+            ErrorDetail::decode(detail.value.clone()).ok()
+        }
+    }
+
     impl Display for DataFinality {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             match self {
@@ -135,7 +182,27 @@ pub mod v1alpha2 {
 
 #[cfg(test)]
 mod tests {
-    use crate::node::v1alpha2::DataFinality;
+    use prost::Message;
+
+    use crate::node::v1alpha2::{DataFinality, ErrorCode, ErrorDetail, RpcStatus};
+
+    #[test]
+    fn test_error_detail_status_details() {
+        let detail = ErrorDetail {
+            code: ErrorCode::InvalidCursor as i32,
+        };
+        let details = detail.to_status_details(3, "invalid cursor");
+
+        // the details are a standard status, with the detail packed in an `Any`.
+        let status = RpcStatus::decode(details.as_slice()).unwrap();
+        assert_eq!(status.code, 3);
+        assert_eq!(status.message, "invalid cursor");
+        assert_eq!(status.details.len(), 1);
+        assert_eq!(status.details[0].type_url, ErrorDetail::TYPE_URL);
+
+        assert_eq!(ErrorDetail::from_status_details(&details), Some(detail));
+        assert_eq!(ErrorDetail::from_status_details(b"garbage"), None);
+    }
 
     #[test]
     fn test_cursor_serialization() {
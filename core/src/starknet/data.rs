@@ -35,6 +35,25 @@ pub enum FieldElementDecodeError {
     DecodeError(#[from] hex::FromHexError),
 }
 
+impl Transaction {
+    /// Returns the address of the account that sent the transaction.
+    ///
+    /// Deploy, deploy account and l1 handler transactions don't have a sender.
+    pub fn sender_address(&self) -> Option<&FieldElement> {
+        match self.transaction.as_ref()? {
+            transaction::Transaction::InvokeV0(tx) => tx.contract_address.as_ref(),
+            transaction::Transaction::InvokeV1(tx) => tx.sender_address.as_ref(),
+            transaction::Transaction::InvokeV3(tx) => tx.sender_address.as_ref(),
+            transaction::Transaction::Declare(tx) => tx.sender_address.as_ref(),
+            transaction::Transaction::DeclareV3(tx) => tx.sender_address.as_ref(),
+            transaction::Transaction::Deploy(_)
+            | transaction::Transaction::DeployAccount(_)
+            | transaction::Transaction::DeployAccountV3(_)
+            | transaction::Transaction::L1Handler(_) => None,
+        }
+    }
+}
+
 impl FieldElement {
     /// Returns a new field element representing the given u64 value.
     pub fn from_u64(value: u64) -> FieldElement {
@@ -46,6 +65,29 @@ impl FieldElement {
         }
     }
 
+    /// Adds two field elements as 256 bits unsigned integers, wrapping around on overflow.
+    pub fn wrapping_add(&self, other: &FieldElement) -> FieldElement {
+        let mut carry = 0u128;
+        let mut add = |a: u64, b: u64| {
+            let sum = a as u128 + b as u128 + carry;
+            carry = sum >> 64;
+            sum as u64
+        };
+
+        // hi_hi holds the least significant bits.
+        let hi_hi = add(self.hi_hi, other.hi_hi);
+        let hi_lo = add(self.hi_lo, other.hi_lo);
+        let lo_hi = add(self.lo_hi, other.lo_hi);
+        let lo_lo = add(self.lo_lo, other.lo_lo);
+
+        FieldElement {
+            lo_lo,
+            lo_hi,
+            hi_lo,
+            hi_hi,
+        }
+    }
+
     /// Returns a new field element from the raw byte representation.
     pub fn from_bytes(bytes: &[u8; 32]) -> Self {
         let lo_lo = u64::from_be_bytes([
@@ -208,6 +250,15 @@ mod tests {
         assert_eq!(felt, back);
     }
 
+    #[test]
+    fn test_wrapping_add() {
+        let sum = FieldElement::from_u64(u64::MAX).wrapping_add(&FieldElement::from_u64(2));
+        assert_eq!(sum.lo_lo, 0);
+        assert_eq!(sum.lo_hi, 0);
+        assert_eq!(sum.hi_lo, 1);
+        assert_eq!(sum.hi_hi, 1);
+    }
+
     #[test]
     fn test_from_hex() {
         let felt = FieldElement::from_hex("0x1").unwrap();
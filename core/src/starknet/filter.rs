@@ -14,6 +14,14 @@ impl HeaderFilter {
 }
 
 impl Filter {
+    /// Create a filter that returns only the header of every block.
+    ///
+    /// Transactions, receipts and events are not read, so blocks are much
+    /// smaller and cheaper to produce than with any other filter.
+    pub fn headers_only() -> Self {
+        Filter::default().with_header(HeaderFilter::new()).build()
+    }
+
     /// Configure filter header.
     pub fn with_header(&mut self, header: HeaderFilter) -> &mut Self {
         self.header = Some(header);
@@ -54,6 +62,45 @@ impl Filter {
         self
     }
 
+    /// Return data grouped by transaction.
+    pub fn with_group_by_transaction(&mut self, group_by_transaction: bool) -> &mut Self {
+        self.group_by_transaction = group_by_transaction;
+        self
+    }
+
+    /// Sort events in each block by the given key.
+    pub fn with_sort_events_by(&mut self, order: EventOrder) -> &mut Self {
+        self.sort_events_by = order as i32;
+        self
+    }
+
+    /// Only include the given fields of each event.
+    pub fn with_event_fields<S: Into<String>>(
+        &mut self,
+        fields: impl IntoIterator<Item = S>,
+    ) -> &mut Self {
+        self.event_fields = fields.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Include the time the node ingested each block.
+    pub fn with_include_ingestion_timestamp(&mut self, include: bool) -> &mut Self {
+        self.include_ingestion_timestamp = include;
+        self
+    }
+
+    /// Return events in the given format.
+    pub fn with_format(&mut self, format: ResponseFormat) -> &mut Self {
+        self.format = format as i32;
+        self
+    }
+
+    /// Compute the given aggregate over the matched events of each block.
+    pub fn add_aggregation(&mut self, aggregation: Aggregation) -> &mut Self {
+        self.aggregations.push(aggregation);
+        self
+    }
+
     /// Build final version of Filter
     pub fn build(&mut self) -> Self {
         // As the ::prost::Message already impl Default trait and doesn't seems to be overridable
@@ -133,6 +180,12 @@ impl TransactionFilter {
         self
     }
 
+    /// Filter transactions sent by the given address.
+    pub fn with_sender_address(&mut self, address: FieldElement) -> &mut Self {
+        self.sender_address = Some(address);
+        self
+    }
+
     /// Builds final `TransactionFilter`
     pub fn build(&mut self) -> Self {
         self.clone()
@@ -408,6 +461,12 @@ impl FilterMatch for Option<FieldElement> {
 
 impl TransactionFilter {
     pub fn matches(&self, tx: &Transaction) -> bool {
+        if let Some(sender_address) = self.sender_address.as_ref() {
+            if tx.sender_address() != Some(sender_address) {
+                return false;
+            }
+        }
+
         match self.filter.as_ref() {
             None => true,
             Some(transaction_filter::Filter::InvokeV0(filter)) => filter.matches(tx),
@@ -563,6 +622,191 @@ impl NonceUpdateFilter {
     }
 }
 
+impl Filter {
+    /// Returns why the filter is too broad, if it matches all data of a kind
+    /// or has no constraints at all.
+    ///
+    /// A filter with only a (non-weak) header filter is not considered broad,
+    /// since headers are cheap to produce.
+    pub fn broad_filter_reason(&self) -> Option<&'static str> {
+        if self
+            .transactions
+            .iter()
+            .any(|filter| filter.filter.is_none() && filter.sender_address.is_none())
+        {
+            return Some("transaction filter matches all transactions");
+        }
+
+        if self.events.iter().any(|filter| {
+            filter.from_address.is_none() && filter.keys.is_empty() && filter.data.is_empty()
+        }) {
+            return Some("event filter matches all events");
+        }
+
+        if self
+            .messages
+            .iter()
+            .any(|filter| filter.to_address.is_none() && filter.payload.is_empty())
+        {
+            return Some("message filter matches all messages");
+        }
+
+        if let Some(state_update) = &self.state_update {
+            if state_update.is_broad() {
+                return Some("state update filter matches all state updates of a kind");
+            }
+        }
+
+        let has_header = self
+            .header
+            .as_ref()
+            .map(|header| !header.weak)
+            .unwrap_or(false);
+        if !has_header
+            && self.transactions.is_empty()
+            && self.events.is_empty()
+            && self.messages.is_empty()
+            && self.state_update.is_none()
+        {
+            return Some("filter is empty");
+        }
+
+        None
+    }
+}
+
+impl StateUpdateFilter {
+    fn is_broad(&self) -> bool {
+        self.storage_diffs
+            .iter()
+            .any(|filter| filter.contract_address.is_none())
+            || self
+                .declared_contracts
+                .iter()
+                .any(|filter| filter.class_hash.is_none())
+            || self
+                .deployed_contracts
+                .iter()
+                .any(|filter| filter.contract_address.is_none() && filter.class_hash.is_none())
+            || self
+                .nonces
+                .iter()
+                .any(|filter| filter.contract_address.is_none() && filter.nonce.is_none())
+            || self
+                .declared_classes
+                .iter()
+                .any(|filter| filter.class_hash.is_none() && filter.compiled_class_hash.is_none())
+            || self
+                .replaced_classes
+                .iter()
+                .any(|filter| filter.contract_address.is_none() && filter.class_hash.is_none())
+    }
+}
+
+impl EventOrder {
+    /// Sorts the events of a single block.
+    ///
+    /// The sort is stable, so events with the same key keep their original order.
+    pub fn sort(&self, events: &mut [EventWithTransaction]) {
+        fn from_address(event: &EventWithTransaction) -> Option<[u8; 32]> {
+            event
+                .event
+                .as_ref()
+                .and_then(|event| event.from_address.as_ref())
+                .map(FieldElement::to_bytes)
+        }
+
+        fn first_key(event: &EventWithTransaction) -> Option<[u8; 32]> {
+            event
+                .event
+                .as_ref()
+                .and_then(|event| event.keys.first())
+                .map(FieldElement::to_bytes)
+        }
+
+        match self {
+            EventOrder::Unspecified => {}
+            EventOrder::FromAddress => events.sort_by_key(from_address),
+            EventOrder::FirstKey => events.sort_by_key(first_key),
+        }
+    }
+}
+
+/// The event fields that can be selected with [Filter::event_fields].
+pub const EVENT_FIELDS: &[&str] = &["from_address", "keys", "data", "index"];
+
+impl Aggregation {
+    /// Computes the aggregate over the given events.
+    pub fn aggregate<'a>(&self, events: impl Iterator<Item = &'a Event>) -> AggregateValue {
+        match self.function() {
+            AggregateFunction::Unspecified => AggregateValue::default(),
+            AggregateFunction::Count => AggregateValue {
+                count: events.count() as u64,
+                sum: None,
+            },
+            AggregateFunction::Sum => {
+                let mut count = 0;
+                let mut sum = FieldElement::from_u64(0);
+                for value in events.filter_map(|event| event.data.get(self.data_index as usize)) {
+                    sum = sum.wrapping_add(value);
+                    count += 1;
+                }
+                AggregateValue {
+                    count,
+                    sum: Some(sum),
+                }
+            }
+        }
+    }
+}
+
+impl EventWithTransaction {
+    /// Converts the event to a flat row, with the given block data.
+    ///
+    /// The transaction hash and index are read from the receipt.
+    pub fn to_row(&self, block_number: u64, block_hash: FieldElement) -> EventRow {
+        let event = self.event.clone().unwrap_or_default();
+        let receipt = self.receipt.as_ref();
+        EventRow {
+            block_number,
+            block_hash: Some(block_hash),
+            transaction_hash: receipt.and_then(|receipt| receipt.transaction_hash.clone()),
+            transaction_index: receipt
+                .map(|receipt| receipt.transaction_index)
+                .unwrap_or_default(),
+            event_index: event.index,
+            from_address: event.from_address,
+            keys: event.keys,
+            data: event.data,
+        }
+    }
+}
+
+impl Event {
+    /// Clears all fields not included in `fields`.
+    ///
+    /// If `fields` is empty, the event is left unchanged.
+    pub fn project(&mut self, fields: &[String]) {
+        if fields.is_empty() {
+            return;
+        }
+
+        let has_field = |name: &str| fields.iter().any(|field| field == name);
+        if !has_field("from_address") {
+            self.from_address = None;
+        }
+        if !has_field("keys") {
+            self.keys.clear();
+        }
+        if !has_field("data") {
+            self.data.clear();
+        }
+        if !has_field("index") {
+            self.index = 0;
+        }
+    }
+}
+
 impl FilterTrait for Filter {
     fn merge_filter(&mut self, other: Self) {
         if let Some(header) = self.header.as_mut() {
@@ -576,6 +820,29 @@ impl FilterTrait for Filter {
         self.events.extend(other.events);
         self.transactions.extend(other.transactions);
         self.messages.extend(other.messages);
+        self.group_by_transaction |= other.group_by_transaction;
+        self.include_ingestion_timestamp |= other.include_ingestion_timestamp;
+        if self.sort_events_by == EventOrder::Unspecified as i32 {
+            self.sort_events_by = other.sort_events_by;
+        }
+        if self.format == ResponseFormat::Unspecified as i32 {
+            self.format = other.format;
+        }
+        for aggregation in other.aggregations {
+            if !self.aggregations.contains(&aggregation) {
+                self.aggregations.push(aggregation);
+            }
+        }
+        // an empty projection includes all fields.
+        if self.event_fields.is_empty() || other.event_fields.is_empty() {
+            self.event_fields.clear();
+        } else {
+            for field in other.event_fields {
+                if !self.event_fields.contains(&field) {
+                    self.event_fields.push(field);
+                }
+            }
+        }
 
         if let Some(state) = self.state_update.as_mut() {
             if let Some(other) = other.state_update {
@@ -585,6 +852,33 @@ impl FilterTrait for Filter {
             self.state_update = other.state_update;
         }
     }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.group_by_transaction && self.format() == ResponseFormat::FlatRows {
+            return Err(
+                "flat rows format cannot be combined with group by transaction".to_string(),
+            );
+        }
+
+        for field in &self.event_fields {
+            if !EVENT_FIELDS.contains(&field.as_str()) {
+                return Err(format!(
+                    "unknown event field `{}`, expected one of: {}",
+                    field,
+                    EVENT_FIELDS.join(", ")
+                ));
+            }
+        }
+
+        // aggregates are computed before events are projected, so they can use any field.
+        for aggregation in &self.aggregations {
+            if aggregation.function() == AggregateFunction::Unspecified {
+                return Err("aggregation function must be specified".to_string());
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl HeaderFilter {
@@ -604,9 +898,264 @@ impl StateUpdateFilter {
 
 #[cfg(test)]
 mod tests {
-    use super::{Filter, HeaderFilter};
+    use super::{
+        transaction, AggregateFunction, Aggregation, DeployAccountTransaction, Event, EventOrder,
+        EventRow, EventWithTransaction, FieldElement, Filter, HeaderFilter, InvokeTransactionV1,
+        ResponseFormat, Transaction, TransactionFilter, TransactionReceipt,
+    };
     use crate::filter::Filter as FilterTrait;
 
+    #[test]
+    fn test_broad_filter_reason() {
+        assert_eq!(
+            Filter::default().broad_filter_reason(),
+            Some("filter is empty")
+        );
+        assert_eq!(
+            Filter::default().build().broad_filter_reason(),
+            Some("filter is empty")
+        );
+        assert!(Filter::headers_only().broad_filter_reason().is_none());
+
+        let filter = Filter::default()
+            .add_event(|event| event.with_from_address(FieldElement::from_u64(1)))
+            .build();
+        assert!(filter.broad_filter_reason().is_none());
+
+        let filter = Filter::default()
+            .add_event(|event| event.with_from_address(FieldElement::from_u64(1)))
+            .add_event(|event| event)
+            .build();
+        assert_eq!(
+            filter.broad_filter_reason(),
+            Some("event filter matches all events")
+        );
+
+        let filter = Filter::default().add_transaction(|tx| tx).build();
+        assert_eq!(
+            filter.broad_filter_reason(),
+            Some("transaction filter matches all transactions")
+        );
+    }
+
+    #[test]
+    fn test_event_projection() {
+        let event = Event {
+            from_address: Some(FieldElement::from_u64(1)),
+            keys: vec![FieldElement::from_u64(2)],
+            data: vec![FieldElement::from_u64(3)],
+            index: 4,
+        };
+
+        let mut projected = event.clone();
+        projected.project(&[]);
+        assert_eq!(projected, event);
+
+        let mut projected = event.clone();
+        projected.project(&["keys".to_string(), "index".to_string()]);
+        assert_eq!(
+            projected,
+            Event {
+                keys: vec![FieldElement::from_u64(2)],
+                index: 4,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_aggregate_events() {
+        let events = vec![
+            Event {
+                data: vec![FieldElement::from_u64(1), FieldElement::from_u64(10)],
+                ..Default::default()
+            },
+            Event {
+                data: vec![FieldElement::from_u64(2)],
+                ..Default::default()
+            },
+            Event {
+                data: vec![FieldElement::from_u64(3), FieldElement::from_u64(20)],
+                ..Default::default()
+            },
+        ];
+
+        let count = Aggregation {
+            function: AggregateFunction::Count as i32,
+            data_index: 0,
+        };
+        let value = count.aggregate(events.iter());
+        assert_eq!(value.count, 3);
+        assert!(value.sum.is_none());
+
+        let sum = Aggregation {
+            function: AggregateFunction::Sum as i32,
+            data_index: 1,
+        };
+        let value = sum.aggregate(events.iter());
+        assert_eq!(value.count, 2);
+        assert_eq!(value.sum, Some(FieldElement::from_u64(30)));
+
+        let filter = Filter::default()
+            .with_event_fields(vec!["keys".to_string()])
+            .add_aggregation(sum)
+            .build();
+        assert!(filter.validate().is_ok());
+    }
+
+    #[test]
+    fn test_event_to_row() {
+        let event = EventWithTransaction {
+            event: Some(Event {
+                from_address: Some(FieldElement::from_u64(1)),
+                keys: vec![FieldElement::from_u64(2)],
+                data: vec![FieldElement::from_u64(3)],
+                index: 4,
+            }),
+            receipt: Some(TransactionReceipt {
+                transaction_hash: Some(FieldElement::from_u64(5)),
+                transaction_index: 6,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let row = event.to_row(7, FieldElement::from_u64(8));
+        assert_eq!(
+            row,
+            EventRow {
+                block_number: 7,
+                block_hash: Some(FieldElement::from_u64(8)),
+                transaction_hash: Some(FieldElement::from_u64(5)),
+                transaction_index: 6,
+                event_index: 4,
+                from_address: Some(FieldElement::from_u64(1)),
+                keys: vec![FieldElement::from_u64(2)],
+                data: vec![FieldElement::from_u64(3)],
+            }
+        );
+    }
+
+    #[test]
+    fn test_validate_flat_rows_format() {
+        let filter = Filter::default()
+            .with_format(ResponseFormat::FlatRows)
+            .build();
+        assert!(filter.validate().is_ok());
+
+        let filter = Filter::default()
+            .with_format(ResponseFormat::FlatRows)
+            .with_group_by_transaction(true)
+            .build();
+        assert!(filter.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_event_fields() {
+        let filter = Filter::default()
+            .with_event_fields(["from_address", "data"])
+            .build();
+        assert!(filter.validate().is_ok());
+
+        let filter = Filter::default().with_event_fields(["topics"]).build();
+        assert!(filter.validate().is_err());
+    }
+
+    #[test]
+    fn test_merge_event_fields() {
+        let mut filter = Filter::default().with_event_fields(["keys"]).build();
+        filter.merge_filter(
+            Filter::default()
+                .with_event_fields(["data", "keys"])
+                .build(),
+        );
+        assert_eq!(filter.event_fields, vec!["keys", "data"]);
+
+        filter.merge_filter(Filter::default());
+        assert!(filter.event_fields.is_empty());
+    }
+
+    #[test]
+    fn test_sort_events() {
+        let new_event = |from_address: u64, key: u64, index: u64| EventWithTransaction {
+            event: Some(Event {
+                from_address: Some(FieldElement::from_u64(from_address)),
+                keys: vec![FieldElement::from_u64(key)],
+                index,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let indices = |events: &[EventWithTransaction]| {
+            events
+                .iter()
+                .map(|e| e.event.as_ref().unwrap().index)
+                .collect::<Vec<_>>()
+        };
+
+        let events = vec![new_event(2, 1, 0), new_event(1, 3, 1), new_event(2, 2, 2)];
+
+        let mut sorted = events.clone();
+        EventOrder::Unspecified.sort(&mut sorted);
+        assert_eq!(indices(&sorted), vec![0, 1, 2]);
+
+        let mut sorted = events.clone();
+        EventOrder::FromAddress.sort(&mut sorted);
+        assert_eq!(indices(&sorted), vec![1, 0, 2]);
+
+        let mut sorted = events;
+        EventOrder::FirstKey.sort(&mut sorted);
+        assert_eq!(indices(&sorted), vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn test_match_sender_address() {
+        let sender = FieldElement::from_u64(1);
+        let other = FieldElement::from_u64(2);
+
+        let invoke = Transaction {
+            transaction: Some(transaction::Transaction::InvokeV1(InvokeTransactionV1 {
+                sender_address: Some(sender.clone()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let deploy_account = Transaction {
+            transaction: Some(transaction::Transaction::DeployAccount(
+                DeployAccountTransaction::default(),
+            )),
+            ..Default::default()
+        };
+
+        let filter = TransactionFilter::default()
+            .with_sender_address(sender.clone())
+            .build();
+        assert!(filter.matches(&invoke));
+        assert!(!filter.matches(&deploy_account));
+
+        let filter = TransactionFilter::default()
+            .with_sender_address(other)
+            .build();
+        assert!(!filter.matches(&invoke));
+
+        // Combined with a type-specific filter, both must match.
+        let filter = TransactionFilter::default()
+            .with_sender_address(sender)
+            .declare_transaction(|tx| tx)
+            .build();
+        assert!(!filter.matches(&invoke));
+    }
+
+    #[test]
+    fn test_headers_only() {
+        let filter = Filter::headers_only();
+        assert!(!filter.header.unwrap().weak);
+        assert!(filter.transactions.is_empty());
+        assert!(filter.events.is_empty());
+        assert!(filter.messages.is_empty());
+        assert!(filter.state_update.is_none());
+    }
+
     #[test]
     fn test_merge_header() {
         {
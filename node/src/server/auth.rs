@@ -0,0 +1,117 @@
+//! Authenticate clients before they open a stream.
+use std::fmt;
+
+use subtle::{Choice, ConstantTimeEq};
+use tonic::metadata::MetadataMap;
+
+/// Header used by clients that don't send a bearer token.
+pub const API_KEY_METADATA_KEY: &str = "x-api-key";
+
+/// Checks that a client is allowed to open a stream.
+///
+/// The check runs once, before the stream is set up.
+pub trait StreamAuth: fmt::Debug + Send + Sync + 'static {
+    /// Returns an `unauthenticated` or `permission_denied` status if the
+    /// client can't open a stream.
+    fn authorize(&self, metadata: &MetadataMap) -> Result<(), tonic::Status>;
+}
+
+/// A [StreamAuth] that accepts a fixed set of API keys.
+///
+/// Clients send their key as a bearer token in the `authorization` header,
+/// or in the [API_KEY_METADATA_KEY] header.
+///
+/// Keys are compared in constant time, so that response times don't leak them.
+#[derive(Clone)]
+pub struct ApiKeyAuth {
+    keys: Vec<String>,
+}
+
+impl ApiKeyAuth {
+    pub fn new(keys: impl IntoIterator<Item = String>) -> Self {
+        ApiKeyAuth {
+            keys: keys.into_iter().collect(),
+        }
+    }
+
+    fn api_key<'a>(&self, metadata: &'a MetadataMap) -> Option<&'a str> {
+        if let Some(value) = metadata.get("authorization") {
+            // the scheme is case-insensitive.
+            let (scheme, token) = value.to_str().ok()?.split_once(' ')?;
+            return scheme
+                .eq_ignore_ascii_case("bearer")
+                .then(|| token.trim_start());
+        }
+
+        metadata.get(API_KEY_METADATA_KEY)?.to_str().ok()
+    }
+
+    /// Returns true if `key` is one of the keys, comparing it with all of them.
+    fn contains(&self, key: &str) -> bool {
+        let found = self.keys.iter().fold(Choice::from(0), |found, candidate| {
+            found | candidate.as_bytes().ct_eq(key.as_bytes())
+        });
+        found.into()
+    }
+}
+
+impl StreamAuth for ApiKeyAuth {
+    fn authorize(&self, metadata: &MetadataMap) -> Result<(), tonic::Status> {
+        let Some(key) = self.api_key(metadata) else {
+            return Err(tonic::Status::unauthenticated("missing api key"));
+        };
+
+        if self.contains(key) {
+            Ok(())
+        } else {
+            Err(tonic::Status::unauthenticated("invalid api key"))
+        }
+    }
+}
+
+impl fmt::Debug for ApiKeyAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKeyAuth")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::metadata::MetadataMap;
+
+    use super::{ApiKeyAuth, StreamAuth};
+
+    #[test]
+    fn test_api_key_auth() {
+        let auth = ApiKeyAuth::new(vec!["secret".to_string()]);
+
+        let mut metadata = MetadataMap::new();
+        let status = auth.authorize(&metadata).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        metadata.insert("x-api-key", "secret".parse().unwrap());
+        assert!(auth.authorize(&metadata).is_ok());
+
+        metadata.insert("authorization", "Bearer other".parse().unwrap());
+        let status = auth.authorize(&metadata).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        metadata.insert("authorization", "Bearer secret".parse().unwrap());
+        assert!(auth.authorize(&metadata).is_ok());
+
+        metadata.insert("authorization", "bearer secret".parse().unwrap());
+        assert!(auth.authorize(&metadata).is_ok());
+
+        metadata.insert("authorization", "Basic secret".parse().unwrap());
+        let status = auth.authorize(&metadata).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        metadata.insert("authorization", "Bearer secre".parse().unwrap());
+        let status = auth.authorize(&metadata).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        assert!(!format!("{:?}", auth).contains("secret"));
+    }
+}
@@ -1,11 +1,23 @@
 use crate::o11y::{self, Counter, KeyValue};
 use tonic::metadata::MetadataMap;
-use tracing::{debug_span, Span};
+use tracing::{debug_span, field, warn, Span};
+
+/// Maximum number of metadata keys used as labels.
+///
+/// Each label adds a dimension to the metrics, so keep this low to avoid
+/// cardinality explosions.
+pub const MAX_METADATA_LABELS: usize = 8;
+
+/// Maximum length (in bytes) of a label value. Longer values are truncated.
+pub const MAX_METADATA_LABEL_VALUE_LEN: usize = 64;
 
 pub trait RequestObserver: Send + Sync + 'static {
     type Meter: RequestMeter;
 
     /// Returns a span to be used when tracing a `stream_data` request.
+    ///
+    /// The span should declare empty `stream_id`, `batch_size`, `finality` and
+    /// `filter` fields, which are recorded once the client configures the stream.
     fn stream_data_span(&self, metadata: &MetadataMap) -> Span;
 
     /// Returns a meter to be used when metering a `stream_data` request.
@@ -33,6 +45,8 @@ pub struct SimpleMeter {
 /// A [RequestObserver] that adds a specific metadata value to the span and meter.
 ///
 /// This can be used to add information like current user or api keys.
+/// At most [MAX_METADATA_LABELS] keys are used, and values are truncated to
+/// [MAX_METADATA_LABEL_VALUE_LEN] bytes.
 pub struct MetadataKeyRequestObserver {
     keys: Vec<String>,
 }
@@ -68,16 +82,43 @@ impl MetadataKeyMeter {
 }
 
 impl MetadataKeyRequestObserver {
-    pub fn new(keys: Vec<String>) -> Self {
+    pub fn new(mut keys: Vec<String>) -> Self {
+        if keys.len() > MAX_METADATA_LABELS {
+            warn!(
+                max = MAX_METADATA_LABELS,
+                ignored = ?&keys[MAX_METADATA_LABELS..],
+                "too many metadata keys, ignoring extra keys"
+            );
+            keys.truncate(MAX_METADATA_LABELS);
+        }
         MetadataKeyRequestObserver { keys }
     }
+
+    /// Returns the labels extracted from the request metadata.
+    fn labels(&self, metadata: &MetadataMap) -> Vec<(String, String)> {
+        let mut result = Vec::with_capacity(self.keys.len());
+        for key in &self.keys {
+            if let Some(value) = metadata.get(key) {
+                if let Ok(value) = value.to_str() {
+                    result.push((key.clone(), truncate_label_value(value).to_owned()));
+                }
+            }
+        }
+        result
+    }
 }
 
 impl RequestObserver for SimpleRequestObserver {
     type Meter = SimpleMeter;
 
     fn stream_data_span(&self, _metadata: &MetadataMap) -> Span {
-        debug_span!("stream_data")
+        debug_span!(
+            "stream_data",
+            stream_id = field::Empty,
+            batch_size = field::Empty,
+            finality = field::Empty,
+            filter = field::Empty,
+        )
     }
 
     fn stream_data_meter(&self, _metadata: &MetadataMap) -> Self::Meter {
@@ -101,20 +142,30 @@ impl RequestMeter for SimpleMeter {
 impl RequestObserver for MetadataKeyRequestObserver {
     type Meter = MetadataKeyMeter;
 
-    fn stream_data_span(&self, _metadata: &MetadataMap) -> Span {
-        debug_span!("stream_data")
+    fn stream_data_span(&self, metadata: &MetadataMap) -> Span {
+        let labels = self
+            .labels(metadata)
+            .into_iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join(",");
+        debug_span!(
+            "stream_data",
+            labels = %labels,
+            stream_id = field::Empty,
+            batch_size = field::Empty,
+            finality = field::Empty,
+            filter = field::Empty,
+        )
     }
 
     fn stream_data_meter(&self, metadata: &MetadataMap) -> Self::Meter {
-        let mut result = Vec::with_capacity(self.keys.len());
-        for key in &self.keys {
-            if let Some(value) = metadata.get(key) {
-                if let Ok(value) = value.to_str() {
-                    result.push(KeyValue::new(key.clone(), value.to_owned()));
-                }
-            }
-        }
-        MetadataKeyMeter::new(result)
+        let labels = self
+            .labels(metadata)
+            .into_iter()
+            .map(|(key, value)| KeyValue::new(key, value))
+            .collect();
+        MetadataKeyMeter::new(labels)
     }
 }
 
@@ -143,3 +194,45 @@ fn new_bytes_sent_counter() -> Counter<u64> {
     let meter = o11y::meter("stream_data");
     meter.u64_counter("stream_bytes_sent").init()
 }
+
+/// Truncates the value to at most [MAX_METADATA_LABEL_VALUE_LEN] bytes, on a char boundary.
+fn truncate_label_value(value: &str) -> &str {
+    if value.len() <= MAX_METADATA_LABEL_VALUE_LEN {
+        return value;
+    }
+    let mut end = MAX_METADATA_LABEL_VALUE_LEN;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::metadata::MetadataMap;
+
+    use super::{MetadataKeyRequestObserver, MAX_METADATA_LABELS, MAX_METADATA_LABEL_VALUE_LEN};
+
+    #[test]
+    fn test_labels_are_bounded() {
+        let keys = (0..MAX_METADATA_LABELS + 4)
+            .map(|i| format!("x-label-{i}"))
+            .collect::<Vec<_>>();
+        let observer = MetadataKeyRequestObserver::new(keys);
+
+        let mut metadata = MetadataMap::new();
+        let long_value = "a".repeat(MAX_METADATA_LABEL_VALUE_LEN * 2);
+        metadata.insert("x-label-0", long_value.parse().unwrap());
+        metadata.insert("x-label-1", "team-a".parse().unwrap());
+        let last_key = format!("x-label-{}", MAX_METADATA_LABELS + 1);
+        metadata.insert(
+            tonic::metadata::MetadataKey::from_bytes(last_key.as_bytes()).unwrap(),
+            "ignored".parse().unwrap(),
+        );
+
+        let labels = observer.labels(&metadata);
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels[0].1.len(), MAX_METADATA_LABEL_VALUE_LEN);
+        assert_eq!(labels[1], ("x-label-1".to_string(), "team-a".to_string()));
+    }
+}
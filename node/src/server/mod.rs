@@ -1,6 +1,9 @@
+mod auth;
 mod metadata;
 mod quota;
 
+pub use self::auth::{ApiKeyAuth, StreamAuth, API_KEY_METADATA_KEY};
+
 pub use self::metadata::{
     MetadataKeyRequestObserver, RequestMeter, RequestObserver, SimpleMeter, SimpleRequestObserver,
 };
@@ -0,0 +1,323 @@
+//! Close streams whose clients stop consuming data.
+
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock},
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
+
+use apibara_core::node::v1alpha2::{ErrorCode, StreamDataResponse};
+use futures::{FutureExt, Stream, StreamExt};
+use pin_project::pin_project;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::warn;
+
+use super::error::status_with_code;
+use crate::o11y::{self, Counter};
+
+type ResponseItem = Result<StreamDataResponse, tonic::Status>;
+
+/// Default time a client can go without reading messages that are waiting for it.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Why the stream was closed before the inner stream ended.
+#[derive(Debug, Clone, Copy)]
+enum CloseReason {
+    /// The buffer was full for longer than the backpressure timeout.
+    SlowClient,
+    /// The client didn't read any message for longer than the stall timeout.
+    Stalled,
+}
+
+/// A response stream backed by a bounded buffer.
+///
+/// The inner stream is driven by a separate task that pushes messages into the
+/// buffer, so data is produced ahead of the client up to the buffer size. When
+/// the buffer is full, the task stops producing data until the client catches
+/// up. If a timeout is set and the buffer stays full for longer than that, the
+/// task stops and the stream is closed with an `aborted` status.
+///
+/// If a stall timeout is set, the stream also tracks when the client last
+/// read a message. When messages wait in the buffer and the client doesn't
+/// read any for longer than the stall timeout, the task stops and the stream
+/// is closed with a `deadline_exceeded` status. Streams without data to send
+/// are never stalled, and clients that read heartbeats make progress.
+///
+/// In both cases the inner stream is dropped as soon as the client is
+/// detected, even if it never reads the status.
+#[pin_project]
+pub struct BackpressureStream {
+    #[pin]
+    inner: ReceiverStream<ResponseItem>,
+    closed: Arc<OnceLock<CloseReason>>,
+    last_progress: Arc<Mutex<Instant>>,
+    terminated: bool,
+}
+
+impl BackpressureStream {
+    pub fn new<S>(
+        inner: S,
+        buffer_size: usize,
+        timeout: Option<Duration>,
+        stall_timeout: Option<Duration>,
+    ) -> Self
+    where
+        S: Stream<Item = ResponseItem> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(buffer_size.max(1));
+        let closed = Arc::new(OnceLock::new());
+        let last_progress = Arc::new(Mutex::new(Instant::now()));
+
+        tokio::spawn({
+            let closed = closed.clone();
+            let last_progress = last_progress.clone();
+            async move {
+                let stall = match stall_timeout {
+                    None => futures::future::pending().boxed(),
+                    Some(stall_timeout) => {
+                        detect_stall(tx.clone(), last_progress, stall_timeout).boxed()
+                    }
+                };
+
+                // stop producing data as soon as the client is stalled.
+                let reason = tokio::select! {
+                    reason = produce(inner, tx, timeout) => reason,
+                    _ = stall => Some(CloseReason::Stalled),
+                };
+
+                if let Some(reason) = reason {
+                    let _ = closed.set(reason);
+                }
+            }
+        });
+
+        BackpressureStream {
+            inner: ReceiverStream::new(rx),
+            closed,
+            last_progress,
+            terminated: false,
+        }
+    }
+}
+
+/// Pushes the messages of the inner stream into the buffer.
+///
+/// Returns the reason the stream must be closed, if the client is too slow.
+async fn produce<S>(
+    inner: S,
+    tx: mpsc::Sender<ResponseItem>,
+    timeout: Option<Duration>,
+) -> Option<CloseReason>
+where
+    S: Stream<Item = ResponseItem> + Send + 'static,
+{
+    let mut inner = Box::pin(inner);
+    loop {
+        let item = tokio::select! {
+            _ = tx.closed() => return None,
+            item = inner.next() => item,
+        };
+
+        let Some(item) = item else {
+            return None;
+        };
+
+        let item = match tx.try_send(item) {
+            Ok(_) => continue,
+            Err(TrySendError::Closed(_)) => return None,
+            Err(TrySendError::Full(item)) => item,
+        };
+
+        let Some(timeout) = timeout else {
+            // Buffer full: wait for the client, however long it takes.
+            match tx.send(item).await {
+                Ok(_) => continue,
+                Err(_) => return None,
+            }
+        };
+
+        // Buffer full: give the client some time to catch up.
+        match tokio::time::timeout(timeout, tx.send(item)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) => return None,
+            Err(_) => {
+                warn!(timeout = ?timeout, "client not consuming data, closing stream");
+                new_closed_counter("stream_backpressure_closed").add(
+                    &o11y::Context::current(),
+                    1,
+                    &[],
+                );
+                return Some(CloseReason::SlowClient);
+            }
+        }
+    }
+}
+
+/// Returns once the client didn't read any of the messages waiting for it for
+/// longer than `stall_timeout`.
+async fn detect_stall(
+    tx: mpsc::Sender<ResponseItem>,
+    last_progress: Arc<Mutex<Instant>>,
+    stall_timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(stall_timeout / 4);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // when messages started waiting for the client.
+    let mut waiting_since: Option<Instant> = None;
+
+    loop {
+        interval.tick().await;
+
+        let has_waiting_messages = tx.capacity() < tx.max_capacity();
+        if !has_waiting_messages {
+            waiting_since = None;
+            continue;
+        }
+
+        let waiting_since = *waiting_since.get_or_insert_with(Instant::now);
+        let last_progress = *last_progress.lock().expect("last progress lock poisoned");
+        let stalled_for = Instant::now() - waiting_since.max(last_progress);
+        if stalled_for > stall_timeout {
+            warn!(stall_timeout = ?stall_timeout, "client not reading messages, closing stream");
+            new_closed_counter("stream_stalled_closed").add(&o11y::Context::current(), 1, &[]);
+            return;
+        }
+    }
+}
+
+impl Stream for BackpressureStream {
+    type Item = ResponseItem;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+
+        if *this.terminated {
+            return Poll::Ready(None);
+        }
+
+        // Don't deliver buffered data to a client that was too slow.
+        if let Some(reason) = this.closed.get() {
+            *this.terminated = true;
+            let status = match reason {
+                CloseReason::SlowClient => status_with_code(
+                    tonic::Code::Aborted,
+                    ErrorCode::SlowClient,
+                    "stream closed because client is not reading data",
+                ),
+                CloseReason::Stalled => status_with_code(
+                    tonic::Code::DeadlineExceeded,
+                    ErrorCode::DeadlineExceeded,
+                    "stream closed because client made no progress",
+                ),
+            };
+            return Poll::Ready(Some(Err(status)));
+        }
+
+        let item = this.inner.poll_next(cx);
+        if let Poll::Ready(Some(_)) = item {
+            *this
+                .last_progress
+                .lock()
+                .expect("last progress lock poisoned") = Instant::now();
+        }
+        item
+    }
+}
+
+fn new_closed_counter(name: &'static str) -> Counter<u64> {
+    let meter = o11y::meter("stream_data");
+    meter.u64_counter(name).init()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use apibara_core::node::v1alpha2::StreamDataResponse;
+    use futures::{stream, FutureExt, StreamExt};
+
+    use super::BackpressureStream;
+
+    #[tokio::test]
+    async fn test_pause_when_buffer_is_full() {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let inner = stream::repeat_with({
+            let produced = produced.clone();
+            move || {
+                produced.fetch_add(1, Ordering::SeqCst);
+                Ok(StreamDataResponse::default())
+            }
+        });
+
+        let mut stream = BackpressureStream::new(inner, 4, None, None);
+        assert!(stream.next().await.unwrap().is_ok());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // the buffer, the message being sent, and the message received.
+        assert!(produced.load(Ordering::SeqCst) <= 6);
+
+        // the stream is never closed without a timeout.
+        for _ in 0..10 {
+            assert!(stream.next().await.unwrap().is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_slow_client_after_timeout() {
+        let timeout = Duration::from_millis(100);
+        let inner = stream::repeat_with(|| Ok(StreamDataResponse::default()));
+
+        // the client doesn't read while the buffer is full.
+        let mut stream = BackpressureStream::new(inner, 4, Some(timeout), None);
+        tokio::time::sleep(timeout * 4).await;
+
+        // buffered data is not delivered to the slow client.
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Aborted);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_keep_client_that_catches_up() {
+        let timeout = Duration::from_millis(200);
+        let inner = stream::repeat_with(|| Ok(StreamDataResponse::default()));
+
+        // the buffer is full, but the client reads before the timeout.
+        let mut stream = BackpressureStream::new(inner, 4, Some(timeout), None);
+        for _ in 0..5 {
+            tokio::time::sleep(timeout / 4).await;
+            for _ in 0..4 {
+                assert!(stream.next().await.unwrap().is_ok());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_stalled_stream() {
+        let stall_timeout = Duration::from_millis(100);
+
+        // a client that reads all messages is never stalled, even if it's idle.
+        let inner =
+            stream::once(async { Ok(StreamDataResponse::default()) }).chain(stream::pending());
+        let mut stream = BackpressureStream::new(inner, 4, None, Some(stall_timeout));
+        assert!(stream.next().await.unwrap().is_ok());
+        tokio::time::sleep(stall_timeout * 4).await;
+        assert!(stream.next().now_or_never().is_none());
+
+        // a client that doesn't read the messages waiting for it is stalled.
+        let inner = stream::iter([Ok(StreamDataResponse::default())]).chain(stream::pending());
+        let mut stream = BackpressureStream::new(inner, 4, None, Some(stall_timeout));
+        tokio::time::sleep(stall_timeout * 4).await;
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+        assert!(stream.next().await.is_none());
+    }
+}
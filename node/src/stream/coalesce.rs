@@ -0,0 +1,146 @@
+//! Hold small finalized batches to send fewer, fuller batches.
+
+use std::time::{Duration, Instant};
+
+use apibara_core::node::v1alpha2::Data;
+
+/// How long data is held if the client doesn't specify it.
+pub const DEFAULT_MAX_BATCH_WAIT: Duration = Duration::from_secs(10);
+
+/// Longest time clients can ask to hold data for.
+pub const MAX_BATCH_WAIT: Duration = Duration::from_secs(600);
+
+/// Merges consecutive batches until they contain at least `min_batch_size` blocks.
+///
+/// Data is held for at most `max_wait`, so that streams that reached the chain
+/// head still send their data.
+#[derive(Debug)]
+pub struct DataCoalescer {
+    min_batch_size: usize,
+    max_wait: Duration,
+    held: Option<HeldData>,
+}
+
+#[derive(Debug)]
+struct HeldData {
+    data: Data,
+    since: Instant,
+}
+
+impl DataCoalescer {
+    pub fn new(min_batch_size: usize, max_wait: Duration) -> Self {
+        DataCoalescer {
+            min_batch_size,
+            max_wait,
+            held: None,
+        }
+    }
+
+    /// Adds the batch to the held data, and returns the data to send, if any.
+    ///
+    /// If `flush` is true, the held data is always returned.
+    pub fn push(&mut self, data: Data, flush: bool) -> Option<Data> {
+        let held = match self.held.take() {
+            // empty batches are handled by the stream, same as without coalescing.
+            None if data.data.is_empty() => return Some(data),
+            None => HeldData {
+                data,
+                since: Instant::now(),
+            },
+            Some(mut held) => {
+                held.data.end_cursor = data.end_cursor;
+                held.data.data.extend(data.data);
+                held.data.caught_up |= data.caught_up;
+                held
+            }
+        };
+
+        if flush
+            || held.data.data.len() >= self.min_batch_size
+            || held.since.elapsed() >= self.max_wait
+        {
+            return Some(held.data);
+        }
+
+        self.held = Some(held);
+        None
+    }
+
+    /// Returns the held data, if any.
+    pub fn take(&mut self) -> Option<Data> {
+        self.held.take().map(|held| held.data)
+    }
+
+    /// Returns when the held data must be sent, if any data is held.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.held
+            .as_ref()
+            .and_then(|held| held.since.checked_add(self.max_wait))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apibara_core::node::v1alpha2::{Cursor, Data};
+
+    use super::DataCoalescer;
+
+    fn new_data(start: u64, end: u64, blocks: usize) -> Data {
+        Data {
+            cursor: Some(Cursor {
+                order_key: start,
+                unique_key: Vec::default(),
+            }),
+            end_cursor: Some(Cursor {
+                order_key: end,
+                unique_key: Vec::default(),
+            }),
+            data: vec![vec![0]; blocks],
+            ..Data::default()
+        }
+    }
+
+    #[test]
+    fn test_coalesce_until_min_batch_size() {
+        let mut coalescer = DataCoalescer::new(3, Duration::from_secs(60));
+
+        // empty batches pass through if nothing is held.
+        let empty = coalescer.push(new_data(0, 10, 0), false).unwrap();
+        assert!(empty.data.is_empty());
+
+        assert!(coalescer.push(new_data(10, 20, 1), false).is_none());
+        assert!(coalescer.deadline().is_some());
+        assert!(coalescer.push(new_data(20, 30, 0), false).is_none());
+
+        let data = coalescer.push(new_data(30, 40, 2), false).unwrap();
+        assert_eq!(data.cursor.unwrap().order_key, 10);
+        assert_eq!(data.end_cursor.unwrap().order_key, 40);
+        assert_eq!(data.data.len(), 3);
+        assert!(coalescer.deadline().is_none());
+    }
+
+    #[test]
+    fn test_coalesce_flush() {
+        let mut coalescer = DataCoalescer::new(3, Duration::from_secs(60));
+
+        assert!(coalescer.push(new_data(0, 10, 1), false).is_none());
+        let data = coalescer.push(new_data(10, 20, 0), true).unwrap();
+        assert_eq!(data.end_cursor.unwrap().order_key, 20);
+        assert_eq!(data.data.len(), 1);
+
+        assert!(coalescer.push(new_data(20, 30, 1), false).is_none());
+        let data = coalescer.take().unwrap();
+        assert_eq!(data.cursor.unwrap().order_key, 20);
+        assert!(coalescer.take().is_none());
+    }
+
+    #[test]
+    fn test_coalesce_max_wait() {
+        let mut coalescer = DataCoalescer::new(3, Duration::ZERO);
+
+        let data = coalescer.push(new_data(0, 10, 1), false).unwrap();
+        assert_eq!(data.data.len(), 1);
+    }
+}
@@ -1,9 +1,13 @@
 use std::{
+    ops::RangeInclusive,
     pin::Pin,
     task::{self, Poll},
+    time::Duration,
 };
 
-use apibara_core::node::v1alpha2::{DataFinality, StreamDataRequest};
+use apibara_core::node::v1alpha2::{
+    BlockRange, Cursor as ProtoCursor, DataFinality, StreamDataRequest,
+};
 use futures::Stream;
 use pin_project::pin_project;
 use prost::Message;
@@ -11,11 +15,65 @@ use tracing::warn;
 
 use crate::core::Cursor;
 
-use super::error::StreamError;
+use super::{
+    coalesce::MAX_BATCH_WAIT,
+    error::StreamError,
+    progress::{filter_digest, ProgressTokenError, ProgressTokenSigner},
+};
 
 const MIN_BATCH_SIZE: usize = 1;
 const MAX_BATCH_SIZE: usize = 50;
 const DEFAULT_BATCH_SIZE: usize = 20;
+/// Maximum number of block ranges in a request.
+const MAX_BLOCK_RANGES: usize = 1000;
+/// Heartbeat intervals that clients can request, `0` disables heartbeats.
+const MIN_HEARTBEAT_INTERVAL_SECONDS: u64 = 1;
+const MAX_HEARTBEAT_INTERVAL_SECONDS: u64 = 600;
+
+/// Batch sizes that clients can request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BatchSizeLimits {
+    min: usize,
+    max: usize,
+    default: usize,
+}
+
+impl BatchSizeLimits {
+    /// Creates new limits, returns `None` unless `1 <= min <= default <= max`.
+    pub fn new(min: usize, max: usize, default: usize) -> Option<Self> {
+        if min == 0 || min > default || default > max {
+            return None;
+        }
+        Some(BatchSizeLimits { min, max, default })
+    }
+
+    pub fn min(&self) -> usize {
+        self.min
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// The batch size of streams that don't request one.
+    pub fn default_batch_size(&self) -> usize {
+        self.default
+    }
+
+    fn contains(&self, batch_size: u64) -> bool {
+        (self.min as u64..=self.max as u64).contains(&batch_size)
+    }
+}
+
+impl Default for BatchSizeLimits {
+    fn default() -> Self {
+        BatchSizeLimits {
+            min: MIN_BATCH_SIZE,
+            max: MAX_BATCH_SIZE,
+            default: DEFAULT_BATCH_SIZE,
+        }
+    }
+}
 
 #[derive(Default, Clone, Debug)]
 pub struct StreamConfiguration<C, F>
@@ -28,6 +86,38 @@ where
     pub finality: DataFinality,
     pub starting_cursor: Option<C>,
     pub filter: Vec<F>,
+    /// Flag data produced longer than this ago as stale.
+    pub max_data_age: Option<Duration>,
+    /// Flag the first batch that reaches the chain head.
+    pub notify_caught_up: bool,
+    /// Stop streaming after this cursor, inclusive.
+    pub end_cursor: Option<C>,
+    /// Heartbeat interval requested by the client, `Duration::ZERO` disables heartbeats.
+    ///
+    /// If `None`, the server default is used.
+    pub heartbeat_interval: Option<Duration>,
+    /// Hold finalized data until it contains at least this many blocks.
+    pub min_batch_size: Option<usize>,
+    /// Send held data after this time, even if smaller than `min_batch_size`.
+    ///
+    /// If `None`, the server default is used.
+    pub max_batch_wait: Option<Duration>,
+    /// Only send invalidate messages, never data.
+    ///
+    /// The filter is empty, since no data is produced.
+    pub invalidations_only: bool,
+    /// Start from the chain head, resolved by the cursor producer.
+    ///
+    /// The starting cursor is `None` when this is set.
+    pub start_from_latest: bool,
+    /// Send the chain head after the stream is configured.
+    pub notify_chain_head: bool,
+    /// Send the finalized head every time it advances.
+    pub notify_finalized: bool,
+    /// Only stream the blocks in these ranges, sorted and without overlaps.
+    ///
+    /// The end cursor is set to the last block in the ranges.
+    pub block_ranges: Vec<RangeInclusive<u64>>,
 }
 
 #[derive(Default)]
@@ -37,6 +127,10 @@ where
     F: Message + Default + Clone,
 {
     current: Option<StreamConfiguration<C, F>>,
+    /// Set after the client closed the stream without configuring it.
+    missing_request: bool,
+    progress_token_signer: Option<ProgressTokenSigner>,
+    batch_size_limits: BatchSizeLimits,
 }
 
 #[pin_project]
@@ -65,6 +159,18 @@ where
             state: Default::default(),
         }
     }
+
+    /// Accept progress tokens signed by the given signer as starting cursors.
+    pub fn with_progress_token_signer(mut self, signer: Option<ProgressTokenSigner>) -> Self {
+        self.state.progress_token_signer = signer;
+        self
+    }
+
+    /// Only accept batch sizes within the given limits.
+    pub fn with_batch_size_limits(mut self, limits: BatchSizeLimits) -> Self {
+        self.state.batch_size_limits = limits;
+        self
+    }
 }
 
 impl<C, F> StreamConfigurationStreamState<C, F>
@@ -76,8 +182,25 @@ where
         &mut self,
         request: StreamDataRequest,
     ) -> Result<StreamConfiguration<C, F>, StreamError> {
-        let batch_size = request.batch_size.unwrap_or(DEFAULT_BATCH_SIZE as u64) as usize;
-        let batch_size = batch_size.clamp(MIN_BATCH_SIZE, MAX_BATCH_SIZE);
+        if self.current.is_none() && request == StreamDataRequest::default() {
+            return Err(StreamError::invalid_request(
+                "initial request is empty".to_string(),
+            ));
+        }
+
+        let limits = self.batch_size_limits;
+        let batch_size = request
+            .batch_size
+            .unwrap_or(limits.default_batch_size() as u64);
+        if !limits.contains(batch_size) {
+            return Err(StreamError::invalid_request(format!(
+                "batch size must be between {} and {}, got {}",
+                limits.min(),
+                limits.max(),
+                batch_size
+            )));
+        }
+        let batch_size = batch_size as usize;
 
         let finality = request
             .finality
@@ -86,7 +209,10 @@ where
 
         let stream_id = request.stream_id.unwrap_or_default();
 
-        let filter: Vec<F> = if request.filter.is_empty() {
+        let filter: Vec<F> = if request.invalidations_only {
+            // no data is produced, so the stream doesn't filter anything.
+            Vec::default()
+        } else if request.filter.is_empty() {
             if request.multi_filter.is_empty() {
                 return Err(StreamError::invalid_request(
                     "missing filter configuration".to_string(),
@@ -115,24 +241,161 @@ where
             vec![filter]
         };
 
-        let starting_cursor = match request.starting_cursor {
+        if request.start_from_latest
+            && (request.starting_cursor.is_some() || !request.progress_token.is_empty())
+        {
+            return Err(StreamError::invalid_request(
+                "start from latest conflicts with the starting cursor".to_string(),
+            ));
+        }
+
+        let starting_cursor = if !request.progress_token.is_empty() {
+            // progress tokens take precedence over the starting cursor.
+            let cursor = self
+                .progress_token_signer
+                .as_ref()
+                .ok_or(ProgressTokenError::Disabled)
+                .and_then(|signer| signer.verify(&request.progress_token, &filter_digest(&filter)))
+                .map_err(|err| StreamError::invalid_cursor(err.to_string()))?;
+            match C::from_proto(&cursor) {
+                Some(cursor) => Some(cursor),
+                None => {
+                    return Err(StreamError::invalid_cursor(
+                        "invalid progress token cursor".to_string(),
+                    ));
+                }
+            }
+        } else {
+            match request.starting_cursor {
+                None => None,
+                Some(starting_cursor) => match C::from_proto(&starting_cursor) {
+                    Some(cursor) => Some(cursor),
+                    None => {
+                        return Err(StreamError::invalid_cursor(
+                            "invalid starting cursor".to_string(),
+                        ));
+                    }
+                },
+            }
+        };
+
+        let mut end_cursor = match request.end_cursor {
             None => None,
-            Some(starting_cursor) => match C::from_proto(&starting_cursor) {
+            Some(end_cursor) => match C::from_proto(&end_cursor) {
                 Some(cursor) => Some(cursor),
                 None => {
-                    return Err(StreamError::invalid_request(
-                        "invalid starting cursor".to_string(),
+                    return Err(StreamError::invalid_cursor(
+                        "invalid end cursor".to_string(),
                     ));
                 }
             },
         };
 
+        let mut block_ranges = parse_block_ranges(&request.block_ranges)?;
+        if !block_ranges.is_empty() {
+            if finality != DataFinality::DataStatusFinalized {
+                return Err(StreamError::invalid_request(
+                    "block ranges are only supported for finalized data".to_string(),
+                ));
+            }
+
+            // the stream ends after the last block requested, before the end cursor.
+            let end = end_cursor
+                .as_ref()
+                .map(|cursor| cursor.to_proto().order_key)
+                .unwrap_or(u64::MAX);
+            block_ranges.retain(|range| *range.start() <= end);
+            let Some(last) = block_ranges.last_mut() else {
+                return Err(StreamError::invalid_request(
+                    "all block ranges are after the end cursor".to_string(),
+                ));
+            };
+            *last = *last.start()..=u64::min(*last.end(), end);
+
+            let last_block = ProtoCursor {
+                order_key: *last.end(),
+                unique_key: Vec::default(),
+            };
+            end_cursor = match C::from_proto(&last_block) {
+                Some(cursor) => Some(cursor),
+                None => {
+                    return Err(StreamError::invalid_cursor(
+                        "invalid block range end".to_string(),
+                    ));
+                }
+            };
+        }
+
+        if let (Some(starting_cursor), Some(end_cursor)) = (&starting_cursor, &end_cursor) {
+            let start = starting_cursor.to_proto().order_key;
+            let end = end_cursor.to_proto().order_key;
+            if end <= start {
+                return Err(StreamError::invalid_request(format!(
+                    "end cursor {} must be after the starting cursor {}",
+                    end, start
+                )));
+            }
+        }
+
+        let max_data_age = request.max_data_age_seconds.map(Duration::from_secs);
+        let heartbeat_interval = request.heartbeat_interval_seconds.map(|seconds| {
+            if seconds == 0 {
+                return Duration::ZERO;
+            }
+            let seconds = seconds.clamp(
+                MIN_HEARTBEAT_INTERVAL_SECONDS,
+                MAX_HEARTBEAT_INTERVAL_SECONDS,
+            );
+            Duration::from_secs(seconds)
+        });
+
+        let min_batch_size = match request.min_batch_size {
+            None => None,
+            Some(min_batch_size) => {
+                if finality != DataFinality::DataStatusFinalized {
+                    return Err(StreamError::invalid_request(
+                        "min batch size is only supported for finalized data".to_string(),
+                    ));
+                }
+                if !limits.contains(min_batch_size) {
+                    return Err(StreamError::invalid_request(format!(
+                        "min batch size must be between {} and {}, got {}",
+                        limits.min(),
+                        limits.max(),
+                        min_batch_size
+                    )));
+                }
+                Some(min_batch_size as usize)
+            }
+        };
+        let max_batch_wait = match request.max_batch_wait_seconds.map(Duration::from_secs) {
+            Some(max_batch_wait) if max_batch_wait > MAX_BATCH_WAIT => {
+                return Err(StreamError::invalid_request(format!(
+                    "max batch wait must be at most {} seconds, got {}",
+                    MAX_BATCH_WAIT.as_secs(),
+                    max_batch_wait.as_secs()
+                )));
+            }
+            max_batch_wait => max_batch_wait,
+        };
+
         let configuration = StreamConfiguration {
             batch_size,
             finality,
             stream_id,
             filter,
             starting_cursor,
+            max_data_age,
+            notify_caught_up: request.notify_caught_up,
+            end_cursor,
+            heartbeat_interval,
+            min_batch_size,
+            max_batch_wait,
+            invalidations_only: request.invalidations_only,
+            start_from_latest: request.start_from_latest,
+            notify_chain_head: request.notify_chain_head,
+            notify_finalized: request.notify_finalized,
+            block_ranges,
         };
 
         self.current = Some(configuration.clone());
@@ -141,6 +404,42 @@ where
     }
 }
 
+/// Returns the block ranges sorted by their first block, merging the ranges
+/// that overlap or are next to each other.
+fn parse_block_ranges(ranges: &[BlockRange]) -> Result<Vec<RangeInclusive<u64>>, StreamError> {
+    if ranges.len() > MAX_BLOCK_RANGES {
+        return Err(StreamError::invalid_request(format!(
+            "at most {} block ranges are supported, got {}",
+            MAX_BLOCK_RANGES,
+            ranges.len()
+        )));
+    }
+
+    let mut sorted = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        if range.end < range.start {
+            return Err(StreamError::invalid_request(format!(
+                "block range {}-{} ends before it starts",
+                range.start, range.end
+            )));
+        }
+        sorted.push(range.start..=range.end);
+    }
+    sorted.sort_by_key(|range| *range.start());
+
+    let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(sorted.len());
+    for range in sorted {
+        match merged.last_mut() {
+            Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                *last = *last.start()..=u64::max(*last.end(), *range.end());
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    Ok(merged)
+}
+
 impl<C, F, S, E> Stream for StreamConfigurationStream<C, F, S, E>
 where
     C: Cursor,
@@ -152,8 +451,19 @@ where
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.project();
+        if this.state.missing_request {
+            return Poll::Ready(None);
+        }
+
         match this.inner.poll_next(cx) {
             Poll::Pending => Poll::Pending,
+            Poll::Ready(None) if this.state.current.is_none() => {
+                // a client that never configures the stream made a protocol
+                // error, without this the stream would wait forever.
+                this.state.missing_request = true;
+                let err = StreamError::invalid_request("missing initial request".to_string());
+                Poll::Ready(Some(Err(err)))
+            }
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Ready(Some(Err(err))) => {
                 warn!(err = ?err, "configuration stream error");
@@ -167,3 +477,280 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apibara_core::node::v1alpha2::{
+        BlockRange, Cursor as ProtoCursor, DataFinality, StreamDataRequest,
+    };
+    use futures::{stream, FutureExt, StreamExt};
+    use prost::Message;
+
+    use crate::core::Cursor;
+
+    use super::{BatchSizeLimits, StreamConfigurationStream, StreamConfigurationStreamState};
+
+    impl Cursor for ProtoCursor {
+        fn from_proto(cursor: &ProtoCursor) -> Option<Self> {
+            Some(cursor.clone())
+        }
+
+        fn to_proto(&self) -> ProtoCursor {
+            self.clone()
+        }
+    }
+
+    fn new_request(batch_size: Option<u64>) -> StreamDataRequest {
+        StreamDataRequest {
+            batch_size,
+            filter: ProtoCursor {
+                order_key: 1,
+                unique_key: Vec::default(),
+            }
+            .encode_to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_end_cursor_before_starting_cursor() {
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor>::default();
+        let cursor = |order_key| ProtoCursor {
+            order_key,
+            unique_key: Vec::default(),
+        };
+
+        let mut request = new_request(None);
+        request.starting_cursor = Some(cursor(10));
+        request.end_cursor = Some(cursor(20));
+        let configuration = state.handle_request(request.clone()).unwrap();
+        assert_eq!(configuration.end_cursor, Some(cursor(20)));
+
+        request.end_cursor = Some(cursor(10));
+        let err = state.handle_request(request).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("must be after the starting cursor"));
+    }
+
+    #[test]
+    fn test_heartbeat_interval_is_clamped() {
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor>::default();
+
+        let mut request = new_request(None);
+        request.heartbeat_interval_seconds = Some(u64::MAX);
+        let configuration = state.handle_request(request.clone()).unwrap();
+        assert_eq!(
+            configuration.heartbeat_interval,
+            Some(Duration::from_secs(600))
+        );
+
+        request.heartbeat_interval_seconds = Some(0);
+        let configuration = state.handle_request(request).unwrap();
+        assert_eq!(configuration.heartbeat_interval, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_batch_size_out_of_range() {
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor>::default();
+
+        for batch_size in [1, 50] {
+            let configuration = state.handle_request(new_request(Some(batch_size))).unwrap();
+            assert_eq!(configuration.batch_size, batch_size as usize);
+        }
+
+        let configuration = state.handle_request(new_request(None)).unwrap();
+        assert_eq!(configuration.batch_size, 20);
+
+        for batch_size in [0, 51, 500] {
+            let err = state
+                .handle_request(new_request(Some(batch_size)))
+                .unwrap_err();
+            assert!(err.to_string().contains("between 1 and 50"));
+        }
+    }
+
+    #[test]
+    fn test_batch_size_limits() {
+        assert!(BatchSizeLimits::new(0, 10, 5).is_none());
+        assert!(BatchSizeLimits::new(5, 10, 1).is_none());
+        assert!(BatchSizeLimits::new(1, 10, 20).is_none());
+
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor> {
+            batch_size_limits: BatchSizeLimits::new(1, 200, 100).unwrap(),
+            ..Default::default()
+        };
+
+        let configuration = state.handle_request(new_request(None)).unwrap();
+        assert_eq!(configuration.batch_size, 100);
+        let configuration = state.handle_request(new_request(Some(200))).unwrap();
+        assert_eq!(configuration.batch_size, 200);
+        let err = state.handle_request(new_request(Some(201))).unwrap_err();
+        assert!(err.to_string().contains("between 1 and 200"));
+    }
+
+    #[test]
+    fn test_min_batch_size() {
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor>::default();
+
+        let mut request = new_request(None);
+        request.min_batch_size = Some(10);
+        let err = state.handle_request(request.clone()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("only supported for finalized data"));
+
+        request.finality = Some(DataFinality::DataStatusFinalized as i32);
+        let configuration = state.handle_request(request.clone()).unwrap();
+        assert_eq!(configuration.min_batch_size, Some(10));
+
+        request.min_batch_size = Some(0);
+        let err = state.handle_request(request).unwrap_err();
+        assert!(err.to_string().contains("between 1 and 50"));
+    }
+
+    #[test]
+    fn test_max_batch_wait() {
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor>::default();
+
+        let mut request = new_request(None);
+        request.finality = Some(DataFinality::DataStatusFinalized as i32);
+        request.min_batch_size = Some(10);
+        request.max_batch_wait_seconds = Some(600);
+        let configuration = state.handle_request(request.clone()).unwrap();
+        assert_eq!(configuration.max_batch_wait, Some(Duration::from_secs(600)));
+
+        request.max_batch_wait_seconds = Some(u64::MAX);
+        let err = state.handle_request(request).unwrap_err();
+        assert!(err.to_string().contains("at most 600 seconds"));
+    }
+
+    #[test]
+    fn test_invalidations_only() {
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor>::default();
+
+        let request = StreamDataRequest {
+            invalidations_only: true,
+            ..Default::default()
+        };
+        let configuration = state.handle_request(request).unwrap();
+        assert!(configuration.invalidations_only);
+        assert!(configuration.filter.is_empty());
+
+        // the filter is ignored.
+        let mut request = new_request(None);
+        request.invalidations_only = true;
+        let configuration = state.handle_request(request).unwrap();
+        assert!(configuration.filter.is_empty());
+
+        let err = state
+            .handle_request(StreamDataRequest::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("missing filter configuration"));
+    }
+
+    #[test]
+    fn test_start_from_latest() {
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor>::default();
+
+        let mut request = new_request(None);
+        request.start_from_latest = true;
+        let configuration = state.handle_request(request.clone()).unwrap();
+        assert!(configuration.start_from_latest);
+        assert!(configuration.starting_cursor.is_none());
+
+        request.starting_cursor = Some(ProtoCursor {
+            order_key: 10,
+            unique_key: Vec::default(),
+        });
+        let err = state.handle_request(request).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("conflicts with the starting cursor"));
+    }
+
+    #[test]
+    fn test_missing_initial_request() {
+        let requests = stream::empty::<Result<StreamDataRequest, std::io::Error>>();
+        let mut configuration =
+            StreamConfigurationStream::<ProtoCursor, ProtoCursor, _, _>::new(requests);
+        let err = configuration
+            .next()
+            .now_or_never()
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert!(err.to_string().contains("missing initial request"));
+        assert_eq!(err.into_status().code(), tonic::Code::InvalidArgument);
+        assert!(configuration.next().now_or_never().unwrap().is_none());
+
+        // closing the stream after configuring it is not an error.
+        let requests = stream::iter(vec![Ok::<_, std::io::Error>(new_request(None))]);
+        let mut configuration =
+            StreamConfigurationStream::<ProtoCursor, ProtoCursor, _, _>::new(requests);
+        assert!(configuration
+            .next()
+            .now_or_never()
+            .unwrap()
+            .unwrap()
+            .is_ok());
+        assert!(configuration.next().now_or_never().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_empty_initial_request() {
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor>::default();
+
+        let err = state
+            .handle_request(StreamDataRequest::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("initial request is empty"));
+
+        state.handle_request(new_request(None)).unwrap();
+        let err = state
+            .handle_request(StreamDataRequest::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("missing filter configuration"));
+    }
+
+    #[test]
+    fn test_block_ranges() {
+        let mut state = StreamConfigurationStreamState::<ProtoCursor, ProtoCursor>::default();
+        let range = |start, end| BlockRange { start, end };
+
+        let mut request = new_request(None);
+        request.block_ranges = vec![range(9000, 9000), range(100, 100), range(500, 500)];
+        let err = state.handle_request(request.clone()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("only supported for finalized data"));
+
+        request.finality = Some(DataFinality::DataStatusFinalized as i32);
+        let configuration = state.handle_request(request.clone()).unwrap();
+        assert_eq!(
+            configuration.block_ranges,
+            vec![100..=100, 500..=500, 9000..=9000]
+        );
+        assert_eq!(configuration.end_cursor.unwrap().order_key, 9000);
+
+        // overlapping and adjacent ranges are merged, and the end cursor clips them.
+        request.block_ranges = vec![range(10, 20), range(15, 30), range(31, 40), range(50, 60)];
+        request.end_cursor = Some(ProtoCursor {
+            order_key: 35,
+            unique_key: Vec::default(),
+        });
+        let configuration = state.handle_request(request.clone()).unwrap();
+        assert_eq!(configuration.block_ranges, vec![10..=35]);
+        assert_eq!(configuration.end_cursor.unwrap().order_key, 35);
+
+        request.block_ranges = vec![range(50, 60)];
+        let err = state.handle_request(request.clone()).unwrap_err();
+        assert!(err.to_string().contains("after the end cursor"));
+
+        request.block_ranges = vec![range(20, 10)];
+        let err = state.handle_request(request).unwrap_err();
+        assert!(err.to_string().contains("ends before it starts"));
+    }
+}
@@ -1,14 +1,18 @@
 use core::num::NonZeroU32;
-use std::time::{Duration, Instant};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime},
+};
 
 use apibara_core::node::v1alpha2::{
-    stream_data_response, Data, DataFinality, Heartbeat, Invalidate, StreamDataResponse,
+    stream_data_response, Cursor as ProtoCursor, Data, DataFinality, FinalizedHead, Heartbeat,
+    Invalidate, ServerInfo, StreamDataResponse, STREAM_PROTOCOL_VERSION,
 };
 use async_stream::stream;
 use futures::{stream::FusedStream, Stream, StreamExt};
 use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
 use prost::Message;
-use tracing::{debug_span, instrument, trace, Instrument};
+use tracing::{debug, debug_span, instrument, trace, warn, Instrument};
 
 use crate::{
     core::Cursor,
@@ -17,10 +21,17 @@ use crate::{
 };
 
 use super::{
-    BatchProducer, CursorProducer, IngestionMessage, IngestionResponse, ReconfigureResponse,
-    StreamConfiguration, StreamError,
+    coalesce::{DataCoalescer, DEFAULT_MAX_BATCH_WAIT},
+    metrics::StreamMetrics,
+    progress::filter_digest,
+    response::{LatestCursor, RequestedHeartbeat},
+    split::{exceeds_max_message_size, split_data},
+    summary::SummaryTracker,
+    BatchProducer, CursorProducer, IngestionMessage, IngestionResponse, ProgressTokenSigner,
+    ReconfigureResponse, StreamConfiguration, StreamError,
 };
 
+#[allow(clippy::too_many_arguments)]
 pub fn new_data_stream<C, F, B, M>(
     configuration_stream: impl Stream<Item = Result<StreamConfiguration<C, F>, StreamError>> + Unpin,
     ingestion_stream: impl Stream<Item = Result<IngestionMessage<C>, StreamError>> + Unpin,
@@ -29,6 +40,10 @@ pub fn new_data_stream<C, F, B, M>(
     blocks_per_second_quota: u32,
     meter: M,
     quota_client: QuotaClient,
+    progress_token_signer: Option<ProgressTokenSigner>,
+    latest_cursor: LatestCursor,
+    requested_heartbeat: RequestedHeartbeat,
+    max_message_size: Option<usize>,
 ) -> impl Stream<Item = Result<StreamDataResponse, StreamError>>
 where
     C: Cursor + Send + Sync,
@@ -40,10 +55,15 @@ where
     let mut ingestion_stream = ingestion_stream.fuse();
 
     let mut limiter = new_rate_limiter(blocks_per_second_quota, 1);
+    let metrics = StreamMetrics::new();
 
     // try_stream! doesn't work with tokio::select! so we have to use stream! and helper functions.
     Box::pin(stream! {
+        // the stream is active until dropped.
+        let _active_stream = metrics.stream_started();
         let mut stream_id = 0;
+        let mut finality = DataFinality::DataStatusUnknown;
+        let mut batch_size = 0;
         let mut has_configuration = false;
         let mut last_batch_sent = Instant::now();
         // Send a batch (no matter if empty or not) at least once every this interval.
@@ -54,7 +74,29 @@ where
 
         let mut data_units = 0u64;
 
-        match quota_client.check().await.map_err(StreamError::internal)? {
+        let mut progress_token_minter = progress_token_signer.map(|signer| signer.minter());
+        let mut current_filter_digest = Vec::default();
+        // The last cursor the client knows about, used to compute the invalidated range.
+        let mut previous_head: Option<ProtoCursor> = None;
+        // Flag data older than this as stale.
+        let mut max_data_age: Option<Duration> = None;
+        // Flag the next batch that reaches the chain head.
+        let mut notify_caught_up = false;
+        // End the stream after sending data up to this block.
+        let mut end_order_key: Option<u64> = None;
+        // Hold small finalized batches, if requested by the client.
+        let mut coalescer: Option<DataCoalescer> = None;
+        // Data sent since the stream was configured, summarized when it reaches the end cursor.
+        let mut summary = SummaryTracker::default();
+        // Follow the chain without producing data, to only send invalidate messages.
+        let mut invalidations_only = false;
+        // Parts of a batch that was split because it was too large, sent before producing new data.
+        let mut batch_parts: VecDeque<(Data, DataFinality)> = VecDeque::default();
+        // Send the finalized head when it advances, if requested by the client.
+        let mut notify_finalized = false;
+        let mut last_finalized: Option<u64> = None;
+
+        match quota_client.check().await.map_err(StreamError::unavailable)? {
             QuotaStatus::Ok => {},
             QuotaStatus::Exceeded => {
                 yield Err(StreamError::quota_exceeded());
@@ -67,14 +109,27 @@ where
             // any data until a configuration is sent, it will result in the
             // client waiting for the first heartbeat message.
             // To avoid this, we send a heartbeat message as soon as possible.
-            use stream_data_response::Message;
+            // This message also tells clients which protocol version the server speaks.
+            let heartbeat = Heartbeat {
+                server_info: Some(ServerInfo {
+                    protocol_version: STREAM_PROTOCOL_VERSION,
+                    server_version: env!("CARGO_PKG_VERSION").to_string(),
+                }),
+                cursor: None,
+                chain_head: None,
+            };
+            metrics.record_heartbeat(finality);
             yield Ok(StreamDataResponse {
                 stream_id,
-                message: Some(Message::Heartbeat(Heartbeat::default())),
+                message: Some(stream_data_response::Message::Heartbeat(heartbeat)),
             });
         }
 
         loop {
+            // Data to send to the client, set by the branches below.
+            let mut next_data: Option<(Data, DataFinality, bool)> = None;
+            let hold_deadline = coalescer.as_ref().and_then(DataCoalescer::deadline);
+
             tokio::select! {
                 // check streams in order.
                 // always check configuration stream first since any change to configuration will
@@ -87,28 +142,68 @@ where
                 configuration_message = configuration_stream.select_next_some() => {
                     has_configuration = true;
                     match handle_configuration_message(&mut cursor_producer, &mut batch_producer, configuration_message).await {
-                        Ok((new_stream_id, batch_size, configure_response)) => {
-                            stream_id = new_stream_id;
-                            limiter = new_rate_limiter(blocks_per_second_quota, batch_size);
+                        Ok((configuration, configure_response)) => {
+                            stream_id = configuration.stream_id;
+                            finality = configuration.finality;
+                            latest_cursor.set_finality(finality);
+                            batch_size = configuration.batch_size;
+                            current_filter_digest = filter_digest(&configuration.filter);
+                            previous_head = configuration.starting_cursor.map(|c| c.to_proto());
+                            latest_cursor.update(previous_head.clone());
+                            max_data_age = configuration.max_data_age;
+                            invalidations_only = configuration.invalidations_only;
+                            notify_caught_up = configuration.notify_caught_up;
+                            notify_finalized = configuration.notify_finalized;
+                            end_order_key = configuration.end_cursor.as_ref().map(|c| c.to_proto().order_key);
+                            requested_heartbeat.request(configuration.heartbeat_interval);
+                            // held data was produced with the previous configuration.
+                            coalescer = configuration.min_batch_size.map(|min_batch_size| {
+                                let max_wait = configuration.max_batch_wait.unwrap_or(DEFAULT_MAX_BATCH_WAIT);
+                                DataCoalescer::new(min_batch_size, max_wait)
+                            });
+                            summary = SummaryTracker::default();
+                            batch_parts.clear();
+                            limiter = new_rate_limiter(blocks_per_second_quota, configuration.batch_size);
                             // send invalidate message if the specified cursor is no longer valid.
                             match configure_response {
                                 ReconfigureResponse::Ok => {},
                                 ReconfigureResponse::MissingStartingCursor => {
-                                    yield Err(StreamError::invalid_request("the specified starting cursor doesn't exist".to_string()));
+                                    yield Err(StreamError::cursor_out_of_range("the specified starting cursor doesn't exist".to_string()));
                                     break;
                                 },
                                 ReconfigureResponse::Invalidate(cursor) => {
-                                    use stream_data_response::Message;
-                                    let message = Invalidate {
-                                        cursor: Some(cursor.to_proto()),
-                                    };
+                                    let message = new_invalidate_message(&cursor, previous_head.replace(cursor.to_proto()));
+                                    latest_cursor.update(Some(cursor.to_proto()));
 
-                                    yield Ok(StreamDataResponse {
+                                    let response = StreamDataResponse {
                                         stream_id,
-                                        message: Some(Message::Invalidate(message)),
-                                    });
+                                        message: Some(stream_data_response::Message::Invalidate(message)),
+                                    };
+                                    metrics.record_message(finality, response.encoded_len());
+                                    yield Ok(response);
                                 },
                             };
+
+                            if configuration.notify_chain_head {
+                                match cursor_producer.chain_head() {
+                                    Ok(chain_head) => {
+                                        let heartbeat = Heartbeat {
+                                            chain_head: Some(chain_head.to_proto()),
+                                            cursor: latest_cursor.get(),
+                                            ..Heartbeat::default()
+                                        };
+                                        metrics.record_heartbeat(finality);
+                                        yield Ok(StreamDataResponse {
+                                            stream_id,
+                                            message: Some(stream_data_response::Message::Heartbeat(heartbeat)),
+                                        });
+                                    },
+                                    Err(err) => {
+                                        yield Err(err);
+                                        break;
+                                    },
+                                }
+                            }
                         },
                         Err(err) => {
                             yield Err(err);
@@ -118,17 +213,27 @@ where
                 },
 
                 ingestion_message = ingestion_stream.select_next_some() => {
+                    let finalized = match &ingestion_message {
+                        Ok(IngestionMessage::Finalized(cursor)) if notify_finalized => Some(cursor.to_proto()),
+                        _ => None,
+                    };
+
                     match handle_ingestion_message(&mut cursor_producer, ingestion_message).await {
                         Ok(IngestionResponse::Invalidate(cursor)) => {
-                            use stream_data_response::Message;
-                            let message = Invalidate {
-                                cursor: Some(cursor.to_proto()),
-                            };
+                            // parts of a split batch after the cursor are no longer valid.
+                            let order_key = cursor.to_proto().order_key;
+                            batch_parts.retain(|(data, _)| {
+                                data.end_cursor.as_ref().map(|end| end.order_key <= order_key).unwrap_or(false)
+                            });
+                            let message = new_invalidate_message(&cursor, previous_head.replace(cursor.to_proto()));
+                            latest_cursor.update(Some(cursor.to_proto()));
 
-                            yield Ok(StreamDataResponse {
+                            let response = StreamDataResponse {
                                 stream_id,
-                                message: Some(Message::Invalidate(message)),
-                            });
+                                message: Some(stream_data_response::Message::Invalidate(message)),
+                            };
+                            metrics.record_message(finality, response.encoded_len());
+                            yield Ok(response);
                         },
                         Ok(IngestionResponse::Ok) => {
                             // nothing to do.
@@ -140,57 +245,182 @@ where
                             break;
                         },
                     }
+
+                    // sent before the data of the new finalized block, since the
+                    // cursor producer only produces it after this message.
+                    if let Some(cursor) = finalized {
+                        if last_finalized.map(|last| cursor.order_key > last).unwrap_or(true) {
+                            last_finalized = Some(cursor.order_key);
+                            let response = StreamDataResponse {
+                                stream_id,
+                                message: Some(stream_data_response::Message::Finalized(FinalizedHead {
+                                    cursor: Some(cursor),
+                                })),
+                            };
+                            metrics.record_message(finality, response.encoded_len());
+                            yield Ok(response);
+                        }
+                    }
                 },
 
-                batch_cursor = cursor_producer.select_next_some(), if has_configuration => {
-                    use stream_data_response::Message;
-
-                    match handle_batch_cursor(&mut cursor_producer, &mut batch_producer, batch_cursor, &meter, &limiter).await {
-                        Ok((data, finality)) => {
-                            let should_send_data =
-                                if !data.data.is_empty() || finality == DataFinality::DataStatusAccepted {
-                                    true
-                                } else {
-                                    last_batch_sent.elapsed() > max_batch_interval
-                                };
-
-                            if !should_send_data {
-                                trace!("skip empty batch");
-                                continue
-                            }
+                batch_cursor = cursor_producer.select_next_some(), if has_configuration && batch_parts.is_empty() => {
+                    if invalidations_only {
+                        // the stream still moves forward, so that it's invalidated when the chain reorganizes.
+                        match batch_cursor {
+                            Ok(batch_cursor) => {
+                                let end_cursor = batch_end_cursor(&batch_cursor).map(|cursor| cursor.to_proto());
+                                latest_cursor.update(end_cursor.clone());
+                                let reached_end = reached_end_cursor(end_order_key, end_cursor.as_ref());
+                                previous_head = end_cursor;
+                                if reached_end {
+                                    debug!(end_cursor = ?previous_head, "stream reached end cursor");
+                                    let response = StreamDataResponse {
+                                        stream_id,
+                                        message: Some(stream_data_response::Message::Summary(summary.summary())),
+                                    };
+                                    metrics.record_message(finality, response.encoded_len());
+                                    yield Ok(response);
+                                    break;
+                                }
+                            },
+                            Err(err) => {
+                                yield Err(err);
+                                break;
+                            },
+                        }
+                    } else {
+                        match handle_batch_cursor(&mut cursor_producer, &mut batch_producer, batch_cursor, &meter, &limiter, max_message_size).await {
+                            Ok((parts, data_finality)) => {
+                                batch_parts.extend(parts.into_iter().map(|data| (data, data_finality)));
+                            },
+                            Err(err) => {
+                                yield Err(err);
+                                break;
+                            },
+                        }
+                    }
+                }
 
-                            data_units += data.data.len() as u64;
+                _ = sleep_until(hold_deadline), if hold_deadline.is_some() => {
+                    if let Some(data) = coalescer.as_mut().and_then(DataCoalescer::take) {
+                        trace!("sending held data");
+                        next_data = Some((data, DataFinality::DataStatusFinalized, false));
+                    }
+                }
 
-                            if last_quota_sent.elapsed() > quota_interval {
-                                match quota_client.update_and_check(data_units).await {
-                                    Ok(QuotaStatus::Ok) => {},
-                                    Ok(QuotaStatus::Exceeded) => {
-                                        yield Err(StreamError::quota_exceeded());
-                                        break;
-                                    },
-                                    Err(err) => {
-                                        yield Err(StreamError::internal(err));
-                                        break;
-                                    }
-                                }
+                // send the next part of a split batch once there are no other messages.
+                _ = futures::future::ready(()), if !batch_parts.is_empty() => {}
+            }
 
-                                data_units = 0;
-                                last_quota_sent = Instant::now();
-                            }
+            if next_data.is_none() {
+                if let Some((mut data, data_finality)) = batch_parts.pop_front() {
+                    if notify_caught_up {
+                        let end_cursor = data.end_cursor.as_ref().and_then(C::from_proto);
+                        if end_cursor.map(|cursor| cursor_producer.is_at_head(&cursor)).unwrap_or(false) {
+                            debug!(end_cursor = ?data.end_cursor, "stream caught up with chain head");
+                            data.caught_up = true;
+                            notify_caught_up = false;
+                        }
+                    }
 
-                            last_batch_sent = Instant::now();
-                            yield Ok(StreamDataResponse {
-                                stream_id,
-                                message: Some(Message::Data(data)),
-                            });
+                    let reached_end = reached_end_cursor(end_order_key, data.end_cursor.as_ref());
+
+                    match coalescer.as_mut() {
+                        Some(coalescer) if data_finality == DataFinality::DataStatusFinalized => {
+                            // held data is not sent yet, so the latest cursor doesn't move.
+                            // parts of a split batch are not merged again.
+                            let flush = data.caught_up || reached_end || !batch_parts.is_empty();
+                            if let Some(data) = coalescer.push(data, flush) {
+                                next_data = Some((data, data_finality, reached_end));
+                            }
                         },
-                        Err(err) => {
-                            yield Err(err);
-                            break;
+                        _ => {
+                            next_data = Some((data, data_finality, reached_end));
                         },
                     }
                 }
             }
+
+            let Some((mut data, data_finality, reached_end)) = next_data else {
+                continue;
+            };
+
+            let should_send_data =
+                if !data.data.is_empty() || data_finality == DataFinality::DataStatusAccepted || data.caught_up || reached_end {
+                    true
+                } else {
+                    last_batch_sent.elapsed() > max_batch_interval
+                };
+
+            // the stream reached this cursor even if the batch is not sent.
+            latest_cursor.update(data.end_cursor.clone());
+
+            if !should_send_data {
+                trace!("skip empty batch");
+                continue
+            }
+
+            data_units += data.data.len() as u64;
+
+            if last_quota_sent.elapsed() > quota_interval {
+                match quota_client.update_and_check(data_units).await {
+                    Ok(QuotaStatus::Ok) => {},
+                    Ok(QuotaStatus::Exceeded) => {
+                        yield Err(StreamError::quota_exceeded());
+                        break;
+                    },
+                    Err(err) => {
+                        yield Err(StreamError::unavailable(err));
+                        break;
+                    }
+                }
+
+                data_units = 0;
+                last_quota_sent = Instant::now();
+            }
+
+            if let Some(max_data_age) = max_data_age {
+                match data_age(&batch_producer, data.end_cursor.as_ref()) {
+                    Ok(Some(age)) if age > max_data_age => {
+                        debug!(age = ?age, max_data_age = ?max_data_age, "sending stale data");
+                        data.stale = true;
+                    },
+                    Ok(_) => {},
+                    Err(err) => {
+                        yield Err(err);
+                        break;
+                    }
+                }
+            }
+
+            if let (Some(minter), Some(end_cursor)) = (progress_token_minter.as_mut(), data.end_cursor.as_ref()) {
+                if let Some(token) = minter.maybe_mint(end_cursor, &current_filter_digest) {
+                    data.progress_token = token;
+                }
+            }
+
+            last_batch_sent = Instant::now();
+            previous_head = data.end_cursor.clone();
+            metrics.record_batch(data_finality, data.data.len(), batch_size);
+            summary.record(&data);
+            let response = StreamDataResponse {
+                stream_id,
+                message: Some(stream_data_response::Message::Data(data)),
+            };
+            metrics.record_message(data_finality, response.encoded_len());
+            yield Ok(response);
+
+            if reached_end {
+                let summary = summary.summary();
+                debug!(end_cursor = ?previous_head, blocks = summary.blocks, batches = summary.batches, "stream reached end cursor");
+                let response = StreamDataResponse {
+                    stream_id,
+                    message: Some(stream_data_response::Message::Summary(summary)),
+                };
+                metrics.record_message(data_finality, response.encoded_len());
+                yield Ok(response);
+                break;
+            }
         }
     })
 }
@@ -200,7 +430,7 @@ async fn handle_configuration_message<C, F, B>(
     cursor_producer: &mut impl CursorProducer<Cursor = C, Filter = F>,
     batch_producer: &mut impl BatchProducer<Cursor = C, Filter = F, Block = B>,
     configuration_message: Result<StreamConfiguration<C, F>, StreamError>,
-) -> Result<(u64, usize, ReconfigureResponse<C>), StreamError>
+) -> Result<(StreamConfiguration<C, F>, ReconfigureResponse<C>), StreamError>
 where
     C: Cursor + Send + Sync,
     F: Message + Default + Clone,
@@ -224,11 +454,49 @@ where
     );
     batch_producer_span.in_scope(|| batch_producer.reconfigure(&configuration_message))?;
 
-    Ok((
-        configuration_message.stream_id,
-        configuration_message.batch_size,
-        ingestion_response,
-    ))
+    Ok((configuration_message, ingestion_response))
+}
+
+/// Waits until the deadline, or forever if there's no deadline.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Returns how long ago the block at `end_cursor` was produced, if known.
+fn data_age<C, F, B>(
+    batch_producer: &impl BatchProducer<Cursor = C, Filter = F, Block = B>,
+    end_cursor: Option<&ProtoCursor>,
+) -> Result<Option<Duration>, StreamError>
+where
+    C: Cursor + Send + Sync,
+    F: Message + Default + Clone,
+    B: Message + Default + Clone,
+{
+    let Some(cursor) = end_cursor.and_then(C::from_proto) else {
+        return Ok(None);
+    };
+
+    let Some(timestamp) = batch_producer.block_timestamp(&cursor)? else {
+        return Ok(None);
+    };
+
+    Ok(SystemTime::now().duration_since(timestamp).ok())
+}
+
+/// Creates an invalidate message for data after `cursor`.
+///
+/// If the client received data past `cursor`, the message also includes the
+/// previous head so that the client knows exactly which cursors were invalidated.
+fn new_invalidate_message<C: Cursor>(cursor: &C, previous_head: Option<ProtoCursor>) -> Invalidate {
+    let cursor = cursor.to_proto();
+    let previous_head = previous_head.filter(|head| head.order_key > cursor.order_key);
+    Invalidate {
+        cursor: Some(cursor),
+        previous_head,
+    }
 }
 
 #[instrument(skip_all, level = "debug")]
@@ -246,13 +514,34 @@ where
         .await
 }
 
+/// Returns true if the stream reached the end cursor requested by the client.
+fn reached_end_cursor(end_order_key: Option<u64>, cursor: Option<&ProtoCursor>) -> bool {
+    match (end_order_key, cursor) {
+        (Some(end), Some(cursor)) => cursor.order_key >= end,
+        _ => false,
+    }
+}
+
+/// Returns the last cursor in the batch.
+fn batch_end_cursor<C: Cursor>(batch_cursor: &BatchCursor<C>) -> Option<&C> {
+    match batch_cursor {
+        BatchCursor::Finalized(_, cursors) => cursors.last(),
+        BatchCursor::Accepted(_, cursor) | BatchCursor::Pending(_, cursor) => Some(cursor),
+    }
+}
+
+/// Produces the data of the batch.
+///
+/// Batches larger than `max_message_size` are split into multiple data
+/// messages, one for each group of consecutive blocks.
 async fn handle_batch_cursor<C, F, B, M>(
     _cursor_producer: &mut impl CursorProducer<Cursor = C, Filter = F>,
     batch_producer: &mut impl BatchProducer<Cursor = C, Filter = F, Block = B>,
     batch_cursor: Result<BatchCursor<C>, StreamError>,
     meter: &M,
     limiter: &DefaultDirectRateLimiter,
-) -> Result<(Data, DataFinality), StreamError>
+    max_message_size: Option<usize>,
+) -> Result<(Vec<Data>, DataFinality), StreamError>
 where
     C: Cursor + Send + Sync,
     F: Message + Default + Clone,
@@ -300,7 +589,7 @@ where
         );
 
         let batch = batch_producer
-            .next_batch(cursors.into_iter(), meter)
+            .next_batch(cursors.iter().cloned(), meter)
             .instrument(next_batch_span)
             .await?;
 
@@ -310,24 +599,57 @@ where
             end_cursor = ?end_cursor,
         );
 
-        let data = serialize_batch_span.in_scope(|| {
+        let blocks = serialize_batch_span.in_scope(|| {
             batch
                 .iter()
-                .map(|block| block.encode_to_vec())
+                .map(|blocks| {
+                    blocks
+                        .iter()
+                        .map(|block| block.encode_to_vec())
+                        .collect::<Vec<_>>()
+                })
                 .collect::<Vec<_>>()
         });
 
-        let total_size_bytes = data.iter().map(|block| block.len()).sum::<usize>();
+        let total_size_bytes = blocks
+            .iter()
+            .flatten()
+            .map(|block| block.len())
+            .sum::<usize>();
         meter.increment_bytes_sent_counter(total_size_bytes as u64);
 
-        let data = Data {
-            cursor: start_cursor.map(|cursor| cursor.to_proto()),
-            end_cursor: end_cursor.map(|cursor| cursor.to_proto()),
-            finality: finality as i32,
-            data,
-        };
-
-        Ok((data, finality))
+        let start_cursor = start_cursor.map(|cursor| cursor.to_proto());
+        match max_message_size {
+            Some(max_message_size)
+                if cursors.len() > 1 && exceeds_max_message_size(&blocks, max_message_size) =>
+            {
+                warn!(
+                    size = total_size_bytes,
+                    max_message_size, "batch is too large, splitting it"
+                );
+                let blocks = cursors
+                    .iter()
+                    .map(|cursor| cursor.to_proto())
+                    .zip(blocks)
+                    .collect();
+                Ok((
+                    split_data(start_cursor, blocks, finality, max_message_size),
+                    finality,
+                ))
+            }
+            _ => {
+                let data = Data {
+                    cursor: start_cursor,
+                    end_cursor: end_cursor.map(|cursor| cursor.to_proto()),
+                    finality: finality as i32,
+                    data: blocks.into_iter().flatten().collect(),
+                    progress_token: Vec::default(),
+                    stale: false,
+                    caught_up: false,
+                };
+                Ok((vec![data], finality))
+            }
+        }
     }
     .instrument(handle_batch_span)
     .await
@@ -341,3 +663,460 @@ fn new_rate_limiter(blocks_per_second_quota: u32, batch_size: usize) -> DefaultD
 
     RateLimiter::direct(quota)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        pin::Pin,
+        task::{self, Poll},
+        time::{Duration, SystemTime},
+    };
+
+    use apibara_core::node::v1alpha2::{
+        stream_data_response, Cursor as ProtoCursor, Data, DataFinality, StreamDataResponse,
+    };
+    use async_trait::async_trait;
+    use futures::{
+        channel::mpsc,
+        stream::{self, FusedStream, LocalBoxStream},
+        Stream, StreamExt,
+    };
+
+    use crate::{
+        core::Cursor,
+        server::{QuotaClient, RequestMeter, SimpleMeter},
+        stream::{
+            BatchCursor, BatchProducer, ChainHead, CursorProducer, IngestionMessage,
+            IngestionResponse, LatestCursor, ReconfigureResponse, RequestedHeartbeat,
+            StreamConfiguration, StreamError,
+        },
+    };
+
+    use super::{super::split::MESSAGE_OVERHEAD, new_data_stream};
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    struct TestCursor(u64);
+
+    impl Cursor for TestCursor {
+        fn from_proto(cursor: &ProtoCursor) -> Option<Self> {
+            Some(TestCursor(cursor.order_key))
+        }
+
+        fn to_proto(&self) -> ProtoCursor {
+            ProtoCursor {
+                order_key: self.0,
+                unique_key: Vec::default(),
+            }
+        }
+    }
+
+    type TestConfiguration = StreamConfiguration<TestCursor, ProtoCursor>;
+
+    /// Produces the given batches, then waits forever.
+    #[derive(Default)]
+    struct TestCursorProducer {
+        batches: VecDeque<BatchCursor<TestCursor>>,
+        chain_head: ChainHead<TestCursor>,
+    }
+
+    impl TestCursorProducer {
+        fn new(batches: impl IntoIterator<Item = BatchCursor<TestCursor>>) -> Self {
+            TestCursorProducer {
+                batches: batches.into_iter().collect(),
+                ..TestCursorProducer::default()
+            }
+        }
+    }
+
+    impl Stream for TestCursorProducer {
+        type Item = Result<BatchCursor<TestCursor>, StreamError>;
+
+        fn poll_next(
+            mut self: Pin<&mut Self>,
+            _cx: &mut task::Context<'_>,
+        ) -> Poll<Option<Self::Item>> {
+            match self.batches.pop_front() {
+                Some(batch) => Poll::Ready(Some(Ok(batch))),
+                None => Poll::Pending,
+            }
+        }
+    }
+
+    impl FusedStream for TestCursorProducer {
+        fn is_terminated(&self) -> bool {
+            false
+        }
+    }
+
+    #[async_trait]
+    impl CursorProducer for TestCursorProducer {
+        type Cursor = TestCursor;
+        type Filter = ProtoCursor;
+
+        async fn reconfigure(
+            &mut self,
+            _configuration: &TestConfiguration,
+        ) -> Result<ReconfigureResponse<TestCursor>, StreamError> {
+            Ok(ReconfigureResponse::Ok)
+        }
+
+        async fn handle_ingestion_message(
+            &mut self,
+            _message: &IngestionMessage<TestCursor>,
+        ) -> Result<IngestionResponse<TestCursor>, StreamError> {
+            Ok(IngestionResponse::Ok)
+        }
+
+        fn chain_head(&mut self) -> Result<ChainHead<TestCursor>, StreamError> {
+            Ok(self.chain_head.clone())
+        }
+    }
+
+    /// Produces one block for each cursor, the block is the cursor itself.
+    #[derive(Default)]
+    struct TestBatchProducer {
+        /// How long ago all blocks were produced, if known.
+        block_age: Option<Duration>,
+    }
+
+    #[async_trait]
+    impl BatchProducer for TestBatchProducer {
+        type Cursor = TestCursor;
+        type Filter = ProtoCursor;
+        type Block = ProtoCursor;
+
+        fn reconfigure(&mut self, _configuration: &TestConfiguration) -> Result<(), StreamError> {
+            Ok(())
+        }
+
+        async fn next_batch<M: RequestMeter>(
+            &mut self,
+            cursors: impl Iterator<Item = TestCursor> + Send + Sync,
+            _meter: &M,
+        ) -> Result<Vec<Vec<ProtoCursor>>, StreamError> {
+            Ok(cursors.map(|cursor| vec![cursor.to_proto()]).collect())
+        }
+
+        fn block_timestamp(&self, _cursor: &TestCursor) -> Result<Option<SystemTime>, StreamError> {
+            Ok(self.block_age.map(|age| SystemTime::now() - age))
+        }
+    }
+
+    fn new_configuration() -> TestConfiguration {
+        TestConfiguration {
+            batch_size: 10,
+            stream_id: 1,
+            finality: DataFinality::DataStatusFinalized,
+            ..TestConfiguration::default()
+        }
+    }
+
+    /// A finalized batch with the blocks from `start` to `end`, inclusive.
+    fn finalized(start: u64, end: u64) -> BatchCursor<TestCursor> {
+        let start_cursor = start.checked_sub(1).map(TestCursor);
+        BatchCursor::new_finalized(start_cursor, (start..=end).map(TestCursor).collect())
+    }
+
+    /// Starts a data stream with the given configuration and skips the initial heartbeat.
+    async fn start_stream(
+        configuration: TestConfiguration,
+        cursor_producer: TestCursorProducer,
+        batch_producer: TestBatchProducer,
+    ) -> LocalBoxStream<'static, Result<StreamDataResponse, StreamError>> {
+        let configuration_stream = stream::iter([Ok(configuration)]).chain(stream::pending());
+        start_stream_with(
+            configuration_stream,
+            stream::pending(),
+            cursor_producer,
+            batch_producer,
+            None,
+        )
+        .await
+    }
+
+    /// Same as [start_stream], but reads the configuration and ingestion
+    /// messages from the given streams and splits batches larger than
+    /// `max_message_size`.
+    async fn start_stream_with(
+        configuration_stream: impl Stream<Item = Result<TestConfiguration, StreamError>>
+            + Unpin
+            + 'static,
+        ingestion_stream: impl Stream<Item = Result<IngestionMessage<TestCursor>, StreamError>>
+            + Unpin
+            + 'static,
+        cursor_producer: TestCursorProducer,
+        batch_producer: TestBatchProducer,
+        max_message_size: Option<usize>,
+    ) -> LocalBoxStream<'static, Result<StreamDataResponse, StreamError>> {
+        let mut stream = new_data_stream(
+            configuration_stream,
+            ingestion_stream,
+            cursor_producer,
+            batch_producer,
+            1_000,
+            SimpleMeter::default(),
+            QuotaClient::no_quota(),
+            None,
+            LatestCursor::default(),
+            RequestedHeartbeat::default(),
+            max_message_size,
+        )
+        .boxed_local();
+
+        let heartbeat = stream.next().await.unwrap().unwrap();
+        assert!(matches!(
+            heartbeat.message,
+            Some(stream_data_response::Message::Heartbeat(_))
+        ));
+        stream
+    }
+
+    async fn next_data(
+        stream: &mut LocalBoxStream<'static, Result<StreamDataResponse, StreamError>>,
+    ) -> Data {
+        match stream.next().await.unwrap().unwrap().message {
+            Some(stream_data_response::Message::Data(data)) => data,
+            message => panic!("expected data, got {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flag_stale_data() {
+        let configuration = TestConfiguration {
+            max_data_age: Some(Duration::from_secs(60)),
+            ..new_configuration()
+        };
+        let batch_producer = TestBatchProducer {
+            block_age: Some(Duration::from_secs(3600)),
+        };
+        let mut stream = start_stream(
+            configuration,
+            TestCursorProducer::new([finalized(1, 3)]),
+            batch_producer,
+        )
+        .await;
+
+        let data = next_data(&mut stream).await;
+        assert_eq!(data.end_cursor.unwrap().order_key, 3);
+        assert!(data.stale);
+    }
+
+    #[tokio::test]
+    async fn test_recent_data_is_not_stale() {
+        let configuration = TestConfiguration {
+            max_data_age: Some(Duration::from_secs(60)),
+            ..new_configuration()
+        };
+        let batch_producer = TestBatchProducer {
+            block_age: Some(Duration::from_secs(1)),
+        };
+        let mut stream = start_stream(
+            configuration,
+            TestCursorProducer::new([finalized(1, 3), finalized(4, 6)]),
+            batch_producer,
+        )
+        .await;
+
+        assert!(!next_data(&mut stream).await.stale);
+        assert!(!next_data(&mut stream).await.stale);
+    }
+
+    #[tokio::test]
+    async fn test_data_age_is_not_checked_by_default() {
+        let batch_producer = TestBatchProducer {
+            block_age: Some(Duration::from_secs(3600)),
+        };
+        let mut stream = start_stream(
+            new_configuration(),
+            TestCursorProducer::new([finalized(1, 3)]),
+            batch_producer,
+        )
+        .await;
+
+        assert!(!next_data(&mut stream).await.stale);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_send_held_data_after_max_batch_wait() {
+        let configuration = TestConfiguration {
+            min_batch_size: Some(10),
+            max_batch_wait: Some(Duration::from_secs(5)),
+            ..new_configuration()
+        };
+        let mut stream = start_stream(
+            configuration,
+            TestCursorProducer::new([finalized(1, 2), finalized(3, 4)]),
+            TestBatchProducer::default(),
+        )
+        .await;
+
+        // both batches are smaller than the minimum, they are sent together once the wait is over.
+        let data = next_data(&mut stream).await;
+        assert_eq!(data.cursor.unwrap().order_key, 0);
+        assert_eq!(data.end_cursor.unwrap().order_key, 4);
+        assert_eq!(data.data.len(), 4);
+    }
+
+    /// Returns the (start, end) order keys of the data.
+    fn data_range(data: &Data) -> (u64, u64) {
+        (
+            data.cursor.as_ref().unwrap().order_key,
+            data.end_cursor.as_ref().unwrap().order_key,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_split_large_batch() {
+        let (configuration_tx, configuration_rx) = mpsc::unbounded();
+        configuration_tx
+            .unbounded_send(Ok(new_configuration()))
+            .unwrap();
+        // each block is 4 bytes once encoded, so each message has 2 blocks.
+        let max_message_size = MESSAGE_OVERHEAD + 8;
+        let mut stream = start_stream_with(
+            configuration_rx,
+            stream::pending(),
+            TestCursorProducer::new([finalized(1, 6), finalized(7, 8)]),
+            TestBatchProducer::default(),
+            Some(max_message_size),
+        )
+        .await;
+
+        let data = next_data(&mut stream).await;
+        assert_eq!(data_range(&data), (0, 2));
+        assert_eq!(data.data.len(), 2);
+        let data = next_data(&mut stream).await;
+        assert_eq!(data_range(&data), (2, 4));
+        assert_eq!(data.data.len(), 2);
+
+        // the new configuration is applied before the rest of the batch is sent.
+        configuration_tx
+            .unbounded_send(Ok(new_configuration()))
+            .unwrap();
+        let data = next_data(&mut stream).await;
+        assert_eq!(data_range(&data), (6, 8));
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_not_split_by_default() {
+        let mut stream = start_stream(
+            new_configuration(),
+            TestCursorProducer::new([finalized(1, 6)]),
+            TestBatchProducer::default(),
+        )
+        .await;
+
+        let data = next_data(&mut stream).await;
+        assert_eq!(data_range(&data), (0, 6));
+        assert_eq!(data.data.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_invalidations_only_stops_at_end_cursor() {
+        let configuration = TestConfiguration {
+            invalidations_only: true,
+            end_cursor: Some(TestCursor(6)),
+            ..new_configuration()
+        };
+        let mut stream = start_stream(
+            configuration,
+            TestCursorProducer::new([finalized(1, 3), finalized(4, 6), finalized(7, 9)]),
+            TestBatchProducer::default(),
+        )
+        .await;
+
+        // no data is sent, only the summary once the stream reaches the end cursor.
+        match stream.next().await.unwrap().unwrap().message {
+            Some(stream_data_response::Message::Summary(summary)) => {
+                assert_eq!(summary.blocks, 0);
+            }
+            message => panic!("expected summary, got {:?}", message),
+        }
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_notify_chain_head() {
+        let configuration = TestConfiguration {
+            notify_chain_head: true,
+            ..new_configuration()
+        };
+        let cursor_producer = TestCursorProducer {
+            chain_head: ChainHead {
+                finalized: Some(TestCursor(5)),
+                accepted: Some(TestCursor(8)),
+                pending: None,
+            },
+            ..TestCursorProducer::new([finalized(1, 3)])
+        };
+        let mut stream =
+            start_stream(configuration, cursor_producer, TestBatchProducer::default()).await;
+
+        // the chain head is sent right after the stream is configured.
+        match stream.next().await.unwrap().unwrap().message {
+            Some(stream_data_response::Message::Heartbeat(heartbeat)) => {
+                let chain_head = heartbeat.chain_head.unwrap();
+                assert_eq!(chain_head.finalized.unwrap().order_key, 5);
+                assert_eq!(chain_head.accepted.unwrap().order_key, 8);
+                assert!(chain_head.pending.is_none());
+            }
+            message => panic!("expected heartbeat, got {:?}", message),
+        }
+
+        let data = next_data(&mut stream).await;
+        assert_eq!(data_range(&data), (0, 3));
+    }
+
+    #[tokio::test]
+    async fn test_summary_at_end_cursor() {
+        let configuration = TestConfiguration {
+            end_cursor: Some(TestCursor(6)),
+            ..new_configuration()
+        };
+        let mut stream = start_stream(
+            configuration,
+            TestCursorProducer::new([finalized(1, 3), finalized(4, 6)]),
+            TestBatchProducer::default(),
+        )
+        .await;
+
+        assert_eq!(data_range(&next_data(&mut stream).await), (0, 3));
+        assert_eq!(data_range(&next_data(&mut stream).await), (3, 6));
+
+        match stream.next().await.unwrap().unwrap().message {
+            Some(stream_data_response::Message::Summary(summary)) => {
+                assert_eq!(summary.blocks, 6);
+                assert_eq!(summary.batches, 2);
+                assert_eq!(summary.first_cursor.unwrap().order_key, 3);
+                assert_eq!(summary.last_cursor.unwrap().order_key, 6);
+            }
+            message => panic!("expected summary, got {:?}", message),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_ends_at_end_cursor() {
+        let configuration = TestConfiguration {
+            end_cursor: Some(TestCursor(5)),
+            ..new_configuration()
+        };
+        let mut stream = start_stream(
+            configuration,
+            TestCursorProducer::new([finalized(1, 3), finalized(4, 5), finalized(6, 8)]),
+            TestBatchProducer::default(),
+        )
+        .await;
+
+        assert_eq!(data_range(&next_data(&mut stream).await), (0, 3));
+        assert_eq!(data_range(&next_data(&mut stream).await), (3, 5));
+
+        // skip the summary.
+        assert!(matches!(
+            stream.next().await.unwrap().unwrap().message,
+            Some(stream_data_response::Message::Summary(_))
+        ));
+        // blocks after the end cursor are never sent.
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,260 @@
+//! Close streams when their deadline expires, unless the client keeps them alive.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+    time::Duration,
+};
+
+use apibara_core::node::v1alpha2::{ErrorCode, StreamDataRequest, StreamDataResponse};
+use futures::Stream;
+use pin_project::pin_project;
+use tokio::time::{Instant, Sleep};
+use tonic::metadata::MetadataMap;
+use tracing::debug;
+
+use super::error::status_with_code;
+
+type ResponseItem = Result<StreamDataResponse, tonic::Status>;
+
+const GRPC_TIMEOUT_HEADER: &str = "grpc-timeout";
+
+/// The deadline of a stream, shared between the request and response streams.
+///
+/// The deadline starts at `timeout` from the stream start. Every keepalive
+/// moves it to `timeout` from now, but never past `timeout + max_extension`
+/// from the stream start.
+#[derive(Debug, Clone)]
+pub struct StreamDeadline {
+    inner: Arc<Mutex<DeadlineState>>,
+}
+
+#[derive(Debug)]
+struct DeadlineState {
+    timeout: Duration,
+    deadline: Instant,
+    max_deadline: Instant,
+}
+
+impl StreamDeadline {
+    pub fn new(timeout: Duration, max_extension: Duration) -> Self {
+        let deadline = Instant::now() + timeout;
+        let state = DeadlineState {
+            timeout,
+            deadline,
+            max_deadline: deadline + max_extension,
+        };
+        StreamDeadline {
+            inner: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Creates a new deadline from the client's `grpc-timeout` header, if any.
+    pub fn from_metadata(metadata: &MetadataMap, max_extension: Duration) -> Option<Self> {
+        let timeout = metadata.get(GRPC_TIMEOUT_HEADER)?.to_str().ok()?;
+        let timeout = parse_grpc_timeout(timeout)?;
+        Some(Self::new(timeout, max_extension))
+    }
+
+    /// Extends the deadline, returning the new deadline.
+    pub fn extend(&self) -> Instant {
+        let mut state = self.inner.lock().expect("stream deadline lock poisoned");
+        let deadline = Instant::now() + state.timeout;
+        state.deadline = deadline.min(state.max_deadline).max(state.deadline);
+        state.deadline
+    }
+
+    /// Returns the current deadline.
+    pub fn deadline(&self) -> Instant {
+        self.inner
+            .lock()
+            .expect("stream deadline lock poisoned")
+            .deadline
+    }
+}
+
+/// Parses a `grpc-timeout` header value, e.g. `30S` or `500m`.
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    // the spec limits the value to 8 digits.
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    let duration = match unit {
+        "H" => Duration::from_secs(amount * 60 * 60),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(duration)
+}
+
+/// A request stream that extends the stream deadline on keepalive messages.
+///
+/// Keepalive messages are consumed by this stream and never forwarded.
+#[pin_project]
+pub struct KeepaliveStream<S> {
+    #[pin]
+    inner: S,
+    deadline: Option<StreamDeadline>,
+}
+
+impl<S> KeepaliveStream<S> {
+    pub fn new(inner: S, deadline: Option<StreamDeadline>) -> Self {
+        KeepaliveStream { inner, deadline }
+    }
+}
+
+impl<S, E> Stream for KeepaliveStream<S>
+where
+    S: Stream<Item = Result<StreamDataRequest, E>>,
+{
+    type Item = Result<StreamDataRequest, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            match this.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(request))) if request.keepalive => {
+                    if let Some(deadline) = this.deadline {
+                        let deadline = deadline.extend();
+                        debug!(deadline = ?deadline, "stream deadline extended");
+                    }
+                }
+                poll => return poll,
+            }
+        }
+    }
+}
+
+/// A response stream that is closed with a `deadline_exceeded` status when
+/// its deadline expires.
+#[pin_project]
+pub struct DeadlineStream<S> {
+    #[pin]
+    inner: S,
+    deadline: StreamDeadline,
+    sleep: Pin<Box<Sleep>>,
+    terminated: bool,
+}
+
+impl<S> DeadlineStream<S> {
+    pub fn new(inner: S, deadline: StreamDeadline) -> Self {
+        let sleep = Box::pin(tokio::time::sleep_until(deadline.deadline()));
+        DeadlineStream {
+            inner,
+            deadline,
+            sleep,
+            terminated: false,
+        }
+    }
+}
+
+impl<S> Stream for DeadlineStream<S>
+where
+    S: Stream<Item = ResponseItem>,
+{
+    type Item = ResponseItem;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+
+        if *this.terminated {
+            return Poll::Ready(None);
+        }
+
+        while this.sleep.as_mut().poll(cx).is_ready() {
+            // the deadline may have been extended since the timer was set.
+            let deadline = this.deadline.deadline();
+            if deadline <= Instant::now() {
+                *this.terminated = true;
+                let status = status_with_code(
+                    tonic::Code::DeadlineExceeded,
+                    ErrorCode::DeadlineExceeded,
+                    "stream deadline exceeded",
+                );
+                return Poll::Ready(Some(Err(status)));
+            }
+            this.sleep.as_mut().reset(deadline);
+        }
+
+        this.inner.poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apibara_core::node::v1alpha2::{StreamDataRequest, StreamDataResponse};
+    use futures::StreamExt;
+    use tokio::time::Instant;
+
+    use super::{parse_grpc_timeout, DeadlineStream, KeepaliveStream, StreamDeadline};
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("30S"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_grpc_timeout("2M"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_grpc_timeout("S"), None);
+        assert_eq!(parse_grpc_timeout("30x"), None);
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+    }
+
+    #[tokio::test]
+    async fn test_extend_is_bounded() {
+        let deadline = StreamDeadline::new(Duration::from_secs(10), Duration::ZERO);
+        let initial = deadline.deadline();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(deadline.extend(), initial);
+
+        let deadline = StreamDeadline::new(Duration::from_secs(10), Duration::from_secs(60));
+        let initial = deadline.deadline();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let extended = deadline.extend();
+        assert!(extended > initial);
+        assert!(extended <= initial + Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_extends_deadline() {
+        let timeout = Duration::from_millis(100);
+        let deadline = StreamDeadline::new(timeout, Duration::from_secs(60));
+        let initial = deadline.deadline();
+
+        let keepalive = StreamDataRequest {
+            keepalive: true,
+            ..StreamDataRequest::default()
+        };
+        let requests = futures::stream::iter(vec![
+            Ok::<_, tonic::Status>(keepalive),
+            Ok(StreamDataRequest::default()),
+        ]);
+        let mut requests = KeepaliveStream::new(requests, Some(deadline.clone()));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // keepalive messages are not forwarded.
+        let request = requests.next().await.unwrap().unwrap();
+        assert!(!request.keepalive);
+        let extended = deadline.deadline();
+        assert!(extended >= initial + Duration::from_millis(50));
+
+        let mut responses = DeadlineStream::new(
+            futures::stream::pending::<Result<StreamDataResponse, tonic::Status>>(),
+            deadline,
+        );
+
+        let status = responses.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+        assert!(Instant::now() >= extended);
+        assert!(responses.next().await.is_none());
+    }
+}
@@ -1,24 +1,96 @@
+use apibara_core::node::v1alpha2::{ErrorCode, ErrorDetail};
 use tracing::warn;
 
 #[derive(Debug, thiserror::Error)]
 pub enum StreamError {
     #[error("internal error: {0}")]
     Internal(Box<dyn std::error::Error + Send + Sync + 'static>),
+    #[error("unavailable: {0}")]
+    Unavailable(Box<dyn std::error::Error + Send + Sync + 'static>),
     #[error("quota exceeded")]
     QuotaExceeded,
     #[error("invalid request: {message}")]
-    InvalidRequest { message: String },
+    InvalidRequest { message: String, code: ErrorCode },
+    #[error("not found: {0}")]
+    NotFound(String),
+}
+
+/// Maps errors to the stream error sent to clients.
+///
+/// Implemented by storage errors, so that clients can tell missing data and
+/// transient failures apart from internal errors.
+pub trait IntoStreamError {
+    fn into_stream_error(self) -> StreamError;
+}
+
+impl IntoStreamError for libmdbx::Error {
+    fn into_stream_error(self) -> StreamError {
+        match self {
+            libmdbx::Error::NotFound => StreamError::not_found("data not found".to_string()),
+            // the database has too many readers or is locked, retrying can succeed.
+            libmdbx::Error::Busy | libmdbx::Error::ReadersFull => StreamError::unavailable(self),
+            _ => StreamError::internal(self),
+        }
+    }
+}
+
+/// Creates a status with the error code in its details.
+pub fn status_with_code(
+    code: tonic::Code,
+    error_code: ErrorCode,
+    message: impl Into<String>,
+) -> tonic::Status {
+    let message = message.into();
+    let detail = ErrorDetail {
+        code: error_code as i32,
+    };
+    let details = detail.to_status_details(code as i32, &message);
+    tonic::Status::with_details(code, message, details.into())
 }
 
 impl StreamError {
     pub fn invalid_request(message: String) -> Self {
-        StreamError::InvalidRequest { message }
+        StreamError::InvalidRequest {
+            message,
+            code: ErrorCode::InvalidRequest,
+        }
+    }
+
+    /// The starting cursor or progress token is not valid.
+    pub fn invalid_cursor(message: String) -> Self {
+        StreamError::InvalidRequest {
+            message,
+            code: ErrorCode::InvalidCursor,
+        }
+    }
+
+    /// The starting cursor doesn't exist.
+    pub fn cursor_out_of_range(message: String) -> Self {
+        StreamError::InvalidRequest {
+            message,
+            code: ErrorCode::CursorOutOfRange,
+        }
     }
 
     pub fn quota_exceeded() -> Self {
         StreamError::QuotaExceeded
     }
 
+    /// A service the stream depends on failed, for example the ingestion
+    /// subscription or the quota server.
+    ///
+    /// Clients should retry later.
+    pub fn unavailable(err: impl Into<Box<dyn std::error::Error + Send + Sync + 'static>>) -> Self {
+        StreamError::Unavailable(err.into())
+    }
+
+    /// Data the stream needs is no longer in storage.
+    ///
+    /// Clients should restart the stream from the last cursor they received.
+    pub fn not_found(message: String) -> Self {
+        StreamError::NotFound(message)
+    }
+
     pub fn internal(err: impl Into<Box<dyn std::error::Error + Send + Sync + 'static>>) -> Self {
         StreamError::Internal(err.into())
     }
@@ -27,12 +99,98 @@ impl StreamError {
         match self {
             StreamError::Internal(err) => {
                 warn!(err = ?err, "stream error");
-                tonic::Status::internal("internal server error")
+                status_with_code(
+                    tonic::Code::Internal,
+                    ErrorCode::Internal,
+                    "internal server error",
+                )
             }
-            StreamError::QuotaExceeded => tonic::Status::resource_exhausted(
+            StreamError::Unavailable(err) => {
+                warn!(err = ?err, "stream dependency unavailable");
+                status_with_code(
+                    tonic::Code::Unavailable,
+                    ErrorCode::Unavailable,
+                    "service temporarily unavailable, retry later",
+                )
+            }
+            StreamError::QuotaExceeded => status_with_code(
+                tonic::Code::ResourceExhausted,
+                ErrorCode::QuotaExceeded,
                 "monthly data quota exceeded. Please contact support.",
             ),
-            StreamError::InvalidRequest { message } => tonic::Status::invalid_argument(message),
+            StreamError::InvalidRequest { message, code } => {
+                let status_code = if code == ErrorCode::CursorOutOfRange {
+                    tonic::Code::OutOfRange
+                } else {
+                    tonic::Code::InvalidArgument
+                };
+                status_with_code(status_code, code, message)
+            }
+            StreamError::NotFound(message) => {
+                status_with_code(tonic::Code::NotFound, ErrorCode::NotFound, message)
+            }
         }
     }
 }
+
+impl From<StreamError> for tonic::Status {
+    fn from(err: StreamError) -> Self {
+        err.into_status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::node::v1alpha2::{ErrorCode, ErrorDetail};
+
+    use super::{IntoStreamError, StreamError};
+
+    fn error_code(status: &tonic::Status) -> ErrorCode {
+        let detail = ErrorDetail::from_status_details(status.details()).unwrap();
+        detail.code()
+    }
+
+    #[test]
+    fn test_status_has_error_code() {
+        let status = StreamError::invalid_cursor("bad cursor".to_string()).into_status();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "bad cursor");
+        assert_eq!(error_code(&status), ErrorCode::InvalidCursor);
+
+        let status = StreamError::cursor_out_of_range("block 42".to_string()).into_status();
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+        assert_eq!(error_code(&status), ErrorCode::CursorOutOfRange);
+
+        let status = StreamError::quota_exceeded().into_status();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(error_code(&status), ErrorCode::QuotaExceeded);
+
+        let status = StreamError::unavailable("quota server down").into_status();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(error_code(&status), ErrorCode::Unavailable);
+        assert!(!status.message().contains("quota server down"));
+
+        let status = StreamError::not_found("block 42".to_string()).into_status();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(error_code(&status), ErrorCode::NotFound);
+
+        let status = StreamError::internal("boom").into_status();
+        assert_eq!(status.code(), tonic::Code::Internal);
+        assert_eq!(error_code(&status), ErrorCode::Internal);
+
+        let status = tonic::Status::from(StreamError::quota_exceeded());
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn test_storage_error_status() {
+        let status = libmdbx::Error::NotFound.into_stream_error().into_status();
+        assert_eq!(error_code(&status), ErrorCode::NotFound);
+
+        let status = libmdbx::Error::Busy.into_stream_error().into_status();
+        assert_eq!(error_code(&status), ErrorCode::Unavailable);
+
+        let status = libmdbx::Error::Corrupted.into_stream_error().into_status();
+        assert_eq!(error_code(&status), ErrorCode::Internal);
+    }
+}
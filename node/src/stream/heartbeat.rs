@@ -12,6 +12,9 @@ use pin_project::pin_project;
 use tokio::time::{Instant, Sleep};
 use tokio_stream::Stream;
 
+/// Longest delay before a heartbeat, about 30 years like tokio's own far future.
+const MAX_DELAY: Duration = Duration::from_secs(86400 * 365 * 30);
+
 pub trait HeartbeatStreamExt: Stream {
     /// Returns a new stream that contains heartbeat messages.
     ///
@@ -39,20 +42,49 @@ pub struct Heartbeat<S> {
     #[pin]
     deadline: Sleep,
     interval: Duration,
+    next_interval: Duration,
     needs_reset: bool,
+    enabled: bool,
 }
 
 impl<S: Stream> Heartbeat<S> {
     pub fn new(stream: S, interval: Duration) -> Heartbeat<S> {
+        Self::with_initial_delay(stream, interval, interval)
+    }
+
+    /// Creates a new heartbeat stream where the first heartbeat is produced
+    /// only after `initial_delay` without messages.
+    pub fn with_initial_delay(
+        stream: S,
+        initial_delay: Duration,
+        interval: Duration,
+    ) -> Heartbeat<S> {
         let stream = stream.fuse();
-        let next = Instant::now() + interval;
-        let deadline = tokio::time::sleep_until(next);
+        let deadline = tokio::time::sleep_until(deadline_after(initial_delay));
 
         Heartbeat {
             stream,
             deadline,
             interval,
+            next_interval: initial_delay,
             needs_reset: true,
+            enabled: true,
+        }
+    }
+
+    /// Changes the heartbeat interval, starting from now.
+    ///
+    /// If `interval` is `None`, no more heartbeats are produced.
+    pub fn set_interval(self: Pin<&mut Self>, interval: Option<Duration>) {
+        let this = self.project();
+        match interval {
+            None => *this.enabled = false,
+            Some(interval) => {
+                *this.interval = interval;
+                *this.next_interval = interval;
+                *this.needs_reset = true;
+                *this.enabled = true;
+            }
         }
     }
 }
@@ -63,8 +95,9 @@ impl<S: Stream> Stream for Heartbeat<S> {
     fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.project();
 
-        if *this.needs_reset {
-            let next = Instant::now() + *this.interval;
+        if *this.needs_reset && *this.enabled {
+            let next = deadline_after(*this.next_interval);
+            *this.next_interval = *this.interval;
             this.deadline.reset(next);
             *this.needs_reset = false;
             cx.waker().wake_by_ref();
@@ -81,6 +114,10 @@ impl<S: Stream> Stream for Heartbeat<S> {
             Poll::Pending => {}
         }
 
+        if !*this.enabled {
+            return Poll::Pending;
+        }
+
         match this.deadline.poll(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(_) => {
@@ -91,6 +128,12 @@ impl<S: Stream> Stream for Heartbeat<S> {
     }
 }
 
+/// Returns the instant `delay` from now, very long delays never elapse.
+fn deadline_after(delay: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_add(delay).unwrap_or_else(|| now + MAX_DELAY)
+}
+
 /// Error returned by `Heartbeat`.
 #[derive(Debug, PartialEq)]
 pub struct Beat(());
@@ -114,3 +157,55 @@ impl From<Beat> for std::io::Error {
         std::io::ErrorKind::TimedOut.into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::time::Instant;
+
+    use super::Heartbeat;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_initial_delay() {
+        let start = Instant::now();
+        let mut stream = Box::pin(Heartbeat::with_initial_delay(
+            futures::stream::pending::<()>(),
+            Duration::from_millis(10),
+            Duration::from_millis(100),
+        ));
+
+        assert!(stream.next().await.unwrap().is_err());
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+
+        // later heartbeats use the regular interval.
+        assert!(stream.next().await.unwrap().is_err());
+        assert_eq!(start.elapsed(), Duration::from_millis(110));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_disabled() {
+        let mut stream = Box::pin(Heartbeat::new(
+            futures::stream::pending::<()>(),
+            Duration::from_millis(10),
+        ));
+        stream.as_mut().set_interval(None);
+
+        let next = tokio::time::timeout(Duration::from_millis(100), stream.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_very_long_interval() {
+        let mut stream = Box::pin(Heartbeat::new(
+            futures::stream::iter([1, 2]).chain(futures::stream::pending()),
+            Duration::MAX,
+        ));
+
+        assert_eq!(stream.next().await.unwrap(), Ok(1));
+        assert_eq!(stream.next().await.unwrap(), Ok(2));
+        let next = tokio::time::timeout(Duration::from_secs(3600), stream.next()).await;
+        assert!(next.is_err());
+    }
+}
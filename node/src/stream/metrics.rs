@@ -0,0 +1,94 @@
+//! Metrics about the streams served by the node.
+
+use apibara_core::node::v1alpha2::DataFinality;
+
+use crate::o11y::{self, Counter, Histogram, KeyValue, Meter, UpDownCounter};
+
+/// Metrics recorded by every stream.
+///
+/// Message, heartbeat and batch metrics are labeled with the stream finality.
+#[derive(Clone)]
+pub struct StreamMetrics {
+    active_streams: UpDownCounter<i64>,
+    messages_sent: Counter<u64>,
+    bytes_sent: Counter<u64>,
+    heartbeats_sent: Counter<u64>,
+    batch_fill_ratio: Histogram<f64>,
+}
+
+/// Counts a stream as active until dropped.
+pub struct ActiveStreamGuard {
+    active_streams: UpDownCounter<i64>,
+}
+
+impl StreamMetrics {
+    pub fn new() -> Self {
+        Self::with_meter(&o11y::meter("stream_data"))
+    }
+
+    /// Creates the metrics with the given meter.
+    pub(crate) fn with_meter(meter: &Meter) -> Self {
+        StreamMetrics {
+            active_streams: meter.i64_up_down_counter("active_streams").init(),
+            messages_sent: meter.u64_counter("stream_messages_sent").init(),
+            bytes_sent: meter.u64_counter("stream_message_bytes_sent").init(),
+            heartbeats_sent: meter.u64_counter("stream_heartbeats_sent").init(),
+            batch_fill_ratio: meter.f64_histogram("stream_batch_fill_ratio").init(),
+        }
+    }
+
+    /// Marks a new stream as active, until the returned guard is dropped.
+    pub fn stream_started(&self) -> ActiveStreamGuard {
+        self.active_streams.add(&o11y::Context::current(), 1, &[]);
+        ActiveStreamGuard {
+            active_streams: self.active_streams.clone(),
+        }
+    }
+
+    /// Records a message sent to the client.
+    pub fn record_message(&self, finality: DataFinality, size_bytes: usize) {
+        let cx = o11y::Context::current();
+        let attributes = finality_attributes(finality);
+        self.messages_sent.add(&cx, 1, &attributes);
+        self.bytes_sent.add(&cx, size_bytes as u64, &attributes);
+    }
+
+    /// Records a batch with `len` blocks, out of a maximum of `batch_size`.
+    pub fn record_batch(&self, finality: DataFinality, len: usize, batch_size: usize) {
+        if batch_size == 0 {
+            return;
+        }
+        let cx = o11y::Context::current();
+        let ratio = len as f64 / batch_size as f64;
+        self.batch_fill_ratio
+            .record(&cx, ratio, &finality_attributes(finality));
+    }
+
+    /// Records a heartbeat sent to the client.
+    pub fn record_heartbeat(&self, finality: DataFinality) {
+        self.heartbeats_sent
+            .add(&o11y::Context::current(), 1, &finality_attributes(finality));
+    }
+}
+
+impl Default for StreamMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ActiveStreamGuard {
+    fn drop(&mut self) {
+        self.active_streams.add(&o11y::Context::current(), -1, &[]);
+    }
+}
+
+fn finality_attributes(finality: DataFinality) -> [KeyValue; 1] {
+    let finality = match finality {
+        DataFinality::DataStatusUnknown => "unknown",
+        DataFinality::DataStatusPending => "pending",
+        DataFinality::DataStatusAccepted => "accepted",
+        DataFinality::DataStatusFinalized => "finalized",
+    };
+    [KeyValue::new("finality", finality)]
+}
@@ -1,17 +1,38 @@
+mod backpressure;
+mod coalesce;
 mod configuration;
 mod data;
+mod deadline;
 mod error;
 mod heartbeat;
 mod ingestion;
+mod metrics;
 mod producers;
+mod progress;
+mod rate_limit;
+mod registry;
 mod response;
+mod shutdown;
+mod split;
+mod summary;
 
-pub use self::configuration::{StreamConfiguration, StreamConfigurationStream};
+pub use self::backpressure::{BackpressureStream, DEFAULT_STALL_TIMEOUT};
+pub use self::coalesce::DEFAULT_MAX_BATCH_WAIT;
+pub use self::configuration::{BatchSizeLimits, StreamConfiguration, StreamConfigurationStream};
 pub use self::data::new_data_stream;
-pub use self::error::StreamError;
+pub use self::deadline::{parse_grpc_timeout, DeadlineStream, KeepaliveStream, StreamDeadline};
+pub use self::error::{status_with_code, IntoStreamError, StreamError};
 pub use self::heartbeat::Heartbeat;
 pub use self::ingestion::IngestionMessage;
+pub use self::metrics::{ActiveStreamGuard, StreamMetrics};
 pub use self::producers::{
-    BatchCursor, BatchProducer, CursorProducer, IngestionResponse, ReconfigureResponse,
+    BatchCursor, BatchProducer, ChainHead, CursorProducer, IngestionResponse, ReconfigureResponse,
 };
-pub use self::response::ResponseStream;
+pub use self::progress::{ProgressTokenError, ProgressTokenSigner};
+pub use self::rate_limit::{RateLimitedStream, StreamRateLimit};
+pub use self::registry::{StreamRegistration, StreamRegistry};
+pub use self::response::{
+    LatestCursor, RequestedHeartbeat, ResponseStream, DEFAULT_HEARTBEAT_INTERVAL,
+    MAX_HEARTBEAT_INTERVAL, MIN_HEARTBEAT_INTERVAL,
+};
+pub use self::shutdown::ShutdownStream;
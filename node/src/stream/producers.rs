@@ -1,3 +1,6 @@
+use std::time::SystemTime;
+
+use apibara_core::node::v1alpha2;
 use async_trait::async_trait;
 use futures::Stream;
 use prost::Message;
@@ -25,6 +28,24 @@ pub enum ReconfigureResponse<C: Cursor> {
     MissingStartingCursor,
 }
 
+/// The highest blocks ingested by the server, by finality.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChainHead<C: Cursor> {
+    pub finalized: Option<C>,
+    pub accepted: Option<C>,
+    pub pending: Option<C>,
+}
+
+impl<C: Cursor> ChainHead<C> {
+    pub fn to_proto(&self) -> v1alpha2::ChainHead {
+        v1alpha2::ChainHead {
+            finalized: self.finalized.as_ref().map(Cursor::to_proto),
+            accepted: self.accepted.as_ref().map(Cursor::to_proto),
+            pending: self.pending.as_ref().map(Cursor::to_proto),
+        }
+    }
+}
+
 /// A batch cursor.
 #[derive(Debug)]
 pub enum BatchCursor<C: Cursor> {
@@ -58,6 +79,21 @@ pub trait CursorProducer: Stream<Item = Result<BatchCursor<Self::Cursor>, Stream
         &mut self,
         message: &IngestionMessage<Self::Cursor>,
     ) -> Result<IngestionResponse<Self::Cursor>, StreamError>;
+
+    /// Returns whether the given cursor reached the chain head for the current configuration.
+    ///
+    /// Used to tell clients when they finished backfilling. The default
+    /// implementation never reports the head.
+    fn is_at_head(&self, _cursor: &Self::Cursor) -> bool {
+        false
+    }
+
+    /// Returns the chain head, sent to clients that request it.
+    ///
+    /// The default implementation doesn't know any block.
+    fn chain_head(&mut self) -> Result<ChainHead<Self::Cursor>, StreamError> {
+        Ok(ChainHead::default())
+    }
 }
 
 #[async_trait]
@@ -71,11 +107,22 @@ pub trait BatchProducer {
         configuration: &StreamConfiguration<Self::Cursor, Self::Filter>,
     ) -> Result<(), StreamError>;
 
+    /// Returns the blocks produced for each cursor, in the same order as `cursors`.
+    ///
+    /// Blocks are grouped by cursor so that large batches can be split
+    /// without producing them again.
     async fn next_batch<M: RequestMeter>(
         &mut self,
         cursors: impl Iterator<Item = Self::Cursor> + Send + Sync,
         meter: &M,
-    ) -> Result<Vec<Self::Block>, StreamError>;
+    ) -> Result<Vec<Vec<Self::Block>>, StreamError>;
+
+    /// Returns when the block at the given cursor was produced, if known.
+    ///
+    /// Used to flag stale data.
+    fn block_timestamp(&self, _cursor: &Self::Cursor) -> Result<Option<SystemTime>, StreamError> {
+        Ok(None)
+    }
 }
 
 impl<C: Cursor> BatchCursor<C> {
@@ -0,0 +1,235 @@
+//! Signed progress tokens used to resume streams.
+//!
+//! A progress token encodes the cursor of the last batch sent, a digest of the
+//! stream filter and an expiry time. Tokens are signed with HMAC-SHA256 so that
+//! the server can trust them when a client presents one on reconnect.
+//!
+//! Tokens don't need to be invalidated explicitly on chain reorganizations:
+//! the cursor includes the block hash, so resuming from a token that points to
+//! a block no longer in the canonical chain behaves like resuming from any other
+//! stale cursor, i.e. the stream starts with an `Invalidate` message.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use apibara_core::node::v1alpha2::Cursor as ProtoCursor;
+use hmac::{Hmac, Mac};
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SIGNATURE_LEN: usize = 32;
+const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_MINT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ProgressTokenError {
+    #[error("progress tokens are not enabled")]
+    Disabled,
+    #[error("malformed progress token")]
+    Malformed,
+    #[error("invalid progress token signature")]
+    InvalidSignature,
+    #[error("progress token expired")]
+    Expired,
+    #[error("progress token was issued for a different filter")]
+    FilterMismatch,
+}
+
+/// Mints and validates progress tokens.
+#[derive(Clone)]
+pub struct ProgressTokenSigner {
+    secret: Vec<u8>,
+    ttl: Duration,
+    interval: Duration,
+}
+
+/// Decides when to attach a new progress token to a batch.
+pub struct ProgressTokenMinter {
+    signer: ProgressTokenSigner,
+    last_minted: Instant,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ProgressTokenPayload {
+    #[prost(message, optional, tag = "1")]
+    cursor: Option<ProtoCursor>,
+    #[prost(bytes = "vec", tag = "2")]
+    filter_digest: Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    expires_at: u64,
+}
+
+impl ProgressTokenSigner {
+    /// Creates a new signer with the given secret.
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        ProgressTokenSigner {
+            secret: secret.into(),
+            ttl: DEFAULT_TOKEN_TTL,
+            interval: DEFAULT_MINT_INTERVAL,
+        }
+    }
+
+    /// Sets how long tokens are valid for.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Sets how often a new token is attached to the data sent to clients.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Returns a minter that emits at most one token every interval.
+    pub fn minter(&self) -> ProgressTokenMinter {
+        ProgressTokenMinter {
+            signer: self.clone(),
+            last_minted: Instant::now(),
+        }
+    }
+
+    /// Returns a new token for the given cursor and filter digest.
+    pub fn mint(&self, cursor: &ProtoCursor, filter_digest: &[u8]) -> Vec<u8> {
+        self.mint_at(cursor, filter_digest, unix_now())
+    }
+
+    /// Validates the token, returning the cursor it points to.
+    pub fn verify(
+        &self,
+        token: &[u8],
+        filter_digest: &[u8],
+    ) -> Result<ProtoCursor, ProgressTokenError> {
+        self.verify_at(token, filter_digest, unix_now())
+    }
+
+    fn mint_at(&self, cursor: &ProtoCursor, filter_digest: &[u8], now: u64) -> Vec<u8> {
+        let payload = ProgressTokenPayload {
+            cursor: Some(cursor.clone()),
+            filter_digest: filter_digest.to_vec(),
+            expires_at: now.saturating_add(self.ttl.as_secs()),
+        };
+
+        let mut token = payload.encode_to_vec();
+        let signature = self.mac().chain_update(&token).finalize().into_bytes();
+        token.extend_from_slice(&signature);
+        token
+    }
+
+    fn verify_at(
+        &self,
+        token: &[u8],
+        filter_digest: &[u8],
+        now: u64,
+    ) -> Result<ProtoCursor, ProgressTokenError> {
+        if token.len() < SIGNATURE_LEN {
+            return Err(ProgressTokenError::Malformed);
+        }
+
+        let (payload, signature) = token.split_at(token.len() - SIGNATURE_LEN);
+        self.mac()
+            .chain_update(payload)
+            .verify_slice(signature)
+            .map_err(|_| ProgressTokenError::InvalidSignature)?;
+
+        let payload =
+            ProgressTokenPayload::decode(payload).map_err(|_| ProgressTokenError::Malformed)?;
+
+        if payload.expires_at < now {
+            return Err(ProgressTokenError::Expired);
+        }
+
+        if payload.filter_digest != filter_digest {
+            return Err(ProgressTokenError::FilterMismatch);
+        }
+
+        payload.cursor.ok_or(ProgressTokenError::Malformed)
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("hmac accepts keys of any size")
+    }
+}
+
+impl ProgressTokenMinter {
+    /// Returns a new token if the mint interval elapsed since the last one.
+    pub fn maybe_mint(&mut self, cursor: &ProtoCursor, filter_digest: &[u8]) -> Option<Vec<u8>> {
+        if self.last_minted.elapsed() < self.signer.interval {
+            return None;
+        }
+
+        self.last_minted = Instant::now();
+        Some(self.signer.mint(cursor, filter_digest))
+    }
+}
+
+/// Returns a digest of the stream filters, used to bind tokens to a filter.
+pub fn filter_digest<F: Message>(filters: &[F]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for filter in filters {
+        hasher.update(filter.encode_length_delimited_to_vec());
+    }
+    hasher.finalize().to_vec()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apibara_core::node::v1alpha2::Cursor as ProtoCursor;
+
+    use super::{ProgressTokenError, ProgressTokenSigner};
+
+    fn new_cursor() -> ProtoCursor {
+        ProtoCursor {
+            order_key: 1234,
+            unique_key: vec![1, 2, 3, 4],
+        }
+    }
+
+    #[test]
+    fn test_mint_and_verify() {
+        let signer = ProgressTokenSigner::new("secret").with_ttl(Duration::from_secs(10));
+        let token = signer.mint_at(&new_cursor(), b"filter", 100);
+        let cursor = signer.verify_at(&token, b"filter", 105).unwrap();
+        assert_eq!(cursor, new_cursor());
+    }
+
+    #[test]
+    fn test_reject_expired_token() {
+        let signer = ProgressTokenSigner::new("secret").with_ttl(Duration::from_secs(10));
+        let token = signer.mint_at(&new_cursor(), b"filter", 100);
+        let err = signer.verify_at(&token, b"filter", 111).unwrap_err();
+        assert_eq!(err, ProgressTokenError::Expired);
+    }
+
+    #[test]
+    fn test_reject_different_filter() {
+        let signer = ProgressTokenSigner::new("secret");
+        let token = signer.mint_at(&new_cursor(), b"filter", 100);
+        let err = signer.verify_at(&token, b"other", 100).unwrap_err();
+        assert_eq!(err, ProgressTokenError::FilterMismatch);
+    }
+
+    #[test]
+    fn test_reject_tampered_token() {
+        let signer = ProgressTokenSigner::new("secret");
+        let mut token = signer.mint_at(&new_cursor(), b"filter", 100);
+        token[0] ^= 0xff;
+        let err = signer.verify_at(&token, b"filter", 100).unwrap_err();
+        assert_eq!(err, ProgressTokenError::InvalidSignature);
+
+        let other = ProgressTokenSigner::new("other secret");
+        let token = signer.mint_at(&new_cursor(), b"filter", 100);
+        let err = other.verify_at(&token, b"filter", 100).unwrap_err();
+        assert_eq!(err, ProgressTokenError::InvalidSignature);
+    }
+}
@@ -0,0 +1,219 @@
+//! Limit the rate at which streams send data.
+
+use std::{
+    num::NonZeroU32,
+    pin::Pin,
+    task::{self, Poll},
+    time::Duration,
+};
+
+use apibara_core::node::v1alpha2::StreamDataResponse;
+use futures::{Future, Stream};
+use governor::{
+    clock::{Clock, DefaultClock},
+    DefaultDirectRateLimiter, InsufficientCapacity, Quota, RateLimiter,
+};
+use pin_project::pin_project;
+use prost::Message;
+use tokio::time::Sleep;
+
+type ResponseItem = Result<StreamDataResponse, tonic::Status>;
+
+/// Per-stream limits on the messages and bytes sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamRateLimit {
+    /// Maximum number of messages sent per second.
+    pub messages_per_second: Option<NonZeroU32>,
+    /// Maximum number of bytes sent per second.
+    pub bytes_per_second: Option<NonZeroU32>,
+}
+
+/// A response stream that slows down once it exceeds its rate limit.
+///
+/// Limits are enforced with rate limiters that allow bursts of up to one
+/// second worth of budget. Streams that exceed their budget are not closed:
+/// the message is held back until the budget is available again. Messages
+/// larger than the bytes limit are sent once the full budget is available.
+#[pin_project]
+pub struct RateLimitedStream<S>
+where
+    S: Stream<Item = ResponseItem>,
+{
+    #[pin]
+    inner: S,
+    clock: DefaultClock,
+    messages: Option<DefaultDirectRateLimiter>,
+    bytes: Option<DefaultDirectRateLimiter>,
+    /// The message waiting for budget, and whether it was already counted
+    /// against the messages limit.
+    pending: Option<(StreamDataResponse, bool)>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl StreamRateLimit {
+    /// Returns true if no limit is configured.
+    pub fn is_unlimited(&self) -> bool {
+        self.messages_per_second.is_none() && self.bytes_per_second.is_none()
+    }
+}
+
+impl<S> RateLimitedStream<S>
+where
+    S: Stream<Item = ResponseItem>,
+{
+    pub fn new(inner: S, limit: StreamRateLimit) -> Self {
+        let clock = DefaultClock::default();
+        let new_limiter = |rate| RateLimiter::direct_with_clock(Quota::per_second(rate), &clock);
+        RateLimitedStream {
+            inner,
+            messages: limit.messages_per_second.map(new_limiter),
+            bytes: limit.bytes_per_second.map(new_limiter),
+            clock,
+            pending: None,
+            delay: None,
+        }
+    }
+}
+
+impl<S> Stream for RateLimitedStream<S>
+where
+    S: Stream<Item = ResponseItem>,
+{
+    type Item = ResponseItem;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+
+        loop {
+            if let Some(delay) = this.delay.as_mut() {
+                match delay.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(_) => *this.delay = None,
+                }
+            }
+
+            let (item, mut counted) = match this.pending.take() {
+                Some(pending) => pending,
+                None => match this.inner.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(Ok(response))) => (response, false),
+                    other => return other,
+                },
+            };
+
+            if !counted {
+                if let Some(messages) = this.messages.as_ref() {
+                    if let Some(wait) = wait_time(messages, this.clock, 1) {
+                        *this.pending = Some((item, false));
+                        *this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+                        continue;
+                    }
+                }
+                counted = true;
+            }
+
+            if let Some(bytes) = this.bytes.as_ref() {
+                if let Some(wait) = wait_time(bytes, this.clock, item.encoded_len()) {
+                    *this.pending = Some((item, counted));
+                    *this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+                    continue;
+                }
+            }
+
+            return Poll::Ready(Some(Ok(item)));
+        }
+    }
+}
+
+/// Consumes `amount` from the limiter, returning how long to wait if the
+/// budget is not available.
+///
+/// Amounts larger than the limiter's burst consume the whole burst.
+fn wait_time(
+    limiter: &DefaultDirectRateLimiter,
+    clock: &DefaultClock,
+    amount: usize,
+) -> Option<Duration> {
+    let amount = u32::try_from(amount).unwrap_or(u32::MAX);
+    let amount = NonZeroU32::new(amount)?;
+    match limiter.check_n(amount) {
+        Ok(Ok(_)) => None,
+        Ok(Err(not_until)) => Some(not_until.wait_time_from(clock.now())),
+        Err(InsufficientCapacity(burst)) => wait_time(limiter, clock, burst as usize),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU32, time::Duration};
+
+    use apibara_core::node::v1alpha2::StreamDataResponse;
+    use futures::{stream, StreamExt};
+    use tokio::time::Instant;
+
+    use super::{RateLimitedStream, StreamRateLimit};
+
+    #[tokio::test]
+    async fn test_limit_messages_per_second() {
+        let messages = (0..30).map(|_| Ok(StreamDataResponse::default()));
+        let limit = StreamRateLimit {
+            messages_per_second: NonZeroU32::new(20),
+            bytes_per_second: None,
+        };
+
+        let start = Instant::now();
+        let received = RateLimitedStream::new(stream::iter(messages), limit)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(received.len(), 30);
+        // the first 20 messages are the initial burst.
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_limit_bytes_per_second() {
+        // each message is 2 bytes long.
+        let messages = (0..20).map(|_| {
+            Ok(StreamDataResponse {
+                stream_id: 1,
+                ..Default::default()
+            })
+        });
+        let limit = StreamRateLimit {
+            messages_per_second: None,
+            bytes_per_second: NonZeroU32::new(20),
+        };
+
+        let start = Instant::now();
+        let received = RateLimitedStream::new(stream::iter(messages), limit)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(received.len(), 20);
+        // the first 10 messages are the initial burst.
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_message_larger_than_bytes_limit() {
+        let messages = (0..2).map(|_| {
+            Ok(StreamDataResponse {
+                stream_id: 1,
+                ..Default::default()
+            })
+        });
+        let limit = StreamRateLimit {
+            messages_per_second: None,
+            bytes_per_second: NonZeroU32::new(1),
+        };
+
+        let start = Instant::now();
+        let received = tokio::time::timeout(
+            Duration::from_secs(5),
+            RateLimitedStream::new(stream::iter(messages), limit).collect::<Vec<_>>(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(received.len(), 2);
+        // each message consumes the whole budget.
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+}
@@ -0,0 +1,157 @@
+//! Track running streams so they can be cancelled individually.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio_util::sync::CancellationToken;
+
+/// The streams running on a server, with the token that cancels each of them.
+///
+/// Streams are identified by the `stream_id` of their current configuration.
+/// Clients choose the stream id, so several streams can share the same id.
+/// Streams that are not configured yet have no stream id.
+#[derive(Clone, Default)]
+pub struct StreamRegistry {
+    inner: Arc<Mutex<RegistryState>>,
+}
+
+#[derive(Default)]
+struct RegistryState {
+    next_key: u64,
+    streams: HashMap<u64, RegisteredStream>,
+}
+
+struct RegisteredStream {
+    stream_id: Option<u64>,
+    ct: CancellationToken,
+}
+
+/// A stream in the registry, removed from it when dropped.
+pub struct StreamRegistration {
+    key: u64,
+    ct: CancellationToken,
+    registry: StreamRegistry,
+}
+
+impl StreamRegistry {
+    /// Registers a new stream, cancelled by `ct`.
+    ///
+    /// The stream has no stream id until it's configured.
+    pub fn register(&self, ct: CancellationToken) -> StreamRegistration {
+        let mut state = self.inner.lock().expect("stream registry poisoned");
+        let key = state.next_key;
+        state.next_key += 1;
+        state.streams.insert(
+            key,
+            RegisteredStream {
+                stream_id: None,
+                ct: ct.clone(),
+            },
+        );
+
+        StreamRegistration {
+            key,
+            ct,
+            registry: self.clone(),
+        }
+    }
+
+    /// Cancels all streams with the given stream id, returning how many were cancelled.
+    ///
+    /// Cancelled streams end cleanly after the message they are sending.
+    pub fn cancel(&self, stream_id: u64) -> usize {
+        let state = self.inner.lock().expect("stream registry poisoned");
+        let mut cancelled = 0;
+        for stream in state.streams.values() {
+            if stream.stream_id == Some(stream_id) && !stream.ct.is_cancelled() {
+                stream.ct.cancel();
+                cancelled += 1;
+            }
+        }
+        cancelled
+    }
+
+    /// Returns the number of running streams.
+    pub fn len(&self) -> usize {
+        let state = self.inner.lock().expect("stream registry poisoned");
+        state.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl StreamRegistration {
+    /// Returns the token that cancels the stream.
+    pub fn token(&self) -> CancellationToken {
+        self.ct.clone()
+    }
+
+    /// Updates the stream id, after the client configures the stream.
+    pub fn set_stream_id(&self, stream_id: u64) {
+        let mut state = self
+            .registry
+            .inner
+            .lock()
+            .expect("stream registry poisoned");
+        if let Some(stream) = state.streams.get_mut(&self.key) {
+            stream.stream_id = Some(stream_id);
+        }
+    }
+}
+
+impl Drop for StreamRegistration {
+    fn drop(&mut self) {
+        let mut state = self
+            .registry
+            .inner
+            .lock()
+            .expect("stream registry poisoned");
+        state.streams.remove(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::sync::CancellationToken;
+
+    use super::StreamRegistry;
+
+    #[test]
+    fn test_cancel_by_stream_id() {
+        let registry = StreamRegistry::default();
+        let shutdown = CancellationToken::new();
+
+        let first = registry.register(shutdown.child_token());
+        let second = registry.register(shutdown.child_token());
+        let third = registry.register(shutdown.child_token());
+        first.set_stream_id(1);
+        second.set_stream_id(2);
+        third.set_stream_id(1);
+        assert_eq!(registry.len(), 3);
+
+        assert_eq!(registry.cancel(1), 2);
+        assert!(first.token().is_cancelled());
+        assert!(!second.token().is_cancelled());
+        assert!(third.token().is_cancelled());
+        // already cancelled.
+        assert_eq!(registry.cancel(1), 0);
+
+        // streams that are not configured yet are never cancelled.
+        let unconfigured = registry.register(shutdown.child_token());
+        assert_eq!(registry.cancel(0), 0);
+        assert!(!unconfigured.token().is_cancelled());
+        drop(unconfigured);
+
+        drop(first);
+        drop(third);
+        assert_eq!(registry.len(), 1);
+
+        // the parent token cancels all streams.
+        shutdown.cancel();
+        assert!(second.token().is_cancelled());
+    }
+}
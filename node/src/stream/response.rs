@@ -1,14 +1,87 @@
 use std::{
     pin::Pin,
+    sync::{Arc, Mutex},
     task::{self, Poll},
     time::Duration,
 };
 
-use apibara_core::node::v1alpha2::StreamDataResponse;
+use apibara_core::node::v1alpha2::{Cursor as ProtoCursor, DataFinality, StreamDataResponse};
 use futures::Stream;
 use pin_project::pin_project;
 
-use super::{error::StreamError, heartbeat::Heartbeat};
+use super::{error::StreamError, heartbeat::Heartbeat, metrics::StreamMetrics};
+
+/// Default interval between heartbeats when no data is sent.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Shortest interval between heartbeats the server can be configured with.
+pub const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest interval between heartbeats the server can be configured with.
+///
+/// Clients like the SDK close streams that don't send messages for 45 seconds.
+pub const MAX_HEARTBEAT_INTERVAL: Duration = DEFAULT_HEARTBEAT_INTERVAL;
+
+/// The latest cursor reached by a data stream, shared with its heartbeats.
+///
+/// It also holds the finality of the stream, used to label heartbeat metrics.
+#[derive(Debug, Clone, Default)]
+pub struct LatestCursor(Arc<Mutex<LatestCursorState>>);
+
+#[derive(Debug, Default)]
+struct LatestCursorState {
+    cursor: Option<ProtoCursor>,
+    finality: Option<DataFinality>,
+}
+
+impl LatestCursor {
+    pub fn update(&self, cursor: Option<ProtoCursor>) {
+        self.0.lock().expect("latest cursor lock poisoned").cursor = cursor;
+    }
+
+    pub fn get(&self) -> Option<ProtoCursor> {
+        self.0
+            .lock()
+            .expect("latest cursor lock poisoned")
+            .cursor
+            .clone()
+    }
+
+    /// Changes the finality of the stream.
+    pub fn set_finality(&self, finality: DataFinality) {
+        self.0.lock().expect("latest cursor lock poisoned").finality = Some(finality);
+    }
+
+    /// Returns the finality of the stream, unknown until the stream is configured.
+    pub fn finality(&self) -> DataFinality {
+        self.0
+            .lock()
+            .expect("latest cursor lock poisoned")
+            .finality
+            .unwrap_or(DataFinality::DataStatusUnknown)
+    }
+}
+
+/// The heartbeat interval requested by a client, shared with its response stream.
+#[derive(Debug, Clone, Default)]
+pub struct RequestedHeartbeat(Arc<Mutex<Option<Option<Duration>>>>);
+
+impl RequestedHeartbeat {
+    /// Requests a new heartbeat interval.
+    ///
+    /// `None` restores the server default, `Duration::ZERO` disables heartbeats.
+    pub fn request(&self, interval: Option<Duration>) {
+        *self.0.lock().expect("requested heartbeat lock poisoned") = Some(interval);
+    }
+
+    /// Returns the interval requested since the last call, if any.
+    fn take(&self) -> Option<Option<Duration>> {
+        self.0
+            .lock()
+            .expect("requested heartbeat lock poisoned")
+            .take()
+    }
+}
 
 #[pin_project]
 pub struct ResponseStream<S>
@@ -17,6 +90,10 @@ where
 {
     #[pin]
     inner: Heartbeat<S>,
+    metrics: StreamMetrics,
+    latest_cursor: Option<LatestCursor>,
+    heartbeat_interval: Duration,
+    requested_heartbeat: Option<RequestedHeartbeat>,
 }
 
 impl<S> ResponseStream<S>
@@ -24,8 +101,45 @@ where
     S: Stream<Item = Result<StreamDataResponse, StreamError>>,
 {
     pub fn new(inner: S) -> Self {
-        let inner = Heartbeat::new(inner, Duration::from_secs(30));
-        ResponseStream { inner }
+        Self::with_initial_heartbeat_delay(inner, DEFAULT_HEARTBEAT_INTERVAL)
+    }
+
+    /// Creates a new response stream that sends the first heartbeat only if
+    /// no message was sent for `initial_delay`.
+    pub fn with_initial_heartbeat_delay(inner: S, initial_delay: Duration) -> Self {
+        Self::with_heartbeat(inner, initial_delay, DEFAULT_HEARTBEAT_INTERVAL)
+    }
+
+    /// Creates a new response stream that sends a heartbeat every `interval`
+    /// without messages, the first one after `initial_delay`.
+    pub fn with_heartbeat(inner: S, initial_delay: Duration, interval: Duration) -> Self {
+        let inner = Heartbeat::with_initial_delay(inner, initial_delay, interval);
+        ResponseStream {
+            inner,
+            metrics: StreamMetrics::new(),
+            latest_cursor: None,
+            heartbeat_interval: interval,
+            requested_heartbeat: None,
+        }
+    }
+
+    /// Includes the latest cursor reached by the data stream in heartbeats.
+    pub fn with_latest_cursor(mut self, latest_cursor: LatestCursor) -> Self {
+        self.latest_cursor = Some(latest_cursor);
+        self
+    }
+
+    /// Records the stream metrics with `metrics`.
+    #[cfg(test)]
+    fn with_metrics(mut self, metrics: StreamMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Lets the client change the heartbeat interval, or disable heartbeats.
+    pub fn with_requested_heartbeat(mut self, requested_heartbeat: RequestedHeartbeat) -> Self {
+        self.requested_heartbeat = Some(requested_heartbeat);
+        self
     }
 }
 
@@ -36,7 +150,22 @@ where
     type Item = Result<StreamDataResponse, tonic::Status>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = self.project();
+        let mut this = self.project();
+
+        if let Some(requested) = this
+            .requested_heartbeat
+            .as_ref()
+            .and_then(RequestedHeartbeat::take)
+        {
+            let interval = requested.unwrap_or(*this.heartbeat_interval);
+            let interval = if interval.is_zero() {
+                None
+            } else {
+                Some(interval)
+            };
+            this.inner.as_mut().set_interval(interval);
+        }
+
         match this.inner.poll_next(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(None) => Poll::Ready(None),
@@ -48,10 +177,20 @@ where
                             stream_data_response::Message, Heartbeat,
                         };
 
+                        let finality = this
+                            .latest_cursor
+                            .as_ref()
+                            .map(LatestCursor::finality)
+                            .unwrap_or(DataFinality::DataStatusUnknown);
+                        this.metrics.record_heartbeat(finality);
                         // stream_id is not relevant for heartbeat messages
+                        let heartbeat = Heartbeat {
+                            cursor: this.latest_cursor.as_ref().and_then(LatestCursor::get),
+                            ..Heartbeat::default()
+                        };
                         let response = StreamDataResponse {
                             stream_id: 0,
-                            message: Some(Message::Heartbeat(Heartbeat {})),
+                            message: Some(Message::Heartbeat(heartbeat)),
                         };
                         Ok(response)
                     }
@@ -63,3 +202,140 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apibara_core::node::v1alpha2::{
+        stream_data_response::Message, Cursor as ProtoCursor, DataFinality, Heartbeat,
+        StreamDataResponse,
+    };
+    use futures::{stream, Stream, StreamExt};
+    use opentelemetry::{
+        metrics::MeterProvider,
+        sdk::{
+            export::metrics::{
+                aggregation::{cumulative_temporality_selector, Sum},
+                InstrumentationLibraryReader,
+            },
+            metrics::{
+                aggregators::SumAggregator,
+                controllers::{self, BasicController},
+                processors, selectors,
+            },
+        },
+        Context,
+    };
+
+    use super::{LatestCursor, ResponseStream};
+    use crate::stream::{metrics::StreamMetrics, StreamError};
+
+    /// Returns a data stream that never sends data.
+    fn idle_stream() -> stream::Pending<Result<StreamDataResponse, StreamError>> {
+        stream::pending()
+    }
+
+    async fn next_heartbeat(
+        stream: &mut (impl Stream<Item = Result<StreamDataResponse, tonic::Status>> + Unpin),
+    ) -> Heartbeat {
+        match stream.next().await.unwrap().unwrap().message {
+            Some(Message::Heartbeat(heartbeat)) => heartbeat,
+            message => panic!("expected heartbeat, got {:?}", message),
+        }
+    }
+
+    /// Returns the number of heartbeats recorded by `controller`, with their finality label.
+    fn recorded_heartbeats(controller: &BasicController) -> Vec<(String, u64)> {
+        let cx = Context::current();
+        controller.collect(&cx).unwrap();
+
+        let mut heartbeats = Vec::default();
+        controller
+            .try_for_each(&mut |_, reader| {
+                reader.try_for_each(&cumulative_temporality_selector(), &mut |record| {
+                    if record.descriptor().name() != "stream_heartbeats_sent" {
+                        return Ok(());
+                    }
+                    let finality = record
+                        .attributes()
+                        .iter()
+                        .find(|(key, _)| key.as_str() == "finality")
+                        .map(|(_, value)| value.to_string())
+                        .unwrap_or_default();
+                    let count = record
+                        .aggregator()
+                        .and_then(|agg| agg.as_any().downcast_ref::<SumAggregator>())
+                        .map(|sum| sum.sum().unwrap().to_u64(record.descriptor().number_kind()))
+                        .unwrap_or_default();
+                    heartbeats.push((finality, count));
+                    Ok(())
+                })
+            })
+            .unwrap();
+        heartbeats
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_with_latest_cursor() {
+        let latest_cursor = LatestCursor::default();
+        let mut stream =
+            Box::pin(ResponseStream::new(idle_stream()).with_latest_cursor(latest_cursor.clone()));
+
+        // no cursor until the stream sends data.
+        assert!(next_heartbeat(&mut stream).await.cursor.is_none());
+
+        let cursor = ProtoCursor {
+            order_key: 5,
+            unique_key: vec![5],
+        };
+        latest_cursor.update(Some(cursor.clone()));
+        assert_eq!(next_heartbeat(&mut stream).await.cursor, Some(cursor));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_without_latest_cursor() {
+        let mut stream = Box::pin(ResponseStream::new(idle_stream()));
+        assert!(next_heartbeat(&mut stream).await.cursor.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat_metrics_are_labeled_with_finality() {
+        let controller = controllers::basic(
+            processors::factory(
+                selectors::simple::inexpensive(),
+                cumulative_temporality_selector(),
+            )
+            // report all metrics, not only the ones updated since the last collection.
+            .with_memory(true),
+        )
+        // collect every time the test reads the metrics.
+        .with_collect_period(Duration::ZERO)
+        .build();
+        let metrics = StreamMetrics::with_meter(&controller.meter("test"));
+
+        let latest_cursor = LatestCursor::default();
+        let mut stream = Box::pin(
+            ResponseStream::new(idle_stream())
+                .with_latest_cursor(latest_cursor.clone())
+                .with_metrics(metrics),
+        );
+
+        // the stream is not configured yet.
+        next_heartbeat(&mut stream).await;
+        assert_eq!(
+            recorded_heartbeats(&controller),
+            vec![("unknown".to_string(), 1)]
+        );
+
+        latest_cursor.set_finality(DataFinality::DataStatusAccepted);
+        next_heartbeat(&mut stream).await;
+        next_heartbeat(&mut stream).await;
+        let mut heartbeats = recorded_heartbeats(&controller);
+        heartbeats.sort();
+        assert_eq!(
+            heartbeats,
+            vec![("accepted".to_string(), 2), ("unknown".to_string(), 1)]
+        );
+    }
+}
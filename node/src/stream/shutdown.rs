@@ -0,0 +1,113 @@
+//! Close streams cleanly when the server shuts down or they run for too long.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{self, Poll},
+    time::Duration,
+};
+
+use futures::Stream;
+use pin_project::pin_project;
+use tokio::time::Sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+/// A stream that ends when the server shuts down, or after its maximum duration.
+///
+/// The stream ends between two messages, so clients never receive a partial
+/// batch and can resume from the last cursor they received.
+#[pin_project]
+pub struct ShutdownStream<S> {
+    #[pin]
+    inner: S,
+    cancelled: Pin<Box<dyn Future<Output = ()> + Send>>,
+    expired: Option<Pin<Box<Sleep>>>,
+    terminated: bool,
+}
+
+impl<S> ShutdownStream<S> {
+    pub fn new(inner: S, ct: CancellationToken) -> Self {
+        let cancelled = Box::pin(async move { ct.cancelled().await });
+        ShutdownStream {
+            inner,
+            cancelled,
+            expired: None,
+            terminated: false,
+        }
+    }
+
+    /// Ends the stream once it has been running for `max_duration`, if any.
+    ///
+    /// Clients are expected to reconnect from the last cursor they received.
+    pub fn with_max_duration(mut self, max_duration: Option<Duration>) -> Self {
+        self.expired = max_duration.map(|duration| Box::pin(tokio::time::sleep(duration)));
+        self
+    }
+}
+
+impl<S> Stream for ShutdownStream<S>
+where
+    S: Stream,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+
+        if *this.terminated {
+            return Poll::Ready(None);
+        }
+
+        if this.cancelled.as_mut().poll(cx).is_ready() {
+            debug!("closing cancelled stream");
+            *this.terminated = true;
+            return Poll::Ready(None);
+        }
+
+        if let Some(expired) = this.expired {
+            if expired.as_mut().poll(cx).is_ready() {
+                debug!("closing stream after its maximum duration");
+                *this.terminated = true;
+                return Poll::Ready(None);
+            }
+        }
+
+        this.inner.poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{stream, StreamExt};
+    use tokio_util::sync::CancellationToken;
+
+    use super::ShutdownStream;
+
+    #[tokio::test]
+    async fn test_stream_ends_on_shutdown() {
+        let ct = CancellationToken::new();
+        let inner = stream::iter(vec![1, 2]).chain(stream::pending());
+        let mut stream = ShutdownStream::new(inner, ct.clone());
+
+        assert_eq!(stream.next().await, Some(1));
+        assert_eq!(stream.next().await, Some(2));
+
+        ct.cancel();
+        assert_eq!(stream.next().await, None);
+        assert_eq!(stream.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_stream_ends_after_max_duration() {
+        let inner = stream::iter(vec![1]).chain(stream::pending());
+        let mut stream = ShutdownStream::new(inner, CancellationToken::new())
+            .with_max_duration(Some(Duration::from_millis(50)));
+
+        assert_eq!(stream.next().await, Some(1));
+        assert_eq!(stream.next().await, None);
+        assert_eq!(stream.next().await, None);
+    }
+}
@@ -0,0 +1,129 @@
+//! Split batches that are too large to send in a single message.
+
+use apibara_core::node::v1alpha2::{Cursor as ProtoCursor, Data, DataFinality};
+use prost::encoding::encoded_len_varint;
+
+/// Bytes reserved for the fields that are not block data, like the cursors
+/// and the progress token, and for the response wrapping the data.
+pub(super) const MESSAGE_OVERHEAD: usize = 1024;
+
+/// Returns true if the response containing all `blocks` may be larger than `max_message_size`.
+///
+/// `blocks` contains the encoded data of each block in the batch.
+pub fn exceeds_max_message_size(blocks: &[Vec<Vec<u8>>], max_message_size: usize) -> bool {
+    let size = blocks
+        .iter()
+        .map(|data| encoded_data_len(data))
+        .sum::<usize>();
+    size + MESSAGE_OVERHEAD > max_message_size
+}
+
+/// Returns the size of the `data` field of the message containing `data`.
+fn encoded_data_len(data: &[Vec<u8>]) -> usize {
+    data.iter()
+        .map(|block| 1 + encoded_len_varint(block.len() as u64) + block.len())
+        .sum()
+}
+
+/// Groups consecutive blocks into data messages not larger than `max_message_size`.
+///
+/// `blocks` contains the cursor and the encoded data of each block in the
+/// batch, in order. Each message starts at the end cursor of the previous
+/// one, so that clients receive a contiguous sequence of cursors as if the
+/// batch was smaller. Blocks larger than the limit are sent in their own message.
+pub fn split_data(
+    start_cursor: Option<ProtoCursor>,
+    blocks: Vec<(ProtoCursor, Vec<Vec<u8>>)>,
+    finality: DataFinality,
+    max_message_size: usize,
+) -> Vec<Data> {
+    let max_data_size = max_message_size.saturating_sub(MESSAGE_OVERHEAD);
+    let new_data = |cursor: Option<ProtoCursor>| Data {
+        cursor,
+        finality: finality as i32,
+        ..Data::default()
+    };
+
+    let mut messages = Vec::default();
+    let mut current = new_data(start_cursor);
+    let mut current_size = 0;
+    for (cursor, data) in blocks {
+        let size = encoded_data_len(&data);
+
+        if !current.data.is_empty() && current_size + size > max_data_size {
+            let next = new_data(current.end_cursor.clone());
+            messages.push(std::mem::replace(&mut current, next));
+            current_size = 0;
+        }
+
+        current.end_cursor = Some(cursor);
+        current.data.extend(data);
+        current_size += size;
+    }
+
+    if current.end_cursor.is_some() {
+        messages.push(current);
+    }
+
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::node::v1alpha2::{Cursor, DataFinality};
+
+    use super::{exceeds_max_message_size, split_data, MESSAGE_OVERHEAD};
+
+    fn new_cursor(order_key: u64) -> Cursor {
+        Cursor {
+            order_key,
+            unique_key: Vec::default(),
+        }
+    }
+
+    #[test]
+    fn test_split_data() {
+        // each block is 100 bytes, plus 2 bytes of encoding.
+        let max_message_size = MESSAGE_OVERHEAD + 250;
+        let blocks = vec![
+            (new_cursor(1), vec![vec![0; 100]]),
+            // the filter doesn't match any data in this block.
+            (new_cursor(2), vec![]),
+            (new_cursor(3), vec![vec![0; 100]]),
+            (new_cursor(4), vec![vec![0; 100]]),
+            // larger than the limit, sent on its own.
+            (new_cursor(5), vec![vec![0; 300]]),
+            (new_cursor(6), vec![vec![0; 100]]),
+        ];
+
+        let messages = split_data(
+            Some(new_cursor(0)),
+            blocks,
+            DataFinality::DataStatusFinalized,
+            max_message_size,
+        );
+
+        let ranges = messages
+            .iter()
+            .map(|data| {
+                (
+                    data.cursor.as_ref().unwrap().order_key,
+                    data.end_cursor.as_ref().unwrap().order_key,
+                    data.data.len(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(0, 3, 2), (3, 4, 1), (4, 5, 1), (5, 6, 1)]);
+        assert!(messages
+            .iter()
+            .all(|data| data.finality == DataFinality::DataStatusFinalized as i32));
+        assert!(!exceeds_max_message_size(
+            &[messages[0].data.clone()],
+            max_message_size
+        ));
+        assert!(exceeds_max_message_size(
+            &[messages[2].data.clone()],
+            max_message_size
+        ));
+    }
+}
@@ -0,0 +1,70 @@
+//! Summarize the data sent by bounded streams.
+
+use apibara_core::node::v1alpha2::{Cursor as ProtoCursor, Data, StreamSummary};
+
+/// Counts the data sent to the client, for the summary sent when a stream
+/// reaches its end cursor.
+#[derive(Debug, Default)]
+pub struct SummaryTracker {
+    blocks: u64,
+    batches: u64,
+    first_cursor: Option<ProtoCursor>,
+    last_cursor: Option<ProtoCursor>,
+}
+
+impl SummaryTracker {
+    /// Records a data message sent to the client.
+    pub fn record(&mut self, data: &Data) {
+        self.blocks += data.data.len() as u64;
+        self.batches += 1;
+        if self.first_cursor.is_none() {
+            self.first_cursor = data.end_cursor.clone();
+        }
+        self.last_cursor = data.end_cursor.clone();
+    }
+
+    pub fn summary(&self) -> StreamSummary {
+        StreamSummary {
+            blocks: self.blocks,
+            batches: self.batches,
+            first_cursor: self.first_cursor.clone(),
+            last_cursor: self.last_cursor.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::node::v1alpha2::{Cursor, Data};
+
+    use super::SummaryTracker;
+
+    fn new_data(end: u64, blocks: usize) -> Data {
+        Data {
+            end_cursor: Some(Cursor {
+                order_key: end,
+                unique_key: Vec::default(),
+            }),
+            data: vec![Vec::default(); blocks],
+            ..Data::default()
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut tracker = SummaryTracker::default();
+        let summary = tracker.summary();
+        assert_eq!(summary.batches, 0);
+        assert!(summary.first_cursor.is_none());
+
+        tracker.record(&new_data(2, 3));
+        tracker.record(&new_data(4, 0));
+        tracker.record(&new_data(6, 2));
+
+        let summary = tracker.summary();
+        assert_eq!(summary.blocks, 5);
+        assert_eq!(summary.batches, 3);
+        assert_eq!(summary.first_cursor.unwrap().order_key, 2);
+        assert_eq!(summary.last_cursor.unwrap().order_key, 6);
+    }
+}
@@ -18,7 +18,7 @@ pub use opentelemetry::{Context, Key, KeyValue};
 use tracing_opentelemetry::MetricsLayer;
 use tracing_subscriber::{prelude::*, registry::LookupSpan, EnvFilter, Layer};
 
-pub use opentelemetry::metrics::{Counter, Meter};
+pub use opentelemetry::metrics::{Counter, Histogram, Meter, UpDownCounter};
 
 const OTEL_SDK_DISABLED: &str = "OTEL_SDK_DISABLED";
 
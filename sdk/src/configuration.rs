@@ -1,4 +1,6 @@
-use apibara_core::node::v1alpha2::{Cursor, DataFinality, StreamDataRequest};
+use std::time::Duration;
+
+use apibara_core::node::v1alpha2::{BlockRange, Cursor, DataFinality, StreamDataRequest};
 use prost::{EncodeError, Message};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
@@ -16,6 +18,34 @@ pub struct Configuration<F: Message + Default> {
     pub finality: Option<DataFinality>,
     /// The data filter.
     pub filter: F,
+    /// Flag data older than this many seconds as stale.
+    pub max_data_age_seconds: Option<u64>,
+    /// Flag the first batch that reaches the chain head.
+    #[serde(default)]
+    pub notify_caught_up: bool,
+    /// Stop streaming after this cursor, inclusive.
+    pub end_cursor: Option<Cursor>,
+    /// Send a heartbeat after this many seconds without messages, `0` disables heartbeats.
+    pub heartbeat_interval_seconds: Option<u64>,
+    /// Hold finalized data until a batch contains at least this many blocks.
+    pub min_batch_size: Option<u64>,
+    /// Send held data after this many seconds, even if smaller than `min_batch_size`.
+    pub max_batch_wait_seconds: Option<u64>,
+    /// Only receive invalidate messages, never data.
+    #[serde(default)]
+    pub invalidations_only: bool,
+    /// Start from the current chain head, ignoring the starting cursor.
+    #[serde(default)]
+    pub start_from_latest: bool,
+    /// Receive the chain head after the stream is configured.
+    #[serde(default)]
+    pub notify_chain_head: bool,
+    /// Receive a message every time the finalized head advances.
+    #[serde(default)]
+    pub notify_finalized: bool,
+    /// Only receive the blocks in these ranges.
+    #[serde(default)]
+    pub block_ranges: Vec<BlockRange>,
 }
 
 pub type ConfigurationClient<F> = mpsc::Sender<Configuration<F>>;
@@ -48,6 +78,17 @@ where
             starting_cursor,
             finality,
             filter,
+            max_data_age_seconds: None,
+            notify_caught_up: false,
+            end_cursor: None,
+            heartbeat_interval_seconds: None,
+            min_batch_size: None,
+            max_batch_wait_seconds: None,
+            invalidations_only: false,
+            start_from_latest: false,
+            notify_chain_head: false,
+            notify_finalized: false,
+            block_ranges: Vec::default(),
         }
     }
 
@@ -63,6 +104,19 @@ where
             finality: self.finality.map(Into::into),
             filter,
             multi_filter: Vec::default(),
+            progress_token: Vec::default(),
+            max_data_age_seconds: self.max_data_age_seconds,
+            keepalive: false,
+            notify_caught_up: self.notify_caught_up,
+            end_cursor: self.end_cursor,
+            heartbeat_interval_seconds: self.heartbeat_interval_seconds,
+            min_batch_size: self.min_batch_size,
+            max_batch_wait_seconds: self.max_batch_wait_seconds,
+            invalidations_only: self.invalidations_only,
+            start_from_latest: self.start_from_latest,
+            notify_chain_head: self.notify_chain_head,
+            notify_finalized: self.notify_finalized,
+            block_ranges: self.block_ranges,
         })
     }
 
@@ -87,12 +141,111 @@ where
         self
     }
 
+    /// Stop streaming after the given block, inclusive.
+    pub fn with_ending_block(mut self, block_number: u64) -> Self {
+        self.end_cursor = Some(Cursor {
+            order_key: block_number,
+            unique_key: vec![],
+        });
+        self
+    }
+
+    /// Only receive the given blocks, then end the stream.
+    ///
+    /// Only valid for finalized data.
+    pub fn with_block_numbers(mut self, block_numbers: impl IntoIterator<Item = u64>) -> Self {
+        self.block_ranges
+            .extend(block_numbers.into_iter().map(|block_number| BlockRange {
+                start: block_number,
+                end: block_number,
+            }));
+        self
+    }
+
+    /// Only receive the blocks between `start` and `end`, inclusive.
+    ///
+    /// Can be called multiple times to receive multiple ranges. The stream
+    /// ends after the last block. Only valid for finalized data.
+    pub fn with_block_range(mut self, start: u64, end: u64) -> Self {
+        self.block_ranges.push(BlockRange { start, end });
+        self
+    }
+
     /// Set the requested data finality.
     pub fn with_finality(mut self, finality: DataFinality) -> Self {
         self.finality = Some(finality);
         self
     }
 
+    /// Ask the server to flag data produced longer than `max_age` ago as stale.
+    pub fn with_max_data_age(mut self, max_age: Duration) -> Self {
+        self.max_data_age_seconds = Some(max_age.as_secs());
+        self
+    }
+
+    /// Ask the server to flag the first batch that reaches the chain head.
+    pub fn with_caught_up_notification(mut self) -> Self {
+        self.notify_caught_up = true;
+        self
+    }
+
+    /// Ask the server to send a heartbeat after `interval` without messages.
+    ///
+    /// The interval is rounded down to whole seconds, with a minimum of one second.
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval_seconds = Some(interval.as_secs().max(1));
+        self
+    }
+
+    /// Ask the server not to send heartbeats.
+    pub fn without_heartbeats(mut self) -> Self {
+        self.heartbeat_interval_seconds = Some(0);
+        self
+    }
+
+    /// Ask the server to merge finalized batches until they contain at least
+    /// `min_batch_size` blocks, waiting at most `max_wait` before sending them.
+    ///
+    /// The wait is rounded down to whole seconds.
+    pub fn with_min_batch_size(mut self, min_batch_size: u64, max_wait: Duration) -> Self {
+        self.min_batch_size = Some(min_batch_size);
+        self.max_batch_wait_seconds = Some(max_wait.as_secs());
+        self
+    }
+
+    /// Ask the server to send the chain head, in a heartbeat, after the
+    /// stream is configured.
+    pub fn with_chain_head_notification(mut self) -> Self {
+        self.notify_chain_head = true;
+        self
+    }
+
+    /// Ask the server to send the finalized head every time it advances,
+    /// whether the stream has data for the new finalized blocks or not.
+    pub fn with_finalized_notification(mut self) -> Self {
+        self.notify_finalized = true;
+        self
+    }
+
+    /// Ask the server to only send invalidate messages, to monitor chain
+    /// reorganizations without receiving data.
+    ///
+    /// The filter is ignored.
+    pub fn with_invalidations_only(mut self) -> Self {
+        self.invalidations_only = true;
+        self
+    }
+
+    /// Ask the server to start from the current chain head, to only receive
+    /// blocks produced after the stream starts.
+    ///
+    /// Clears the starting cursor, since the server rejects requests with both.
+    pub fn with_start_from_latest(mut self) -> Self {
+        self.starting_cursor = None;
+        self.start_from_latest = true;
+        self
+    }
+
     /// Configure the data filter.
     pub fn with_filter<G>(mut self, filter_closure: G) -> Self
     where
@@ -114,6 +267,17 @@ where
             starting_cursor: None,
             finality: None,
             filter: F::default(),
+            max_data_age_seconds: None,
+            notify_caught_up: false,
+            end_cursor: None,
+            heartbeat_interval_seconds: None,
+            min_batch_size: None,
+            max_batch_wait_seconds: None,
+            invalidations_only: false,
+            start_from_latest: false,
+            notify_chain_head: false,
+            notify_finalized: false,
+            block_ranges: Vec::default(),
         }
     }
 }
@@ -9,8 +9,9 @@ use std::{
 };
 
 use apibara_core::node::v1alpha2::{
-    stream_client::StreamClient as ProtoStreamClient, stream_data_response, Cursor, DataFinality,
-    StatusRequest, StatusResponse, StreamDataRequest, StreamDataResponse,
+    stream_client::StreamClient as ProtoStreamClient, stream_data_response, ChainHead, Cursor,
+    Data, DataFinality, ErrorCode, ErrorDetail, Heartbeat, ServerInfo, StatusRequest,
+    StatusResponse, StreamDataRequest, StreamDataResponse, StreamSummary, STREAM_PROTOCOL_VERSION,
 };
 use error_stack::{Result, ResultExt};
 use futures::Stream;
@@ -26,7 +27,7 @@ use tonic::{
     transport::Channel,
     Streaming,
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
 // Re-export tonic Uri
 pub use http::uri::InvalidUri;
@@ -75,8 +76,40 @@ pub enum DataMessage<D: Message + Default> {
     Invalidate {
         /// The cursor.
         cursor: Option<Cursor>,
+        /// The last cursor received before the invalidation, if known.
+        ///
+        /// All data after `cursor`, up to and including this cursor, is invalid.
+        previous_head: Option<Cursor>,
+    },
+    /// The finalized head advanced to the given cursor.
+    ///
+    /// Only received if requested with [Configuration::with_finalized_notification].
+    Finalized {
+        /// The new finalized head.
+        cursor: Cursor,
+    },
+    /// The stream is alive, but has no new data.
+    Heartbeat {
+        /// The latest cursor reached by the stream, if any.
+        cursor: Option<Cursor>,
+        /// The chain head when the stream was configured.
+        ///
+        /// Only received if requested with [Configuration::with_chain_head_notification].
+        chain_head: Option<ChainHead>,
+    },
+    /// The stream reached its end cursor, no more messages are received.
+    ///
+    /// Only received by streams with an ending block, see [Configuration::with_ending_block].
+    Summary {
+        /// Number of blocks received.
+        blocks: u64,
+        /// Number of data messages received, including empty ones.
+        batches: u64,
+        /// End cursor of the first batch received.
+        first_cursor: Option<Cursor>,
+        /// End cursor of the last batch received.
+        last_cursor: Option<Cursor>,
     },
-    Heartbeat,
 }
 
 /// Data stream client.
@@ -84,6 +117,7 @@ pub enum DataMessage<D: Message + Default> {
 pub struct StreamClient {
     inner: ProtoStreamClient<InterceptedService<Channel, MetadataInterceptor>>,
     timeout: Duration,
+    strict_protocol_version: bool,
 }
 
 /// Data stream builder.
@@ -94,6 +128,7 @@ pub struct ClientBuilder {
     max_message_size: Option<usize>,
     metadata: MetadataMap,
     timeout: Duration,
+    strict_protocol_version: bool,
 }
 
 /// A stream of on-chain data.
@@ -111,6 +146,7 @@ where
     #[pin]
     inner: Pin<Box<Timeout<Streaming<StreamDataResponse>>>>,
     inner_tx: Sender<StreamDataRequest>,
+    strict_protocol_version: bool,
     _data: PhantomData<D>,
 }
 
@@ -122,6 +158,7 @@ where
 {
     #[pin]
     inner: Pin<Box<Timeout<Streaming<StreamDataResponse>>>>,
+    strict_protocol_version: bool,
     _data: PhantomData<D>,
 }
 
@@ -151,6 +188,14 @@ impl ClientBuilder {
         self
     }
 
+    /// Fail the stream if the server uses a different stream protocol version.
+    ///
+    /// By default, the client only logs a warning on mismatch.
+    pub fn with_strict_protocol_version(mut self, strict: bool) -> Self {
+        self.strict_protocol_version = strict;
+        self
+    }
+
     /// Create and connect to the stream at the given url.
     ///
     /// If a configuration was provided, the client will immediately send it to the server upon
@@ -172,6 +217,7 @@ impl ClientBuilder {
         Ok(StreamClient {
             inner: default_client,
             timeout: self.timeout,
+            strict_protocol_version: self.strict_protocol_version,
         })
     }
 }
@@ -183,6 +229,7 @@ impl Default for ClientBuilder {
             max_message_size: None,
             metadata: MetadataMap::default(),
             timeout: Duration::from_secs(45),
+            strict_protocol_version: false,
         }
     }
 }
@@ -218,6 +265,7 @@ impl StreamClient {
             configuration_stream: configuration,
             inner: inner_stream,
             inner_tx,
+            strict_protocol_version: self.strict_protocol_version,
             _data: PhantomData,
         };
 
@@ -240,6 +288,19 @@ impl StreamClient {
             finality: configuration.finality.map(|f| f as i32),
             filter: configuration.filter.encode_to_vec(),
             multi_filter: Vec::default(),
+            progress_token: Vec::default(),
+            max_data_age_seconds: configuration.max_data_age_seconds,
+            keepalive: false,
+            notify_caught_up: configuration.notify_caught_up,
+            end_cursor: configuration.end_cursor,
+            heartbeat_interval_seconds: configuration.heartbeat_interval_seconds,
+            min_batch_size: configuration.min_batch_size,
+            max_batch_wait_seconds: configuration.max_batch_wait_seconds,
+            invalidations_only: configuration.invalidations_only,
+            start_from_latest: configuration.start_from_latest,
+            notify_chain_head: configuration.notify_chain_head,
+            notify_finalized: configuration.notify_finalized,
+            block_ranges: configuration.block_ranges.clone(),
         };
 
         let inner_stream = self
@@ -254,6 +315,7 @@ impl StreamClient {
 
         let stream = ImmutableDataStream {
             inner: inner_stream,
+            strict_protocol_version: self.strict_protocol_version,
             _data: PhantomData,
         };
 
@@ -285,6 +347,19 @@ impl StreamClient {
             finality: configuration.finality.map(|f| f as i32),
             filter: Vec::default(),
             multi_filter,
+            progress_token: Vec::default(),
+            max_data_age_seconds: configuration.max_data_age_seconds,
+            keepalive: false,
+            notify_caught_up: configuration.notify_caught_up,
+            end_cursor: configuration.end_cursor,
+            heartbeat_interval_seconds: configuration.heartbeat_interval_seconds,
+            min_batch_size: configuration.min_batch_size,
+            max_batch_wait_seconds: configuration.max_batch_wait_seconds,
+            invalidations_only: configuration.invalidations_only,
+            start_from_latest: configuration.start_from_latest,
+            notify_chain_head: configuration.notify_chain_head,
+            notify_finalized: configuration.notify_finalized,
+            block_ranges: configuration.block_ranges.clone(),
         };
 
         let inner_stream = self
@@ -299,6 +374,7 @@ impl StreamClient {
 
         let stream = ImmutableDataStream {
             inner: inner_stream,
+            strict_protocol_version: self.strict_protocol_version,
             _data: PhantomData,
         };
 
@@ -317,6 +393,24 @@ impl StreamClient {
     }
 }
 
+impl<F, D, C> DataStream<F, D, C>
+where
+    F: Message + Default,
+    D: Message + Default,
+    C: Stream<Item = Configuration<F>> + Send + Sync + 'static,
+{
+    /// Ask the server to extend the stream deadline.
+    ///
+    /// Only useful if the client sets a `grpc-timeout` but needs a longer-lived stream.
+    pub fn send_keepalive(&self) -> Result<(), ClientError> {
+        let request = StreamDataRequest {
+            keepalive: true,
+            ..StreamDataRequest::default()
+        };
+        self.inner_tx.try_send(request).change_context(ClientError)
+    }
+}
+
 impl<F, D, C> Stream for DataStream<F, D, C>
 where
     F: Message + Default,
@@ -338,6 +432,19 @@ where
                     finality: configuration.finality.map(|f| f as i32),
                     filter: configuration.filter.encode_to_vec(),
                     multi_filter: Vec::default(),
+                    progress_token: Vec::default(),
+                    max_data_age_seconds: configuration.max_data_age_seconds,
+                    keepalive: false,
+                    notify_caught_up: configuration.notify_caught_up,
+                    end_cursor: configuration.end_cursor,
+                    heartbeat_interval_seconds: configuration.heartbeat_interval_seconds,
+                    min_batch_size: configuration.min_batch_size,
+                    max_batch_wait_seconds: configuration.max_batch_wait_seconds,
+                    invalidations_only: configuration.invalidations_only,
+                    start_from_latest: configuration.start_from_latest,
+                    notify_chain_head: configuration.notify_chain_head,
+                    notify_finalized: configuration.notify_finalized,
+                    block_ranges: configuration.block_ranges.clone(),
                 };
 
                 this.inner_tx
@@ -356,6 +463,15 @@ where
             Poll::Ready(Some(Ok(inner_message))) => match inner_message {
                 Err(err) => Poll::Ready(Some(Err(err).change_context(ClientError))),
                 Ok(response) => {
+                    // the first heartbeat is sent before the stream is configured.
+                    if let Some(stream_data_response::Message::Heartbeat(Heartbeat {
+                        server_info: Some(server_info),
+                        ..
+                    })) = &response.message
+                    {
+                        check_server_info(server_info, *this.strict_protocol_version)?;
+                    }
+
                     if response.stream_id != *this.stream_id {
                         cx.waker().wake_by_ref();
                         return Poll::Pending;
@@ -367,6 +483,7 @@ where
                             Poll::Pending
                         }
                         Some(stream_data_response::Message::Data(data)) => {
+                            warn_if_stale(&data);
                             let batch = data
                                 .data
                                 .into_iter()
@@ -384,13 +501,27 @@ where
                         Some(stream_data_response::Message::Invalidate(invalidate)) => {
                             let message = DataMessage::Invalidate {
                                 cursor: invalidate.cursor,
+                                previous_head: invalidate.previous_head,
+                            };
+                            Poll::Ready(Some(Ok(message)))
+                        }
+                        Some(stream_data_response::Message::Finalized(finalized)) => {
+                            let message = DataMessage::Finalized {
+                                cursor: finalized.cursor.unwrap_or_default(),
                             };
                             Poll::Ready(Some(Ok(message)))
                         }
-                        Some(stream_data_response::Message::Heartbeat(_)) => {
+                        Some(stream_data_response::Message::Heartbeat(heartbeat)) => {
                             debug!("received heartbeat");
-                            cx.waker().wake_by_ref();
-                            Poll::Pending
+                            let message = DataMessage::Heartbeat {
+                                cursor: heartbeat.cursor,
+                                chain_head: heartbeat.chain_head,
+                            };
+                            Poll::Ready(Some(Ok(message)))
+                        }
+                        Some(stream_data_response::Message::Summary(summary)) => {
+                            debug!(summary = ?summary, "received stream summary");
+                            Poll::Ready(Some(Ok(summary.into())))
                         }
                     }
                 }
@@ -399,12 +530,35 @@ where
     }
 }
 
+impl<D: Message + Default> From<StreamSummary> for DataMessage<D> {
+    fn from(summary: StreamSummary) -> Self {
+        DataMessage::Summary {
+            blocks: summary.blocks,
+            batches: summary.batches,
+            first_cursor: summary.first_cursor,
+            last_cursor: summary.last_cursor,
+        }
+    }
+}
+
 impl<D: Message + Default> DataMessage<D> {
     pub fn from_stream_data_response(response: StreamDataResponse) -> Option<Self> {
         match response.message {
             None => None,
-            Some(stream_data_response::Message::Heartbeat(_)) => Some(DataMessage::Heartbeat),
+            Some(stream_data_response::Message::Summary(summary)) => Some(summary.into()),
+            Some(stream_data_response::Message::Heartbeat(heartbeat)) => {
+                Some(DataMessage::Heartbeat {
+                    cursor: heartbeat.cursor,
+                    chain_head: heartbeat.chain_head,
+                })
+            }
+            Some(stream_data_response::Message::Finalized(finalized)) => {
+                Some(DataMessage::Finalized {
+                    cursor: finalized.cursor.unwrap_or_default(),
+                })
+            }
             Some(stream_data_response::Message::Data(data)) => {
+                warn_if_stale(&data);
                 let batch = data
                     .data
                     .into_iter()
@@ -422,6 +576,7 @@ impl<D: Message + Default> DataMessage<D> {
             Some(stream_data_response::Message::Invalidate(invalidate)) => {
                 let message = DataMessage::Invalidate {
                     cursor: invalidate.cursor,
+                    previous_head: invalidate.previous_head,
                 };
                 Some(message)
             }
@@ -450,6 +605,7 @@ where
                         Poll::Pending
                     }
                     Some(stream_data_response::Message::Data(data)) => {
+                        warn_if_stale(&data);
                         let batch = data
                             .data
                             .into_iter()
@@ -469,13 +625,30 @@ where
                     Some(stream_data_response::Message::Invalidate(invalidate)) => {
                         let message = DataMessage::Invalidate {
                             cursor: invalidate.cursor,
+                            previous_head: invalidate.previous_head,
                         };
                         Poll::Ready(Some(Ok(message)))
                     }
-                    Some(stream_data_response::Message::Heartbeat(_)) => {
+                    Some(stream_data_response::Message::Finalized(finalized)) => {
+                        let message = DataMessage::Finalized {
+                            cursor: finalized.cursor.unwrap_or_default(),
+                        };
+                        Poll::Ready(Some(Ok(message)))
+                    }
+                    Some(stream_data_response::Message::Heartbeat(heartbeat)) => {
                         debug!("received heartbeat");
-                        cx.waker().wake_by_ref();
-                        Poll::Pending
+                        if let Some(server_info) = heartbeat.server_info {
+                            check_server_info(&server_info, *this.strict_protocol_version)?;
+                        }
+                        let message = DataMessage::Heartbeat {
+                            cursor: heartbeat.cursor,
+                            chain_head: heartbeat.chain_head,
+                        };
+                        Poll::Ready(Some(Ok(message)))
+                    }
+                    Some(stream_data_response::Message::Summary(summary)) => {
+                        debug!(summary = ?summary, "received stream summary");
+                        Poll::Ready(Some(Ok(summary.into())))
                     }
                 },
             },
@@ -483,6 +656,42 @@ where
     }
 }
 
+fn warn_if_stale(data: &Data) {
+    if data.stale {
+        warn!(
+            block = data
+                .end_cursor
+                .as_ref()
+                .map(|c| c.order_key)
+                .unwrap_or_default(),
+            "received stale data, the server may be lagging behind the chain"
+        );
+    }
+}
+
+/// Checks that the server uses the same stream protocol version as the client.
+fn check_server_info(server_info: &ServerInfo, strict: bool) -> Result<(), ClientError> {
+    if server_info.protocol_version == STREAM_PROTOCOL_VERSION {
+        return Ok(());
+    }
+
+    if strict {
+        return Err(ClientError).attach_printable(format!(
+            "server stream protocol version {} (server {}) is not supported, expected {}",
+            server_info.protocol_version, server_info.server_version, STREAM_PROTOCOL_VERSION
+        ));
+    }
+
+    warn!(
+        server_protocol_version = server_info.protocol_version,
+        server_version = %server_info.server_version,
+        client_protocol_version = STREAM_PROTOCOL_VERSION,
+        "server uses a different stream protocol version, data may be misinterpreted"
+    );
+
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct MetadataInterceptor {
     metadata: MetadataMap,
@@ -533,6 +742,15 @@ impl Interceptor for MetadataInterceptor {
     }
 }
 
+/// Returns the machine-readable error code of a status returned by the server, if any.
+///
+/// Use this to decide whether to retry, for example after the stream was
+/// closed because of an expired deadline.
+pub fn error_code(status: &tonic::Status) -> Option<ErrorCode> {
+    let detail = ErrorDetail::from_status_details(status.details())?;
+    ErrorCode::from_i32(detail.code)
+}
+
 fn status_to_error<T>(status: tonic::Status) -> Result<T, ClientError> {
     use tonic::Code;
 
@@ -540,9 +758,51 @@ fn status_to_error<T>(status: tonic::Status) -> Result<T, ClientError> {
         Code::Unauthenticated => Err(status)
             .attach_printable("hint: did you forget to set the authentication token?")
             .change_context(ClientError),
-        Code::OutOfRange => Err(status)
+        Code::OutOfRange if error_code(&status) != Some(ErrorCode::CursorOutOfRange) => Err(status)
             .attach_printable("hint: you should increase the maximum message size")
             .change_context(ClientError),
         _ => Err(status).change_context(ClientError),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::node::v1alpha2::{
+        ErrorCode, ErrorDetail, ServerInfo, STREAM_PROTOCOL_VERSION,
+    };
+
+    use super::{check_server_info, error_code};
+
+    #[test]
+    fn test_check_server_info() {
+        let mut server_info = ServerInfo {
+            protocol_version: STREAM_PROTOCOL_VERSION,
+            server_version: "1.0.0".to_string(),
+        };
+        assert!(check_server_info(&server_info, true).is_ok());
+
+        server_info.protocol_version += 1;
+        assert!(check_server_info(&server_info, false).is_ok());
+        assert!(check_server_info(&server_info, true).is_err());
+    }
+
+    #[test]
+    fn test_error_code() {
+        let detail = ErrorDetail {
+            code: ErrorCode::InvalidCursor as i32,
+        };
+        let details = detail.to_status_details(
+            tonic::Code::InvalidArgument as i32,
+            "invalid starting cursor",
+        );
+        let status = tonic::Status::with_details(
+            tonic::Code::InvalidArgument,
+            "invalid starting cursor",
+            details.into(),
+        );
+        assert_eq!(error_code(&status), Some(ErrorCode::InvalidCursor));
+
+        let status = tonic::Status::internal("no details");
+        assert_eq!(error_code(&status), None);
+    }
+}
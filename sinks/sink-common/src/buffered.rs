@@ -0,0 +1,545 @@
+//! Send data from multiple batches to a sink in fewer, larger batches.
+
+use std::time::{Duration, Instant};
+
+use apibara_core::node::v1alpha2::{Cursor, DataFinality};
+use async_trait::async_trait;
+use error_stack::Result;
+use serde_json::Value;
+use tracing::debug;
+
+use crate::sink::{ConcurrentSink, Context, CursorAction, InvalidatedRange, Sink, SinkMetadata};
+
+/// When buffered data is sent to the inner sink.
+///
+/// If neither limit is set, batches are sent as they are received.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufferOptions {
+    /// Send the buffered data once it contains at least this many items.
+    pub max_items: Option<usize>,
+    /// Send the buffered data once the oldest batch was received this long ago.
+    ///
+    /// The age is checked when new data is received, on heartbeats and on ticks.
+    pub max_age: Option<Duration>,
+}
+
+impl BufferOptions {
+    fn is_buffering(&self) -> bool {
+        self.max_items.is_some() || self.max_age.is_some()
+    }
+}
+
+/// A sink that buffers the items of consecutive batches, and sends them to
+/// the inner sink as a single batch.
+///
+/// The cursor is not persisted while data is buffered, so that after a
+/// restart the stream resumes from the last batch sent to the inner sink.
+/// Buffered data is sent before pending data, batches with a different
+/// finality, and when the sink is stopped. Data sent on heartbeats or when
+/// the sink is stopped is sent again after a restart. Buffered batches after
+/// an invalidated cursor are dropped without being sent.
+///
+/// Batches must be arrays of items, other batches are sent as they are.
+pub struct BufferedSink<S> {
+    inner: S,
+    options: BufferOptions,
+    buffer: Option<BufferedData>,
+}
+
+#[derive(Debug)]
+struct BufferedData {
+    cursor: Option<Cursor>,
+    finality: DataFinality,
+    /// The end cursor and items of each buffered batch.
+    batches: Vec<(Cursor, Vec<Value>)>,
+    since: Instant,
+}
+
+impl BufferedData {
+    fn end_cursor(&self) -> Option<&Cursor> {
+        self.batches.last().map(|(end_cursor, _)| end_cursor)
+    }
+
+    fn len(&self) -> usize {
+        self.batches.iter().map(|(_, items)| items.len()).sum()
+    }
+}
+
+impl<S> BufferedSink<S>
+where
+    S: Sink + Send + Sync,
+{
+    pub fn new(inner: S, options: BufferOptions) -> Self {
+        BufferedSink {
+            inner,
+            options,
+            buffer: None,
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn should_flush(&self) -> bool {
+        let Some(buffer) = &self.buffer else {
+            return false;
+        };
+
+        let is_full = self
+            .options
+            .max_items
+            .map(|max_items| buffer.len() >= max_items)
+            .unwrap_or(false);
+        let is_old = self
+            .options
+            .max_age
+            .map(|max_age| buffer.since.elapsed() >= max_age)
+            .unwrap_or(false);
+
+        is_full || is_old
+    }
+
+    /// Sends the buffered data to the inner sink, returning its cursor action.
+    ///
+    /// The action refers to the end cursor of the buffered data, not to the
+    /// batch being handled. The data stays in the buffer if the inner sink fails.
+    async fn flush(&mut self) -> Result<Option<CursorAction>, S::Error> {
+        let Some(buffer) = &self.buffer else {
+            return Ok(None);
+        };
+        let Some(end_cursor) = buffer.end_cursor() else {
+            return Ok(None);
+        };
+
+        debug!(items = buffer.len(), "flushing buffered data");
+        let ctx = Context {
+            cursor: buffer.cursor.clone(),
+            end_cursor: end_cursor.clone(),
+            finality: buffer.finality,
+        };
+        let items = buffer
+            .batches
+            .iter()
+            .flat_map(|(_, items)| items.iter().cloned())
+            .collect();
+        let action = match self.inner.handle_data(&ctx, &Value::Array(items)).await? {
+            CursorAction::Persist => CursorAction::PersistAt(ctx.end_cursor),
+            action => action,
+        };
+
+        self.buffer = None;
+        Ok(Some(action))
+    }
+
+    /// Drops the buffered batches after `cursor`, they were never sent.
+    fn invalidate_buffer(&mut self, cursor: &Option<Cursor>) {
+        let Some(buffer) = &mut self.buffer else {
+            return;
+        };
+
+        let order_key = cursor.as_ref().map(|cursor| cursor.order_key);
+        buffer.batches.retain(|(end_cursor, _)| {
+            order_key
+                .map(|order_key| end_cursor.order_key <= order_key)
+                .unwrap_or(false)
+        });
+        if buffer.batches.is_empty() {
+            debug!("dropping invalidated buffered data");
+            self.buffer = None;
+        }
+    }
+}
+
+#[async_trait]
+impl<S> Sink for BufferedSink<S>
+where
+    S: Sink + Send + Sync,
+    S::Options: Send,
+{
+    type Options = S::Options;
+    type Error = S::Error;
+
+    /// Creates the inner sink, without buffering. Use [BufferedSink::new] to set the limits.
+    async fn from_options(options: Self::Options) -> Result<Self, Self::Error> {
+        let inner = S::from_options(options).await?;
+        Ok(BufferedSink::new(inner, BufferOptions::default()))
+    }
+
+    async fn handle_data(
+        &mut self,
+        ctx: &Context,
+        batch: &Value,
+    ) -> Result<CursorAction, Self::Error> {
+        // pending data is replaced by the next pending block, so it's never held.
+        let items = match batch.as_array() {
+            Some(items)
+                if self.options.is_buffering()
+                    && ctx.finality != DataFinality::DataStatusPending =>
+            {
+                items
+            }
+            _ => {
+                let flushed = self.flush().await?;
+                return match self.inner.handle_data(ctx, batch).await? {
+                    // the previously buffered data may still need its cursor persisted.
+                    CursorAction::Skip => Ok(flushed.unwrap_or(CursorAction::Skip)),
+                    action => Ok(action),
+                };
+            }
+        };
+
+        let finality_changed = self
+            .buffer
+            .as_ref()
+            .map(|buffer| buffer.finality != ctx.finality)
+            .unwrap_or(false);
+        let flushed = if finality_changed {
+            self.flush().await?
+        } else {
+            None
+        };
+
+        match &mut self.buffer {
+            // the connector retries batches that failed to flush, only add them once.
+            Some(buffer) if buffer.end_cursor() == Some(&ctx.end_cursor) => {}
+            Some(buffer) => {
+                buffer.batches.push((ctx.end_cursor.clone(), items.clone()));
+            }
+            None => {
+                self.buffer = Some(BufferedData {
+                    cursor: ctx.cursor.clone(),
+                    finality: ctx.finality,
+                    batches: vec![(ctx.end_cursor.clone(), items.clone())],
+                    since: Instant::now(),
+                });
+            }
+        }
+
+        if !self.should_flush() {
+            return Ok(flushed.unwrap_or(CursorAction::Skip));
+        }
+
+        match self.flush().await? {
+            // the flushed data ends with this batch.
+            Some(CursorAction::PersistAt(cursor)) if cursor == ctx.end_cursor => {
+                Ok(CursorAction::Persist)
+            }
+            action => Ok(action.unwrap_or(CursorAction::Persist)),
+        }
+    }
+
+    /// Batches are only handled concurrently by the inner sink if no data is buffered.
+    fn concurrent(&self) -> Option<&dyn ConcurrentSink<Error = Self::Error>> {
+        if self.options.is_buffering() {
+            return None;
+        }
+        self.inner.concurrent()
+    }
+
+    async fn handle_invalidate(&mut self, cursor: &Option<Cursor>) -> Result<(), Self::Error> {
+        self.invalidate_buffer(cursor);
+        self.inner.handle_invalidate(cursor).await
+    }
+
+    async fn handle_invalidate_range(
+        &mut self,
+        cursor: &Option<Cursor>,
+        invalidated: Option<&InvalidatedRange>,
+    ) -> Result<(), Self::Error> {
+        self.invalidate_buffer(cursor);
+        self.inner
+            .handle_invalidate_range(cursor, invalidated)
+            .await
+    }
+
+    async fn cleanup(&mut self) -> Result<(), Self::Error> {
+        self.flush().await?;
+        self.inner.cleanup().await
+    }
+
+    async fn handle_heartbeat(&mut self) -> Result<(), Self::Error> {
+        if self.should_flush() {
+            self.flush().await?;
+        }
+        self.inner.handle_heartbeat().await
+    }
+
+    async fn tick(&mut self) -> Result<(), Self::Error> {
+        if self.should_flush() {
+            self.flush().await?;
+        }
+        self.inner.tick().await
+    }
+
+    fn delivered_cursor(&self) -> Option<Cursor> {
+        self.inner.delivered_cursor()
+    }
+
+    async fn restore_delivered_cursor(
+        &mut self,
+        cursor: Option<Cursor>,
+    ) -> Result<(), Self::Error> {
+        self.inner.restore_delivered_cursor(cursor).await
+    }
+
+    fn metadata(&self) -> SinkMetadata {
+        let mut metadata = self.inner.metadata();
+        if let Some(max_items) = self.options.max_items {
+            metadata = metadata.with_summary("buffer_max_items", max_items);
+        }
+        if let Some(max_age) = self.options.max_age {
+            metadata = metadata.with_summary("buffer_max_age", format!("{:?}", max_age));
+        }
+        metadata
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::node::v1alpha2::{Cursor, DataFinality};
+    use async_trait::async_trait;
+    use error_stack::Result;
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    use crate::{
+        error::SinkError,
+        sink::{ConcurrentSink, Context, CursorAction, DeliveredBatch, Sink, SinkOptions},
+    };
+
+    use super::{BufferOptions, BufferedSink};
+
+    #[derive(Debug, Default, Deserialize)]
+    struct TestOptions {}
+
+    impl SinkOptions for TestOptions {
+        fn merge(self, _other: Self) -> Self {
+            self
+        }
+    }
+
+    #[derive(Default)]
+    struct TestSink {
+        batches: Vec<(Context, Value)>,
+        invalidated: usize,
+        concurrent: bool,
+        /// Return `Skip` instead of `Persist` for all batches.
+        skip: bool,
+    }
+
+    #[async_trait]
+    impl Sink for TestSink {
+        type Options = TestOptions;
+        type Error = SinkError;
+
+        async fn from_options(_options: Self::Options) -> Result<Self, Self::Error> {
+            Ok(TestSink::default())
+        }
+
+        async fn handle_data(
+            &mut self,
+            ctx: &Context,
+            batch: &Value,
+        ) -> Result<CursorAction, Self::Error> {
+            self.batches.push((ctx.clone(), batch.clone()));
+            if self.skip {
+                return Ok(CursorAction::Skip);
+            }
+            Ok(CursorAction::Persist)
+        }
+
+        async fn handle_invalidate(&mut self, _cursor: &Option<Cursor>) -> Result<(), Self::Error> {
+            self.invalidated += 1;
+            Ok(())
+        }
+
+        fn concurrent(&self) -> Option<&dyn ConcurrentSink<Error = Self::Error>> {
+            if self.concurrent {
+                Some(self)
+            } else {
+                None
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ConcurrentSink for TestSink {
+        type Error = SinkError;
+
+        fn max_in_flight_batches(&self) -> usize {
+            2
+        }
+
+        async fn handle_data_concurrently(
+            &self,
+            ctx: &Context,
+            _batch: &Value,
+        ) -> Result<DeliveredBatch, Self::Error> {
+            Ok(DeliveredBatch {
+                action: CursorAction::Persist,
+                delivered_cursor: Some(ctx.end_cursor.clone()),
+            })
+        }
+    }
+
+    fn new_cursor(order_key: u64) -> Cursor {
+        Cursor {
+            order_key,
+            unique_key: Vec::default(),
+        }
+    }
+
+    fn new_context(start: u64, end: u64, finality: DataFinality) -> Context {
+        Context {
+            cursor: Some(new_cursor(start)),
+            end_cursor: new_cursor(end),
+            finality,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_max_items() {
+        let options = BufferOptions {
+            max_items: Some(3),
+            max_age: None,
+        };
+        let mut sink = BufferedSink::new(TestSink::default(), options);
+        let finalized = DataFinality::DataStatusFinalized;
+
+        let ctx = new_context(0, 1, finalized);
+        let action = sink.handle_data(&ctx, &json!([1, 2])).await.unwrap();
+        assert_eq!(action, CursorAction::Skip);
+        // the same batch, retried by the connector.
+        let action = sink.handle_data(&ctx, &json!([1, 2])).await.unwrap();
+        assert_eq!(action, CursorAction::Skip);
+        assert!(sink.inner().batches.is_empty());
+
+        let ctx = new_context(1, 2, finalized);
+        let action = sink.handle_data(&ctx, &json!([3])).await.unwrap();
+        assert_eq!(action, CursorAction::Persist);
+
+        let (ctx, batch) = &sink.inner().batches[0];
+        assert_eq!(ctx.cursor.as_ref().unwrap().order_key, 0);
+        assert_eq!(ctx.end_cursor.order_key, 2);
+        assert_eq!(batch, &json!([1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn test_flush_before_pending() {
+        let options = BufferOptions {
+            max_items: Some(10),
+            max_age: None,
+        };
+        let mut sink = BufferedSink::new(TestSink::default(), options);
+
+        let ctx = new_context(0, 1, DataFinality::DataStatusAccepted);
+        sink.handle_data(&ctx, &json!([1])).await.unwrap();
+        let ctx = new_context(1, 2, DataFinality::DataStatusPending);
+        let action = sink.handle_data(&ctx, &json!([2])).await.unwrap();
+        assert_eq!(action, CursorAction::Persist);
+        let batches = &sink.inner().batches;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].1, json!([1]));
+        assert_eq!(batches[1].1, json!([2]));
+
+        sink.cleanup().await.unwrap();
+        assert_eq!(sink.inner().batches.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_drops_buffered_data() {
+        let options = BufferOptions {
+            max_items: Some(10),
+            max_age: None,
+        };
+        let mut sink = BufferedSink::new(TestSink::default(), options);
+        let accepted = DataFinality::DataStatusAccepted;
+
+        sink.handle_data(&new_context(0, 1, accepted), &json!([1]))
+            .await
+            .unwrap();
+        sink.handle_data(&new_context(1, 2, accepted), &json!([2]))
+            .await
+            .unwrap();
+        sink.handle_data(&new_context(2, 3, accepted), &json!([3]))
+            .await
+            .unwrap();
+
+        // only the batches after the cursor are dropped.
+        sink.handle_invalidate(&Some(new_cursor(1))).await.unwrap();
+        assert!(sink.inner().batches.is_empty());
+        assert_eq!(sink.inner().invalidated, 1);
+
+        sink.cleanup().await.unwrap();
+        let (ctx, batch) = &sink.inner().batches[0];
+        assert_eq!(ctx.end_cursor.order_key, 1);
+        assert_eq!(batch, &json!([1]));
+
+        sink.handle_data(&new_context(1, 2, accepted), &json!([4]))
+            .await
+            .unwrap();
+        sink.handle_invalidate(&None).await.unwrap();
+        sink.cleanup().await.unwrap();
+        assert_eq!(sink.inner().batches.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_flush_action_is_returned() {
+        let options = BufferOptions {
+            max_items: Some(10),
+            max_age: None,
+        };
+        let mut sink = BufferedSink::new(TestSink::default(), options);
+
+        // the finalized data is flushed when accepted data arrives, and its
+        // cursor is persisted even though the accepted data is buffered.
+        let ctx = new_context(0, 1, DataFinality::DataStatusFinalized);
+        sink.handle_data(&ctx, &json!([1])).await.unwrap();
+        let ctx = new_context(1, 2, DataFinality::DataStatusAccepted);
+        let action = sink.handle_data(&ctx, &json!([2])).await.unwrap();
+        assert_eq!(action, CursorAction::PersistAt(new_cursor(1)));
+
+        // the inner sink doesn't persist data it didn't acknowledge.
+        let inner = TestSink {
+            skip: true,
+            ..TestSink::default()
+        };
+        let mut sink = BufferedSink::new(inner, options);
+        let ctx = new_context(0, 1, DataFinality::DataStatusFinalized);
+        sink.handle_data(&ctx, &json!([1])).await.unwrap();
+        let ctx = new_context(1, 2, DataFinality::DataStatusAccepted);
+        let action = sink.handle_data(&ctx, &json!([2])).await.unwrap();
+        assert_eq!(action, CursorAction::Skip);
+    }
+
+    #[tokio::test]
+    async fn test_forward_concurrent_batches_without_buffering() {
+        let inner = TestSink {
+            concurrent: true,
+            ..TestSink::default()
+        };
+        let sink = BufferedSink::new(inner, BufferOptions::default());
+        let concurrent = sink.concurrent().unwrap();
+        assert_eq!(concurrent.max_in_flight_batches(), 2);
+
+        let ctx = new_context(0, 1, DataFinality::DataStatusFinalized);
+        let delivered = concurrent
+            .handle_data_concurrently(&ctx, &json!([1]))
+            .await
+            .unwrap();
+        assert_eq!(delivered.delivered_cursor, Some(ctx.end_cursor));
+
+        // buffered batches must be handled one at a time.
+        let inner = TestSink {
+            concurrent: true,
+            ..TestSink::default()
+        };
+        let options = BufferOptions {
+            max_items: Some(10),
+            max_age: None,
+        };
+        let sink = BufferedSink::new(inner, options);
+        assert!(sink.concurrent().is_none());
+    }
+}
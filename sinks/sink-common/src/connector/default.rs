@@ -2,22 +2,27 @@ use std::marker::PhantomData;
 
 use apibara_core::{filter::Filter, node::v1alpha2::Cursor};
 use apibara_script::Script;
-use apibara_sdk::{Configuration, DataMessage};
+use apibara_sdk::{Configuration, DataMessage, ImmutableDataStream};
+use async_trait::async_trait;
 use error_stack::{Result, ResultExt};
+use futures::{future::LocalBoxFuture, FutureExt};
 use prost::Message;
 use serde::Serialize;
 use serde_json::Value;
+use tokio::time::Interval;
 use tokio_stream::StreamExt;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, info};
 
 use crate::{
-    error::SinkError, sink::Sink, Context, CursorAction, DisplayCursor, PersistedState,
-    SinkErrorReportExt, SinkErrorResultExt,
+    error::SinkError, sink::Sink, Context, CursorAction, DeliveredBatch, DisplayCursor,
+    InvalidatedRange, PersistedState, SinkErrorReportExt, SinkErrorResultExt,
 };
 
 use super::{
+    pipeline::{ConcurrentExit, ConcurrentPipeline, PersistBatch, TransformBatch},
     sink::SinkWithBackoff,
+    sink_tick_interval,
     state::StateManager,
     stream::{StreamAction, StreamClientFactory},
 };
@@ -71,11 +76,28 @@ where
 
         let starting_cursor = state.cursor.clone();
 
+        self.sink
+            .restore_delivered_cursor(state.delivered_cursor.clone())
+            .await?;
+
         let mut configuration = self.starting_configuration.clone();
         if starting_cursor.is_some() {
             info!(cursor = %DisplayCursor(&starting_cursor), "restarting from last cursor");
             configuration.starting_cursor = starting_cursor.clone();
-            self.handle_invalidate(starting_cursor, &mut state, ct.clone())
+        }
+
+        // data up to the delivered cursor is finalized and won't be sent again,
+        // so only data after it must be invalidated.
+        let invalidate_cursor = match (&starting_cursor, &state.delivered_cursor) {
+            (Some(cursor), Some(delivered)) if delivered.order_key > cursor.order_key => {
+                Some(delivered.clone())
+            }
+            (None, Some(delivered)) => Some(delivered.clone()),
+            _ => starting_cursor,
+        };
+        if invalidate_cursor.is_some() {
+            self.sink
+                .handle_invalidate(&invalidate_cursor, None, ct.clone())
                 .await?;
         }
 
@@ -92,6 +114,7 @@ where
 
         self.needs_invalidation = false;
 
+        let mut tick_interval = sink_tick_interval();
         let mut ret = Ok(());
         loop {
             tokio::select! {
@@ -99,6 +122,9 @@ where
                     info!("sink stopped: cancelled");
                     break;
                 }
+                _ = tick_interval.tick() => {
+                    self.tick(&mut state, ct.clone()).await?;
+                }
                 maybe_message = data_stream.try_next() => {
                     match maybe_message {
                         Err(err) => {
@@ -111,7 +137,43 @@ where
                             break;
                         }
                         Ok(Some(message)) => {
+                            let message = if self.can_handle_concurrently(&message) {
+                                let exit = self
+                                    .handle_data_concurrently(
+                                        message,
+                                        &mut data_stream,
+                                        &mut state,
+                                        &mut tick_interval,
+                                        ct.clone(),
+                                    )
+                                    .await?;
+                                match exit {
+                                    ConcurrentExit::Message(message) => message,
+                                    ConcurrentExit::Tick => {
+                                        self.tick(&mut state, ct.clone()).await?;
+                                        continue;
+                                    }
+                                    ConcurrentExit::Stop => {
+                                        self.state_manager
+                                            .put_state(state.clone(), CursorAction::Persist)
+                                            .await?;
+                                        break;
+                                    }
+                                    ConcurrentExit::Cancelled => {
+                                        info!("sink stopped: cancelled");
+                                        break;
+                                    }
+                                    ConcurrentExit::StreamError(err) => {
+                                        ret = Err(err);
+                                        break;
+                                    }
+                                }
+                            } else {
+                                message
+                            };
+
                             let (cursor_action, stream_action) = self.handle_message(message, &mut state, ct.clone()).await?;
+                            state.delivered_cursor = self.sink.delivered_cursor();
                             self.state_manager.put_state(state.clone(), cursor_action).await?;
                             if stream_action == StreamAction::Stop {
                                 break;
@@ -132,6 +194,65 @@ where
         ret
     }
 
+    async fn tick(
+        &mut self,
+        state: &mut PersistedState<F>,
+        ct: CancellationToken,
+    ) -> Result<(), SinkError> {
+        self.sink.tick(ct).await?;
+        // the sink may have delivered buffered data.
+        let delivered_cursor = self.sink.delivered_cursor();
+        if delivered_cursor == state.delivered_cursor {
+            return Ok(());
+        }
+        state.delivered_cursor = delivered_cursor;
+        self.state_manager
+            .put_state(state.clone(), CursorAction::Skip)
+            .await
+    }
+
+    /// Returns true if the message is a batch that can be delivered together with the next ones.
+    ///
+    /// Pending data is always handled on its own, since it's invalidated by
+    /// the next message.
+    fn can_handle_concurrently(&self, message: &DataMessage<B>) -> bool {
+        let DataMessage::Data { finality, .. } = message else {
+            return false;
+        };
+        self.sink.max_in_flight_batches() > 1 && !self.needs_invalidation && !finality.is_pending()
+    }
+
+    /// Delivers consecutive batches at the same time, starting from `message`.
+    ///
+    /// Once all batches are delivered, the sink's delivered cursor is updated
+    /// to the one the batches reached in stream order.
+    async fn handle_data_concurrently(
+        &mut self,
+        message: DataMessage<B>,
+        data_stream: &mut ImmutableDataStream<B>,
+        state: &mut PersistedState<F>,
+        tick_interval: &mut Interval,
+        ct: CancellationToken,
+    ) -> Result<ConcurrentExit<B>, SinkError> {
+        let mut persistence = StatePersistence {
+            state_manager: &mut self.state_manager,
+            state,
+        };
+        let pipeline = ConcurrentPipeline {
+            sink: &self.sink,
+            script: &mut self.script,
+            persistence: &mut persistence,
+            ending_block: self.ending_block,
+        };
+        let exit = pipeline.run(message, data_stream, tick_interval, ct).await;
+
+        self.sink
+            .restore_delivered_cursor(state.delivered_cursor.clone())
+            .await?;
+
+        exit
+    }
+
     async fn handle_message(
         &mut self,
         message: DataMessage<B>,
@@ -157,15 +278,30 @@ where
                 };
                 self.handle_data(context, batch, state, ct).await
             }
-            DataMessage::Invalidate { cursor } => {
+            DataMessage::Invalidate {
+                cursor,
+                previous_head,
+            } => {
                 info!(block = %DisplayCursor(&cursor), "handle invalidate");
-                self.handle_invalidate(cursor, state, ct).await
+                let invalidated = InvalidatedRange::new(&cursor, previous_head);
+                self.handle_invalidate(cursor, invalidated, state, ct).await
             }
-            DataMessage::Heartbeat => {
+            DataMessage::Finalized { cursor } => {
+                // sinks don't request finalized head notifications.
+                debug!(cursor = %cursor, "ignoring finalized head");
+                Ok((CursorAction::Skip, StreamAction::Continue))
+            }
+            DataMessage::Heartbeat { .. } => {
                 self.sink.handle_heartbeat().await?;
                 self.state_manager.heartbeat().await?;
                 Ok((CursorAction::Skip, StreamAction::Continue))
             }
+            DataMessage::Summary {
+                blocks, batches, ..
+            } => {
+                info!(blocks, batches, "stream reached ending block");
+                Ok((CursorAction::Skip, StreamAction::Stop))
+            }
         }
     }
 
@@ -177,21 +313,12 @@ where
         ct: CancellationToken,
     ) -> Result<(CursorAction, StreamAction), SinkError> {
         if self.needs_invalidation {
-            self.handle_invalidate(context.cursor.clone(), state, ct.clone())
+            self.handle_invalidate(context.cursor.clone(), None, state, ct.clone())
                 .await?;
             self.needs_invalidation = false;
         }
 
-        // fatal error since if the sink is restarted it will receive the same data again.
-        let json_batch = batch
-            .into_iter()
-            .map(|b| serde_json::to_value(b).fatal("failed to serialize batch data"))
-            .collect::<Result<Vec<Value>, _>>()?;
-        let data = self
-            .script
-            .transform(json_batch)
-            .await
-            .map_err(|err| err.fatal("failed to transform batch data"))?;
+        let data = self.script.transform_batch(batch).await?;
 
         let block_end_cursor = context.end_cursor.order_key;
 
@@ -222,11 +349,53 @@ where
     async fn handle_invalidate(
         &mut self,
         cursor: Option<Cursor>,
+        invalidated: Option<InvalidatedRange>,
         state: &mut PersistedState<F>,
         ct: CancellationToken,
     ) -> Result<(CursorAction, StreamAction), SinkError> {
-        self.sink.handle_invalidate(&cursor, ct).await?;
+        self.sink
+            .handle_invalidate(&cursor, invalidated.as_ref(), ct)
+            .await?;
         state.cursor = cursor;
         Ok((CursorAction::Persist, StreamAction::Continue))
     }
 }
+
+/// Persists the connector state for batches delivered concurrently.
+struct StatePersistence<'a, F: Filter> {
+    state_manager: &'a mut StateManager,
+    state: &'a mut PersistedState<F>,
+}
+
+#[async_trait(?Send)]
+impl<'a, F: Filter> PersistBatch for StatePersistence<'a, F> {
+    async fn persist_batch(
+        &mut self,
+        ctx: Context,
+        delivered: DeliveredBatch,
+    ) -> Result<(), SinkError> {
+        self.state.cursor = Some(ctx.end_cursor);
+        if delivered.delivered_cursor.is_some() {
+            self.state.delivered_cursor = delivered.delivered_cursor;
+        }
+        self.state_manager
+            .put_state(self.state.clone(), delivered.action)
+            .await
+    }
+}
+
+impl<B: Serialize> TransformBatch<B> for Script {
+    fn transform_batch(&mut self, batch: Vec<B>) -> LocalBoxFuture<'_, Result<Value, SinkError>> {
+        // fatal error since if the sink is restarted it will receive the same data again.
+        let json_batch = batch
+            .into_iter()
+            .map(|b| serde_json::to_value(b).fatal("failed to serialize batch data"))
+            .collect::<Result<Vec<Value>, _>>();
+        async move {
+            self.transform(json_batch?)
+                .await
+                .map_err(|err| err.fatal("failed to transform batch data"))
+        }
+        .boxed_local()
+    }
+}
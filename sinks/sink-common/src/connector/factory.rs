@@ -11,12 +11,13 @@ use tokio_util::sync::CancellationToken;
 use tracing::{debug, info};
 
 use crate::{
-    error::SinkError, sink::Sink, Context, CursorAction, DisplayCursor, PersistedState,
-    SinkErrorReportExt, SinkErrorResultExt,
+    error::SinkError, sink::Sink, Context, CursorAction, DisplayCursor, InvalidatedRange,
+    PersistedState, SinkErrorReportExt, SinkErrorResultExt,
 };
 
 use super::{
     sink::SinkWithBackoff,
+    sink_tick_interval,
     state::StateManager,
     stream::{StreamAction, StreamClientFactory},
 };
@@ -72,7 +73,7 @@ where
 
         if state.cursor.is_some() {
             info!(cursor = %DisplayCursor(&state.cursor), "restarting from last cursor");
-            self.handle_invalidate(state.cursor.clone(), &mut state, ct.clone())
+            self.handle_invalidate(state.cursor.clone(), None, &mut state, ct.clone())
                 .await?;
         }
 
@@ -81,6 +82,7 @@ where
         self.needs_invalidation = false;
         self.skip_factory = false;
 
+        let mut tick_interval = sink_tick_interval();
         let mut ret = Ok(());
         loop {
             tokio::select! {
@@ -88,6 +90,9 @@ where
                     info!("sink stopped: cancelled");
                     break;
                 }
+                _ = tick_interval.tick() => {
+                    self.sink.tick(ct.clone()).await?;
+                }
                 maybe_message = data_stream.try_next() => {
                     match maybe_message {
                         Err(err) => {
@@ -226,15 +231,30 @@ where
                     Ok((CursorAction::Skip, StreamAction::Continue))
                 }
             }
-            DataMessage::Invalidate { cursor } => {
+            DataMessage::Invalidate {
+                cursor,
+                previous_head,
+            } => {
                 info!(block = %DisplayCursor(&cursor), "handle invalidate");
-                self.handle_invalidate(cursor, state, ct).await
+                let invalidated = InvalidatedRange::new(&cursor, previous_head);
+                self.handle_invalidate(cursor, invalidated, state, ct).await
             }
-            DataMessage::Heartbeat => {
+            DataMessage::Finalized { cursor } => {
+                // sinks don't request finalized head notifications.
+                debug!(cursor = %cursor, "ignoring finalized head");
+                Ok((CursorAction::Skip, StreamAction::Continue))
+            }
+            DataMessage::Heartbeat { .. } => {
                 self.sink.handle_heartbeat().await?;
                 self.state_manager.heartbeat().await?;
                 Ok((CursorAction::Skip, StreamAction::Continue))
             }
+            DataMessage::Summary {
+                blocks, batches, ..
+            } => {
+                info!(blocks, batches, "stream reached ending block");
+                Ok((CursorAction::Skip, StreamAction::Stop))
+            }
         }
     }
 
@@ -278,7 +298,7 @@ where
         ct: CancellationToken,
     ) -> Result<(CursorAction, StreamAction), SinkError> {
         if self.needs_invalidation {
-            self.handle_invalidate(context.cursor.clone(), state, ct.clone())
+            self.handle_invalidate(context.cursor.clone(), None, state, ct.clone())
                 .await?;
             self.needs_invalidation = false;
         }
@@ -308,10 +328,13 @@ where
     async fn handle_invalidate(
         &mut self,
         cursor: Option<Cursor>,
+        invalidated: Option<InvalidatedRange>,
         state: &mut PersistedState<F>,
         ct: CancellationToken,
     ) -> Result<(CursorAction, StreamAction), SinkError> {
-        self.sink.handle_invalidate(&cursor, ct).await?;
+        self.sink
+            .handle_invalidate(&cursor, invalidated.as_ref(), ct)
+            .await?;
         state.cursor = cursor;
         Ok((CursorAction::Persist, StreamAction::Continue))
     }
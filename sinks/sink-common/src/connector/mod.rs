@@ -1,6 +1,7 @@
 pub mod batching;
 mod default;
 mod factory;
+mod pipeline;
 mod sink;
 mod state;
 mod stream;
@@ -230,6 +231,18 @@ where
     }
 }
 
+/// Interval between calls to [Sink::tick].
+const SINK_TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Returns the interval used to tick the sink, starting one period from now.
+fn sink_tick_interval() -> tokio::time::Interval {
+    let start = tokio::time::Instant::now() + SINK_TICK_INTERVAL;
+    let mut interval = tokio::time::interval_at(start, SINK_TICK_INTERVAL);
+    // don't catch up on ticks missed while the sink handled data.
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval
+}
+
 fn default_backoff() -> Backoff {
     let retries = 10;
     let min_delay = Duration::from_secs(3);
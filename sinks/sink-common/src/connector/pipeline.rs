@@ -0,0 +1,481 @@
+//! Deliver consecutive batches at the same time, completing them in stream order.
+
+use std::collections::VecDeque;
+
+use apibara_sdk::{ClientError, DataMessage};
+use async_trait::async_trait;
+use error_stack::{Report, Result};
+use futures::{
+    future::LocalBoxFuture,
+    stream::{FuturesOrdered, StreamExt},
+    Future, FutureExt, Stream, TryStreamExt,
+};
+use prost::Message;
+use serde_json::Value;
+use tokio::time::Interval;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{
+    error::SinkError,
+    sink::{Context, DeliveredBatch, Sink},
+    SinkErrorReportExt,
+};
+
+use super::sink::SinkWithBackoff;
+
+type BatchResult = (Context, Result<DeliveredBatch, SinkError>);
+
+/// The batches being delivered by the sink.
+///
+/// Batches are returned in the order they were pushed, even if the sink
+/// finishes delivering them in a different order.
+pub struct InFlightBatches<'a> {
+    max_in_flight: usize,
+    batches: FuturesOrdered<LocalBoxFuture<'a, BatchResult>>,
+    /// Batches that completed while [InFlightBatches::run] was running.
+    completed: VecDeque<BatchResult>,
+}
+
+impl<'a> InFlightBatches<'a> {
+    pub fn new(max_in_flight: usize) -> Self {
+        InFlightBatches {
+            max_in_flight: max_in_flight.max(1),
+            batches: FuturesOrdered::new(),
+            completed: VecDeque::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty() && self.completed.is_empty()
+    }
+
+    /// Returns true if no more batches can be delivered until the oldest one completes.
+    pub fn is_full(&self) -> bool {
+        self.batches.len() + self.completed.len() >= self.max_in_flight
+    }
+
+    /// Starts tracking the batch with the given context, delivered by `delivery`.
+    pub fn push<Fut>(&mut self, ctx: Context, delivery: Fut)
+    where
+        Fut: Future<Output = Result<DeliveredBatch, SinkError>> + 'a,
+    {
+        let delivery = delivery.map(|result| (ctx, result)).boxed_local();
+        self.batches.push_back(delivery);
+    }
+
+    /// Waits for the oldest batch to complete, returning its context and result.
+    ///
+    /// Returns `None` if there are no batches in flight.
+    pub async fn next(&mut self) -> Option<BatchResult> {
+        if let Some(batch) = self.completed.pop_front() {
+            return Some(batch);
+        }
+        self.batches.next().await
+    }
+
+    /// Runs `fut` to completion while the batches in flight keep being delivered.
+    pub async fn run<T>(&mut self, fut: impl Future<Output = T>) -> T {
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                biased;
+                output = &mut fut => return output,
+                Some(batch) = self.batches.next(), if !self.batches.is_empty() => {
+                    self.completed.push_back(batch);
+                }
+            }
+        }
+    }
+}
+
+/// Transforms a batch before it's delivered.
+pub trait TransformBatch<B> {
+    fn transform_batch(&mut self, batch: Vec<B>) -> LocalBoxFuture<'_, Result<Value, SinkError>>;
+}
+
+/// Persists the cursor of batches, in stream order.
+#[async_trait(?Send)]
+pub trait PersistBatch {
+    async fn persist_batch(
+        &mut self,
+        ctx: Context,
+        delivered: DeliveredBatch,
+    ) -> Result<(), SinkError>;
+}
+
+/// Why the pipeline stopped delivering batches.
+pub enum ConcurrentExit<B: Message + Default> {
+    /// A message that must be handled on its own.
+    Message(DataMessage<B>),
+    /// The sink must be ticked.
+    Tick,
+    /// The ending block was reached.
+    Stop,
+    Cancelled,
+    /// The data stream failed or was closed.
+    StreamError(Report<SinkError>),
+}
+
+/// Delivers consecutive batches from the data stream at the same time.
+pub struct ConcurrentPipeline<'a, S, T, P>
+where
+    S: Sink + Send + Sync,
+{
+    pub sink: &'a SinkWithBackoff<S>,
+    pub script: &'a mut T,
+    pub persistence: &'a mut P,
+    pub ending_block: Option<u64>,
+}
+
+impl<'a, S, T, P> ConcurrentPipeline<'a, S, T, P>
+where
+    S: Sink + Send + Sync,
+    P: PersistBatch,
+{
+    /// Delivers consecutive batches, starting from `message`.
+    ///
+    /// Up to [SinkWithBackoff::max_in_flight_batches] batches are in flight,
+    /// but their cursors are persisted in stream order. If a batch fails, the
+    /// batches after it are dropped and their cursors are not persisted.
+    ///
+    /// Stops reading the stream when it receives a message that must be
+    /// handled on its own, or on ticks, and returns once all batches in
+    /// flight are delivered.
+    pub async fn run<B: Message + Default, D>(
+        self,
+        message: DataMessage<B>,
+        data_stream: &mut D,
+        tick_interval: &mut Interval,
+        ct: CancellationToken,
+    ) -> Result<ConcurrentExit<B>, SinkError>
+    where
+        T: TransformBatch<B>,
+        D: Stream<Item = Result<DataMessage<B>, ClientError>> + Unpin,
+    {
+        let sink = self.sink;
+        let mut in_flight = InFlightBatches::new(sink.max_in_flight_batches());
+        let mut next_message = Some(message);
+        let mut exit = None;
+        loop {
+            while exit.is_none() && !in_flight.is_full() {
+                let Some(message) = next_message.take() else {
+                    break;
+                };
+
+                let (context, batch) = match message {
+                    DataMessage::Data {
+                        cursor,
+                        end_cursor,
+                        finality,
+                        batch,
+                    } if !finality.is_pending() => {
+                        let context = Context {
+                            cursor,
+                            end_cursor,
+                            finality,
+                        };
+                        (context, batch)
+                    }
+                    message => {
+                        exit = Some(ConcurrentExit::Message(message));
+                        break;
+                    }
+                };
+
+                info!(
+                    block = context.end_cursor.order_key,
+                    status = %context.finality,
+                    "handle block batch"
+                );
+                let data = in_flight.run(self.script.transform_batch(batch)).await?;
+
+                if let Some(ending_block) = self.ending_block {
+                    if context.end_cursor.order_key >= ending_block {
+                        info!(
+                            block = context.end_cursor.order_key,
+                            ending_block = ending_block,
+                            "ending block reached"
+                        );
+                        exit = Some(ConcurrentExit::Stop);
+                        break;
+                    }
+                }
+
+                let ct = ct.clone();
+                in_flight.push(context.clone(), async move {
+                    sink.handle_data_concurrently(&context, &data, ct).await
+                });
+            }
+
+            if in_flight.is_empty() {
+                if let Some(exit) = exit {
+                    return Ok(exit);
+                }
+            }
+
+            let can_read = exit.is_none() && next_message.is_none() && !in_flight.is_full();
+            tokio::select! {
+                _ = ct.cancelled() => {
+                    return Ok(ConcurrentExit::Cancelled);
+                }
+                _ = tick_interval.tick(), if exit.is_none() && next_message.is_none() => {
+                    // ticks are not concurrent with the other methods of the sink.
+                    exit = Some(ConcurrentExit::Tick);
+                }
+                Some((context, result)) = in_flight.next(), if !in_flight.is_empty() => {
+                    // the batches after a failed one are dropped together with `in_flight`.
+                    let delivered = result?;
+                    in_flight
+                        .run(self.persistence.persist_batch(context, delivered))
+                        .await?;
+                }
+                maybe_message = data_stream.try_next(), if can_read => {
+                    match maybe_message {
+                        Err(err) => {
+                            let err = err.temporary("data stream error");
+                            exit = Some(ConcurrentExit::StreamError(err));
+                        }
+                        Ok(None) => {
+                            let err = Report::new(SinkError::Temporary)
+                                .attach_printable("data stream closed");
+                            exit = Some(ConcurrentExit::StreamError(err));
+                        }
+                        Ok(Some(message)) => {
+                            next_message = Some(message);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apibara_core::node::v1alpha2::{Cursor, DataFinality};
+    use apibara_sdk::{ClientError, DataMessage};
+    use async_trait::async_trait;
+    use error_stack::{Report, Result};
+    use exponential_backoff::Backoff;
+    use futures::{future::LocalBoxFuture, stream, FutureExt, StreamExt};
+    use serde::Deserialize;
+    use serde_json::Value;
+    use tokio::sync::oneshot;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        connector::sink::SinkWithBackoff,
+        error::SinkError,
+        sink::{ConcurrentSink, Context, CursorAction, DeliveredBatch, Sink, SinkOptions},
+    };
+
+    use super::{ConcurrentPipeline, InFlightBatches, PersistBatch, TransformBatch};
+
+    fn new_cursor(order_key: u64) -> Cursor {
+        Cursor {
+            order_key,
+            unique_key: Vec::default(),
+        }
+    }
+
+    fn new_context(end: u64) -> Context {
+        Context {
+            cursor: None,
+            end_cursor: new_cursor(end),
+            finality: DataFinality::DataStatusFinalized,
+        }
+    }
+
+    fn delivered(end: u64) -> DeliveredBatch {
+        DeliveredBatch {
+            action: CursorAction::Persist,
+            delivered_cursor: Some(new_cursor(end)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_complete_in_order() {
+        let mut batches = InFlightBatches::new(3);
+        let mut senders = Vec::default();
+        for end in 1..=3 {
+            let (tx, rx) = oneshot::channel::<()>();
+            senders.push(tx);
+            batches.push(new_context(end), async move {
+                rx.await.unwrap();
+                Ok(delivered(end))
+            });
+        }
+        assert!(batches.is_full());
+
+        // the later batches are delivered first.
+        let first = senders.remove(0);
+        for tx in senders {
+            tx.send(()).unwrap();
+        }
+        assert!(batches.next().now_or_never().is_none());
+
+        first.send(()).unwrap();
+        for end in 1..=3 {
+            let (ctx, result) = batches.next().await.unwrap();
+            assert_eq!(ctx.end_cursor.order_key, end);
+            assert_eq!(result.unwrap(), delivered(end));
+        }
+        assert!(batches.is_empty());
+        assert!(batches.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deliver_while_running() {
+        let mut batches = InFlightBatches::new(2);
+        let (tx, rx) = oneshot::channel::<()>();
+        batches.push(new_context(1), async move {
+            tx.send(()).unwrap();
+            Ok(delivered(1))
+        });
+
+        // the batch in flight completes while waiting for it.
+        batches.run(rx).await.unwrap();
+        let (ctx, _) = batches.next().now_or_never().unwrap().unwrap();
+        assert_eq!(ctx.end_cursor.order_key, 1);
+        assert!(batches.is_empty());
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    struct TestOptions {}
+
+    impl SinkOptions for TestOptions {
+        fn merge(self, _other: Self) -> Self {
+            self
+        }
+    }
+
+    /// Fails to deliver the batch that ends at `failing_block`.
+    struct TestSink {
+        failing_block: u64,
+    }
+
+    #[async_trait]
+    impl Sink for TestSink {
+        type Options = TestOptions;
+        type Error = SinkError;
+
+        async fn from_options(_options: Self::Options) -> Result<Self, Self::Error> {
+            Ok(TestSink { failing_block: 0 })
+        }
+
+        async fn handle_data(
+            &mut self,
+            _ctx: &Context,
+            _batch: &Value,
+        ) -> Result<CursorAction, Self::Error> {
+            unreachable!("batches are handled concurrently")
+        }
+
+        async fn handle_invalidate(&mut self, _cursor: &Option<Cursor>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn concurrent(&self) -> Option<&dyn ConcurrentSink<Error = Self::Error>> {
+            Some(self)
+        }
+    }
+
+    #[async_trait]
+    impl ConcurrentSink for TestSink {
+        type Error = SinkError;
+
+        fn max_in_flight_batches(&self) -> usize {
+            4
+        }
+
+        async fn handle_data_concurrently(
+            &self,
+            ctx: &Context,
+            _batch: &Value,
+        ) -> Result<DeliveredBatch, Self::Error> {
+            let end = ctx.end_cursor.order_key;
+            if end == self.failing_block {
+                // fail after the next batch was delivered.
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                return Err(SinkError::temporary("endpoint is down"));
+            }
+            Ok(delivered(end))
+        }
+    }
+
+    struct IdentityScript;
+
+    impl TransformBatch<u64> for IdentityScript {
+        fn transform_batch(
+            &mut self,
+            batch: Vec<u64>,
+        ) -> LocalBoxFuture<'_, Result<Value, SinkError>> {
+            async move { Ok(batch.into()) }.boxed_local()
+        }
+    }
+
+    #[derive(Default)]
+    struct TestPersistence {
+        cursors: Vec<u64>,
+    }
+
+    #[async_trait(?Send)]
+    impl PersistBatch for TestPersistence {
+        async fn persist_batch(
+            &mut self,
+            ctx: Context,
+            _delivered: DeliveredBatch,
+        ) -> Result<(), SinkError> {
+            self.cursors.push(ctx.end_cursor.order_key);
+            Ok(())
+        }
+    }
+
+    fn new_batch(end: u64) -> DataMessage<u64> {
+        DataMessage::Data {
+            cursor: Some(new_cursor(end - 1)),
+            end_cursor: new_cursor(end),
+            finality: DataFinality::DataStatusFinalized,
+            batch: vec![end],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_after_failed_batch_are_not_persisted() {
+        let sink = SinkWithBackoff::new(
+            TestSink { failing_block: 3 },
+            Backoff::new(1, Duration::ZERO, Some(Duration::ZERO)),
+        );
+        let mut script = IdentityScript;
+        let mut persistence = TestPersistence::default();
+        let mut data_stream =
+            stream::iter((2..=5).map(|end| Ok::<_, Report<ClientError>>(new_batch(end))))
+                .chain(stream::pending());
+        let mut tick_interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + Duration::from_secs(3600),
+            Duration::from_secs(3600),
+        );
+
+        let pipeline = ConcurrentPipeline {
+            sink: &sink,
+            script: &mut script,
+            persistence: &mut persistence,
+            ending_block: None,
+        };
+        let result = pipeline
+            .run(
+                new_batch(1),
+                &mut data_stream,
+                &mut tick_interval,
+                CancellationToken::new(),
+            )
+            .await;
+
+        // batch 4 is delivered before batch 3 fails, but its cursor is never persisted.
+        assert!(result.is_err());
+        assert_eq!(persistence.cursors, vec![1, 2]);
+    }
+}
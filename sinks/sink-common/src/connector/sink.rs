@@ -7,8 +7,8 @@ use tracing::warn;
 
 use crate::{
     error::SinkError,
-    sink::{Context, Sink},
-    CursorAction, SinkErrorReportExt,
+    sink::{Context, DeliveredBatch, InvalidatedRange, Sink},
+    CursorAction, RetryAfter, SinkErrorReportExt,
 };
 
 pub struct SinkWithBackoff<S: Sink + Send + Sync> {
@@ -40,6 +40,7 @@ impl<S: Sink + Send + Sync> SinkWithBackoff<S> {
                             .change_context(SinkError::Fatal)
                             .attach_printable("failed to handle data (cancelled)");
                     }
+                    let duration = RetryAfter::delay(&err, duration);
                     tokio::select! {
                         _ = tokio::time::sleep(duration) => {
                             // info!("retrying to handle data after sleeping");
@@ -56,13 +57,67 @@ impl<S: Sink + Send + Sync> SinkWithBackoff<S> {
         Err(SinkError::Fatal).attach_printable("handle data failed after retry")
     }
 
+    /// Like [SinkWithBackoff::handle_data], but without exclusive access to the sink.
+    ///
+    /// Fails if the sink doesn't handle batches concurrently.
+    pub async fn handle_data_concurrently(
+        &self,
+        ctx: &Context,
+        batch: &Value,
+        ct: CancellationToken,
+    ) -> Result<DeliveredBatch, SinkError> {
+        let Some(sink) = self.inner.concurrent() else {
+            return Err(SinkError::Fatal)
+                .attach_printable("the sink doesn't handle batches concurrently");
+        };
+
+        for duration in &self.backoff {
+            match sink.handle_data_concurrently(ctx, batch).await {
+                Ok(delivered) => return Ok(delivered),
+                Err(err) => {
+                    warn!(err = ?err, "failed to handle data");
+                    if ct.is_cancelled() {
+                        return Err(err)
+                            .change_context(SinkError::Fatal)
+                            .attach_printable("failed to handle data (cancelled)");
+                    }
+                    let duration = RetryAfter::delay(&err, duration);
+                    tokio::select! {
+                        _ = tokio::time::sleep(duration) => {},
+                        _ = ct.cancelled() => {
+                            return Ok(DeliveredBatch {
+                                action: CursorAction::Skip,
+                                delivered_cursor: None,
+                            });
+                        }
+                    };
+                }
+            }
+        }
+
+        Err(SinkError::Fatal).attach_printable("handle data failed after retry")
+    }
+
+    /// Returns how many consecutive batches are handled at the same time.
+    pub fn max_in_flight_batches(&self) -> usize {
+        self.inner
+            .concurrent()
+            .map(|sink| sink.max_in_flight_batches())
+            .unwrap_or(1)
+    }
+
     pub async fn handle_invalidate(
         &mut self,
         cursor: &Option<Cursor>,
+        invalidated: Option<&InvalidatedRange>,
         ct: CancellationToken,
     ) -> Result<(), SinkError> {
         for duration in &self.backoff {
-            match self.inner.handle_invalidate(cursor).await {
+            match self
+                .inner
+                .handle_invalidate_range(cursor, invalidated)
+                .await
+            {
                 Ok(_) => return Ok(()),
                 Err(err) => {
                     warn!(err = ?err, "failed to handle invalidate");
@@ -99,4 +154,130 @@ impl<S: Sink + Send + Sync> SinkWithBackoff<S> {
             .map_err(|err| err.temporary("failed to handle heartbeat"))?;
         Ok(())
     }
+
+    pub async fn tick(&mut self, ct: CancellationToken) -> Result<(), SinkError> {
+        for duration in &self.backoff {
+            match self.inner.tick().await {
+                Ok(_) => return Ok(()),
+                Err(err) => {
+                    warn!(err = ?err, "failed to handle tick");
+                    if ct.is_cancelled() {
+                        return Err(err)
+                            .change_context(SinkError::Fatal)
+                            .attach_printable("failed to handle tick (cancelled)");
+                    }
+                    let duration = RetryAfter::delay(&err, duration);
+                    tokio::select! {
+                        _ = tokio::time::sleep(duration) => {},
+                        _ = ct.cancelled() => {
+                            return Ok(());
+                        }
+                    };
+                }
+            }
+        }
+
+        Err(SinkError::Fatal).attach_printable("handle tick failed after retry")
+    }
+
+    pub fn delivered_cursor(&self) -> Option<Cursor> {
+        self.inner.delivered_cursor()
+    }
+
+    pub async fn restore_delivered_cursor(
+        &mut self,
+        cursor: Option<Cursor>,
+    ) -> Result<(), SinkError> {
+        self.inner
+            .restore_delivered_cursor(cursor)
+            .await
+            .map_err(|err| err.temporary("failed to restore delivered cursor"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apibara_core::node::v1alpha2::Cursor;
+    use async_trait::async_trait;
+    use error_stack::Result;
+    use exponential_backoff::Backoff;
+    use serde::Deserialize;
+    use serde_json::Value;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        error::SinkError,
+        sink::{Context, CursorAction, Sink, SinkOptions},
+    };
+
+    use super::SinkWithBackoff;
+
+    #[derive(Debug, Default, Deserialize)]
+    struct TestOptions {}
+
+    impl SinkOptions for TestOptions {
+        fn merge(self, _other: Self) -> Self {
+            self
+        }
+    }
+
+    /// Fails the first `failing_ticks` ticks.
+    struct TestSink {
+        failing_ticks: usize,
+        ticks: usize,
+    }
+
+    #[async_trait]
+    impl Sink for TestSink {
+        type Options = TestOptions;
+        type Error = SinkError;
+
+        async fn from_options(_options: Self::Options) -> Result<Self, Self::Error> {
+            Ok(TestSink {
+                failing_ticks: 0,
+                ticks: 0,
+            })
+        }
+
+        async fn handle_data(
+            &mut self,
+            _ctx: &Context,
+            _batch: &Value,
+        ) -> Result<CursorAction, Self::Error> {
+            Ok(CursorAction::Persist)
+        }
+
+        async fn handle_invalidate(&mut self, _cursor: &Option<Cursor>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn tick(&mut self) -> Result<(), Self::Error> {
+            self.ticks += 1;
+            if self.ticks <= self.failing_ticks {
+                return Err(SinkError::temporary("endpoint is down"));
+            }
+            Ok(())
+        }
+    }
+
+    fn new_sink(failing_ticks: usize) -> SinkWithBackoff<TestSink> {
+        let sink = TestSink {
+            failing_ticks,
+            ticks: 0,
+        };
+        SinkWithBackoff::new(sink, Backoff::new(3, Duration::from_millis(1), None))
+    }
+
+    #[tokio::test]
+    async fn test_tick_is_retried() {
+        let mut sink = new_sink(2);
+        sink.tick(CancellationToken::new()).await.unwrap();
+        assert_eq!(sink.inner.ticks, 3);
+
+        let mut sink = new_sink(10);
+        assert!(sink.tick(CancellationToken::new()).await.is_err());
+    }
 }
@@ -6,7 +6,7 @@ use crate::{
     status::StatusServer,
     CursorAction, PersistedState, StatusServerClient,
 };
-use apibara_core::filter::Filter;
+use apibara_core::{filter::Filter, node::v1alpha2::Cursor};
 use apibara_sdk::StreamClient;
 use error_stack::{Result, ResultExt};
 use tokio::task::JoinHandle;
@@ -16,6 +16,8 @@ use tracing::info;
 pub struct StateManager {
     persistence: PersistenceClient,
     status_client: StatusServerClient,
+    persisted_cursor: Option<Cursor>,
+    persisted_delivered_cursor: Option<Cursor>,
 }
 
 impl StateManager {
@@ -37,13 +39,17 @@ impl StateManager {
         let manager = StateManager {
             persistence,
             status_client,
+            persisted_cursor: None,
+            persisted_delivered_cursor: None,
         };
 
         Ok((manager, status_server))
     }
 
     pub async fn get_state<F: Filter>(&mut self) -> Result<PersistedState<F>, SinkError> {
-        let state = self.persistence.get_state().await?;
+        let state: PersistedState<F> = self.persistence.get_state().await?;
+        self.persisted_cursor = state.cursor.clone();
+        self.persisted_delivered_cursor = state.delivered_cursor.clone();
 
         Ok(state)
     }
@@ -57,15 +63,26 @@ impl StateManager {
             .update_cursor(state.cursor.clone())
             .await?;
 
-        match action {
-            CursorAction::PersistAt(cursor) => {
-                self.persistence
-                    .put_state(PersistedState::new(Some(cursor), state.filter))
-                    .await
+        let state = match action {
+            CursorAction::PersistAt(cursor) => PersistedState {
+                cursor: Some(cursor),
+                ..state
+            },
+            CursorAction::Persist => state,
+            // the sink delivered data past the persisted cursor, store it so
+            // that it's not sent again after a restart.
+            CursorAction::Skip if state.delivered_cursor != self.persisted_delivered_cursor => {
+                PersistedState {
+                    cursor: self.persisted_cursor.clone(),
+                    ..state
+                }
             }
-            CursorAction::Persist => self.persistence.put_state(state).await,
-            CursorAction::Skip => Ok(()),
-        }
+            CursorAction::Skip => return Ok(()),
+        };
+
+        self.persisted_cursor = state.cursor.clone();
+        self.persisted_delivered_cursor = state.delivered_cursor.clone();
+        self.persistence.put_state(state).await
     }
 
     pub async fn heartbeat(&mut self) -> Result<(), SinkError> {
@@ -1,4 +1,4 @@
-use std::{fmt, process::ExitCode};
+use std::{fmt, process::ExitCode, time::Duration};
 
 use error_stack::{report, Context, Report, Result, ResultExt};
 
@@ -31,6 +31,22 @@ pub trait ReportExt {
 
 impl error_stack::Context for SinkError {}
 
+/// Attached to temporary errors when the sink must wait before retrying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryAfter(pub Duration);
+
+impl RetryAfter {
+    /// Returns how long to wait before retrying after `err`.
+    ///
+    /// That's `delay`, unless the error asks to wait longer.
+    pub fn delay<C>(err: &Report<C>, delay: Duration) -> Duration {
+        match err.downcast_ref::<RetryAfter>() {
+            Some(RetryAfter(retry_after)) => delay.max(*retry_after),
+            None => delay,
+        }
+    }
+}
+
 impl fmt::Display for SinkError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
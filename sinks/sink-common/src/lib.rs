@@ -1,3 +1,4 @@
+mod buffered;
 mod cli;
 mod configuration;
 mod connector;
@@ -13,7 +14,9 @@ use error_stack::Result;
 use error_stack::ResultExt;
 use serde::Deserialize;
 use tokio_util::sync::CancellationToken;
+use tracing::info;
 
+pub use self::buffered::*;
 pub use self::cli::*;
 pub use self::configuration::*;
 pub use self::connector::*;
@@ -69,6 +72,7 @@ where
     let sink = S::from_options(sink_options)
         .await
         .map_err(|err| err.configuration("invalid sink options"))?;
+    info!(sink = %sink.metadata(), "sink configured");
 
     // Setup connector.
     let connector_options_from_script = options_from_script.connector;
@@ -12,6 +12,10 @@ pub struct PersistedState<F: Message + Default> {
     pub cursor: Option<Cursor>,
     #[prost(message, tag = "2")]
     pub filter: Option<F>,
+    /// The last cursor delivered by the sink, if it can be ahead of `cursor`.
+    #[prost(message, tag = "3")]
+    #[serde(default)]
+    pub delivered_cursor: Option<Cursor>,
 }
 
 /// Client used to interact with the persistence backend.
@@ -38,11 +42,16 @@ impl<F: Message + Default> PersistedState<F> {
         Self {
             cursor: Some(cursor),
             filter: None,
+            delivered_cursor: None,
         }
     }
 
     pub fn new(cursor: Option<Cursor>, filter: Option<F>) -> Self {
-        Self { cursor, filter }
+        Self {
+            cursor,
+            filter,
+            delivered_cursor: None,
+        }
     }
 }
 
@@ -1,9 +1,9 @@
-use std::fmt::Display;
+use std::{fmt::Display, ops::RangeInclusive};
 
 use apibara_core::node::v1alpha2::{Cursor, DataFinality};
 use async_trait::async_trait;
 use error_stack::Result;
-use serde::de::DeserializeOwned;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 
 use crate::cursor::DisplayCursor;
@@ -26,6 +26,69 @@ pub struct Context {
     pub finality: DataFinality,
 }
 
+/// The cursors invalidated by a chain reorganization.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InvalidatedRange {
+    /// The first invalidated order key, that is the block after the new head.
+    pub start_order_key: u64,
+    /// The last invalidated cursor.
+    pub end_cursor: Cursor,
+}
+
+impl InvalidatedRange {
+    /// Creates the range of cursors invalidated when the chain head moves back
+    /// to `cursor` from `previous_head`.
+    ///
+    /// Returns `None` if `previous_head` is unknown or no data was invalidated.
+    pub fn new(cursor: &Option<Cursor>, previous_head: Option<Cursor>) -> Option<Self> {
+        let start_order_key = cursor.as_ref().map(|c| c.order_key + 1).unwrap_or(0);
+        let end_cursor = previous_head?;
+        if end_cursor.order_key < start_order_key {
+            return None;
+        }
+
+        Some(InvalidatedRange {
+            start_order_key,
+            end_cursor,
+        })
+    }
+
+    /// Returns the invalidated order keys, inclusive.
+    pub fn order_keys(&self) -> RangeInclusive<u64> {
+        self.start_order_key..=self.end_cursor.order_key
+    }
+}
+
+/// Describes a sink and its configuration, without secrets.
+#[derive(Debug, Clone, Default)]
+pub struct SinkMetadata {
+    /// The sink type, e.g. `webhook`.
+    pub sink_type: String,
+    /// Where data is sent to, with any credentials redacted.
+    pub target: Option<String>,
+    /// Summary of the most important configuration values.
+    pub summary: Vec<(String, String)>,
+}
+
+impl SinkMetadata {
+    pub fn new(sink_type: impl Into<String>) -> Self {
+        SinkMetadata {
+            sink_type: sink_type.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn with_summary(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.summary.push((key.into(), value.to_string()));
+        self
+    }
+}
+
 #[async_trait]
 pub trait Sink {
     type Options: SinkOptions;
@@ -41,8 +104,27 @@ pub trait Sink {
         batch: &Value,
     ) -> Result<CursorAction, Self::Error>;
 
+    /// Returns the sink as a [ConcurrentSink], if it delivers consecutive batches at the same time.
+    ///
+    /// The connector then calls [ConcurrentSink::handle_data_concurrently]
+    /// instead of [Sink::handle_data] for finalized and accepted data.
+    fn concurrent(&self) -> Option<&dyn ConcurrentSink<Error = Self::Error>> {
+        None
+    }
+
     async fn handle_invalidate(&mut self, cursor: &Option<Cursor>) -> Result<(), Self::Error>;
 
+    /// Handles an invalidation, together with the invalidated cursors if known.
+    ///
+    /// Sinks that can delete data precisely should override this method.
+    async fn handle_invalidate_range(
+        &mut self,
+        cursor: &Option<Cursor>,
+        _invalidated: Option<&InvalidatedRange>,
+    ) -> Result<(), Self::Error> {
+        self.handle_invalidate(cursor).await
+    }
+
     async fn cleanup(&mut self) -> Result<(), Self::Error> {
         Ok(())
     }
@@ -50,6 +132,94 @@ pub trait Sink {
     async fn handle_heartbeat(&mut self) -> Result<(), Self::Error> {
         Ok(())
     }
+
+    /// Called at a fixed interval, whether the stream sends data or not.
+    ///
+    /// Use it for time-based work, like flushing buffers or renewing
+    /// credentials. Ticks are never concurrent with the other methods: the
+    /// connector calls them one at a time, and ticks are skipped while the
+    /// sink handles a message.
+    async fn tick(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Returns the end cursor of the last finalized batch the destination confirmed it received.
+    ///
+    /// The connector persists it together with the stream cursor. Sinks can
+    /// deliver data before the stream cursor is persisted, for example if the
+    /// destination asks not to persist the cursor, so this cursor can be
+    /// ahead of the stream cursor.
+    fn delivered_cursor(&self) -> Option<Cursor> {
+        None
+    }
+
+    /// Restores the delivered cursor persisted by a previous run.
+    ///
+    /// Called once when the connector starts, before any data is received.
+    /// Sinks that track delivery should not send finalized data up to this
+    /// cursor again.
+    async fn restore_delivered_cursor(
+        &mut self,
+        _cursor: Option<Cursor>,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Returns a description of the sink, used for logging.
+    ///
+    /// Implementations must never include secrets in the returned value.
+    fn metadata(&self) -> SinkMetadata {
+        SinkMetadata::new(std::any::type_name::<Self>())
+    }
+}
+
+/// The result of a batch handled by [ConcurrentSink::handle_data_concurrently].
+#[derive(Debug, PartialEq)]
+pub struct DeliveredBatch {
+    pub action: CursorAction,
+    /// The new delivered cursor, see [Sink::delivered_cursor].
+    ///
+    /// The connector updates the delivered cursor in stream order, once the
+    /// batches before this one were handled.
+    pub delivered_cursor: Option<Cursor>,
+}
+
+/// A sink that handles consecutive batches at the same time.
+#[async_trait]
+pub trait ConcurrentSink: Send + Sync {
+    type Error: error_stack::Context + Send + Sync + 'static;
+
+    /// Returns how many consecutive batches the connector delivers at the same time.
+    fn max_in_flight_batches(&self) -> usize;
+
+    /// Handles a batch without exclusive access to the sink.
+    ///
+    /// The connector calls it for up to [ConcurrentSink::max_in_flight_batches]
+    /// batches at the same time, in stream order. Batches can be delivered in
+    /// any order, but the connector persists their cursors in stream order:
+    /// the cursor of a batch is persisted only after the batches before it
+    /// were handled, and never if one of them fails.
+    async fn handle_data_concurrently(
+        &self,
+        ctx: &Context,
+        batch: &Value,
+    ) -> Result<DeliveredBatch, Self::Error>;
+}
+
+impl Display for SinkMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}(", self.sink_type)?;
+        if let Some(target) = &self.target {
+            write!(f, "target={}", target)?;
+        }
+        for (i, (key, value)) in self.summary.iter().enumerate() {
+            if i > 0 || self.target.is_some() {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+        write!(f, ")")
+    }
 }
 
 impl Display for Context {
@@ -62,3 +232,30 @@ impl Display for Context {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::node::v1alpha2::Cursor;
+
+    use super::InvalidatedRange;
+
+    fn new_cursor(order_key: u64) -> Cursor {
+        Cursor {
+            order_key,
+            unique_key: order_key.to_be_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_invalidated_range() {
+        let range = InvalidatedRange::new(&Some(new_cursor(10)), Some(new_cursor(13))).unwrap();
+        assert_eq!(range.order_keys(), 11..=13);
+        assert_eq!(range.end_cursor, new_cursor(13));
+
+        let range = InvalidatedRange::new(&None, Some(new_cursor(2))).unwrap();
+        assert_eq!(range.order_keys(), 0..=2);
+
+        assert!(InvalidatedRange::new(&Some(new_cursor(10)), None).is_none());
+        assert!(InvalidatedRange::new(&Some(new_cursor(10)), Some(new_cursor(10))).is_none());
+    }
+}
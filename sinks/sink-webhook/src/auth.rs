@@ -0,0 +1,54 @@
+//! Authenticate requests with static credentials.
+//!
+//! For endpoints that don't accept bearer tokens: HTTP Basic auth, or an API
+//! key in the query string. Both are sent together with the static headers.
+
+use std::fmt;
+
+use reqwest::RequestBuilder;
+
+/// HTTP Basic auth credentials.
+#[derive(Clone)]
+pub struct BasicAuthConfiguration {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+/// An API key sent as a query parameter of the target url.
+#[derive(Clone)]
+pub struct QueryApiKeyConfiguration {
+    /// Name of the query parameter.
+    pub param: String,
+    pub key: String,
+}
+
+impl BasicAuthConfiguration {
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        request.basic_auth(&self.username, self.password.as_ref())
+    }
+}
+
+impl QueryApiKeyConfiguration {
+    /// Appends the key to the query parameters already in the url.
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        request.query(&[(&self.param, &self.key)])
+    }
+}
+
+impl fmt::Debug for BasicAuthConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicAuthConfiguration")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl fmt::Debug for QueryApiKeyConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueryApiKeyConfiguration")
+            .field("param", &self.param)
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
@@ -0,0 +1,211 @@
+//! Stop sending requests to an endpoint that keeps failing.
+
+use std::time::{Duration, Instant};
+
+use reqwest::Method;
+use tracing::{info, warn};
+
+/// Default time the circuit stays open before probing the endpoint again.
+pub const DEFAULT_CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfiguration {
+    /// Open the circuit after this many consecutive failures.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before probing the endpoint.
+    pub cooldown: Duration,
+    /// The request used to probe the endpoint when half-open.
+    pub probe: CircuitProbe,
+}
+
+/// How the endpoint is probed when the circuit is half-open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitProbe {
+    /// Use the next real request as the probe.
+    Request,
+    /// Send a request with the given method and no body.
+    ///
+    /// The path replaces the target url path, if set.
+    Lightweight {
+        method: Method,
+        path: Option<String>,
+    },
+}
+
+/// What to do with a request, based on the circuit state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircuitCheck {
+    /// Send the request.
+    Allow,
+    /// Send the given probe and only send the request if it succeeds.
+    Probe(CircuitProbe),
+    /// Fail the request without sending it.
+    Reject { retry_in: Duration },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+/// A circuit breaker for a single endpoint.
+///
+/// The circuit opens after `failure_threshold` consecutive failures and
+/// rejects all requests for `cooldown`. After that, it's half-open: the next
+/// request is preceded by a probe (or is the probe itself) and, depending on
+/// its result, the circuit closes or opens again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfiguration,
+    state: CircuitState,
+    /// Index of the endpoint in the sink targets, used in logs.
+    target: usize,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfiguration) -> Self {
+        CircuitBreaker {
+            config,
+            state: CircuitState::Closed { failures: 0 },
+            target: 0,
+        }
+    }
+
+    /// Sets the index of the endpoint protected by the circuit breaker.
+    pub fn with_target(mut self, target: usize) -> Self {
+        self.target = target;
+        self
+    }
+
+    pub fn config(&self) -> &CircuitBreakerConfiguration {
+        &self.config
+    }
+
+    /// Returns true if the circuit is open and still cooling down.
+    ///
+    /// Unlike `check`, it never moves the circuit to half-open.
+    pub fn is_open(&self) -> bool {
+        matches!(self.state, CircuitState::Open { until } if Instant::now() < until)
+    }
+
+    /// Checks whether a request can be sent.
+    pub fn check(&mut self) -> CircuitCheck {
+        match self.state {
+            CircuitState::Closed { .. } => CircuitCheck::Allow,
+            CircuitState::Open { until } => {
+                let now = Instant::now();
+                if now < until {
+                    return CircuitCheck::Reject {
+                        retry_in: until - now,
+                    };
+                }
+                info!(target = self.target, "circuit half-open, probing endpoint");
+                self.state = CircuitState::HalfOpen;
+                self.half_open_check()
+            }
+            CircuitState::HalfOpen => self.half_open_check(),
+        }
+    }
+
+    /// Records a successful request or probe.
+    ///
+    /// Returns true if the circuit closed.
+    pub fn record_success(&mut self) -> bool {
+        let closed = self.state == CircuitState::HalfOpen;
+        if closed {
+            info!(target = self.target, "circuit closed");
+        }
+        self.state = CircuitState::Closed { failures: 0 };
+        closed
+    }
+
+    /// Records a failed request or probe.
+    ///
+    /// With retries enabled, a request counts as a single failure after all
+    /// its attempts failed. Returns true if the circuit opened.
+    pub fn record_failure(&mut self) -> bool {
+        let failures = match self.state {
+            CircuitState::Closed { failures } => failures + 1,
+            // the probe failed.
+            CircuitState::HalfOpen => self.config.failure_threshold,
+            CircuitState::Open { .. } => return false,
+        };
+
+        if failures < self.config.failure_threshold {
+            self.state = CircuitState::Closed { failures };
+            return false;
+        }
+
+        warn!(
+            target = self.target,
+            failures,
+            cooldown = ?self.config.cooldown,
+            "circuit opened"
+        );
+        self.state = CircuitState::Open {
+            until: Instant::now() + self.config.cooldown,
+        };
+        true
+    }
+
+    fn half_open_check(&self) -> CircuitCheck {
+        match self.config.probe {
+            CircuitProbe::Request => CircuitCheck::Allow,
+            ref probe => CircuitCheck::Probe(probe.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::Method;
+
+    use super::{CircuitBreaker, CircuitBreakerConfiguration, CircuitCheck, CircuitProbe};
+
+    fn new_breaker(cooldown: Duration, probe: CircuitProbe) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfiguration {
+            failure_threshold: 2,
+            cooldown,
+            probe,
+        })
+    }
+
+    #[test]
+    fn test_open_after_consecutive_failures() {
+        let mut breaker = new_breaker(Duration::from_secs(60), CircuitProbe::Request);
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.check(), CircuitCheck::Allow);
+
+        assert!(breaker.record_failure());
+        assert!(breaker.is_open());
+        assert!(matches!(breaker.check(), CircuitCheck::Reject { .. }));
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let probe = CircuitProbe::Lightweight {
+            method: Method::HEAD,
+            path: Some("/health".to_string()),
+        };
+        let mut breaker = new_breaker(Duration::ZERO, probe.clone());
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.check(), CircuitCheck::Probe(probe.clone()));
+
+        // a failed probe opens the circuit again.
+        breaker.record_failure();
+        assert_eq!(breaker.check(), CircuitCheck::Probe(probe));
+
+        assert!(!breaker.is_open());
+        assert!(breaker.record_success());
+        assert_eq!(breaker.check(), CircuitCheck::Allow);
+    }
+}
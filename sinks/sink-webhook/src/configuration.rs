@@ -1,15 +1,291 @@
+use std::time::Duration;
+
 use apibara_sink_common::SinkOptions;
 use apibara_sink_common::{SinkError, SinkErrorResultExt};
 use clap::Args;
 use error_stack::Result;
-use http::{HeaderMap, HeaderName, HeaderValue, Uri};
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode, Uri};
+use reqwest::Method;
 use serde::Deserialize;
 
+use crate::{
+    enrich::RESERVED_FIELDS, BasicAuthConfiguration, CircuitBreakerConfiguration, CircuitProbe,
+    ContextHeaders, EnrichmentConfiguration, OAuthConfiguration, PayloadSampleConfiguration,
+    QueryApiKeyConfiguration, RetryConfiguration, SignatureConfiguration, SignatureScheme,
+    SpoolConfiguration, TlsConfiguration, DEFAULT_CIRCUIT_COOLDOWN, DEFAULT_IDEMPOTENCY_KEY_HEADER,
+    DEFAULT_RETRY_BASE_DELAY, DEFAULT_RETRY_JITTER, DEFAULT_RETRY_MAX_DELAY,
+    DEFAULT_SAMPLE_MAX_FILE_BYTES, DEFAULT_SIGNATURE_HEADER, DEFAULT_SPOOL_MAX_BYTES,
+};
+
 #[derive(Debug)]
 pub struct SinkWebhookConfiguration {
     pub target_url: Uri,
+    pub method: Method,
     pub headers: HeaderMap,
+    /// Endpoints that receive the same requests as `target_url`.
+    pub fan_out_targets: Vec<WebhookTarget>,
+    pub fan_out_mode: FanOutMode,
     pub raw: bool,
+    pub raw_failure_mode: RawFailureMode,
+    pub raw_item_max_retries: usize,
+    pub raw_concurrency: usize,
+    /// Deliver up to this many consecutive batches at the same time.
+    pub max_in_flight_batches: usize,
+    pub raw_invalidate: bool,
+    pub invalidate_failure_mode: InvalidateFailureMode,
+    pub invalidate_max_retries: usize,
+    pub raw_batch: bool,
+    /// Headers describing the data sent in raw mode.
+    pub context_headers: ContextHeaders,
+    pub compression: Option<Compression>,
+    pub compression_threshold_bytes: usize,
+    pub max_body_bytes: Option<usize>,
+    pub split_oversized_batches: bool,
+    pub sample: Option<PayloadSampleConfiguration>,
+    pub body_format: BodyFormat,
+    pub envelope: Envelope,
+    pub pretty_json: bool,
+    /// Fields added to and removed from the data before it's sent.
+    pub enrichment: Option<EnrichmentConfiguration>,
+    /// Don't send batches without items, only persist their cursor.
+    pub skip_empty: bool,
+    pub circuit_breaker: Option<CircuitBreakerConfiguration>,
+    pub spool: Option<SpoolConfiguration>,
+    pub retry: Option<RetryConfiguration>,
+    pub retryable_status: Vec<StatusCodeMatcher>,
+    /// Responses with these status codes ask to send the data again later.
+    pub skip_status: Vec<StatusCodeMatcher>,
+    /// Only persist the cursor after the endpoint acknowledges the data.
+    pub require_ack: bool,
+    pub signature: Option<SignatureConfiguration>,
+    /// Header containing the idempotency key, if enabled.
+    pub idempotency_key_header: Option<HeaderName>,
+    /// Header containing a new id for each request, shared by its retries.
+    pub request_id_header: Option<HeaderName>,
+    pub user_agent: HeaderValue,
+    pub oauth: Option<OAuthConfiguration>,
+    pub basic_auth: Option<BasicAuthConfiguration>,
+    pub query_api_key: Option<QueryApiKeyConfiguration>,
+    pub dead_letter_url: Option<Uri>,
+    /// Check that the targets are reachable with a request with this method on startup.
+    pub preflight_method: Option<Method>,
+    pub request_timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Read and log at most this many bytes of the response body.
+    pub max_response_body_bytes: usize,
+    pub tls: TlsConfiguration,
+}
+
+/// An endpoint that receives the same requests as the target url.
+#[derive(Debug, Clone)]
+pub struct WebhookTarget {
+    pub url: Uri,
+    pub method: Method,
+    pub headers: HeaderMap,
+    /// The credentials of the target url are never sent to this endpoint.
+    pub credentials: TargetCredentials,
+}
+
+/// Credentials sent to a single endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct TargetCredentials {
+    pub signature: Option<SignatureConfiguration>,
+    pub oauth: Option<OAuthConfiguration>,
+    pub basic_auth: Option<BasicAuthConfiguration>,
+    pub query_api_key: Option<QueryApiKeyConfiguration>,
+}
+
+/// How failures are handled when requests are sent to multiple endpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum FanOutMode {
+    /// The request fails if it fails for any endpoint.
+    #[default]
+    AllMustSucceed,
+    /// The request succeeds if it succeeds for at least one endpoint.
+    ///
+    /// Failures of the other endpoints are logged, and the data is not sent
+    /// to them again.
+    BestEffort,
+}
+
+/// An additional endpoint, as configured in the script options.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FanOutTargetOptions {
+    target_url: String,
+    /// Defaults to `POST`.
+    method: Option<String>,
+    /// Headers in the `key: value` format, the target url headers are not sent.
+    header: Option<Vec<String>>,
+    /// Signed with the same scheme and header as the target url.
+    signature_secret: Option<String>,
+    oauth_token_url: Option<String>,
+    oauth_client_id: Option<String>,
+    oauth_client_secret: Option<String>,
+    oauth_scope: Option<String>,
+    basic_auth_username: Option<String>,
+    basic_auth_password: Option<String>,
+    api_key_query_param: Option<String>,
+    api_key: Option<String>,
+}
+
+/// Default minimum body size, in bytes, before compression is applied.
+const DEFAULT_COMPRESSION_THRESHOLD_BYTES: usize = 1024;
+
+/// Default timeout of each request, from connecting to reading the response.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default timeout to connect to the endpoint.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default user agent of the requests, with the sink version.
+pub const DEFAULT_USER_AGENT: &str = concat!("apibara-sink-webhook/", env!("CARGO_PKG_VERSION"));
+
+/// Default number of bytes of the response body that are read and logged.
+pub const DEFAULT_MAX_RESPONSE_BODY_BYTES: usize = 4 * 1024;
+
+/// Default name of the header containing the request id.
+pub const DEFAULT_REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Default number of attempts for each request when failed requests are
+/// stored in the spool or sent to the dead-letter url.
+const DEFAULT_FALLBACK_RETRY_MAX_ATTEMPTS: u32 = 3;
+
+/// Default number of times a raw item is retried with `retry_item`.
+const DEFAULT_RAW_ITEM_MAX_RETRIES: usize = 3;
+
+/// How failures to send an item are handled in raw mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum RawFailureMode {
+    /// Fail the whole batch. The batch is retried from its first item.
+    #[default]
+    FailBatch,
+    /// Log the error and continue with the next item.
+    ///
+    /// The batch cursor is persisted once all items were handled, so skipped
+    /// items are never sent again.
+    SkipItem,
+    /// Retry the failed item on its own, then fail the batch if it still fails.
+    RetryItem,
+}
+
+/// Default number of times an invalidate request is retried with `retry_then_continue`.
+const DEFAULT_INVALIDATE_MAX_RETRIES: usize = 3;
+
+/// How failures to send an invalidate request are handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum InvalidateFailureMode {
+    /// Stop the sink. The invalidation is sent again when the sink restarts.
+    #[default]
+    Halt,
+    /// Log the error and continue with the next message.
+    ///
+    /// The endpoint is not told about the invalidation, and may keep the
+    /// invalidated data.
+    Continue,
+    /// Retry the request on its own, then log the error and continue if it still fails.
+    RetryThenContinue,
+}
+
+/// Matches the HTTP status codes of the endpoint responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCodeMatcher {
+    /// All codes in a class, e.g. `5xx`.
+    Class(u16),
+    /// A single code, e.g. `429`.
+    Code(u16),
+}
+
+impl StatusCodeMatcher {
+    pub fn matches(&self, status: StatusCode) -> bool {
+        match self {
+            StatusCodeMatcher::Class(class) => status.as_u16() / 100 == *class,
+            StatusCodeMatcher::Code(code) => status.as_u16() == *code,
+        }
+    }
+}
+
+impl std::str::FromStr for StatusCodeMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        if let Some(class) = s.strip_suffix("xx") {
+            return match class.parse::<u16>() {
+                Ok(class) if (1..=5).contains(&class) => Ok(StatusCodeMatcher::Class(class)),
+                _ => Err(format!("invalid status class: {s}")),
+            };
+        }
+
+        match s.parse::<u16>() {
+            Ok(code) if (100..600).contains(&code) => Ok(StatusCodeMatcher::Code(code)),
+            _ => Err(format!("invalid status code: {s}")),
+        }
+    }
+}
+
+/// Status codes that are retried by default: server errors and rate limits.
+const DEFAULT_RETRYABLE_STATUS: &[StatusCodeMatcher] =
+    &[StatusCodeMatcher::Class(5), StatusCodeMatcher::Code(429)];
+
+/// Format of the request body.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyFormat {
+    /// A single JSON value.
+    #[default]
+    Json,
+    /// Newline-delimited JSON, with one line per element if the body is an array.
+    Ndjson,
+    /// MessagePack, with the same structure as the JSON body.
+    #[value(name = "msgpack")]
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl BodyFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            BodyFormat::Json => "application/json",
+            BodyFormat::Ndjson => "application/x-ndjson",
+            BodyFormat::MessagePack => "application/msgpack",
+        }
+    }
+}
+
+/// Structure of the request body in non-raw mode.
+///
+/// With `{"batch": [...]}` standing for the batch, and `cursor`, `end_cursor`
+/// and `finality` for the metadata of the batch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Envelope {
+    /// The batch and its metadata under a `data` key:
+    /// `{"data": {"cursor", "end_cursor", "finality", "batch"}}`.
+    #[default]
+    Nested,
+    /// The batch and its metadata at the top level:
+    /// `{"cursor", "end_cursor", "finality", "batch"}`.
+    Flat,
+    /// The batch at the top level and its metadata under a `meta` key:
+    /// `{"batch", "meta": {"cursor", "end_cursor", "finality"}}`.
+    Meta,
+}
+
+/// Compression algorithm applied to the request body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    /// The zlib format, as expected by `Content-Encoding: deflate`.
+    Deflate,
+    Zstd,
 }
 
 #[derive(Debug, Args, Default, SinkOptions)]
@@ -19,23 +295,538 @@ pub struct SinkWebhookOptions {
     #[arg(long, env = "WEBHOOK_TARGET_URL")]
     target_url: Option<String>,
 
+    /// The HTTP method used to send the request, one of `POST`, `PUT`, or `PATCH`.
+    /// Defaults to `POST`.
+    #[arg(long, env = "WEBHOOK_METHOD")]
+    method: Option<String>,
+
     /// Additional headers to send with the request.
     #[arg(long, short = 'H', value_delimiter = ',', env = "WEBHOOK_HEADERS")]
     header: Option<Vec<String>>,
 
+    /// Send the same requests to these endpoints too, each with its own method and headers.
+    ///
+    /// Only available in the script options, as a list of objects with the
+    /// `targetUrl`, `method` and `header` keys. Requests are sent to all
+    /// endpoints at the same time, with the same body. Invalidate requests are
+    /// sent to all endpoints too.
+    ///
+    /// Each endpoint has its own credentials, with the same keys as the options
+    /// of the target url: `signatureSecret` (with the same scheme and header),
+    /// `oauthTokenUrl`, `oauthClientId`, `oauthClientSecret`, `oauthScope`,
+    /// `basicAuthUsername`, `basicAuthPassword`, `apiKeyQueryParam` and
+    /// `apiKey`. The credentials of the target url are not sent to them.
+    #[clap(skip)]
+    fan_out_targets: Option<Vec<FanOutTargetOptions>>,
+
+    /// How failures are handled with fan-out targets. Defaults to `all_must_succeed`.
+    ///
+    /// With `all_must_succeed`, the cursor is only persisted once all endpoints
+    /// accepted the data, and a failed batch is only sent again to the
+    /// endpoints that didn't accept it.
+    /// With `best_effort`, the cursor is persisted once any endpoint accepted it.
+    #[arg(long, env = "WEBHOOK_FAN_OUT_MODE")]
+    fan_out_mode: Option<FanOutMode>,
+
     /// Send the data received from the transform step as is.
     ///
     /// Use this to interact with any API like Discord or Telegram.
     #[arg(long, action, env = "WEBHOOK_RAW")]
     raw: Option<bool>,
+
+    /// How to handle failures to send an item in raw mode. Defaults to `fail_batch`.
+    ///
+    /// With `skip_item`, failed items are dropped and the cursor still moves
+    /// past them.
+    #[arg(long, env = "WEBHOOK_RAW_FAILURE_MODE")]
+    raw_failure_mode: Option<RawFailureMode>,
+
+    /// Number of times an item is retried with `retry_item`.
+    /// Retries wait with the same backoff as request retries. Defaults to 3.
+    #[arg(long, env = "WEBHOOK_RAW_ITEM_MAX_RETRIES")]
+    raw_item_max_retries: Option<usize>,
+
+    /// How to handle failures to send an invalidate request. Defaults to `halt`.
+    ///
+    /// With `continue` and `retry_then_continue`, the sink continues after
+    /// logging the error, so the endpoint may keep data from blocks removed by
+    /// a chain reorganization. Requests are retried according to the retry
+    /// options first, if enabled.
+    #[arg(long, env = "WEBHOOK_INVALIDATE_FAILURE_MODE")]
+    invalidate_failure_mode: Option<InvalidateFailureMode>,
+
+    /// Number of times an invalidate request is retried with `retry_then_continue`.
+    /// Retries wait with the same backoff as request retries. Defaults to 3.
+    #[arg(long, env = "WEBHOOK_INVALIDATE_MAX_RETRIES")]
+    invalidate_max_retries: Option<usize>,
+
+    /// Send up to this many items at the same time in raw mode. Defaults to 1.
+    ///
+    /// With the default, items are sent one at a time in the order returned by
+    /// the transform step. With a higher value, items may be received in any
+    /// order. In both cases, the cursor is only persisted once all items were
+    /// handled and the batch fails if any item fails.
+    #[arg(long, env = "WEBHOOK_RAW_CONCURRENCY")]
+    raw_concurrency: Option<usize>,
+
+    /// Deliver up to this many consecutive batches at the same time. Defaults to 1.
+    ///
+    /// Batches can be received in any order, but cursors are persisted in
+    /// order: the cursor of a batch is only persisted once all batches before
+    /// it were delivered. In raw mode, up to this value times the raw
+    /// concurrency requests are in flight. Can't be combined with the spool.
+    #[arg(long, env = "WEBHOOK_MAX_IN_FLIGHT_BATCHES")]
+    max_in_flight_batches: Option<usize>,
+
+    /// Send chain reorganizations in raw mode too. Off by default.
+    ///
+    /// The request body is the same as without raw mode, an object with an
+    /// `invalidate` key containing the cursor of the new chain head, so it can
+    /// be told apart from the data items.
+    #[arg(long, action, env = "WEBHOOK_RAW_INVALIDATE")]
+    raw_invalidate: Option<bool>,
+
+    /// Send all items of a batch as a single JSON array in raw mode. Off by default.
+    ///
+    /// The whole batch fails if the request fails. Without this option, each
+    /// item is sent in a separate request.
+    ///
+    /// Can't be combined with the raw concurrency and raw failure mode options.
+    #[arg(long, action, env = "WEBHOOK_RAW_BATCH")]
+    raw_batch: Option<bool>,
+
+    /// Prefix of the headers sent with each request in raw mode. Defaults to `x-dna`.
+    ///
+    /// Requests have the `<prefix>-cursor`, `<prefix>-end-cursor` and
+    /// `<prefix>-finality` headers, with the block numbers and finality of the
+    /// batch. When items are sent one by one, `<prefix>-item-index` contains the
+    /// position of the item in the batch. Requests sent from the spool don't have
+    /// these headers.
+    #[arg(long, env = "WEBHOOK_RAW_HEADER_PREFIX")]
+    raw_header_prefix: Option<String>,
+
+    /// Compress the request body with the given algorithm, one of `gzip`, `deflate`, or `zstd`.
+    ///
+    /// The `Content-Encoding` header is set accordingly. Applies to all
+    /// requests, in raw mode too. Off by default.
+    #[arg(long, env = "WEBHOOK_COMPRESSION")]
+    compression: Option<Compression>,
+
+    /// Only compress bodies larger than this size, in bytes. Defaults to 1024.
+    #[arg(long, env = "WEBHOOK_COMPRESSION_THRESHOLD_BYTES")]
+    compression_threshold_bytes: Option<usize>,
+
+    /// Fail if the request body, before compression, is larger than this size in bytes.
+    #[arg(long, env = "WEBHOOK_MAX_BODY_BYTES")]
+    max_body_bytes: Option<usize>,
+
+    /// Split batches larger than `max_body_bytes` into multiple requests instead of failing.
+    ///
+    /// Each request contains the same cursors and a subset of the batch.
+    /// Can't be combined with raw mode.
+    #[arg(long, action, env = "WEBHOOK_SPLIT_OVERSIZED_BATCHES")]
+    split_oversized_batches: Option<bool>,
+
+    /// Write a sample of the delivered payloads to this file, one JSON payload per line.
+    ///
+    /// Use this to inspect the data produced by the transform step.
+    #[arg(long, env = "WEBHOOK_SAMPLE_FILE")]
+    sample_file: Option<String>,
+
+    /// Fraction of the delivered payloads written to the sample file. Defaults to 1.
+    #[arg(long, env = "WEBHOOK_SAMPLE_RATE")]
+    sample_rate: Option<f64>,
+
+    /// Rotate the sample file once it's larger than this size, in bytes. Defaults to 10 MiB.
+    #[arg(long, env = "WEBHOOK_SAMPLE_MAX_FILE_BYTES")]
+    sample_max_file_bytes: Option<u64>,
+
+    /// Format of the request body, one of `json`, `ndjson` or `msgpack`. Defaults to `json`.
+    ///
+    /// With `ndjson`, the body contains one JSON value per line, and the
+    /// `Content-Type` header is `application/x-ndjson`. Each line contains a
+    /// single item of the batch, with the same cursors and finality. In raw
+    /// mode, arrays are sent with one element per line. Signatures and
+    /// compression apply to the newline-delimited body.
+    ///
+    /// With `msgpack`, the body is the JSON body encoded as MessagePack, and
+    /// the `Content-Type` header is `application/msgpack`. Signatures and
+    /// compression apply to the encoded body.
+    #[arg(long, env = "WEBHOOK_BODY_FORMAT")]
+    body_format: Option<BodyFormat>,
+
+    /// Structure of the request body, one of `nested`, `flat` or `meta`. Defaults to `nested`.
+    ///
+    /// With `nested`, the batch and its cursors and finality are under a
+    /// `data` key. With `flat`, they are at the top level of the body. With
+    /// `meta`, the batch is at the top level and the cursors and finality are
+    /// under a `meta` key. Invalidate messages are not changed. Not valid in
+    /// raw mode.
+    #[arg(long, env = "WEBHOOK_ENVELOPE")]
+    envelope: Option<Envelope>,
+
+    /// Pretty-print the JSON request body, with indentation.
+    ///
+    /// Useful for endpoints that log the request body. Off by default.
+    #[arg(long, action, env = "WEBHOOK_PRETTY_JSON")]
+    pretty_json: Option<bool>,
+
+    /// Static fields added to the request body, in the `key=value` format.
+    ///
+    /// The fields are added to the top level of the body, next to the
+    /// envelope keys, or to each item in raw mode. Values are strings. The
+    /// envelope keys (`data`, `batch`, `cursor`, `end_cursor`, `finality` and
+    /// `meta`) can't be used.
+    #[arg(long, value_delimiter = ',', env = "WEBHOOK_ENRICH_FIELDS")]
+    enrich_field: Option<Vec<String>>,
+
+    /// JSON pointers, like `/transaction/calldata`, of the fields removed from
+    /// each item of the batch before it's sent.
+    #[arg(long, value_delimiter = ',', env = "WEBHOOK_REDACT")]
+    redact: Option<Vec<String>>,
+
+    /// Don't send batches without items (an empty array or `null`).
+    ///
+    /// The cursor of skipped batches is still persisted. Off by default, so
+    /// endpoints receive a request for every batch.
+    #[arg(long, action, env = "WEBHOOK_SKIP_EMPTY")]
+    skip_empty: Option<bool>,
+
+    /// Stop sending requests after this many consecutive failures.
+    ///
+    /// Requests fail immediately while the circuit is open. With retries,
+    /// a request counts as one failure after all its attempts failed. Each
+    /// fan-out target has its own circuit. Disabled by default.
+    #[arg(long, env = "WEBHOOK_CIRCUIT_FAILURE_THRESHOLD")]
+    circuit_failure_threshold: Option<u32>,
+
+    /// How long to stop sending requests for, in seconds. Defaults to 30.
+    #[arg(long, env = "WEBHOOK_CIRCUIT_COOLDOWN_SECS")]
+    circuit_cooldown_secs: Option<u64>,
+
+    /// Probe the endpoint with a request with this method, e.g. `HEAD`, before resuming.
+    ///
+    /// If not set, the next request is used as the probe.
+    #[arg(long, env = "WEBHOOK_CIRCUIT_PROBE_METHOD")]
+    circuit_probe_method: Option<String>,
+
+    /// Send the probe request to this path instead of the target url path.
+    ///
+    /// The probe uses the `HEAD` method if no method is set.
+    #[arg(long, env = "WEBHOOK_CIRCUIT_PROBE_PATH")]
+    circuit_probe_path: Option<String>,
+
+    /// Store requests that fail to send in this file, and send them once the endpoint recovers.
+    ///
+    /// Cursors keep advancing while requests are stored. Requests are always
+    /// sent in order: while the file contains requests, new ones are appended
+    /// to it. Stored requests are sent before handling new data and on every
+    /// heartbeat. Requests can be sent more than once if the sink is stopped
+    /// while sending them.
+    ///
+    /// Requests are only stored after all retries failed. With the spool, requests
+    /// are sent up to 3 times unless `retry_max_attempts` is set.
+    #[arg(long, env = "WEBHOOK_SPOOL_PATH")]
+    spool_path: Option<String>,
+
+    /// Stop storing requests once the spool file is larger than this size, in bytes.
+    /// Defaults to 100 MiB.
+    ///
+    /// When the spool is full, the sink stops advancing cursors until it's drained.
+    #[arg(long, env = "WEBHOOK_SPOOL_MAX_BYTES")]
+    spool_max_bytes: Option<u64>,
+
+    /// Send each request up to this many times, retrying connection errors and the
+    /// `retryable_status` responses.
+    ///
+    /// Other errors fail immediately. Retries are disabled by default, unless the
+    /// spool or the dead-letter url is enabled.
+    #[arg(long, env = "WEBHOOK_RETRY_MAX_ATTEMPTS")]
+    retry_max_attempts: Option<u32>,
+
+    /// Delay before the first retry, in milliseconds. Doubled on every retry. Defaults to 500.
+    #[arg(long, env = "WEBHOOK_RETRY_BASE_DELAY_MS")]
+    retry_base_delay_ms: Option<u64>,
+
+    /// Maximum delay between retries, in milliseconds. Defaults to 30000.
+    #[arg(long, env = "WEBHOOK_RETRY_MAX_DELAY_MS")]
+    retry_max_delay_ms: Option<u64>,
+
+    /// Fraction of each retry delay that is randomized, from 0 up to (excluding) 1. Defaults to 0.3.
+    #[arg(long, env = "WEBHOOK_RETRY_JITTER")]
+    retry_jitter: Option<f32>,
+
+    /// Response status codes, or classes like `5xx`, that are temporary errors.
+    ///
+    /// Responses with these codes are retried, after the delay in their
+    /// `Retry-After` header if it's longer than the retry delay. All other
+    /// responses outside of `2xx` are fatal errors and stop the sink without
+    /// persisting the cursor. Defaults to `5xx,429`.
+    #[arg(long, value_delimiter = ',', env = "WEBHOOK_RETRYABLE_STATUS")]
+    retryable_status: Option<Vec<String>>,
+
+    /// Response status codes, or classes, that ask the sink to send the data again later.
+    ///
+    /// Added to `retryable_status`, without replacing its defaults. Endpoints
+    /// can also respond with a 2xx status and a `{"cursor_action": "skip"}`
+    /// JSON body. In both cases, the request is retried after the delay in the
+    /// `Retry-After` header (in seconds, up to 10 minutes) if it's longer than
+    /// the retry delay, and the sink doesn't move to the next batch until the
+    /// endpoint accepts the data.
+    #[arg(long, value_delimiter = ',', env = "WEBHOOK_SKIP_STATUS")]
+    skip_status: Option<Vec<String>>,
+
+    /// Only persist the cursor after the endpoint acknowledges the data. Off by default.
+    ///
+    /// Endpoints acknowledge the data once it's stored durably, by responding
+    /// with a 2xx status and the end cursor of the data in a JSON body:
+    /// `{"ack_cursor": {"orderKey": 10, "uniqueKey": "0x..."}}`. The cursor
+    /// must be equal to the `end_cursor` sent with the data. In raw mode,
+    /// every item must be acknowledged with the end cursor of the batch.
+    ///
+    /// Data that isn't acknowledged is a temporary error: the request is
+    /// retried and the cursor is not persisted until the endpoint acknowledges
+    /// it. Together with an endpoint that ignores data it already stored, each
+    /// batch is stored exactly once. Not compatible with the spool, which
+    /// persists the cursor of data the endpoint didn't receive.
+    #[arg(long, action, env = "WEBHOOK_REQUIRE_ACK")]
+    require_ack: Option<bool>,
+
+    /// Sign the request body with this shared secret.
+    ///
+    /// The signature is computed over the exact bytes sent, after compression,
+    /// and sent in the `signature_header` header as `sha256=<hex>`. In raw mode,
+    /// each item is signed separately.
+    #[arg(long, env = "WEBHOOK_SIGNATURE_SECRET")]
+    signature_secret: Option<String>,
+
+    /// Algorithm used to sign the request body. Defaults to `hmac_sha256`.
+    #[arg(long, env = "WEBHOOK_SIGNATURE_SCHEME")]
+    signature_scheme: Option<SignatureScheme>,
+
+    /// Header containing the request signature. Defaults to `X-Signature`.
+    #[arg(long, env = "WEBHOOK_SIGNATURE_HEADER")]
+    signature_header: Option<String>,
+
+    /// Send a key that identifies the data in each request. Off by default.
+    ///
+    /// The key is derived from the cursors and finality of the batch, and the
+    /// position of the item in raw mode, so requests sent again after a retry
+    /// or a restart have the same key. Pending data is sent with the same key
+    /// every time the pending block changes. Requests sent from the spool don't
+    /// have a key.
+    #[arg(long, action, env = "WEBHOOK_IDEMPOTENCY_KEY")]
+    idempotency_key: Option<bool>,
+
+    /// Header containing the idempotency key. Defaults to `Idempotency-Key`.
+    #[arg(long, env = "WEBHOOK_IDEMPOTENCY_KEY_HEADER")]
+    idempotency_key_header: Option<String>,
+
+    /// Header containing the request id. Defaults to `X-Request-Id`.
+    ///
+    /// Each request has a new random id, and retries of the request, to any
+    /// of the endpoints, have the same id, so that the sink logs can be matched
+    /// with the endpoint logs. This includes the retries of a batch after the
+    /// sink failed to send it. In raw mode, every item has its own id, even if
+    /// items have the same content.
+    #[arg(long, env = "WEBHOOK_REQUEST_ID_HEADER")]
+    request_id_header: Option<String>,
+
+    /// Don't send the request id header.
+    #[arg(long, action, env = "WEBHOOK_NO_REQUEST_ID")]
+    no_request_id: Option<bool>,
+
+    /// The `User-Agent` header of the requests. Defaults to `apibara-sink-webhook/<version>`.
+    #[arg(long, env = "WEBHOOK_USER_AGENT")]
+    user_agent: Option<String>,
+
+    /// Fail requests that don't complete within this time, in milliseconds. Defaults to 30000.
+    ///
+    /// Timed out requests are temporary errors and are retried.
+    #[arg(long, env = "WEBHOOK_REQUEST_TIMEOUT_MS")]
+    request_timeout_ms: Option<u64>,
+
+    /// Fail requests that can't connect to the endpoint within this time, in milliseconds.
+    /// Defaults to 10000.
+    #[arg(long, env = "WEBHOOK_CONNECT_TIMEOUT_MS")]
+    connect_timeout_ms: Option<u64>,
+
+    /// Read and log at most this many bytes of the response body. Defaults to 4096.
+    ///
+    /// The rest of the body is discarded. Responses that tell the sink not to
+    /// persist the cursor or that acknowledge the data must fit in this size.
+    #[arg(long, env = "WEBHOOK_MAX_RESPONSE_BODY_BYTES")]
+    max_response_body_bytes: Option<usize>,
+
+    /// Trust the certificates in this PEM file, in addition to the system trust store.
+    ///
+    /// Use this for endpoints with certificates signed by an internal
+    /// certificate authority. The file can contain multiple certificates.
+    #[arg(long, env = "WEBHOOK_TLS_CA_CERTIFICATE")]
+    tls_ca_certificate: Option<String>,
+
+    /// Send the client certificate in this PEM file to endpoints that require mutual TLS.
+    ///
+    /// Requires `tls_client_key`.
+    #[arg(long, env = "WEBHOOK_TLS_CLIENT_CERTIFICATE")]
+    tls_client_certificate: Option<String>,
+
+    /// Private key of the client certificate, in PEM format.
+    #[arg(long, env = "WEBHOOK_TLS_CLIENT_KEY")]
+    tls_client_key: Option<String>,
+
+    /// DANGER: accept any TLS certificate, including self-signed and expired ones.
+    ///
+    /// Anyone on the network path can then read and modify the requests.
+    /// Only use this in development. Off by default.
+    #[arg(long, action, env = "WEBHOOK_TLS_DANGER_ACCEPT_INVALID_CERTIFICATES")]
+    tls_danger_accept_invalid_certificates: Option<bool>,
+
+    /// Authenticate requests with an OAuth2 access token from this token endpoint.
+    ///
+    /// Tokens are requested with the client credentials grant and sent in the
+    /// `Authorization` header. They are refreshed before they expire, and when
+    /// the endpoint responds with `401`, in which case the request is sent again
+    /// once. Requires `oauth_client_id` and `oauth_client_secret`.
+    #[arg(long, env = "WEBHOOK_OAUTH_TOKEN_URL")]
+    oauth_token_url: Option<String>,
+
+    /// Client id used to request access tokens.
+    #[arg(long, env = "WEBHOOK_OAUTH_CLIENT_ID")]
+    oauth_client_id: Option<String>,
+
+    /// Client secret used to request access tokens.
+    #[arg(long, env = "WEBHOOK_OAUTH_CLIENT_SECRET")]
+    oauth_client_secret: Option<String>,
+
+    /// Space-separated scopes requested with the access token.
+    #[arg(long, env = "WEBHOOK_OAUTH_SCOPE")]
+    oauth_scope: Option<String>,
+
+    /// Authenticate requests with HTTP Basic auth, with this username.
+    ///
+    /// Can't be combined with OAuth or an `Authorization` header.
+    #[arg(long, env = "WEBHOOK_BASIC_AUTH_USERNAME")]
+    basic_auth_username: Option<String>,
+
+    /// Password sent with the Basic auth username. Requires `basic_auth_username`.
+    #[arg(long, env = "WEBHOOK_BASIC_AUTH_PASSWORD")]
+    basic_auth_password: Option<String>,
+
+    /// Send the API key in this query parameter of the target url, e.g. `api_key`.
+    ///
+    /// For endpoints that don't accept credentials in headers. The parameter is
+    /// added to the query parameters already in the url. Requires `api_key`.
+    #[arg(long, env = "WEBHOOK_API_KEY_QUERY_PARAM")]
+    api_key_query_param: Option<String>,
+
+    /// API key sent in the `api_key_query_param` query parameter.
+    #[arg(long, env = "WEBHOOK_API_KEY")]
+    api_key: Option<String>,
+
+    /// Send batches that fail to deliver to this url, and continue with the next batch.
+    ///
+    /// The request body contains the batch, its cursors, and the error. Batches
+    /// are sent after all retries failed, immediately for responses that can't
+    /// be retried, and immediately while the circuit is open. Requests are sent
+    /// up to 3 times unless `retry_max_attempts` is set. In raw mode, the whole
+    /// batch is sent even if some items were delivered. If the dead-letter
+    /// request fails, the sink stops. Batches that are stored in the spool are
+    /// not sent.
+    #[arg(long, env = "WEBHOOK_DEAD_LETTER_URL")]
+    dead_letter_url: Option<String>,
+
+    /// Check that the target url is reachable on startup, with a request with this method,
+    /// e.g. `HEAD`, `GET`, or `OPTIONS`. Off by default.
+    ///
+    /// The sink fails before streaming any data if the request can't connect,
+    /// times out, or fails the TLS handshake. Any response, whatever its status,
+    /// means the endpoint is reachable. Fan-out targets are checked too.
+    #[arg(long, env = "WEBHOOK_PREFLIGHT_METHOD")]
+    preflight_method: Option<String>,
 }
 
 impl SinkOptions for SinkWebhookOptions {
     fn merge(self, other: SinkWebhookOptions) -> Self {
         Self {
             target_url: self.target_url.or(other.target_url),
+            method: self.method.or(other.method),
             header: self.header.or(other.header),
+            fan_out_targets: self.fan_out_targets.or(other.fan_out_targets),
+            fan_out_mode: self.fan_out_mode.or(other.fan_out_mode),
             raw: self.raw.or(other.raw),
+            raw_failure_mode: self.raw_failure_mode.or(other.raw_failure_mode),
+            raw_item_max_retries: self.raw_item_max_retries.or(other.raw_item_max_retries),
+            invalidate_failure_mode: self
+                .invalidate_failure_mode
+                .or(other.invalidate_failure_mode),
+            invalidate_max_retries: self.invalidate_max_retries.or(other.invalidate_max_retries),
+            raw_concurrency: self.raw_concurrency.or(other.raw_concurrency),
+            max_in_flight_batches: self.max_in_flight_batches.or(other.max_in_flight_batches),
+            raw_invalidate: self.raw_invalidate.or(other.raw_invalidate),
+            raw_batch: self.raw_batch.or(other.raw_batch),
+            raw_header_prefix: self.raw_header_prefix.or(other.raw_header_prefix),
+            compression: self.compression.or(other.compression),
+            compression_threshold_bytes: self
+                .compression_threshold_bytes
+                .or(other.compression_threshold_bytes),
+            max_body_bytes: self.max_body_bytes.or(other.max_body_bytes),
+            split_oversized_batches: self
+                .split_oversized_batches
+                .or(other.split_oversized_batches),
+            sample_file: self.sample_file.or(other.sample_file),
+            sample_rate: self.sample_rate.or(other.sample_rate),
+            sample_max_file_bytes: self.sample_max_file_bytes.or(other.sample_max_file_bytes),
+            body_format: self.body_format.or(other.body_format),
+            envelope: self.envelope.or(other.envelope),
+            pretty_json: self.pretty_json.or(other.pretty_json),
+            enrich_field: self.enrich_field.or(other.enrich_field),
+            redact: self.redact.or(other.redact),
+            skip_empty: self.skip_empty.or(other.skip_empty),
+            circuit_failure_threshold: self
+                .circuit_failure_threshold
+                .or(other.circuit_failure_threshold),
+            circuit_cooldown_secs: self.circuit_cooldown_secs.or(other.circuit_cooldown_secs),
+            circuit_probe_method: self.circuit_probe_method.or(other.circuit_probe_method),
+            circuit_probe_path: self.circuit_probe_path.or(other.circuit_probe_path),
+            spool_path: self.spool_path.or(other.spool_path),
+            spool_max_bytes: self.spool_max_bytes.or(other.spool_max_bytes),
+            retry_max_attempts: self.retry_max_attempts.or(other.retry_max_attempts),
+            retry_base_delay_ms: self.retry_base_delay_ms.or(other.retry_base_delay_ms),
+            retry_max_delay_ms: self.retry_max_delay_ms.or(other.retry_max_delay_ms),
+            retry_jitter: self.retry_jitter.or(other.retry_jitter),
+            retryable_status: self.retryable_status.or(other.retryable_status),
+            skip_status: self.skip_status.or(other.skip_status),
+            require_ack: self.require_ack.or(other.require_ack),
+            signature_secret: self.signature_secret.or(other.signature_secret),
+            signature_scheme: self.signature_scheme.or(other.signature_scheme),
+            signature_header: self.signature_header.or(other.signature_header),
+            idempotency_key: self.idempotency_key.or(other.idempotency_key),
+            idempotency_key_header: self.idempotency_key_header.or(other.idempotency_key_header),
+            request_id_header: self.request_id_header.or(other.request_id_header),
+            no_request_id: self.no_request_id.or(other.no_request_id),
+            user_agent: self.user_agent.or(other.user_agent),
+            request_timeout_ms: self.request_timeout_ms.or(other.request_timeout_ms),
+            connect_timeout_ms: self.connect_timeout_ms.or(other.connect_timeout_ms),
+            max_response_body_bytes: self
+                .max_response_body_bytes
+                .or(other.max_response_body_bytes),
+            tls_ca_certificate: self.tls_ca_certificate.or(other.tls_ca_certificate),
+            tls_client_certificate: self.tls_client_certificate.or(other.tls_client_certificate),
+            tls_client_key: self.tls_client_key.or(other.tls_client_key),
+            tls_danger_accept_invalid_certificates: self
+                .tls_danger_accept_invalid_certificates
+                .or(other.tls_danger_accept_invalid_certificates),
+            oauth_token_url: self.oauth_token_url.or(other.oauth_token_url),
+            oauth_client_id: self.oauth_client_id.or(other.oauth_client_id),
+            oauth_client_secret: self.oauth_client_secret.or(other.oauth_client_secret),
+            oauth_scope: self.oauth_scope.or(other.oauth_scope),
+            basic_auth_username: self.basic_auth_username.or(other.basic_auth_username),
+            basic_auth_password: self.basic_auth_password.or(other.basic_auth_password),
+            api_key_query_param: self.api_key_query_param.or(other.api_key_query_param),
+            api_key: self.api_key.or(other.api_key),
+            dead_letter_url: self.dead_letter_url.or(other.dead_letter_url),
+            preflight_method: self.preflight_method.or(other.preflight_method),
         }
     }
 }
@@ -48,19 +839,579 @@ impl SinkWebhookOptions {
             .parse::<Uri>()
             .runtime_error("malformed target url")?;
 
+        let method = match self.method {
+            None => Method::POST,
+            Some(method) => parse_method(&method)?,
+        };
+
         let headers = match self.header {
             None => HeaderMap::new(),
             Some(headers) => parse_headers(&headers)?,
         };
 
+        let sample = match self.sample_file {
+            None => None,
+            Some(path) => {
+                let rate = self.sample_rate.unwrap_or(1.0);
+                if !(rate > 0.0 && rate <= 1.0) {
+                    return Err(SinkError::runtime_error(
+                        "sample rate must be between 0 and 1",
+                    ));
+                }
+
+                Some(PayloadSampleConfiguration {
+                    path: path.into(),
+                    rate,
+                    max_file_bytes: self
+                        .sample_max_file_bytes
+                        .unwrap_or(DEFAULT_SAMPLE_MAX_FILE_BYTES),
+                })
+            }
+        };
+
+        let circuit_breaker = match self.circuit_failure_threshold {
+            None => None,
+            Some(0) => {
+                return Err(SinkError::runtime_error(
+                    "circuit failure threshold must be greater than 0",
+                ))
+            }
+            Some(failure_threshold) => {
+                let probe = match (self.circuit_probe_method, self.circuit_probe_path) {
+                    (None, None) => CircuitProbe::Request,
+                    (method, path) => {
+                        let method = match method {
+                            None => Method::HEAD,
+                            Some(method) => method
+                                .to_uppercase()
+                                .parse::<Method>()
+                                .runtime_error("malformed circuit probe method")?,
+                        };
+                        CircuitProbe::Lightweight { method, path }
+                    }
+                };
+
+                Some(CircuitBreakerConfiguration {
+                    failure_threshold,
+                    cooldown: self
+                        .circuit_cooldown_secs
+                        .map(Duration::from_secs)
+                        .unwrap_or(DEFAULT_CIRCUIT_COOLDOWN),
+                    probe,
+                })
+            }
+        };
+
+        // requests are retried before they are stored in the spool or dead-lettered.
+        let retry_max_attempts = match self.retry_max_attempts {
+            None if self.spool_path.is_some() || self.dead_letter_url.is_some() => {
+                Some(DEFAULT_FALLBACK_RETRY_MAX_ATTEMPTS)
+            }
+            max_attempts => max_attempts,
+        };
+        let retry = match retry_max_attempts {
+            None | Some(1) => None,
+            Some(0) => {
+                return Err(SinkError::runtime_error(
+                    "retry max attempts must be greater than 0",
+                ))
+            }
+            Some(max_attempts) => {
+                let jitter = self.retry_jitter.unwrap_or(DEFAULT_RETRY_JITTER);
+                if !(0.0..1.0).contains(&jitter) {
+                    return Err(SinkError::runtime_error(
+                        "retry jitter must be at least 0 and less than 1",
+                    ));
+                }
+
+                Some(RetryConfiguration {
+                    max_attempts,
+                    base_delay: self
+                        .retry_base_delay_ms
+                        .map(Duration::from_millis)
+                        .unwrap_or(DEFAULT_RETRY_BASE_DELAY),
+                    max_delay: self
+                        .retry_max_delay_ms
+                        .map(Duration::from_millis)
+                        .unwrap_or(DEFAULT_RETRY_MAX_DELAY),
+                    jitter,
+                })
+            }
+        };
+
+        let retryable_status = match self.retryable_status {
+            None => DEFAULT_RETRYABLE_STATUS.to_vec(),
+            Some(status) => status
+                .iter()
+                .map(|status| status.parse::<StatusCodeMatcher>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|err| SinkError::runtime_error(&err))?,
+        };
+
+        let skip_status = self
+            .skip_status
+            .unwrap_or_default()
+            .iter()
+            .map(|status| status.parse::<StatusCodeMatcher>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|err| SinkError::runtime_error(&err))?;
+
+        let signature_scheme = self.signature_scheme.unwrap_or_default();
+        let signature_header = self
+            .signature_header
+            .as_deref()
+            .unwrap_or(DEFAULT_SIGNATURE_HEADER)
+            .parse::<HeaderName>()
+            .runtime_error("malformed signature header name")?;
+        let signature =
+            parse_signature(self.signature_secret, signature_scheme, &signature_header)?;
+
+        let idempotency_key_header = if self.idempotency_key.unwrap_or(false) {
+            let header = self
+                .idempotency_key_header
+                .as_deref()
+                .unwrap_or(DEFAULT_IDEMPOTENCY_KEY_HEADER)
+                .parse::<HeaderName>()
+                .runtime_error("malformed idempotency key header name")?;
+            Some(header)
+        } else if self.idempotency_key_header.is_some() {
+            return Err(SinkError::runtime_error(
+                "idempotency key header requires idempotency key",
+            ));
+        } else {
+            None
+        };
+
+        let request_id_header = if !self.no_request_id.unwrap_or(false) {
+            let header = self
+                .request_id_header
+                .as_deref()
+                .unwrap_or(DEFAULT_REQUEST_ID_HEADER)
+                .parse::<HeaderName>()
+                .runtime_error("malformed request id header name")?;
+            Some(header)
+        } else if self.request_id_header.is_some() {
+            return Err(SinkError::runtime_error(
+                "request id header conflicts with no request id",
+            ));
+        } else {
+            None
+        };
+
+        let user_agent = self
+            .user_agent
+            .as_deref()
+            .unwrap_or(DEFAULT_USER_AGENT)
+            .parse::<HeaderValue>()
+            .runtime_error("malformed user agent")?;
+
+        let oauth = parse_oauth(
+            self.oauth_token_url,
+            self.oauth_client_id,
+            self.oauth_client_secret,
+            self.oauth_scope,
+            &headers,
+        )?;
+        let basic_auth = parse_basic_auth(
+            self.basic_auth_username,
+            self.basic_auth_password,
+            oauth.is_some(),
+            &headers,
+        )?;
+        let query_api_key = parse_query_api_key(self.api_key_query_param, self.api_key)?;
+
+        let fan_out_targets = self
+            .fan_out_targets
+            .unwrap_or_default()
+            .into_iter()
+            .map(|target| target.into_target(signature_scheme, &signature_header))
+            .collect::<Result<Vec<_>, SinkError>>()?;
+
+        let dead_letter_url = self
+            .dead_letter_url
+            .map(|url| url.parse::<Uri>())
+            .transpose()
+            .runtime_error("malformed dead-letter url")?;
+
+        let preflight_method = self
+            .preflight_method
+            .map(|method| method.to_uppercase().parse::<Method>())
+            .transpose()
+            .runtime_error("malformed preflight method")?;
+
+        let body_format = self.body_format.unwrap_or_default();
+        let pretty_json = self.pretty_json.unwrap_or(false);
+        if pretty_json && body_format != BodyFormat::Json {
+            return Err(SinkError::runtime_error(
+                "pretty json requires the json body format",
+            ));
+        }
+
+        let require_ack = self.require_ack.unwrap_or(false);
+        if require_ack && self.spool_path.is_some() {
+            return Err(SinkError::runtime_error("require ack conflicts with spool"));
+        }
+
+        let enrichment = parse_enrichment(
+            self.enrich_field.unwrap_or_default(),
+            self.redact.unwrap_or_default(),
+        )?;
+
+        let raw = self.raw.unwrap_or(false);
+        let raw_batch = self.raw_batch.unwrap_or(false);
+        if raw_batch && !raw {
+            return Err(SinkError::runtime_error("raw batch requires raw mode"));
+        }
+
+        // raw batch sends a single request, the options about items don't apply.
+        if raw_batch && self.raw_concurrency.unwrap_or(1) > 1 {
+            return Err(SinkError::runtime_error(
+                "raw concurrency can't be combined with raw batch",
+            ));
+        }
+
+        if raw_batch
+            && (self.raw_failure_mode.unwrap_or_default() != RawFailureMode::FailBatch
+                || self.raw_item_max_retries.is_some())
+        {
+            return Err(SinkError::runtime_error(
+                "raw failure mode can't be combined with raw batch",
+            ));
+        }
+
+        if raw && self.envelope.is_some() {
+            return Err(SinkError::runtime_error(
+                "envelope can't be combined with raw mode",
+            ));
+        }
+
+        if raw && self.split_oversized_batches.unwrap_or(false) {
+            return Err(SinkError::runtime_error(
+                "split oversized batches can't be combined with raw mode",
+            ));
+        }
+
+        let context_headers = match self.raw_header_prefix {
+            None => ContextHeaders::default(),
+            Some(prefix) => {
+                ContextHeaders::with_prefix(&prefix).runtime_error("malformed raw header prefix")?
+            }
+        };
+
+        if self.raw_concurrency == Some(0) {
+            return Err(SinkError::runtime_error(
+                "raw concurrency must be greater than 0",
+            ));
+        }
+
+        let max_in_flight_batches = self.max_in_flight_batches.unwrap_or(1);
+        if max_in_flight_batches == 0 {
+            return Err(SinkError::runtime_error(
+                "max in-flight batches must be greater than 0",
+            ));
+        }
+
+        // the spool keeps requests in order, sending them one at a time.
+        if max_in_flight_batches > 1 && self.spool_path.is_some() {
+            return Err(SinkError::runtime_error(
+                "max in-flight batches conflicts with spool",
+            ));
+        }
+
+        let root_certificates = match self.tls_ca_certificate {
+            None => Vec::default(),
+            Some(path) => TlsConfiguration::load_root_certificates(path.as_ref())?,
+        };
+
+        let identity = match (self.tls_client_certificate, self.tls_client_key) {
+            (None, None) => None,
+            (Some(certificate), Some(key)) => Some(TlsConfiguration::load_identity(
+                certificate.as_ref(),
+                key.as_ref(),
+            )?),
+            _ => {
+                return Err(SinkError::runtime_error(
+                    "tls client certificate and key must be set together",
+                ))
+            }
+        };
+
+        let tls = TlsConfiguration {
+            root_certificates,
+            identity,
+            danger_accept_invalid_certificates: self
+                .tls_danger_accept_invalid_certificates
+                .unwrap_or(false),
+        };
+
+        if self.request_timeout_ms == Some(0) || self.connect_timeout_ms == Some(0) {
+            return Err(SinkError::runtime_error("timeouts must be greater than 0"));
+        }
+
         Ok(SinkWebhookConfiguration {
             target_url,
+            method,
+            headers,
+            fan_out_targets,
+            fan_out_mode: self.fan_out_mode.unwrap_or_default(),
+            raw,
+            raw_failure_mode: self.raw_failure_mode.unwrap_or_default(),
+            raw_item_max_retries: self
+                .raw_item_max_retries
+                .unwrap_or(DEFAULT_RAW_ITEM_MAX_RETRIES),
+            raw_concurrency: self.raw_concurrency.unwrap_or(1),
+            max_in_flight_batches,
+            raw_invalidate: self.raw_invalidate.unwrap_or(false),
+            invalidate_failure_mode: self.invalidate_failure_mode.unwrap_or_default(),
+            invalidate_max_retries: self
+                .invalidate_max_retries
+                .unwrap_or(DEFAULT_INVALIDATE_MAX_RETRIES),
+            raw_batch,
+            context_headers,
+            compression: self.compression,
+            compression_threshold_bytes: self
+                .compression_threshold_bytes
+                .unwrap_or(DEFAULT_COMPRESSION_THRESHOLD_BYTES),
+            max_body_bytes: self.max_body_bytes,
+            split_oversized_batches: self.split_oversized_batches.unwrap_or(false),
+            sample,
+            body_format,
+            envelope: self.envelope.unwrap_or_default(),
+            pretty_json,
+            enrichment,
+            skip_empty: self.skip_empty.unwrap_or(false),
+            circuit_breaker,
+            spool: self.spool_path.map(|path| SpoolConfiguration {
+                path: path.into(),
+                max_bytes: self.spool_max_bytes.unwrap_or(DEFAULT_SPOOL_MAX_BYTES),
+            }),
+            retry,
+            retryable_status,
+            skip_status,
+            require_ack,
+            signature,
+            idempotency_key_header,
+            request_id_header,
+            user_agent,
+            oauth,
+            basic_auth,
+            query_api_key,
+            dead_letter_url,
+            preflight_method,
+            request_timeout: self
+                .request_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+            connect_timeout: self
+                .connect_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            max_response_body_bytes: self
+                .max_response_body_bytes
+                .unwrap_or(DEFAULT_MAX_RESPONSE_BODY_BYTES),
+            tls,
+        })
+    }
+}
+
+/// Parses the request method, only accepting methods that send a body.
+fn parse_method(method: &str) -> Result<Method, SinkError> {
+    let method = method
+        .to_uppercase()
+        .parse::<Method>()
+        .runtime_error("malformed http method")?;
+
+    if ![Method::POST, Method::PUT, Method::PATCH].contains(&method) {
+        return Err(SinkError::runtime_error(&format!(
+            "unsupported http method {method}, must be one of POST, PUT, or PATCH"
+        )));
+    }
+
+    Ok(method)
+}
+
+impl FanOutTargetOptions {
+    fn into_target(
+        self,
+        signature_scheme: SignatureScheme,
+        signature_header: &HeaderName,
+    ) -> Result<WebhookTarget, SinkError> {
+        let url = self
+            .target_url
+            .parse::<Uri>()
+            .runtime_error("malformed fan-out target url")?;
+        let method = match self.method {
+            None => Method::POST,
+            Some(method) => parse_method(&method)?,
+        };
+        let headers = match self.header {
+            None => HeaderMap::new(),
+            Some(headers) => parse_headers(&headers)?,
+        };
+
+        let oauth = parse_oauth(
+            self.oauth_token_url,
+            self.oauth_client_id,
+            self.oauth_client_secret,
+            self.oauth_scope,
+            &headers,
+        )?;
+        let basic_auth = parse_basic_auth(
+            self.basic_auth_username,
+            self.basic_auth_password,
+            oauth.is_some(),
+            &headers,
+        )?;
+        let credentials = TargetCredentials {
+            signature: parse_signature(self.signature_secret, signature_scheme, signature_header)?,
+            oauth,
+            basic_auth,
+            query_api_key: parse_query_api_key(self.api_key_query_param, self.api_key)?,
+        };
+
+        Ok(WebhookTarget {
+            url,
+            method,
             headers,
-            raw: self.raw.unwrap_or(false),
+            credentials,
         })
     }
 }
 
+fn parse_signature(
+    secret: Option<String>,
+    scheme: SignatureScheme,
+    header: &HeaderName,
+) -> Result<Option<SignatureConfiguration>, SinkError> {
+    match secret {
+        None => Ok(None),
+        Some(secret) if secret.is_empty() => Err(SinkError::runtime_error(
+            "signature secret must not be empty",
+        )),
+        Some(secret) => Ok(Some(SignatureConfiguration {
+            secret: secret.into_bytes(),
+            scheme,
+            header: header.clone(),
+        })),
+    }
+}
+
+/// Parses the OAuth options of an endpoint, which can't also send an `Authorization` header.
+fn parse_oauth(
+    token_url: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    scope: Option<String>,
+    headers: &HeaderMap,
+) -> Result<Option<OAuthConfiguration>, SinkError> {
+    let Some(token_url) = token_url else {
+        return Ok(None);
+    };
+
+    let (Some(client_id), Some(client_secret)) = (client_id, client_secret) else {
+        return Err(SinkError::runtime_error(
+            "oauth requires a client id and a client secret",
+        ));
+    };
+
+    token_url
+        .parse::<Uri>()
+        .runtime_error("malformed oauth token url")?;
+
+    if headers.contains_key(http::header::AUTHORIZATION) {
+        return Err(SinkError::runtime_error(
+            "oauth can't be combined with an authorization header",
+        ));
+    }
+
+    Ok(Some(OAuthConfiguration {
+        token_url,
+        client_id,
+        client_secret,
+        scope,
+    }))
+}
+
+fn parse_basic_auth(
+    username: Option<String>,
+    password: Option<String>,
+    has_oauth: bool,
+    headers: &HeaderMap,
+) -> Result<Option<BasicAuthConfiguration>, SinkError> {
+    match (username, password) {
+        (None, None) => Ok(None),
+        (None, Some(_)) => Err(SinkError::runtime_error(
+            "basic auth password requires a username",
+        )),
+        (Some(username), password) => {
+            if has_oauth || headers.contains_key(http::header::AUTHORIZATION) {
+                return Err(SinkError::runtime_error(
+                    "basic auth can't be combined with oauth or an authorization header",
+                ));
+            }
+            Ok(Some(BasicAuthConfiguration { username, password }))
+        }
+    }
+}
+
+fn parse_query_api_key(
+    param: Option<String>,
+    key: Option<String>,
+) -> Result<Option<QueryApiKeyConfiguration>, SinkError> {
+    match (param, key) {
+        (None, None) => Ok(None),
+        (Some(param), Some(key)) if !param.is_empty() => {
+            Ok(Some(QueryApiKeyConfiguration { param, key }))
+        }
+        (Some(_), Some(_)) => Err(SinkError::runtime_error(
+            "api key query parameter must not be empty",
+        )),
+        _ => Err(SinkError::runtime_error(
+            "api key and api key query parameter must be set together",
+        )),
+    }
+}
+
+/// Parses the enrichment options, returning `None` if there's nothing to change.
+fn parse_enrichment(
+    fields: Vec<String>,
+    redact: Vec<String>,
+) -> Result<Option<EnrichmentConfiguration>, SinkError> {
+    if fields.is_empty() && redact.is_empty() {
+        return Ok(None);
+    }
+
+    let mut enrichment = EnrichmentConfiguration::default();
+    for field in fields {
+        let Some((key, value)) = field.split_once('=') else {
+            return Err(SinkError::runtime_error(
+                "enrich field not in the `key=value` format",
+            ));
+        };
+        let key = key.trim();
+        if RESERVED_FIELDS.contains(&key) {
+            return Err(SinkError::runtime_error(&format!(
+                "enrich field `{}` would replace a field of the request body",
+                key
+            )));
+        }
+        enrichment
+            .fields
+            .insert(key.to_string(), value.trim().into());
+    }
+
+    for pointer in redact {
+        if !pointer.starts_with('/') {
+            return Err(SinkError::runtime_error(
+                "redacted field must be a json pointer starting with `/`",
+            ));
+        }
+        enrichment.redact.push(pointer);
+    }
+
+    Ok(Some(enrichment))
+}
+
 fn parse_headers(headers: &[String]) -> Result<HeaderMap, SinkError> {
     let mut new_headers = HeaderMap::new();
     for header in headers {
@@ -84,3 +1435,167 @@ fn parse_headers(headers: &[String]) -> Result<HeaderMap, SinkError> {
 
     Ok(new_headers)
 }
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use reqwest::Method;
+
+    use super::{parse_method, RawFailureMode, SinkWebhookOptions, StatusCodeMatcher};
+
+    #[test]
+    fn test_parse_method() {
+        assert_eq!(parse_method("put").unwrap(), Method::PUT);
+        assert_eq!(parse_method("PATCH").unwrap(), Method::PATCH);
+        assert!(parse_method("GET").is_err());
+        assert!(parse_method("DELETE").is_err());
+    }
+
+    #[test]
+    fn test_status_code_matcher() {
+        let class = "5xx".parse::<StatusCodeMatcher>().unwrap();
+        assert_eq!(class, StatusCodeMatcher::Class(5));
+        assert!(class.matches(StatusCode::BAD_GATEWAY));
+        assert!(!class.matches(StatusCode::NOT_FOUND));
+
+        let code = "429".parse::<StatusCodeMatcher>().unwrap();
+        assert!(code.matches(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!code.matches(StatusCode::BAD_REQUEST));
+
+        assert!("9xx".parse::<StatusCodeMatcher>().is_err());
+        assert!("abc".parse::<StatusCodeMatcher>().is_err());
+    }
+
+    #[test]
+    fn test_fallback_enables_retries() {
+        let options = SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            spool_path: Some("/tmp/spool".to_string()),
+            ..Default::default()
+        };
+        let config = options.to_webhook_configuration().unwrap();
+        assert_eq!(config.retry.unwrap().max_attempts, 3);
+
+        let options = SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            spool_path: Some("/tmp/spool".to_string()),
+            retry_max_attempts: Some(1),
+            ..Default::default()
+        };
+        let config = options.to_webhook_configuration().unwrap();
+        assert!(config.retry.is_none());
+
+        let options = SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            dead_letter_url: Some("http://localhost:8080/dead-letter".to_string()),
+            ..Default::default()
+        };
+        let config = options.to_webhook_configuration().unwrap();
+        assert_eq!(config.retry.unwrap().max_attempts, 3);
+    }
+
+    #[test]
+    fn test_raw_batch_rejects_item_options() {
+        let raw_batch = || SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            raw: Some(true),
+            raw_batch: Some(true),
+            ..Default::default()
+        };
+        assert!(raw_batch().to_webhook_configuration().is_ok());
+
+        let options = SinkWebhookOptions {
+            raw_concurrency: Some(4),
+            ..raw_batch()
+        };
+        assert!(options.to_webhook_configuration().is_err());
+
+        let options = SinkWebhookOptions {
+            raw_failure_mode: Some(RawFailureMode::SkipItem),
+            ..raw_batch()
+        };
+        assert!(options.to_webhook_configuration().is_err());
+
+        let options = SinkWebhookOptions {
+            raw_item_max_retries: Some(2),
+            ..raw_batch()
+        };
+        assert!(options.to_webhook_configuration().is_err());
+    }
+
+    #[test]
+    fn test_raw_rejects_split_oversized_batches() {
+        let options = SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            split_oversized_batches: Some(true),
+            ..Default::default()
+        };
+        assert!(options.to_webhook_configuration().is_ok());
+
+        let options = SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            raw: Some(true),
+            split_oversized_batches: Some(true),
+            ..Default::default()
+        };
+        assert!(options.to_webhook_configuration().is_err());
+    }
+
+    #[test]
+    fn test_enrich_fields_can_not_replace_envelope() {
+        let options = SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            enrich_field: Some(vec!["env=staging".to_string()]),
+            ..Default::default()
+        };
+        assert!(options.to_webhook_configuration().is_ok());
+
+        let options = SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            enrich_field: Some(vec![" cursor = 1".to_string()]),
+            ..Default::default()
+        };
+        assert!(options.to_webhook_configuration().is_err());
+    }
+
+    #[test]
+    fn test_fan_out_target_credentials() {
+        let targets = serde_json::json!([
+            {
+                "targetUrl": "http://localhost:8081",
+                "basicAuthUsername": "other",
+                "signatureSecret": "secret",
+            },
+            { "targetUrl": "http://localhost:8082" },
+        ]);
+        let options = SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            basic_auth_username: Some("user".to_string()),
+            fan_out_targets: Some(serde_json::from_value(targets).unwrap()),
+            ..Default::default()
+        };
+        let config = options.to_webhook_configuration().unwrap();
+        assert_eq!(config.basic_auth.unwrap().username, "user");
+
+        let other = &config.fan_out_targets[0].credentials;
+        assert_eq!(other.basic_auth.as_ref().unwrap().username, "other");
+        assert_eq!(other.signature.as_ref().unwrap().secret, b"secret");
+        assert!(config.fan_out_targets[1].credentials.basic_auth.is_none());
+        assert!(config.fan_out_targets[1].credentials.signature.is_none());
+
+        // oauth can't be combined with an authorization header on the same target.
+        let targets = serde_json::json!([{
+            "targetUrl": "http://localhost:8081",
+            "header": ["authorization: Bearer token"],
+            "oauthTokenUrl": "http://localhost:8081/token",
+            "oauthClientId": "id",
+            "oauthClientSecret": "secret",
+        }]);
+        let options = SinkWebhookOptions {
+            target_url: Some("http://localhost:8080".to_string()),
+            fan_out_targets: Some(serde_json::from_value(targets).unwrap()),
+            ..Default::default()
+        };
+        assert!(options.to_webhook_configuration().is_err());
+    }
+}
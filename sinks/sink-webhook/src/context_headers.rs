@@ -0,0 +1,106 @@
+//! Describe the data sent in raw mode with request headers.
+//!
+//! In raw mode the body is the item returned by the transform step, so the
+//! cursors and finality of the batch are sent as headers instead. Cursors are
+//! sent as their block number, finality as `pending`, `accepted` or `finalized`.
+
+use apibara_sink_common::Context;
+use http::{header::InvalidHeaderName, HeaderMap, HeaderName, HeaderValue};
+
+/// Default prefix of the context headers.
+pub const DEFAULT_CONTEXT_HEADER_PREFIX: &str = "x-dna";
+
+#[derive(Debug, Clone)]
+pub struct ContextHeaders {
+    /// Cursor of the block before the batch. Not sent at the start of the stream.
+    pub cursor: HeaderName,
+    /// Cursor of the last block in the batch.
+    pub end_cursor: HeaderName,
+    pub finality: HeaderName,
+    /// Position of the item in the batch, only sent when items are sent one by one.
+    pub item_index: HeaderName,
+}
+
+impl ContextHeaders {
+    /// Creates the headers `<prefix>-cursor`, `<prefix>-end-cursor`, `<prefix>-finality`
+    /// and `<prefix>-item-index`.
+    pub fn with_prefix(prefix: &str) -> Result<Self, InvalidHeaderName> {
+        let name = |suffix: &str| format!("{}-{}", prefix, suffix).parse::<HeaderName>();
+        Ok(ContextHeaders {
+            cursor: name("cursor")?,
+            end_cursor: name("end-cursor")?,
+            finality: name("finality")?,
+            item_index: name("item-index")?,
+        })
+    }
+
+    /// Returns the headers of a request containing the whole batch.
+    pub fn batch(&self, ctx: &Context) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(cursor) = &ctx.cursor {
+            headers.insert(self.cursor.clone(), HeaderValue::from(cursor.order_key));
+        }
+        headers.insert(
+            self.end_cursor.clone(),
+            HeaderValue::from(ctx.end_cursor.order_key),
+        );
+        let finality = HeaderValue::from_str(&ctx.finality.to_string())
+            .expect("finality is a valid header value");
+        headers.insert(self.finality.clone(), finality);
+        headers
+    }
+
+    /// Returns the headers of a request containing the item at `index` in the batch.
+    pub fn item(&self, ctx: &Context, index: usize) -> HeaderMap {
+        let mut headers = self.batch(ctx);
+        headers.insert(self.item_index.clone(), HeaderValue::from(index));
+        headers
+    }
+}
+
+impl Default for ContextHeaders {
+    fn default() -> Self {
+        ContextHeaders::with_prefix(DEFAULT_CONTEXT_HEADER_PREFIX)
+            .expect("default prefix is a valid header name")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::node::v1alpha2::{Cursor, DataFinality};
+    use apibara_sink_common::Context;
+
+    use super::ContextHeaders;
+
+    #[test]
+    fn test_context_headers() {
+        let headers = ContextHeaders::with_prefix("x-test").unwrap();
+        let ctx = Context {
+            cursor: None,
+            end_cursor: Cursor {
+                order_key: 10,
+                unique_key: vec![],
+            },
+            finality: DataFinality::DataStatusAccepted,
+        };
+
+        let batch = headers.batch(&ctx);
+        assert!(batch.get("x-test-cursor").is_none());
+        assert_eq!(batch.get("x-test-end-cursor").unwrap(), "10");
+        assert_eq!(batch.get("x-test-finality").unwrap(), "accepted");
+        assert!(batch.get("x-test-item-index").is_none());
+
+        let ctx = Context {
+            cursor: Some(Cursor {
+                order_key: 5,
+                unique_key: vec![],
+            }),
+            ..ctx
+        };
+        let item = headers.item(&ctx, 2);
+        assert_eq!(item.get("x-test-cursor").unwrap(), "5");
+        assert_eq!(item.get("x-test-item-index").unwrap(), "2");
+
+        assert!(ContextHeaders::with_prefix("not valid").is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Add static fields to the data and remove fields from it before it's sent.
+//!
+//! Use this for small changes that would otherwise need a change to the
+//! transform script, like tagging the data with the environment that
+//! produced it or dropping a field the endpoint doesn't need.
+
+use std::{borrow::Cow, cmp::Reverse};
+
+use serde_json::{Map, Value};
+
+/// Keys of the request envelope, that enrichment fields can't replace.
+pub(crate) const RESERVED_FIELDS: &[&str] =
+    &["data", "batch", "cursor", "end_cursor", "finality", "meta"];
+
+#[derive(Debug, Clone, Default)]
+pub struct EnrichmentConfiguration {
+    /// Fields added to the top level of the body, replacing fields with the same name.
+    ///
+    /// In raw mode, the fields are added to each item instead.
+    pub fields: Map<String, Value>,
+    /// JSON pointers, like `/transaction/calldata`, of the fields removed from
+    /// each item of the batch.
+    pub redact: Vec<String>,
+}
+
+impl EnrichmentConfiguration {
+    /// Returns the batch with the redacted fields removed from each item.
+    ///
+    /// If `add_fields` is true, the fields are also added to each item. The
+    /// batch is only copied if it changes.
+    pub fn apply_to_batch<'a>(&self, batch: &'a Value, add_fields: bool) -> Cow<'a, Value> {
+        let items = match batch {
+            Value::Array(items) => items.as_slice(),
+            item => std::slice::from_ref(item),
+        };
+        if !items.iter().any(|item| self.changes_item(item, add_fields)) {
+            return Cow::Borrowed(batch);
+        }
+
+        let mut batch = batch.clone();
+        match &mut batch {
+            Value::Array(items) => {
+                for item in items {
+                    self.apply_to_item(item, add_fields);
+                }
+            }
+            item => self.apply_to_item(item, add_fields),
+        }
+        Cow::Owned(batch)
+    }
+
+    /// Adds the fields to the body, if it's an object.
+    pub fn add_fields(&self, body: &mut Value) {
+        if let Value::Object(body) = body {
+            for (key, value) in &self.fields {
+                body.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    fn changes_item(&self, item: &Value, add_fields: bool) -> bool {
+        if add_fields && !self.fields.is_empty() && item.is_object() {
+            return true;
+        }
+        self.redact
+            .iter()
+            .any(|pointer| item.pointer(pointer).is_some())
+    }
+
+    fn apply_to_item(&self, item: &mut Value, add_fields: bool) {
+        // remove nested fields first, and the items of an array from the
+        // last one, so that removing a value doesn't move the next ones.
+        let mut redact = self.redact.iter().collect::<Vec<_>>();
+        redact.sort_by_key(|pointer| Reverse((pointer.matches('/').count(), array_index(pointer))));
+        for pointer in redact {
+            remove_pointer(item, pointer);
+        }
+        if add_fields {
+            self.add_fields(item);
+        }
+    }
+}
+
+/// Returns the index referenced by the last segment of the pointer, if it's a number.
+fn array_index(pointer: &str) -> Option<usize> {
+    pointer.rsplit_once('/')?.1.parse().ok()
+}
+
+/// Removes the value at the JSON pointer, if it exists.
+fn remove_pointer(value: &mut Value, pointer: &str) {
+    let Some((parent, key)) = pointer.rsplit_once('/') else {
+        return;
+    };
+
+    let key = key.replace("~1", "/").replace("~0", "~");
+    match value.pointer_mut(parent) {
+        Some(Value::Object(parent)) => {
+            parent.remove(&key);
+        }
+        Some(Value::Array(parent)) => {
+            if let Ok(index) = key.parse::<usize>() {
+                if index < parent.len() {
+                    parent.remove(index);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use serde_json::{json, Map};
+
+    use super::EnrichmentConfiguration;
+
+    fn new_enrichment() -> EnrichmentConfiguration {
+        let mut fields = Map::new();
+        fields.insert("env".to_string(), json!("staging"));
+        EnrichmentConfiguration {
+            fields,
+            redact: vec![
+                "/secret".to_string(),
+                "/tx/calldata".to_string(),
+                "/a~1b".to_string(),
+                "/missing/field".to_string(),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_redact_batch_items() {
+        let batch = json!([
+            {"secret": 1, "tx": {"hash": "0x1", "calldata": ["0x2"]}, "a/b": 2},
+            {"value": 3},
+            "not an object",
+        ]);
+
+        let batch = new_enrichment().apply_to_batch(&batch, false);
+        assert_eq!(
+            batch.as_ref(),
+            &json!([{"tx": {"hash": "0x1"}}, {"value": 3}, "not an object"])
+        );
+    }
+
+    #[test]
+    fn test_redact_array_items() {
+        let enrichment = EnrichmentConfiguration {
+            redact: vec![
+                "/calls/0".to_string(),
+                "/calls/2".to_string(),
+                "/calls/1/secret".to_string(),
+            ],
+            ..EnrichmentConfiguration::default()
+        };
+
+        let batch = json!([{"calls": [0, {"secret": 1, "value": 1}, 2, 3]}]);
+        let batch = enrichment.apply_to_batch(&batch, false);
+        assert_eq!(batch.as_ref(), &json!([{"calls": [{"value": 1}, 3]}]));
+    }
+
+    #[test]
+    fn test_unchanged_batch_is_not_copied() {
+        let batch = json!([{"value": 1}]);
+        let batch = new_enrichment().apply_to_batch(&batch, false);
+        assert!(matches!(batch, Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_add_fields() {
+        let enrichment = new_enrichment();
+
+        let batch = json!([{"secret": 1, "env": "prod"}]);
+        let batch = enrichment.apply_to_batch(&batch, true);
+        assert_eq!(batch.as_ref(), &json!([{"env": "staging"}]));
+
+        let mut body = json!({"data": {"batch": []}});
+        enrichment.add_fields(&mut body);
+        assert_eq!(body, json!({"data": {"batch": []}, "env": "staging"}));
+    }
+}
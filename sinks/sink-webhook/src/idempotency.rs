@@ -0,0 +1,90 @@
+//! Let endpoints recognize requests they already received.
+//!
+//! The key is derived from the cursors and finality of the batch, and the
+//! position of the request in the batch, so a request sent again (after a
+//! retry or a restart) has the same key, and different requests have
+//! different keys.
+//!
+//! Pending data is sent again every time the pending block changes, with the
+//! same key, so endpoints should only drop accepted and finalized data with a
+//! known key.
+
+use apibara_core::node::v1alpha2::Cursor;
+use apibara_sink_common::Context;
+use http::HeaderValue;
+use sha2::{Digest, Sha256};
+
+/// Default name of the header containing the idempotency key.
+pub const DEFAULT_IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// Returns the idempotency key of the request at `index` in the batch.
+///
+/// `index` is `None` if the batch is sent in a single request.
+pub fn idempotency_key(ctx: &Context, index: Option<usize>) -> HeaderValue {
+    let mut hasher = Sha256::new();
+    update_cursor(&mut hasher, ctx.cursor.as_ref());
+    update_cursor(&mut hasher, Some(&ctx.end_cursor));
+    hasher.update((ctx.finality as i32).to_be_bytes());
+    match index {
+        None => hasher.update([0]),
+        Some(index) => {
+            hasher.update([1]);
+            hasher.update((index as u64).to_be_bytes());
+        }
+    }
+
+    HeaderValue::from_str(&hex::encode(hasher.finalize())).expect("hex is a valid header value")
+}
+
+fn update_cursor(hasher: &mut Sha256, cursor: Option<&Cursor>) {
+    let Some(cursor) = cursor else {
+        hasher.update([0]);
+        return;
+    };
+    hasher.update([1]);
+    hasher.update(cursor.order_key.to_be_bytes());
+    hasher.update((cursor.unique_key.len() as u64).to_be_bytes());
+    hasher.update(&cursor.unique_key);
+}
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::node::v1alpha2::{Cursor, DataFinality};
+    use apibara_sink_common::Context;
+
+    use super::idempotency_key;
+
+    fn new_context(order_key: u64, unique_key: u8, finality: DataFinality) -> Context {
+        Context {
+            cursor: None,
+            end_cursor: Cursor {
+                order_key,
+                unique_key: vec![unique_key],
+            },
+            finality,
+        }
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let ctx = new_context(10, 1, DataFinality::DataStatusAccepted);
+        let key = idempotency_key(&ctx, None);
+        assert_eq!(key, idempotency_key(&ctx, None));
+        assert_eq!(key.len(), 64);
+
+        // different requests in the same batch.
+        assert_ne!(key, idempotency_key(&ctx, Some(0)));
+        assert_ne!(
+            idempotency_key(&ctx, Some(0)),
+            idempotency_key(&ctx, Some(1))
+        );
+
+        // same block after a reorg, finalized, or the next block.
+        let reorg = new_context(10, 2, DataFinality::DataStatusAccepted);
+        assert_ne!(key, idempotency_key(&reorg, None));
+        let finalized = new_context(10, 1, DataFinality::DataStatusFinalized);
+        assert_ne!(key, idempotency_key(&finalized, None));
+        let next = new_context(11, 1, DataFinality::DataStatusAccepted);
+        assert_ne!(key, idempotency_key(&next, None));
+    }
+}
@@ -1,5 +1,37 @@
+mod auth;
+mod circuit_breaker;
 mod configuration;
+mod context_headers;
+mod enrich;
+mod idempotency;
+mod metrics;
+mod oauth;
+mod retry;
+mod sample;
+mod signature;
 mod sink;
+mod spool;
+mod tls;
 
-pub use self::configuration::{SinkWebhookConfiguration, SinkWebhookOptions};
+pub use self::auth::{BasicAuthConfiguration, QueryApiKeyConfiguration};
+pub use self::circuit_breaker::{
+    CircuitBreakerConfiguration, CircuitProbe, DEFAULT_CIRCUIT_COOLDOWN,
+};
+pub use self::configuration::{
+    BodyFormat, Compression, Envelope, FanOutMode, InvalidateFailureMode, RawFailureMode,
+    SinkWebhookConfiguration, SinkWebhookOptions, StatusCodeMatcher, TargetCredentials,
+    WebhookTarget, DEFAULT_CONNECT_TIMEOUT, DEFAULT_MAX_RESPONSE_BODY_BYTES,
+    DEFAULT_REQUEST_ID_HEADER, DEFAULT_REQUEST_TIMEOUT, DEFAULT_USER_AGENT,
+};
+pub use self::context_headers::{ContextHeaders, DEFAULT_CONTEXT_HEADER_PREFIX};
+pub use self::enrich::EnrichmentConfiguration;
+pub use self::idempotency::DEFAULT_IDEMPOTENCY_KEY_HEADER;
+pub use self::oauth::OAuthConfiguration;
+pub use self::retry::{
+    RetryConfiguration, DEFAULT_RETRY_BASE_DELAY, DEFAULT_RETRY_JITTER, DEFAULT_RETRY_MAX_DELAY,
+};
+pub use self::sample::{PayloadSampleConfiguration, DEFAULT_SAMPLE_MAX_FILE_BYTES};
+pub use self::signature::{SignatureConfiguration, SignatureScheme, DEFAULT_SIGNATURE_HEADER};
 pub use self::sink::WebhookSink;
+pub use self::spool::{SpoolConfiguration, DEFAULT_SPOOL_MAX_BYTES};
+pub use self::tls::TlsConfiguration;
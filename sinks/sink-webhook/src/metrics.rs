@@ -0,0 +1,205 @@
+//! Metrics about the requests sent to the endpoint.
+
+use std::time::Duration;
+
+use apibara_core::node::v1alpha2::DataFinality;
+use apibara_observability::{self as o11y, Counter, Histogram, KeyValue};
+use http::StatusCode;
+
+/// Metrics recorded for every request sent to the endpoint.
+///
+/// In raw mode, every item (or batch, with raw batch) is a request.
+/// Metrics are labeled with the finality of the data sent, and `unknown` for
+/// invalidate requests and requests sent from the spool.
+#[derive(Clone)]
+pub struct WebhookMetrics {
+    requests: Counter<u64>,
+    requests_succeeded: Counter<u64>,
+    requests_failed: Counter<u64>,
+    request_retries: Counter<u64>,
+    request_duration: Histogram<f64>,
+    circuit_opened: Counter<u64>,
+    circuit_closed: Counter<u64>,
+}
+
+impl WebhookMetrics {
+    pub fn new() -> Self {
+        let meter = o11y::meter("sink_webhook");
+        WebhookMetrics {
+            requests: meter
+                .u64_counter("webhook_requests")
+                .with_description("Requests sent to the endpoint")
+                .init(),
+            requests_succeeded: meter
+                .u64_counter("webhook_requests_succeeded")
+                .with_description("Requests that returned a success status")
+                .init(),
+            requests_failed: meter
+                .u64_counter("webhook_requests_failed")
+                .with_description("Requests that failed, by status class")
+                .init(),
+            request_retries: meter
+                .u64_counter("webhook_request_retries")
+                .with_description("Requests sent again after a failure")
+                .init(),
+            request_duration: meter
+                .f64_histogram("webhook_request_duration")
+                .with_description("Time to send a request and receive the response, in seconds")
+                .init(),
+            circuit_opened: meter
+                .u64_counter("webhook_circuit_opened")
+                .with_description("Times the circuit of a target opened")
+                .init(),
+            circuit_closed: meter
+                .u64_counter("webhook_circuit_closed")
+                .with_description("Times the circuit of a target closed after a probe")
+                .init(),
+        }
+    }
+
+    /// Records a request, with the response status or `None` if no response was received.
+    pub fn record_request(
+        &self,
+        finality: DataFinality,
+        status: Option<StatusCode>,
+        elapsed: Duration,
+    ) {
+        let cx = o11y::Context::current();
+        let finality = finality_attribute(finality);
+        self.requests.add(&cx, 1, &[finality.clone()]);
+        self.request_duration
+            .record(&cx, elapsed.as_secs_f64(), &[finality.clone()]);
+
+        match status {
+            Some(status) if status.is_success() => {
+                self.requests_succeeded.add(&cx, 1, &[finality]);
+            }
+            _ => {
+                let status_class = KeyValue::new("status_class", status_class(status));
+                self.requests_failed.add(&cx, 1, &[finality, status_class]);
+            }
+        }
+    }
+
+    /// Records a request that is sent again after a failure.
+    pub fn record_retry(&self, finality: DataFinality) {
+        self.request_retries.add(
+            &o11y::Context::current(),
+            1,
+            &[finality_attribute(finality)],
+        );
+    }
+
+    /// Records that the circuit of the target at the given index opened.
+    pub fn record_circuit_opened(&self, target: usize) {
+        self.circuit_opened
+            .add(&o11y::Context::current(), 1, &[target_attribute(target)]);
+    }
+
+    /// Records that the circuit of the target at the given index closed.
+    pub fn record_circuit_closed(&self, target: usize) {
+        self.circuit_closed
+            .add(&o11y::Context::current(), 1, &[target_attribute(target)]);
+    }
+}
+
+impl Default for WebhookMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn finality_attribute(finality: DataFinality) -> KeyValue {
+    let finality = match finality {
+        DataFinality::DataStatusUnknown => "unknown",
+        DataFinality::DataStatusPending => "pending",
+        DataFinality::DataStatusAccepted => "accepted",
+        DataFinality::DataStatusFinalized => "finalized",
+    };
+    KeyValue::new("finality", finality)
+}
+
+fn target_attribute(target: usize) -> KeyValue {
+    KeyValue::new("target", target as i64)
+}
+
+/// Returns the class of the status, for example `5xx`, or `error` if there's no status.
+fn status_class(status: Option<StatusCode>) -> &'static str {
+    match status.map(|status| status.as_u16() / 100) {
+        Some(1) => "1xx",
+        Some(2) => "2xx",
+        Some(3) => "3xx",
+        Some(4) => "4xx",
+        Some(5) => "5xx",
+        _ => "error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apibara_core::node::v1alpha2::DataFinality;
+    use apibara_observability::KeyValue;
+    use http::StatusCode;
+
+    use super::{finality_attribute, status_class, target_attribute, WebhookMetrics};
+
+    #[test]
+    fn test_status_class() {
+        assert_eq!(status_class(Some(StatusCode::CONTINUE)), "1xx");
+        assert_eq!(status_class(Some(StatusCode::OK)), "2xx");
+        assert_eq!(status_class(Some(StatusCode::NO_CONTENT)), "2xx");
+        assert_eq!(status_class(Some(StatusCode::MOVED_PERMANENTLY)), "3xx");
+        assert_eq!(status_class(Some(StatusCode::NOT_FOUND)), "4xx");
+        assert_eq!(status_class(Some(StatusCode::TOO_MANY_REQUESTS)), "4xx");
+        assert_eq!(status_class(Some(StatusCode::BAD_GATEWAY)), "5xx");
+        assert_eq!(status_class(None), "error");
+    }
+
+    #[test]
+    fn test_finality_attribute() {
+        assert_eq!(
+            finality_attribute(DataFinality::DataStatusUnknown),
+            KeyValue::new("finality", "unknown")
+        );
+        assert_eq!(
+            finality_attribute(DataFinality::DataStatusPending),
+            KeyValue::new("finality", "pending")
+        );
+        assert_eq!(
+            finality_attribute(DataFinality::DataStatusAccepted),
+            KeyValue::new("finality", "accepted")
+        );
+        assert_eq!(
+            finality_attribute(DataFinality::DataStatusFinalized),
+            KeyValue::new("finality", "finalized")
+        );
+    }
+
+    #[test]
+    fn test_target_attribute() {
+        assert_eq!(target_attribute(2), KeyValue::new("target", 2i64));
+    }
+
+    #[test]
+    fn test_record_without_meter_provider() {
+        // without a meter provider, recording is a no-op.
+        let metrics = WebhookMetrics::default();
+        let elapsed = Duration::from_millis(10);
+        metrics.record_request(
+            DataFinality::DataStatusAccepted,
+            Some(StatusCode::OK),
+            elapsed,
+        );
+        metrics.record_request(
+            DataFinality::DataStatusFinalized,
+            Some(StatusCode::SERVICE_UNAVAILABLE),
+            elapsed,
+        );
+        metrics.record_request(DataFinality::DataStatusUnknown, None, elapsed);
+        metrics.record_retry(DataFinality::DataStatusPending);
+        metrics.record_circuit_opened(0);
+        metrics.record_circuit_closed(0);
+    }
+}
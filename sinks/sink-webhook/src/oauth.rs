@@ -0,0 +1,157 @@
+//! Authenticate requests with OAuth2 access tokens.
+//!
+//! Tokens are requested with the client credentials grant, cached, and
+//! refreshed shortly before they expire. If the endpoint rejects a token
+//! before then, the sink drops it and requests a new one.
+
+use std::{fmt, time::Duration};
+
+use apibara_sink_common::{SinkError, SinkErrorResultExt};
+use error_stack::Result;
+use http::HeaderValue;
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::{sync::Mutex, time::Instant};
+use tracing::debug;
+
+/// Refresh tokens this long before they expire.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct OAuthConfiguration {
+    /// Url of the token endpoint.
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Space-separated scopes requested with the token.
+    pub scope: Option<String>,
+}
+
+/// Requests access tokens and caches them until they expire.
+pub struct TokenProvider {
+    config: OAuthConfiguration,
+    token: Mutex<Option<CachedToken>>,
+}
+
+struct CachedToken {
+    authorization: HeaderValue,
+    /// `None` if the token endpoint didn't say when the token expires.
+    refresh_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl TokenProvider {
+    pub fn new(config: OAuthConfiguration) -> Self {
+        TokenProvider {
+            config,
+            token: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> &OAuthConfiguration {
+        &self.config
+    }
+
+    /// Returns the value of the `Authorization` header, requesting a new token if needed.
+    pub async fn authorization(&self, client: &Client) -> Result<HeaderValue, SinkError> {
+        // hold the lock while requesting a token, so that concurrent requests share it.
+        let mut token = self.token.lock().await;
+
+        if let Some(cached) = token.as_ref() {
+            let is_fresh = cached
+                .refresh_at
+                .map(|refresh_at| Instant::now() < refresh_at)
+                .unwrap_or(true);
+            if is_fresh {
+                return Ok(cached.authorization.clone());
+            }
+        }
+
+        let new_token = self.request_token(client).await?;
+        let authorization = new_token.authorization.clone();
+        *token = Some(new_token);
+        Ok(authorization)
+    }
+
+    /// Refreshes the cached token if it's about to expire, so that requests
+    /// don't wait for a new token.
+    ///
+    /// Does nothing if no token was requested yet.
+    pub async fn refresh(&self, client: &Client) -> Result<(), SinkError> {
+        let has_token = self.token.lock().await.is_some();
+        if has_token {
+            self.authorization(client).await?;
+        }
+        Ok(())
+    }
+
+    /// Drops the cached token, for example after the endpoint rejected it.
+    pub async fn invalidate(&self) {
+        self.token.lock().await.take();
+    }
+
+    async fn request_token(&self, client: &Client) -> Result<CachedToken, SinkError> {
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", self.config.client_id.as_str()),
+            ("client_secret", self.config.client_secret.as_str()),
+        ];
+        if let Some(scope) = &self.config.scope {
+            form.push(("scope", scope.as_str()));
+        }
+
+        let response = client
+            .post(&self.config.token_url)
+            .form(&form)
+            .send()
+            .await
+            .temporary("failed to request access token")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let message = format!("token endpoint returned status {}", status);
+            return if status.is_server_error() {
+                Err(SinkError::temporary(&message))
+            } else {
+                Err(SinkError::configuration(&message))
+            };
+        }
+
+        let response = response
+            .json::<TokenResponse>()
+            .await
+            .temporary("failed to read access token response")?;
+
+        let mut authorization = HeaderValue::from_str(&format!("Bearer {}", response.access_token))
+            .configuration("malformed access token")?;
+        authorization.set_sensitive(true);
+
+        let refresh_at = response.expires_in.map(|expires_in| {
+            let lifetime = Duration::from_secs(expires_in);
+            Instant::now() + lifetime.saturating_sub(TOKEN_REFRESH_MARGIN)
+        });
+
+        debug!(expires_in = ?response.expires_in, "received new access token");
+
+        Ok(CachedToken {
+            authorization,
+            refresh_at,
+        })
+    }
+}
+
+impl fmt::Debug for OAuthConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OAuthConfiguration")
+            .field("token_url", &self.token_url)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .field("scope", &self.scope)
+            .finish()
+    }
+}
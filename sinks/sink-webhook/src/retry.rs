@@ -0,0 +1,120 @@
+//! Retry failed requests with exponential backoff.
+
+use std::time::Duration;
+
+use exponential_backoff::Backoff;
+use http::{header::RETRY_AFTER, HeaderMap};
+
+/// Default delay before the first retry.
+pub const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Default maximum delay between retries.
+pub const DEFAULT_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Default fraction of each delay that is randomized.
+pub const DEFAULT_RETRY_JITTER: f32 = 0.3;
+
+/// Longest delay requested by an endpoint with `Retry-After` that is honored.
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+pub struct RetryConfiguration {
+    /// Maximum number of attempts for each request, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled on every retry.
+    pub base_delay: Duration,
+    /// Maximum delay between retries.
+    pub max_delay: Duration,
+    /// Fraction of each delay that is randomized, at least 0 and less than 1.
+    pub jitter: f32,
+}
+
+impl Default for RetryConfiguration {
+    fn default() -> Self {
+        RetryConfiguration {
+            max_attempts: 1,
+            base_delay: DEFAULT_RETRY_BASE_DELAY,
+            max_delay: DEFAULT_RETRY_MAX_DELAY,
+            jitter: DEFAULT_RETRY_JITTER,
+        }
+    }
+}
+
+impl RetryConfiguration {
+    /// Returns the same configuration, with `max_retries` retries after the first attempt.
+    pub fn with_max_retries(&self, max_retries: usize) -> Self {
+        RetryConfiguration {
+            max_attempts: u32::try_from(max_retries)
+                .unwrap_or(u32::MAX)
+                .saturating_add(1),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the delays between the attempts of a single request.
+    pub fn backoff(&self) -> Backoff {
+        let retries = self.max_attempts.saturating_sub(1);
+        let mut backoff = Backoff::new(retries, self.base_delay, Some(self.max_delay));
+        backoff.set_factor(2);
+        // the backoff panics with a jitter of 0, which means no jitter anyway.
+        if self.jitter > 0.0 {
+            backoff.set_jitter(self.jitter);
+        }
+        backoff
+    }
+}
+
+/// Returns the delay requested by the endpoint with the `Retry-After` header.
+///
+/// Only delays in seconds are supported, up to [MAX_RETRY_AFTER].
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds).min(MAX_RETRY_AFTER))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::{header::RETRY_AFTER, HeaderMap, HeaderValue};
+
+    use super::{retry_after, RetryConfiguration, MAX_RETRY_AFTER};
+
+    #[test]
+    fn test_backoff_delays() {
+        let config = RetryConfiguration {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            jitter: 0.0,
+        };
+
+        let backoff = config.backoff();
+        let delays = (&backoff).into_iter().take(4).collect::<Vec<_>>();
+        assert!(!delays.is_empty());
+        assert!(delays.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(delays.iter().all(|delay| *delay <= config.max_delay));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let with_value = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, HeaderValue::from_static(value));
+            retry_after(&headers)
+        };
+
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+        assert_eq!(with_value("3"), Some(Duration::from_secs(3)));
+        assert_eq!(with_value("86400"), Some(MAX_RETRY_AFTER));
+        // dates are not supported.
+        assert_eq!(with_value("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(with_value("-1"), None);
+    }
+}
@@ -0,0 +1,183 @@
+//! Write a sample of the delivered payloads to a local file.
+
+use std::path::PathBuf;
+
+use apibara_sink_common::{SinkError, SinkErrorResultExt};
+use error_stack::Result;
+use serde::Serialize;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use tracing::{debug, warn};
+
+/// Default maximum size of the sample file, 10 MiB.
+pub const DEFAULT_SAMPLE_MAX_FILE_BYTES: u64 = 10 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct PayloadSampleConfiguration {
+    /// Where samples are written to, one JSON payload per line.
+    pub path: PathBuf,
+    /// Fraction of the payloads written to the file, between 0 and 1.
+    pub rate: f64,
+    /// Rotate the file once it grows larger than this size.
+    pub max_file_bytes: u64,
+}
+
+/// Writes a fraction of the payloads to a local file.
+///
+/// Payloads are sampled at a fixed rate, so that with `rate = 0.1` every
+/// tenth payload is written. When the file grows past the maximum size, it's
+/// renamed to `<path>.1`, replacing any previous rotated file.
+pub struct PayloadSampler {
+    config: PayloadSampleConfiguration,
+    state: Mutex<SampleFile>,
+}
+
+#[derive(Default)]
+struct SampleFile {
+    file: Option<File>,
+    file_size: u64,
+    credit: f64,
+}
+
+impl PayloadSampler {
+    pub fn new(config: PayloadSampleConfiguration) -> Self {
+        PayloadSampler {
+            config,
+            state: Mutex::new(SampleFile::default()),
+        }
+    }
+
+    /// Returns the fraction of payloads written to the file.
+    pub fn rate(&self) -> f64 {
+        self.config.rate
+    }
+
+    /// Writes the payload to the sample file, if it's selected by sampling.
+    ///
+    /// Errors are logged and ignored, since sampling is only used for debugging.
+    pub async fn sample<P: Serialize + ?Sized>(&self, payload: &P) {
+        let mut state = self.state.lock().await;
+        state.credit += self.config.rate;
+        if state.credit < 1.0 {
+            return;
+        }
+        state.credit -= 1.0;
+
+        if let Err(err) = self.write(&mut state, payload).await {
+            warn!(err = ?err, path = ?self.config.path, "failed to write payload sample");
+        }
+    }
+
+    async fn write<P: Serialize + ?Sized>(
+        &self,
+        state: &mut SampleFile,
+        payload: &P,
+    ) -> Result<(), SinkError> {
+        let mut line = serde_json::to_vec(payload).runtime_error("failed to serialize sample")?;
+        line.push(b'\n');
+
+        if state.file_size > 0 && state.file_size + line.len() as u64 > self.config.max_file_bytes {
+            self.rotate(state).await?;
+        }
+
+        let file = match state.file {
+            Some(ref mut file) => file,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.config.path)
+                    .await
+                    .runtime_error("failed to open sample file")?;
+                state.file_size = file
+                    .metadata()
+                    .await
+                    .runtime_error("failed to read sample file metadata")?
+                    .len();
+                state.file.insert(file)
+            }
+        };
+
+        file.write_all(&line)
+            .await
+            .runtime_error("failed to write sample")?;
+        // tokio writes in the background, wait for the line to reach the file.
+        file.flush().await.runtime_error("failed to write sample")?;
+        state.file_size += line.len() as u64;
+
+        Ok(())
+    }
+
+    async fn rotate(&self, state: &mut SampleFile) -> Result<(), SinkError> {
+        state.file = None;
+        state.file_size = 0;
+
+        let mut rotated = self.config.path.clone().into_os_string();
+        rotated.push(".1");
+        debug!(path = ?self.config.path, "rotating sample file");
+        fs::rename(&self.config.path, rotated)
+            .await
+            .runtime_error("failed to rotate sample file")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use super::{PayloadSampleConfiguration, PayloadSampler};
+
+    fn read_lines(path: &std::path::Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_sample_rate() {
+        let dir = tempdir::TempDir::new("webhook-sample").unwrap();
+        let path = dir.path().join("samples.jsonl");
+        let sampler = PayloadSampler::new(PayloadSampleConfiguration {
+            path: path.clone(),
+            rate: 0.25,
+            max_file_bytes: 1024,
+        });
+
+        for i in 0..8 {
+            sampler.sample(&json!({ "i": i })).await;
+        }
+
+        assert_eq!(read_lines(&path), vec![r#"{"i":3}"#, r#"{"i":7}"#]);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_file() {
+        let dir = tempdir::TempDir::new("webhook-sample").unwrap();
+        let path = dir.path().join("samples.jsonl");
+        let sampler = PayloadSampler::new(PayloadSampleConfiguration {
+            path: path.clone(),
+            rate: 1.0,
+            max_file_bytes: 16,
+        });
+
+        // each line is 8 bytes, so the file holds two.
+        for i in 0..5 {
+            sampler.sample(&json!({ "i": i })).await;
+        }
+
+        assert_eq!(read_lines(&path), vec![r#"{"i":4}"#]);
+        assert_eq!(
+            read_lines(&dir.path().join("samples.jsonl.1")),
+            vec![r#"{"i":2}"#, r#"{"i":3}"#]
+        );
+    }
+}
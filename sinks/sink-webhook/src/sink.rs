@@ -1,57 +1,1424 @@
-use apibara_core::node::v1alpha2::Cursor;
-use apibara_sink_common::{Context, CursorAction, Sink};
-use apibara_sink_common::{SinkError, SinkErrorResultExt};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    fmt, io,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use apibara_core::node::v1alpha2::{Cursor, DataFinality};
+use apibara_sink_common::{
+    ConcurrentSink, Context, CursorAction, DeliveredBatch, InvalidatedRange, Sink, SinkMetadata,
+};
+use apibara_sink_common::{RetryAfter, SinkError, SinkErrorResultExt};
 use async_trait::async_trait;
 use error_stack::Result;
-use http::HeaderMap;
-use reqwest::Client;
-use serde::ser::Serialize;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use futures::{future, stream, Future, StreamExt, TryStreamExt};
+use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode, Uri};
+use reqwest::{Client, Method, RequestBuilder};
+use serde::{ser::Serialize, Deserialize};
 use serde_json::{json, Value};
-use tracing::{debug, instrument, warn};
+use tracing::{debug, info, instrument, warn};
+use uuid::Uuid;
 
-use crate::{configuration::SinkWebhookOptions, SinkWebhookConfiguration};
+use crate::{
+    circuit_breaker::{CircuitBreaker, CircuitCheck},
+    configuration::SinkWebhookOptions,
+    context_headers::ContextHeaders,
+    idempotency::idempotency_key,
+    metrics::WebhookMetrics,
+    oauth::TokenProvider,
+    retry::retry_after,
+    sample::PayloadSampler,
+    spool::Spool,
+    BasicAuthConfiguration, BodyFormat, CircuitProbe, Compression, EnrichmentConfiguration,
+    Envelope, FanOutMode, InvalidateFailureMode, QueryApiKeyConfiguration, RawFailureMode,
+    RetryConfiguration, SignatureConfiguration, SinkWebhookConfiguration, StatusCodeMatcher,
+    TargetCredentials,
+};
 
 pub struct WebhookSink {
     client: Client,
-    target_url: String,
-    headers: HeaderMap,
+    /// The target url first, then the fan-out targets.
+    targets: Vec<Target>,
+    fan_out_mode: FanOutMode,
     raw: bool,
+    raw_failure_mode: RawFailureMode,
+    /// Retries of raw items that failed, with the `RetryItem` mode.
+    raw_item_retry: RetryConfiguration,
+    raw_concurrency: usize,
+    max_in_flight_batches: usize,
+    raw_invalidate: bool,
+    invalidate_failure_mode: InvalidateFailureMode,
+    /// Retries of invalidate requests that failed, with the `RetryThenContinue` mode.
+    invalidate_retry: RetryConfiguration,
+    raw_batch: bool,
+    context_headers: ContextHeaders,
+    compression: Option<Compression>,
+    compression_threshold_bytes: usize,
+    max_body_bytes: Option<usize>,
+    split_oversized_batches: bool,
+    sampler: Option<PayloadSampler>,
+    body_format: BodyFormat,
+    envelope: Envelope,
+    skip_empty: bool,
+    pretty_json: bool,
+    enrichment: Option<EnrichmentConfiguration>,
+    spool: Option<Spool>,
+    retry: Option<RetryConfiguration>,
+    retryable_status: Vec<StatusCodeMatcher>,
+    skip_status: Vec<StatusCodeMatcher>,
+    require_ack: bool,
+    idempotency_key_header: Option<HeaderName>,
+    request_id_header: Option<HeaderName>,
+    dead_letter_url: Option<String>,
+    preflight_method: Option<Method>,
+    max_response_body_bytes: usize,
+    /// End cursor of the last finalized batch the endpoint accepted.
+    ///
+    /// Delivery stays at-least-once: a batch delivered just before the sink
+    /// stops, but not yet persisted, is sent again. Use an idempotency key to
+    /// detect these duplicates.
+    delivered_cursor: Option<Cursor>,
+    /// Deliveries of batches that failed, kept until the batch is sent again.
+    failed_deliveries: Mutex<HashMap<DeliveryKey, Delivery>>,
+    metrics: WebhookMetrics,
+}
+
+/// An endpoint that receives the requests.
+struct Target {
+    url: String,
+    method: Method,
+    headers: HeaderMap,
+    credentials: Credentials,
+    /// Each target has its own circuit, so that a failing target doesn't
+    /// stop requests to the other targets.
+    circuit_breaker: Option<Mutex<CircuitBreaker>>,
+}
+
+/// Credentials sent to a single target.
+struct Credentials {
+    token_provider: Option<TokenProvider>,
+    basic_auth: Option<BasicAuthConfiguration>,
+    query_api_key: Option<QueryApiKeyConfiguration>,
+    signature: Option<SignatureConfiguration>,
+}
+
+impl Credentials {
+    fn new(credentials: TargetCredentials) -> Self {
+        Credentials {
+            token_provider: credentials.oauth.map(TokenProvider::new),
+            basic_auth: credentials.basic_auth,
+            query_api_key: credentials.query_api_key,
+            signature: credentials.signature,
+        }
+    }
+
+    /// Adds the Basic auth credentials and the query API key, if configured.
+    fn with_static_auth(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(basic_auth) = &self.basic_auth {
+            request = basic_auth.apply(request);
+        }
+        if let Some(query_api_key) = &self.query_api_key {
+            request = query_api_key.apply(request);
+        }
+        request
+    }
+
+    /// Removes the url from request errors if it contains the API key, so that it's not logged.
+    fn redact_url(&self, err: reqwest::Error) -> reqwest::Error {
+        if self.query_api_key.is_some() {
+            err.without_url()
+        } else {
+            err
+        }
+    }
 }
 
 impl WebhookSink {
     pub fn new(config: SinkWebhookConfiguration) -> Self {
-        Self {
-            client: Client::new(),
-            target_url: config.target_url.to_string(),
+        // only fails if the TLS backend can't be initialized, like `Client::new`,
+        // or doesn't support the client certificate.
+        let client = config
+            .tls
+            .apply(Client::builder())
+            .timeout(config.request_timeout)
+            .connect_timeout(config.connect_timeout)
+            .user_agent(config.user_agent.clone())
+            .build()
+            .expect("failed to build http client");
+
+        let circuit_breaker = |index: usize| {
+            config
+                .circuit_breaker
+                .clone()
+                .map(|breaker| Mutex::new(CircuitBreaker::new(breaker).with_target(index)))
+        };
+        let target = Target {
+            url: config.target_url.to_string(),
+            method: config.method,
             headers: config.headers,
+            credentials: Credentials::new(TargetCredentials {
+                signature: config.signature,
+                oauth: config.oauth,
+                basic_auth: config.basic_auth,
+                query_api_key: config.query_api_key,
+            }),
+            circuit_breaker: circuit_breaker(0),
+        };
+        let fan_out_targets =
+            config
+                .fan_out_targets
+                .into_iter()
+                .enumerate()
+                .map(|(index, target)| Target {
+                    url: target.url.to_string(),
+                    method: target.method,
+                    headers: target.headers,
+                    credentials: Credentials::new(target.credentials),
+                    circuit_breaker: circuit_breaker(index + 1),
+                });
+
+        Self {
+            client,
+            targets: std::iter::once(target).chain(fan_out_targets).collect(),
+            fan_out_mode: config.fan_out_mode,
             raw: config.raw,
+            raw_failure_mode: config.raw_failure_mode,
+            raw_item_retry: config
+                .retry
+                .clone()
+                .unwrap_or_default()
+                .with_max_retries(config.raw_item_max_retries),
+            raw_concurrency: config.raw_concurrency,
+            max_in_flight_batches: config.max_in_flight_batches,
+            raw_invalidate: config.raw_invalidate,
+            invalidate_failure_mode: config.invalidate_failure_mode,
+            invalidate_retry: config
+                .retry
+                .clone()
+                .unwrap_or_default()
+                .with_max_retries(config.invalidate_max_retries),
+            raw_batch: config.raw_batch,
+            context_headers: config.context_headers,
+            compression: config.compression,
+            compression_threshold_bytes: config.compression_threshold_bytes,
+            max_body_bytes: config.max_body_bytes,
+            split_oversized_batches: config.split_oversized_batches,
+            sampler: config.sample.map(PayloadSampler::new),
+            body_format: config.body_format,
+            envelope: config.envelope,
+            skip_empty: config.skip_empty,
+            pretty_json: config.pretty_json,
+            enrichment: config.enrichment,
+            spool: config.spool.map(Spool::new),
+            retry: config.retry,
+            retryable_status: config.retryable_status,
+            skip_status: config.skip_status,
+            require_ack: config.require_ack,
+            idempotency_key_header: config.idempotency_key_header,
+            request_id_header: config.request_id_header,
+            dead_letter_url: config.dead_letter_url.map(|url| url.to_string()),
+            preflight_method: config.preflight_method,
+            max_response_body_bytes: config.max_response_body_bytes,
+            delivered_cursor: None,
+            failed_deliveries: Mutex::default(),
+            metrics: WebhookMetrics::default(),
         }
     }
 
-    #[instrument(skip(self, body), err(Debug))]
-    async fn send<B: Serialize + ?Sized>(&self, body: &B) -> Result<(), SinkError> {
-        let response = self
+    #[instrument(skip(self, body, headers, delivery), err(Debug))]
+    async fn send<B: Serialize + ?Sized>(
+        &self,
+        body: &B,
+        headers: &HeaderMap,
+        finality: DataFinality,
+        delivery: &Delivery,
+        position: usize,
+    ) -> Result<(), SinkError> {
+        let body = self.serialize(body)?;
+        self.check_body_size(&body)?;
+        self.send_bytes(body, headers, finality, delivery, position)
+            .await
+    }
+
+    /// Returns the delivery of the batch, with the targets that accepted it in a failed attempt.
+    fn start_delivery(&self, ctx: &Context) -> Delivery {
+        let failed = self
+            .failed_deliveries
+            .lock()
+            .expect("failed deliveries poisoned")
+            .remove(&DeliveryKey::new(ctx));
+        failed.unwrap_or_else(|| Delivery::new(self.require_ack.then(|| ctx.end_cursor.clone())))
+    }
+
+    /// Keeps the delivery of a batch that failed, so that retries reuse its
+    /// request ids and the targets that accepted it don't receive it again.
+    fn keep_failed_delivery(&self, ctx: &Context, delivery: Delivery) {
+        self.failed_deliveries
+            .lock()
+            .expect("failed deliveries poisoned")
+            .insert(DeliveryKey::new(ctx), delivery);
+    }
+
+    /// Returns true if the circuit of every target is open, so that no request can be sent.
+    fn all_circuits_open(&self) -> bool {
+        self.targets.iter().all(|target| {
+            target
+                .circuit_breaker
+                .as_ref()
+                .map(|breaker| breaker.lock().expect("circuit breaker poisoned").is_open())
+                .unwrap_or(false)
+        })
+    }
+
+    /// Checks the circuit breaker of the target before sending a request,
+    /// probing the target if the circuit is half-open.
+    async fn check_circuit(&self, index: usize, target: &Target) -> Result<(), SinkError> {
+        let check = match &target.circuit_breaker {
+            None => return Ok(()),
+            Some(breaker) => breaker.lock().expect("circuit breaker poisoned").check(),
+        };
+
+        match check {
+            CircuitCheck::Allow => Ok(()),
+            CircuitCheck::Reject { retry_in } => Err(SinkError::temporary(&format!(
+                "circuit open, target {} is probed again in {:?}",
+                index, retry_in
+            ))),
+            CircuitCheck::Probe(probe) => {
+                let result = self.send_probe(target, &probe).await;
+                self.record_circuit(index, target, &result);
+                result
+            }
+        }
+    }
+
+    /// Records the result of a request in the circuit breaker of the target.
+    fn record_circuit<T>(&self, index: usize, target: &Target, result: &Result<T, SinkError>) {
+        let Some(breaker) = &target.circuit_breaker else {
+            return;
+        };
+
+        let mut breaker = breaker.lock().expect("circuit breaker poisoned");
+        match result {
+            Ok(_) => {
+                if breaker.record_success() {
+                    self.metrics.record_circuit_closed(index);
+                }
+            }
+            Err(err) if is_endpoint_error(err) => {
+                if breaker.record_failure() {
+                    self.metrics.record_circuit_opened(index);
+                }
+            }
+            Err(_) => {}
+        }
+    }
+
+    async fn has_spooled_data(&mut self) -> Result<bool, SinkError> {
+        match self.spool.as_mut() {
+            Some(spool) => Ok(!spool.is_empty().await?),
+            None => Ok(false),
+        }
+    }
+
+    /// Appends the requests for the batch to the spool.
+    async fn spool_data(&mut self, ctx: &Context, batch: &Value) -> Result<(), SinkError> {
+        let bodies = if self.raw && self.raw_batch {
+            let body = self.serialize(batch)?;
+            self.check_body_size(&body)?;
+            vec![body]
+        } else if self.raw {
+            batch
+                .as_array()
+                .map(|items| {
+                    items
+                        .iter()
+                        .map(|item| {
+                            let body = self.serialize(item)?;
+                            self.check_body_size(&body)?;
+                            Ok(body)
+                        })
+                        .collect::<Result<Vec<_>, SinkError>>()
+                })
+                .transpose()?
+                .unwrap_or_default()
+        } else {
+            self.data_bodies(ctx, batch)?
+        };
+
+        self.spool_bodies(&bodies).await
+    }
+
+    async fn spool_bodies(&mut self, bodies: &[Vec<u8>]) -> Result<(), SinkError> {
+        let Some(spool) = self.spool.as_mut() else {
+            return Err(SinkError::runtime_error("spool is not enabled"));
+        };
+        for body in bodies {
+            spool.push(body).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the spooled requests, oldest first, until one of them fails.
+    async fn drain_spool(&mut self) -> Result<(), SinkError> {
+        if !self.has_spooled_data().await? {
+            return Ok(());
+        }
+
+        if self.all_circuits_open() {
+            debug!("endpoint is down, not draining spool");
+            return Ok(());
+        }
+
+        let mut sent = 0;
+        while let Some(body) = self.first_spooled().await? {
+            if let Err(err) = self
+                .send_bytes(
+                    body.clone(),
+                    &HeaderMap::new(),
+                    DataFinality::DataStatusUnknown,
+                    &Delivery::default(),
+                    0,
+                )
+                .await
+            {
+                warn!(err = ?err, sent, "failed to drain spool");
+                return Ok(());
+            }
+
+            if let Some(spool) = self.spool.as_mut() {
+                spool.remove_first(&body).await?;
+            }
+            sent += 1;
+        }
+
+        info!(sent, "drained spool");
+        Ok(())
+    }
+
+    async fn first_spooled(&mut self) -> Result<Option<Vec<u8>>, SinkError> {
+        match self.spool.as_mut() {
+            Some(spool) => spool.first().await,
+            None => Ok(None),
+        }
+    }
+
+    /// Checks that all targets are reachable, if enabled.
+    ///
+    /// Only fails if a target doesn't respond, the response status is ignored.
+    async fn preflight(&self) -> Result<(), SinkError> {
+        let Some(method) = &self.preflight_method else {
+            return Ok(());
+        };
+
+        for (index, target) in self.targets.iter().enumerate() {
+            let request = self
+                .client
+                .request(method.clone(), &target.url)
+                .headers(target.headers.clone());
+            let response = target
+                .credentials
+                .with_static_auth(request)
+                .send()
+                .await
+                .map_err(|err| target.credentials.redact_url(err));
+
+            match response {
+                Ok(response) => {
+                    debug!(target = index, status = %response.status(), "preflight succeeded");
+                }
+                Err(err) if err.is_timeout() => {
+                    return Err(err).configuration("preflight request to target url timed out");
+                }
+                Err(err) if err.is_connect() => {
+                    return Err(err).configuration("preflight failed to connect to target url");
+                }
+                Err(err) => {
+                    return Err(err).configuration("preflight request to target url failed");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_probe(&self, target: &Target, probe: &CircuitProbe) -> Result<(), SinkError> {
+        let CircuitProbe::Lightweight { method, path } = probe else {
+            return Ok(());
+        };
+
+        let mut url = reqwest::Url::parse(&target.url).runtime_error("malformed target url")?;
+        if let Some(path) = path {
+            url.set_path(path);
+        }
+
+        let request = self
             .client
-            .post(&self.target_url)
-            .headers(self.headers.clone())
-            .json(body)
+            .request(method.clone(), url)
+            .headers(target.headers.clone());
+        let response = target
+            .credentials
+            .with_static_auth(request)
             .send()
             .await
-            .runtime_error("failed to POST json data")?;
+            .map_err(|err| target.credentials.redact_url(err))
+            .temporary("failed to send circuit probe")?;
+
+        let status = response.status();
+        if status.is_server_error() {
+            return Err(SinkError::temporary(&format!(
+                "circuit probe failed with status {}",
+                status
+            )));
+        }
+
+        debug!(status = %status, "circuit probe succeeded");
+        Ok(())
+    }
+
+    /// Sends the batch, either as a single request or an item at a time in raw mode.
+    async fn deliver_data(
+        &self,
+        ctx: &Context,
+        batch: &Value,
+        delivery: &Delivery,
+    ) -> Result<(), SinkError> {
+        if self.raw && self.raw_batch {
+            // Send the items returned by the transform script in a single request
+            let mut headers = self.context_headers.batch(ctx);
+            self.add_idempotency_key(&mut headers, ctx, None);
+            self.send(batch, &headers, ctx.finality, delivery, 0)
+                .await?;
+            self.sample(batch).await;
+        } else if self.raw {
+            // Send each item returned by the transform script as a separate request
+            let Some(batch) = batch.as_array() else {
+                warn!("raw mode: batch is not an array");
+                return Ok(());
+            };
+
+            if self.raw_concurrency > 1 {
+                return self.deliver_raw_concurrently(ctx, batch, delivery).await;
+            }
+
+            for (index, item) in batch.iter().enumerate() {
+                if self.send_raw_item(ctx, index, item, delivery).await? {
+                    self.sample(item).await;
+                }
+            }
+        } else {
+            self.send_data(ctx, batch, delivery).await?;
+            if self.sampler.is_some() {
+                self.sample(&self.envelope_body(ctx, batch)).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the raw items with up to `raw_concurrency` requests in flight.
+    ///
+    /// Items can be received in any order. Stops at the first item that fails.
+    async fn deliver_raw_concurrently(
+        &self,
+        ctx: &Context,
+        batch: &[Value],
+        delivery: &Delivery,
+    ) -> Result<(), SinkError> {
+        // create the futures upfront, a closure in the stream makes the
+        // future of `handle_data` not `Send`.
+        let requests = batch
+            .iter()
+            .enumerate()
+            .map(|(index, item)| async move {
+                let sent = self.send_raw_item(ctx, index, item, delivery).await?;
+                Ok::<_, error_stack::Report<SinkError>>(sent.then_some(item))
+            })
+            .collect::<Vec<_>>();
+        let sent = stream::iter(requests)
+            .buffer_unordered(self.raw_concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        for item in sent.into_iter().flatten() {
+            self.sample(item).await;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the payload to the sample file, if sampling is enabled.
+    async fn sample<P: Serialize + ?Sized>(&self, payload: &P) {
+        if let Some(sampler) = &self.sampler {
+            sampler.sample(payload).await;
+        }
+    }
+
+    /// Sends a single raw item, handling failures according to the raw failure mode.
+    ///
+    /// Returns whether the item was sent.
+    async fn send_raw_item(
+        &self,
+        ctx: &Context,
+        index: usize,
+        item: &Value,
+        delivery: &Delivery,
+    ) -> Result<bool, SinkError> {
+        let mut headers = self.context_headers.item(ctx, index);
+        self.add_idempotency_key(&mut headers, ctx, Some(index));
+        let err = match self
+            .send(item, &headers, ctx.finality, delivery, index)
+            .await
+        {
+            Ok(_) => return Ok(true),
+            Err(err) => err,
+        };
+
+        match self.raw_failure_mode {
+            RawFailureMode::FailBatch => Err(err),
+            RawFailureMode::SkipItem => {
+                warn!(err = ?err, "raw mode: skipping item that failed to send");
+                Ok(false)
+            }
+            RawFailureMode::RetryItem => {
+                self.retry_failed_request(&self.raw_item_retry, ctx.finality, err, || {
+                    self.send(item, &headers, ctx.finality, delivery, index)
+                })
+                .await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Handles a failure to send an invalidate request according to the invalidate failure mode.
+    async fn handle_invalidate_failure(
+        &self,
+        body: Vec<u8>,
+        err: error_stack::Report<SinkError>,
+        delivery: &Delivery,
+    ) -> Result<(), SinkError> {
+        let err = match self.invalidate_failure_mode {
+            InvalidateFailureMode::Halt => return Err(err),
+            InvalidateFailureMode::Continue => err,
+            InvalidateFailureMode::RetryThenContinue => {
+                let headers = HeaderMap::new();
+                let finality = DataFinality::DataStatusUnknown;
+                match self
+                    .retry_failed_request(&self.invalidate_retry, finality, err, || {
+                        self.send_bytes(body.clone(), &headers, finality, delivery, 0)
+                    })
+                    .await
+                {
+                    Ok(_) => return Ok(()),
+                    Err(err) => err,
+                }
+            }
+        };
+
+        warn!(err = ?err, "failed to send invalidate, continuing");
+        Ok(())
+    }
+
+    /// Sends the batch, splitting it into multiple requests if it's too large and
+    /// splitting is enabled.
+    async fn send_data(
+        &self,
+        ctx: &Context,
+        batch: &Value,
+        delivery: &Delivery,
+    ) -> Result<(), SinkError> {
+        let bodies = self.data_bodies(ctx, batch)?;
+        let is_split = bodies.len() > 1;
+        for (index, body) in bodies.into_iter().enumerate() {
+            let mut headers = HeaderMap::new();
+            self.add_idempotency_key(&mut headers, ctx, is_split.then_some(index));
+            self.send_bytes(body, &headers, ctx.finality, delivery, index)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Adds the idempotency key of the request at `index` in the batch, if enabled.
+    fn add_idempotency_key(&self, headers: &mut HeaderMap, ctx: &Context, index: Option<usize>) {
+        if let Some(header) = &self.idempotency_key_header {
+            headers.insert(header.clone(), idempotency_key(ctx, index));
+        }
+    }
+
+    /// Serializes the batch into the bodies of the requests that deliver it.
+    fn data_bodies(&self, ctx: &Context, batch: &Value) -> Result<Vec<Vec<u8>>, SinkError> {
+        let data_body = |batch: &Value| self.data_body(ctx, batch);
+
+        let items = match batch.as_array() {
+            Some(items) if self.split_oversized_batches => items,
+            _ => {
+                let body = self.serialize(&data_body(batch))?;
+                self.check_body_size(&body)?;
+                return Ok(vec![body]);
+            }
+        };
+
+        // Split chunks in order, halving the ones that are too large.
+        let mut bodies = Vec::default();
+        let mut chunks = vec![items.as_slice()];
+        while let Some(chunk) = chunks.pop() {
+            let body = data_body(&Value::from(chunk.to_vec()));
+            let body = self.serialize(&body)?;
+
+            if chunk.len() > 1 && self.is_body_too_large(&body) {
+                debug!(size = body.len(), items = chunk.len(), "splitting batch");
+                let (left, right) = chunk.split_at(chunk.len() / 2);
+                chunks.push(right);
+                chunks.push(left);
+                continue;
+            }
+
+            self.check_body_size(&body)?;
+            bodies.push(body);
+        }
+
+        Ok(bodies)
+    }
+
+    /// Returns the body that delivers the batch.
+    ///
+    /// With NDJSON, the body is an array with the body of each item, so that
+    /// items are sent one per line.
+    fn data_body(&self, ctx: &Context, batch: &Value) -> Value {
+        match (self.body_format, batch.as_array()) {
+            (BodyFormat::Ndjson, Some(items)) if !items.is_empty() => items
+                .iter()
+                .map(|item| self.envelope_body(ctx, &Value::Array(vec![item.clone()])))
+                .collect(),
+            _ => self.envelope_body(ctx, batch),
+        }
+    }
+
+    /// Wraps the batch in the envelope, with the enrichment fields.
+    fn envelope_body(&self, ctx: &Context, batch: &Value) -> Value {
+        let mut body = data_body(self.envelope, ctx, batch);
+        if let Some(enrichment) = &self.enrichment {
+            enrichment.add_fields(&mut body);
+        }
+        body
+    }
+
+    fn serialize<B: Serialize + ?Sized>(&self, body: &B) -> Result<Vec<u8>, SinkError> {
+        let body = match self.body_format {
+            BodyFormat::Ndjson => return to_ndjson(body),
+            // maps keep their keys, so the body has the same structure as the json one.
+            BodyFormat::MessagePack => {
+                return rmp_serde::to_vec_named(body).runtime_error("failed to serialize body")
+            }
+            BodyFormat::Json if self.pretty_json => serde_json::to_vec_pretty(body),
+            BodyFormat::Json => serde_json::to_vec(body),
+        };
+        body.runtime_error("failed to serialize body")
+    }
+
+    fn is_body_too_large(&self, body: &[u8]) -> bool {
+        self.max_body_bytes
+            .map(|max_body_bytes| body.len() > max_body_bytes)
+            .unwrap_or(false)
+    }
+
+    fn check_body_size(&self, body: &[u8]) -> Result<(), SinkError> {
+        if !self.is_body_too_large(body) {
+            return Ok(());
+        }
+
+        let max_body_bytes = self.max_body_bytes.unwrap_or_default();
+        Err(SinkError::fatal(&format!(
+            "request body is {} bytes, larger than the maximum of {} bytes",
+            body.len(),
+            max_body_bytes
+        )))
+    }
+
+    /// Returns true if the batch doesn't need to be sent.
+    fn should_skip_data(&self, ctx: &Context, batch: &Value) -> bool {
+        if self.is_delivered(ctx) {
+            debug!(ctx = %ctx, "skip data already delivered");
+            return true;
+        }
+
+        if self.skip_empty && is_empty_batch(batch) {
+            debug!(ctx = %ctx, "skip empty batch");
+            return true;
+        }
+
+        false
+    }
+
+    /// Returns the batch with the enrichment applied, if any.
+    fn enrich<'a>(&self, batch: &'a Value) -> Cow<'a, Value> {
+        // in raw mode the items are the body, so they get the fields.
+        match &self.enrichment {
+            None => Cow::Borrowed(batch),
+            Some(enrichment) => enrichment.apply_to_batch(batch, self.raw),
+        }
+    }
+
+    /// Returns true if the endpoint already received the batch, in this run or a previous one.
+    ///
+    /// Only finalized data is tracked, since other data can change after a restart.
+    fn is_delivered(&self, ctx: &Context) -> bool {
+        let Some(delivered) = &self.delivered_cursor else {
+            return false;
+        };
+        ctx.finality == DataFinality::DataStatusFinalized
+            && ctx.end_cursor.order_key <= delivered.order_key
+    }
+
+    /// Returns the target url, the first of the targets.
+    fn target(&self) -> &Target {
+        &self.targets[0]
+    }
+
+    /// Sends the body to all targets, handling failures according to the fan-out mode.
+    ///
+    /// `position` is the position of the request in the delivery: the item index
+    /// in raw mode, or the part index of split batches. All attempts of the
+    /// request at the same position carry the same request id.
+    async fn send_bytes(
+        &self,
+        body: Vec<u8>,
+        headers: &HeaderMap,
+        finality: DataFinality,
+        delivery: &Delivery,
+        position: usize,
+    ) -> Result<(), SinkError> {
+        let DeliveryRequest {
+            request_id,
+            mut accepted,
+        } = delivery.request(position, self.targets.len());
+
+        let mut headers = headers.clone();
+        if let Some(header) = &self.request_id_header {
+            debug!(request_id, "sending request");
+            headers.insert(
+                header.clone(),
+                HeaderValue::from_str(&request_id).expect("uuid is a valid header value"),
+            );
+        }
+        let headers = &headers;
+
+        if let [target] = self.targets.as_slice() {
+            return self
+                .send_bytes_to(0, target, body, headers, finality, delivery)
+                .await;
+        }
+
+        // only send the body to the targets that didn't accept it in a previous attempt.
+        let results = future::join_all(
+            self.targets
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !accepted[*index])
+                .map(|(index, target)| {
+                    let body = body.clone();
+                    async move {
+                        let result = self
+                            .send_bytes_to(index, target, body, headers, finality, delivery)
+                            .await;
+                        (index, result)
+                    }
+                }),
+        )
+        .await;
+
+        let mut first_err = None;
+        for (index, result) in results {
+            match result {
+                Ok(_) => accepted[index] = true,
+                Err(err) => {
+                    warn!(err = ?err, target = index, "failed to send request to target");
+                    first_err.get_or_insert(err);
+                }
+            }
+        }
+        let sent = accepted.iter().filter(|accepted| **accepted).count();
+        delivery.set_accepted_targets(position, accepted);
+
+        match first_err {
+            None => Ok(()),
+            Some(_) if self.fan_out_mode == FanOutMode::BestEffort && sent > 0 => Ok(()),
+            Some(err) => Err(err),
+        }
+    }
+
+    /// Sends the body to the target, unless its circuit is open.
+    ///
+    /// The circuit breaker records the result after all retries, so that
+    /// retries don't open the circuit on their own.
+    async fn send_bytes_to(
+        &self,
+        index: usize,
+        target: &Target,
+        body: Vec<u8>,
+        headers: &HeaderMap,
+        finality: DataFinality,
+        delivery: &Delivery,
+    ) -> Result<(), SinkError> {
+        self.check_circuit(index, target).await?;
+        let result = self
+            .send_bytes_with_retry(target, body, headers, finality, delivery)
+            .await;
+        self.record_circuit(index, target, &result);
+        result
+    }
+
+    /// Sends the body to the target, retrying temporary failures if retries are enabled.
+    async fn send_bytes_with_retry(
+        &self,
+        target: &Target,
+        body: Vec<u8>,
+        headers: &HeaderMap,
+        finality: DataFinality,
+        delivery: &Delivery,
+    ) -> Result<(), SinkError> {
+        let Some(retry) = &self.retry else {
+            return self
+                .send_bytes_once(target, body, headers, finality, delivery)
+                .await;
+        };
+
+        let err = match self
+            .send_bytes_once(target, body.clone(), headers, finality, delivery)
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        };
+
+        self.retry_failed_request(retry, finality, err, || {
+            self.send_bytes_once(target, body.clone(), headers, finality, delivery)
+        })
+        .await
+    }
+
+    /// Sends a request that failed with `err` again, waiting with the backoff of `retry`.
+    ///
+    /// Only temporary errors are retried. Returns the last error if all attempts fail.
+    async fn retry_failed_request<F, Fut>(
+        &self,
+        retry: &RetryConfiguration,
+        finality: DataFinality,
+        mut err: error_stack::Report<SinkError>,
+        mut send: F,
+    ) -> Result<(), SinkError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), SinkError>>,
+    {
+        let backoff = retry.backoff();
+        let mut delays = (&backoff).into_iter();
+        for attempt in 1..retry.max_attempts {
+            if !is_retryable_error(&err) {
+                break;
+            }
+            let delay = RetryAfter::delay(&err, delays.next().unwrap_or(retry.max_delay));
+            warn!(err = ?err, attempt, delay = ?delay, "failed to send request, retrying");
+            tokio::time::sleep(delay).await;
+            self.metrics.record_retry(finality);
+            match send().await {
+                Ok(_) => return Ok(()),
+                Err(new_err) => err = new_err,
+            }
+        }
+        Err(err)
+    }
+
+    async fn send_bytes_once(
+        &self,
+        target: &Target,
+        body: Vec<u8>,
+        headers: &HeaderMap,
+        finality: DataFinality,
+        delivery: &Delivery,
+    ) -> Result<(), SinkError> {
+        let started_at = Instant::now();
+        let response = self.send_authorized_request(target, body, headers).await;
+        let status = response.as_ref().ok().map(|response| response.status());
+        self.metrics
+            .record_request(finality, status, started_at.elapsed());
+        let response = response?;
 
-        match response.text().await {
-            Ok(text) => {
-                debug!(response = ?text, "call success");
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        if !status.is_success() {
+            // the status decides how the error is handled, the body is only logged.
+            if let Ok((text, truncated)) =
+                read_response_body(response, self.max_response_body_bytes).await
+            {
+                debug!(status = %status, response = ?text, truncated, "call failed");
+            }
+
+            let message = format!("endpoint returned status {}", status);
+            let is_retryable = self
+                .retryable_status
+                .iter()
+                .chain(&self.skip_status)
+                .any(|matcher| matcher.matches(status));
+            return if is_retryable {
+                Err(with_retry_after(
+                    SinkError::temporary(&message),
+                    retry_after,
+                ))
+            } else {
+                Err(SinkError::fatal(&message))
+            };
+        }
+
+        let directive = match read_response_body(response, self.max_response_body_bytes).await {
+            Ok((text, truncated)) => {
+                debug!(response = ?text, truncated, "call success");
+                if truncated {
+                    ResponseDirective::default()
+                } else {
+                    response_directive(&text)
+                }
             }
             Err(err) => {
                 warn!(err = ?err, "error reading response");
+                ResponseDirective::default()
+            }
+        };
+
+        if directive.cursor_action == Some(ResponseCursorAction::Skip) {
+            debug!("endpoint asked to send the data again later");
+            return Err(with_retry_after(
+                SinkError::temporary("endpoint asked to send the data again later"),
+                retry_after,
+            ));
+        }
+
+        delivery.check_ack(directive.ack_cursor.as_ref())
+    }
+
+    /// Sends a batch that failed to deliver, with the reason, to the dead-letter url.
+    async fn send_dead_letter(
+        &self,
+        ctx: &Context,
+        batch: &Value,
+        err: &error_stack::Report<SinkError>,
+    ) -> Result<(), SinkError> {
+        let Some(dead_letter_url) = &self.dead_letter_url else {
+            return Err(SinkError::runtime_error(
+                "dead-letter url is not configured",
+            ));
+        };
+
+        let body = json!({
+            "dead_letter": {
+                "cursor": ctx.cursor,
+                "end_cursor": ctx.end_cursor,
+                "finality": ctx.finality,
+                "error": error_message(err),
+                "batch": batch,
+            },
+        });
+
+        let response = self
+            .client
+            .post(dead_letter_url)
+            .json(&body)
+            .send()
+            .await
+            .fatal("failed to send batch to dead-letter url")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(SinkError::fatal(&format!(
+                "dead-letter url returned status {}",
+                status
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Sends the body to the endpoint, requesting a new access token if the
+    /// endpoint rejected the current one.
+    async fn send_authorized_request(
+        &self,
+        target: &Target,
+        body: Vec<u8>,
+        headers: &HeaderMap,
+    ) -> Result<reqwest::Response, SinkError> {
+        let Some(token_provider) = &target.credentials.token_provider else {
+            return self.send_request(target, body, headers).await;
+        };
+
+        let response = self.send_request(target, body.clone(), headers).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        // the token can be revoked before it expires, retry once with a new one.
+        debug!("endpoint rejected access token, requesting a new one");
+        token_provider.invalidate().await;
+        self.send_request(target, body, headers).await
+    }
+
+    /// Sends the body to the endpoint and returns its response, whatever the status.
+    ///
+    /// The request headers are added after the headers of the target.
+    async fn send_request(
+        &self,
+        target: &Target,
+        body: Vec<u8>,
+        headers: &HeaderMap,
+    ) -> Result<reqwest::Response, SinkError> {
+        let request = self
+            .client
+            .request(target.method.clone(), &target.url)
+            .headers(target.headers.clone())
+            .headers(headers.clone())
+            .header(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(self.body_format.content_type()),
+            );
+        let mut request = target.credentials.with_static_auth(request);
+
+        let body = match self.compression {
+            Some(compression) if body.len() > self.compression_threshold_bytes => {
+                let (encoding, body) = compress(compression, &body)?;
+                request = request.header(header::CONTENT_ENCODING, encoding);
+                body
+            }
+            _ => body,
+        };
+
+        if let Some(token_provider) = &target.credentials.token_provider {
+            let authorization = token_provider.authorization(&self.client).await?;
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        // sign the body as sent, after compression.
+        if let Some(signature) = &target.credentials.signature {
+            request = request.header(signature.header.clone(), signature.sign(&body));
+        }
+
+        match request
+            .body(body)
+            .send()
+            .await
+            .map_err(|err| target.credentials.redact_url(err))
+        {
+            Ok(response) => Ok(response),
+            Err(err) if is_partial_write(&err) => {
+                // the endpoint can't have processed an incomplete body, so it's safe to retry.
+                Err(err).temporary("connection closed while sending request body")
             }
+            Err(err) if err.is_timeout() => Err(err).temporary("request to endpoint timed out"),
+            Err(err) if err.is_connect() => Err(err).temporary("failed to connect to endpoint"),
+            Err(err) => Err(err).runtime_error("failed to send json data"),
+        }
+    }
+}
+
+/// Cursor action requested by the endpoint in the response body.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ResponseCursorAction {
+    Persist,
+    Skip,
+}
+
+#[derive(Default, Deserialize)]
+struct ResponseDirective {
+    cursor_action: Option<ResponseCursorAction>,
+    /// End cursor of the data the endpoint stored, with acknowledged delivery.
+    ack_cursor: Option<Cursor>,
+}
+
+/// Identifies a batch, to find its failed delivery when it's sent again.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DeliveryKey {
+    order_key: u64,
+    unique_key: Vec<u8>,
+    finality: i32,
+}
+
+impl DeliveryKey {
+    fn new(ctx: &Context) -> Self {
+        DeliveryKey {
+            order_key: ctx.end_cursor.order_key,
+            unique_key: ctx.end_cursor.unique_key.clone(),
+            finality: ctx.finality as i32,
+        }
+    }
+}
+
+/// The state of the requests that deliver a batch.
+///
+/// Requests that don't deliver data, like invalidations, use the default.
+#[derive(Default)]
+struct Delivery {
+    /// The cursor the endpoint must acknowledge, if it's required.
+    expected_ack: Option<Cursor>,
+    /// The requests sent so far, by position in the delivery.
+    requests: Mutex<HashMap<usize, DeliveryRequest>>,
+}
+
+/// A request sent in a delivery.
+#[derive(Clone)]
+struct DeliveryRequest {
+    /// Sent in the request id header of every attempt.
+    request_id: String,
+    /// Whether each target accepted the body.
+    accepted: Vec<bool>,
+}
+
+impl Delivery {
+    fn new(expected_ack: Option<Cursor>) -> Self {
+        Delivery {
+            expected_ack,
+            ..Delivery::default()
+        }
+    }
+
+    /// Returns the request at the given position, with a new request id if it
+    /// wasn't sent before.
+    fn request(&self, position: usize, targets: usize) -> DeliveryRequest {
+        let mut requests = self.requests.lock().expect("delivery poisoned");
+        requests
+            .entry(position)
+            .or_insert_with(|| DeliveryRequest {
+                request_id: Uuid::new_v4().hyphenated().to_string(),
+                accepted: vec![false; targets],
+            })
+            .clone()
+    }
+
+    fn set_accepted_targets(&self, position: usize, targets: Vec<bool>) {
+        let mut requests = self.requests.lock().expect("delivery poisoned");
+        if let Some(request) = requests.get_mut(&position) {
+            request.accepted = targets;
+        }
+    }
+
+    /// Returns a temporary error if the endpoint didn't acknowledge the
+    /// expected cursor, so that the data is sent again.
+    fn check_ack(&self, ack_cursor: Option<&Cursor>) -> Result<(), SinkError> {
+        let Some(expected) = &self.expected_ack else {
+            return Ok(());
+        };
+
+        if ack_cursor != Some(expected) {
+            warn!(
+                expected = %expected,
+                ack_cursor = ?ack_cursor,
+                "endpoint didn't acknowledge the data"
+            );
+            return Err(SinkError::temporary(&format!(
+                "endpoint didn't acknowledge the data up to {}",
+                expected
+            )));
         }
 
         Ok(())
     }
 }
 
+/// Reads at most `max_bytes` of the response body, as text.
+///
+/// Returns whether the body was truncated. The rest of the body is never read,
+/// so large responses don't use more memory than the limit.
+async fn read_response_body(
+    mut response: reqwest::Response,
+    max_bytes: usize,
+) -> std::result::Result<(String, bool), reqwest::Error> {
+    let mut body = Vec::new();
+    let mut truncated = false;
+    while let Some(chunk) = response.chunk().await? {
+        let remaining = max_bytes - body.len();
+        if chunk.len() > remaining {
+            body.extend_from_slice(&chunk[..remaining]);
+            truncated = true;
+            break;
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((String::from_utf8_lossy(&body).into_owned(), truncated))
+}
+
+/// Asks to wait `retry_after` before retrying the request, if set.
+fn with_retry_after(
+    err: error_stack::Report<SinkError>,
+    retry_after: Option<Duration>,
+) -> error_stack::Report<SinkError> {
+    match retry_after {
+        Some(retry_after) => err.attach(RetryAfter(retry_after)),
+        None => err,
+    }
+}
+
+/// Returns the directives in the response body, if the body is a JSON
+/// object with a `cursor_action` or `ack_cursor` key.
+fn response_directive(body: &str) -> ResponseDirective {
+    serde_json::from_str::<ResponseDirective>(body).unwrap_or_default()
+}
+
+fn data_body(envelope: Envelope, ctx: &Context, batch: &Value) -> Value {
+    match envelope {
+        Envelope::Nested => json!({
+            "data": {
+                "cursor": ctx.cursor,
+                "end_cursor": ctx.end_cursor,
+                "finality": ctx.finality,
+                "batch": batch,
+            },
+        }),
+        Envelope::Flat => json!({
+            "cursor": ctx.cursor,
+            "end_cursor": ctx.end_cursor,
+            "finality": ctx.finality,
+            "batch": batch,
+        }),
+        Envelope::Meta => json!({
+            "batch": batch,
+            "meta": {
+                "cursor": ctx.cursor,
+                "end_cursor": ctx.end_cursor,
+                "finality": ctx.finality,
+            },
+        }),
+    }
+}
+
+/// Returns true if the batch has no items.
+fn is_empty_batch(batch: &Value) -> bool {
+    match batch {
+        Value::Null => true,
+        Value::Array(items) => items.is_empty(),
+        _ => false,
+    }
+}
+
+/// Serializes the body as newline-delimited JSON, one line per element if it's an array.
+fn to_ndjson<B: Serialize + ?Sized>(body: &B) -> Result<Vec<u8>, SinkError> {
+    let body = serde_json::to_value(body).runtime_error("failed to serialize body")?;
+    let lines = match body {
+        Value::Array(items) => items,
+        body => vec![body],
+    };
+
+    let mut ndjson = Vec::new();
+    for line in &lines {
+        serde_json::to_writer(&mut ndjson, line).runtime_error("failed to serialize body")?;
+        ndjson.push(b'\n');
+    }
+    Ok(ndjson)
+}
+
+/// Returns the error and the reasons attached to it, most recent first.
+fn error_message(err: &error_stack::Report<SinkError>) -> String {
+    let mut message = err.current_context().to_string();
+    for reason in err
+        .frames()
+        .filter_map(|frame| frame.downcast_ref::<String>())
+    {
+        message.push_str(": ");
+        message.push_str(reason);
+    }
+    message
+}
+
+/// Returns true if the error is caused by the endpoint, and not by the data.
+fn is_endpoint_error(err: &error_stack::Report<SinkError>) -> bool {
+    !matches!(
+        err.current_context(),
+        SinkError::Fatal | SinkError::Configuration
+    )
+}
+
+/// Returns true if the endpoint didn't accept the data, after all retries if
+/// the error is temporary.
+///
+/// Other errors, like failures to serialize the data, are not delivery errors.
+fn is_delivery_error(err: &error_stack::Report<SinkError>) -> bool {
+    matches!(
+        err.current_context(),
+        SinkError::Temporary | SinkError::Fatal
+    )
+}
+
+/// Returns true if the request can be sent again, for example after a connection error.
+fn is_retryable_error(err: &error_stack::Report<SinkError>) -> bool {
+    matches!(err.current_context(), SinkError::Temporary)
+}
+
+/// Returns true if the endpoint closed the connection before the request was sent in full.
+///
+/// Only errors that happen before the response is read qualify: hyper's incomplete message
+/// and broken pipes. Other errors, like a reset while reading the response, can happen after
+/// the endpoint processed the request.
+fn is_partial_write(err: &reqwest::Error) -> bool {
+    let mut source = err.source();
+    while let Some(inner) = source {
+        if let Some(err) = inner.downcast_ref::<hyper::Error>() {
+            if err.is_incomplete_message() {
+                return true;
+            }
+        }
+        if let Some(err) = inner.downcast_ref::<io::Error>() {
+            return err.kind() == io::ErrorKind::BrokenPipe;
+        }
+        source = inner.source();
+    }
+    false
+}
+
+/// Returns the name of an enum variant in kebab-case, like `fail-batch` for `FailBatch`.
+fn kebab_case(value: impl fmt::Debug) -> String {
+    let mut name = String::new();
+    for (index, c) in format!("{value:?}").char_indices() {
+        if index > 0 && c.is_uppercase() {
+            name.push('-');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+/// Compresses the body, returning the `Content-Encoding` header value and the compressed body.
+fn compress(compression: Compression, body: &[u8]) -> Result<(HeaderValue, Vec<u8>), SinkError> {
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(body)
+                .runtime_error("failed to compress body with gzip")?;
+            let compressed = encoder
+                .finish()
+                .runtime_error("failed to compress body with gzip")?;
+            Ok((HeaderValue::from_static("gzip"), compressed))
+        }
+        Compression::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(body)
+                .runtime_error("failed to compress body with deflate")?;
+            let compressed = encoder
+                .finish()
+                .runtime_error("failed to compress body with deflate")?;
+            Ok((HeaderValue::from_static("deflate"), compressed))
+        }
+        Compression::Zstd => {
+            let compressed =
+                zstd::encode_all(body, 0).runtime_error("failed to compress body with zstd")?;
+            Ok((HeaderValue::from_static("zstd"), compressed))
+        }
+    }
+}
+
 #[async_trait]
 impl Sink for WebhookSink {
     type Options = SinkWebhookOptions;
@@ -59,7 +1426,122 @@ impl Sink for WebhookSink {
 
     async fn from_options(options: Self::Options) -> Result<Self, Self::Error> {
         let config = options.to_webhook_configuration()?;
-        Ok(WebhookSink::new(config))
+        let sink = WebhookSink::new(config);
+        sink.preflight().await?;
+        Ok(sink)
+    }
+
+    fn metadata(&self) -> SinkMetadata {
+        let compression = match self.compression {
+            None => "none".to_string(),
+            Some(compression) => format!(
+                "{:?} (>{} bytes)",
+                compression, self.compression_threshold_bytes
+            )
+            .to_lowercase(),
+        };
+
+        let target = self.target();
+        let header_names = target
+            .headers
+            .keys()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut metadata = SinkMetadata::new("webhook")
+            .with_summary("method", target.method.as_str())
+            .with_summary("raw", self.raw)
+            .with_summary("raw_concurrency", self.raw_concurrency)
+            .with_summary("max_in_flight_batches", self.max_in_flight_batches)
+            .with_summary("raw_invalidate", self.raw_invalidate)
+            .with_summary("raw_batch", self.raw_batch)
+            .with_summary("raw_failure_mode", kebab_case(self.raw_failure_mode))
+            .with_summary("compression", compression)
+            .with_summary("body_format", kebab_case(self.body_format))
+            .with_summary("envelope", kebab_case(self.envelope))
+            .with_summary(
+                "max_body_bytes",
+                self.max_body_bytes
+                    .map(|max| max.to_string())
+                    .unwrap_or("none".to_string()),
+            )
+            .with_summary("headers", header_names);
+
+        if self.targets.len() > 1 {
+            metadata = metadata
+                .with_summary("fan_out_targets", self.targets.len() - 1)
+                .with_summary("fan_out_mode", kebab_case(self.fan_out_mode));
+        }
+
+        if let Some(sampler) = &self.sampler {
+            metadata = metadata.with_summary("sample_rate", sampler.rate());
+        }
+
+        if let Some(spool) = &self.spool {
+            metadata = metadata.with_summary("spool_max_bytes", spool.config().max_bytes);
+        }
+
+        if let Some(retry) = &self.retry {
+            metadata = metadata.with_summary("retry_max_attempts", retry.max_attempts);
+        }
+
+        let credentials = &target.credentials;
+        if let Some(signature) = &credentials.signature {
+            metadata = metadata.with_summary("signature_header", signature.header.as_str());
+        }
+
+        if let Some(header) = &self.idempotency_key_header {
+            metadata = metadata.with_summary("idempotency_key_header", header.as_str());
+        }
+
+        if let Some(header) = &self.request_id_header {
+            metadata = metadata.with_summary("request_id_header", header.as_str());
+        }
+
+        if let Some(enrichment) = &self.enrichment {
+            metadata = metadata
+                .with_summary("enrich_fields", enrichment.fields.len())
+                .with_summary("redacted_fields", enrichment.redact.len());
+        }
+
+        if self.dead_letter_url.is_some() {
+            metadata = metadata.with_summary("dead_letter", true);
+        }
+
+        if self.require_ack {
+            metadata = metadata.with_summary("require_ack", true);
+        }
+
+        if let Some(token_provider) = &credentials.token_provider {
+            metadata = metadata.with_summary("oauth_client_id", &token_provider.config().client_id);
+        }
+
+        if credentials.basic_auth.is_some() {
+            metadata = metadata.with_summary("basic_auth", true);
+        }
+
+        if let Some(query_api_key) = &credentials.query_api_key {
+            metadata = metadata.with_summary("api_key_query_param", &query_api_key.param);
+        }
+
+        if let Some(breaker) = &target.circuit_breaker {
+            let breaker = breaker.lock().expect("circuit breaker poisoned");
+            metadata = metadata.with_summary(
+                "circuit_failure_threshold",
+                breaker.config().failure_threshold,
+            );
+        }
+
+        if let Ok(url) = target.url.parse::<Uri>() {
+            if let Some(authority) = url.authority() {
+                let scheme = url.scheme_str().unwrap_or("http");
+                // only keep the host, since the path and query may contain tokens.
+                metadata = metadata.with_target(format!("{scheme}://{}", authority.host()));
+            }
+        }
+
+        metadata
     }
 
     #[instrument(skip(self, batch), err(Debug))]
@@ -70,34 +1552,74 @@ impl Sink for WebhookSink {
     ) -> Result<CursorAction, Self::Error> {
         debug!(ctx = %ctx, "calling with data");
 
-        if self.raw {
-            // Send each item returned by the transform script as a separate request
-            let Some(batch) = batch.as_array() else {
-                warn!("raw mode: batch is not an array");
-                return Ok(CursorAction::Persist);
-            };
+        if self.should_skip_data(ctx, batch) {
+            return Ok(CursorAction::Persist);
+        }
+
+        let batch = self.enrich(batch);
+        let batch = batch.as_ref();
+
+        self.drain_spool().await?;
+        if self.has_spooled_data().await? {
+            // keep requests in order until the spool is drained.
+            self.spool_data(ctx, batch).await?;
+            return Ok(CursorAction::Persist);
+        }
 
-            for item in batch {
-                self.send(&item).await?;
+        let delivery = self.start_delivery(ctx);
+        let result = self.deliver_data(ctx, batch, &delivery).await;
+
+        match result {
+            Ok(_) => {
+                if ctx.finality == DataFinality::DataStatusFinalized {
+                    self.delivered_cursor = Some(ctx.end_cursor.clone());
+                }
+            }
+            Err(err) if self.spool.is_some() && is_endpoint_error(&err) => {
+                warn!(err = ?err, "failed to send data, appending it to the spool");
+                self.spool_data(ctx, batch).await?;
+            }
+            Err(err) if self.dead_letter_url.is_some() && is_delivery_error(&err) => {
+                warn!(err = ?err, "failed to send data, sending it to the dead-letter url");
+                self.send_dead_letter(ctx, batch, &err).await?;
+            }
+            Err(err) => {
+                self.keep_failed_delivery(ctx, delivery);
+                return Err(err);
             }
-        } else {
-            let body = &json!({
-                "data": {
-                    "cursor": ctx.cursor,
-                    "end_cursor": ctx.end_cursor,
-                    "finality": ctx.finality,
-                    "batch": batch,
-                },
-            });
-            self.send(&body).await?;
         }
 
         Ok(CursorAction::Persist)
     }
 
-    #[instrument(skip(self), err(Debug))]
+    fn concurrent(&self) -> Option<&dyn ConcurrentSink<Error = Self::Error>> {
+        if self.max_in_flight_batches > 1 {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
     async fn handle_invalidate(&mut self, cursor: &Option<Cursor>) -> Result<(), Self::Error> {
-        if self.raw {
+        self.handle_invalidate_range(cursor, None).await
+    }
+
+    #[instrument(skip(self), err(Debug))]
+    async fn handle_invalidate_range(
+        &mut self,
+        cursor: &Option<Cursor>,
+        invalidated: Option<&InvalidatedRange>,
+    ) -> Result<(), Self::Error> {
+        let is_before_delivered = match (cursor, &self.delivered_cursor) {
+            (Some(cursor), Some(delivered)) => cursor.order_key < delivered.order_key,
+            (None, delivered) => delivered.is_some(),
+            _ => false,
+        };
+        if is_before_delivered {
+            self.delivered_cursor = cursor.clone();
+        }
+
+        if self.raw && !self.raw_invalidate {
             return Ok(());
         }
 
@@ -107,12 +1629,136 @@ impl Sink for WebhookSink {
             .unwrap_or("genesis".into());
 
         debug!(cursor = %cursor_str, "calling with invalidate");
-        let body = json!({
+        let mut body = json!({
             "invalidate": {
                 "cursor": cursor,
             },
         });
 
-        self.send(&body).await
+        if let Some(invalidated) = invalidated {
+            body["invalidate"]["invalidated"] = json!(invalidated);
+        }
+
+        let body = self.serialize(&body)?;
+        self.check_body_size(&body)?;
+
+        self.drain_spool().await?;
+        if self.has_spooled_data().await? {
+            return self.spool_bodies(&[body]).await;
+        }
+
+        // batches that failed before the invalidation are not retried as they were.
+        self.failed_deliveries
+            .get_mut()
+            .expect("failed deliveries poisoned")
+            .clear();
+
+        let delivery = Delivery::default();
+        let result = self
+            .send_bytes(
+                body.clone(),
+                &HeaderMap::new(),
+                DataFinality::DataStatusUnknown,
+                &delivery,
+                0,
+            )
+            .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) if self.spool.is_some() && is_endpoint_error(&err) => {
+                warn!(err = ?err, "failed to send invalidate, appending it to the spool");
+                self.spool_bodies(&[body]).await
+            }
+            Err(err) => self.handle_invalidate_failure(body, err, &delivery).await,
+        }
+    }
+
+    async fn handle_heartbeat(&mut self) -> Result<(), Self::Error> {
+        self.drain_spool().await
+    }
+
+    async fn tick(&mut self) -> Result<(), Self::Error> {
+        for (index, target) in self.targets.iter().enumerate() {
+            let Some(token_provider) = &target.credentials.token_provider else {
+                continue;
+            };
+            // the next request requests a new token if this one fails.
+            if let Err(err) = token_provider.refresh(&self.client).await {
+                warn!(err = ?err, target = index, "failed to refresh access token");
+            }
+        }
+        Ok(())
+    }
+
+    fn delivered_cursor(&self) -> Option<Cursor> {
+        self.delivered_cursor.clone()
+    }
+
+    async fn restore_delivered_cursor(
+        &mut self,
+        cursor: Option<Cursor>,
+    ) -> Result<(), Self::Error> {
+        if let Some(cursor) = &cursor {
+            info!(cursor = %cursor, "skipping finalized data already delivered");
+        }
+        self.delivered_cursor = cursor;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ConcurrentSink for WebhookSink {
+    type Error = SinkError;
+
+    fn max_in_flight_batches(&self) -> usize {
+        self.max_in_flight_batches
+    }
+
+    /// Like [WebhookSink::handle_data], but returns the delivered cursor instead of storing it.
+    ///
+    /// The spool can't be used with concurrent batches.
+    #[instrument(skip(self, batch), err(Debug))]
+    async fn handle_data_concurrently(
+        &self,
+        ctx: &Context,
+        batch: &Value,
+    ) -> Result<DeliveredBatch, Self::Error> {
+        debug!(ctx = %ctx, "calling with data");
+
+        if self.should_skip_data(ctx, batch) {
+            return Ok(DeliveredBatch {
+                action: CursorAction::Persist,
+                delivered_cursor: None,
+            });
+        }
+
+        let batch = self.enrich(batch);
+        let batch = batch.as_ref();
+
+        let delivery = self.start_delivery(ctx);
+        let result = self.deliver_data(ctx, batch, &delivery).await;
+
+        let mut delivered_cursor = None;
+        match result {
+            Ok(_) => {
+                if ctx.finality == DataFinality::DataStatusFinalized {
+                    delivered_cursor = Some(ctx.end_cursor.clone());
+                }
+            }
+            Err(err) if self.dead_letter_url.is_some() && is_delivery_error(&err) => {
+                warn!(err = ?err, "failed to send data, sending it to the dead-letter url");
+                self.send_dead_letter(ctx, batch, &err).await?;
+            }
+            Err(err) => {
+                self.keep_failed_delivery(ctx, delivery);
+                return Err(err);
+            }
+        }
+
+        Ok(DeliveredBatch {
+            action: CursorAction::Persist,
+            delivered_cursor,
+        })
     }
 }
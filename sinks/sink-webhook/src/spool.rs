@@ -0,0 +1,366 @@
+//! Store requests on disk while the endpoint is down.
+
+use std::{io::SeekFrom, path::PathBuf};
+
+use apibara_sink_common::{SinkError, SinkErrorResultExt};
+use error_stack::Result;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::{debug, warn};
+
+/// Default maximum size of the spool file, 100 MiB.
+pub const DEFAULT_SPOOL_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+/// Size of the length prefix of each entry.
+const ENTRY_HEADER_BYTES: u64 = 8;
+
+#[derive(Debug, Clone)]
+pub struct SpoolConfiguration {
+    /// The file where requests are stored.
+    pub path: PathBuf,
+    /// Stop accepting requests once the file is larger than this size.
+    pub max_bytes: u64,
+}
+
+/// A durable, append-only queue of request bodies.
+///
+/// Each entry is stored as its length (8 bytes, big endian) followed by the
+/// body. Entries are flushed to disk before `push` returns, so that they
+/// survive restarts. The position of the first entry that wasn't sent is
+/// stored next to the spool (with the `.offset` suffix), so that sent entries
+/// are not sent again after a restart. The files are removed once all entries
+/// are sent.
+///
+/// The files are read the first time the spool is used.
+pub struct Spool {
+    config: SpoolConfiguration,
+    state: Option<SpoolState>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct SpoolState {
+    /// Size of the spool file.
+    size: u64,
+    /// Position of the first entry that wasn't sent.
+    offset: u64,
+}
+
+impl Spool {
+    pub fn new(config: SpoolConfiguration) -> Self {
+        Spool {
+            config,
+            state: None,
+        }
+    }
+
+    pub fn config(&self) -> &SpoolConfiguration {
+        &self.config
+    }
+
+    /// Returns true if all entries have been sent.
+    pub async fn is_empty(&mut self) -> Result<bool, SinkError> {
+        let state = self.state().await?;
+        Ok(state.offset == state.size)
+    }
+
+    /// Appends a request body to the spool.
+    ///
+    /// Fails with a temporary error if the spool is full.
+    pub async fn push(&mut self, body: &[u8]) -> Result<(), SinkError> {
+        let state = self.state().await?;
+        let entry_size = ENTRY_HEADER_BYTES + body.len() as u64;
+        if state.size + entry_size > self.config.max_bytes {
+            return Err(SinkError::temporary(&format!(
+                "spool is full ({} bytes)",
+                state.size
+            )));
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)
+            .await
+            .runtime_error("failed to open spool file")?;
+        let mut entry = Vec::with_capacity(entry_size as usize);
+        entry.extend_from_slice(&(body.len() as u64).to_be_bytes());
+        entry.extend_from_slice(body);
+        file.write_all(&entry)
+            .await
+            .runtime_error("failed to write spool entry")?;
+        file.sync_data()
+            .await
+            .runtime_error("failed to flush spool file")?;
+
+        self.state = Some(SpoolState {
+            size: state.size + entry_size,
+            ..state
+        });
+        Ok(())
+    }
+
+    /// Returns the oldest entry that wasn't sent.
+    pub async fn first(&mut self) -> Result<Option<Vec<u8>>, SinkError> {
+        let state = self.state().await?;
+        if state.offset == state.size {
+            return Ok(None);
+        }
+
+        let mut file = File::open(&self.config.path)
+            .await
+            .runtime_error("failed to open spool file")?;
+        file.seek(SeekFrom::Start(state.offset))
+            .await
+            .runtime_error("failed to read spool file")?;
+        let len = file
+            .read_u64()
+            .await
+            .runtime_error("failed to read spool entry")?;
+        let mut body = vec![0; len as usize];
+        file.read_exact(&mut body)
+            .await
+            .runtime_error("failed to read spool entry")?;
+
+        Ok(Some(body))
+    }
+
+    /// Marks the entry returned by [Spool::first] as sent.
+    pub async fn remove_first(&mut self, body: &[u8]) -> Result<(), SinkError> {
+        let state = self.state().await?;
+        let offset = (state.offset + ENTRY_HEADER_BYTES + body.len() as u64).min(state.size);
+
+        if offset == state.size {
+            remove_if_exists(&self.config.path).await?;
+            remove_if_exists(&self.offset_path()).await?;
+            debug!("spool drained");
+            self.state = Some(SpoolState::default());
+            return Ok(());
+        }
+
+        // write to a new file first, so that a crash never loses the offset.
+        let offset_path = self.offset_path();
+        let mut tmp_path = offset_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path)
+            .await
+            .runtime_error("failed to create spool offset file")?;
+        file.write_all(&offset.to_be_bytes())
+            .await
+            .runtime_error("failed to write spool offset")?;
+        file.sync_data()
+            .await
+            .runtime_error("failed to flush spool offset")?;
+        fs::rename(&tmp_path, &offset_path)
+            .await
+            .runtime_error("failed to replace spool offset file")?;
+
+        self.state = Some(SpoolState { offset, ..state });
+        Ok(())
+    }
+
+    fn offset_path(&self) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(".offset");
+        path.into()
+    }
+
+    async fn state(&mut self) -> Result<SpoolState, SinkError> {
+        if let Some(state) = self.state {
+            return Ok(state);
+        }
+
+        let state = self.load().await?;
+        self.state = Some(state);
+        Ok(state)
+    }
+
+    /// Reads the entries left by a previous run.
+    async fn load(&self) -> Result<SpoolState, SinkError> {
+        let mut file = match File::open(&self.config.path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                remove_if_exists(&self.offset_path()).await?;
+                return Ok(SpoolState::default());
+            }
+            Err(err) => {
+                return Err(err).runtime_error("failed to open spool file");
+            }
+        };
+
+        let file_size = file
+            .metadata()
+            .await
+            .runtime_error("failed to read spool file")?
+            .len();
+
+        let stored_offset = match fs::read(self.offset_path()).await {
+            Ok(content) => content.try_into().ok().map(u64::from_be_bytes),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Some(0),
+            Err(err) => return Err(err).runtime_error("failed to read spool offset file"),
+        };
+
+        // only read the entry headers to find where the last complete entry ends.
+        let mut size = 0;
+        let mut offset_is_valid = stored_offset == Some(0);
+        while size + ENTRY_HEADER_BYTES <= file_size {
+            file.seek(SeekFrom::Start(size))
+                .await
+                .runtime_error("failed to read spool file")?;
+            let len = file
+                .read_u64()
+                .await
+                .runtime_error("failed to read spool entry")?;
+            let entry_end = size + ENTRY_HEADER_BYTES + len;
+            if entry_end > file_size {
+                break;
+            }
+            size = entry_end;
+            offset_is_valid |= stored_offset == Some(size);
+        }
+
+        if size < file_size {
+            // the sink was stopped while writing the last entry, which
+            // was never acknowledged.
+            warn!(path = ?self.config.path, "removing truncated spool entry");
+            let truncated = OpenOptions::new()
+                .write(true)
+                .open(&self.config.path)
+                .await
+                .runtime_error("failed to open spool file")?;
+            truncated
+                .set_len(size)
+                .await
+                .runtime_error("failed to truncate spool file")?;
+        }
+
+        let offset = match stored_offset {
+            Some(offset) if offset_is_valid => offset,
+            _ => {
+                // sending entries again is better than losing them.
+                warn!(path = ?self.config.path, "invalid spool offset, sending all entries");
+                0
+            }
+        };
+
+        Ok(SpoolState { size, offset })
+    }
+}
+
+async fn remove_if_exists(path: &PathBuf) -> Result<(), SinkError> {
+    match fs::remove_file(path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(err).runtime_error("failed to remove spool file")
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Spool, SpoolConfiguration};
+
+    fn new_spool(dir: &tempdir::TempDir, max_bytes: u64) -> Spool {
+        Spool::new(SpoolConfiguration {
+            path: dir.path().join("spool"),
+            max_bytes,
+        })
+    }
+
+    async fn entries(spool: &mut Spool) -> Vec<Vec<u8>> {
+        // read the entries from a new spool, so that the original is not changed.
+        let mut copy = Spool::new(spool.config().clone());
+        let mut entries = Vec::default();
+        while let Some(body) = copy.first().await.unwrap() {
+            copy.state = copy.state.map(|mut state| {
+                state.offset += super::ENTRY_HEADER_BYTES + body.len() as u64;
+                state
+            });
+            entries.push(body);
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn test_push_and_remove() {
+        let dir = tempdir::TempDir::new("webhook-spool").unwrap();
+        let mut spool = new_spool(&dir, 1024);
+        assert!(spool.is_empty().await.unwrap());
+
+        spool.push(b"first").await.unwrap();
+        spool.push(b"second").await.unwrap();
+        spool.push(b"third").await.unwrap();
+
+        // entries survive restarts.
+        let mut spool = new_spool(&dir, 1024);
+        assert_eq!(
+            entries(&mut spool).await,
+            vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+
+        assert_eq!(spool.first().await.unwrap(), Some(b"first".to_vec()));
+        spool.remove_first(b"first").await.unwrap();
+        assert_eq!(spool.first().await.unwrap(), Some(b"second".to_vec()));
+        spool.remove_first(b"second").await.unwrap();
+
+        // sent entries are not sent again after a restart.
+        let mut spool = new_spool(&dir, 1024);
+        assert_eq!(entries(&mut spool).await, vec![b"third".to_vec()]);
+
+        spool.remove_first(b"third").await.unwrap();
+        assert!(spool.is_empty().await.unwrap());
+        assert!(spool.first().await.unwrap().is_none());
+        assert!(!dir.path().join("spool").exists());
+        assert!(!dir.path().join("spool.offset").exists());
+    }
+
+    #[tokio::test]
+    async fn test_spool_full() {
+        let dir = tempdir::TempDir::new("webhook-spool").unwrap();
+        // each entry is 8 bytes of header plus the body.
+        let mut spool = new_spool(&dir, 22);
+
+        spool.push(b"0123").await.unwrap();
+        assert!(spool.push(b"0123456789").await.is_err());
+        spool.push(b"01").await.unwrap();
+        assert_eq!(entries(&mut spool).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_entry_is_removed() {
+        let dir = tempdir::TempDir::new("webhook-spool").unwrap();
+        let mut spool = new_spool(&dir, 1024);
+        spool.push(b"first").await.unwrap();
+
+        // simulate a crash while writing the second entry.
+        let mut content = std::fs::read(dir.path().join("spool")).unwrap();
+        content.extend_from_slice(&10u64.to_be_bytes());
+        content.extend_from_slice(b"sec");
+        std::fs::write(dir.path().join("spool"), content).unwrap();
+
+        let mut spool = new_spool(&dir, 1024);
+        spool.push(b"second").await.unwrap();
+        assert_eq!(
+            entries(&mut spool).await,
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_offset_sends_all_entries() {
+        let dir = tempdir::TempDir::new("webhook-spool").unwrap();
+        let mut spool = new_spool(&dir, 1024);
+        spool.push(b"first").await.unwrap();
+        spool.push(b"second").await.unwrap();
+
+        // the offset doesn't point to the start of an entry.
+        std::fs::write(dir.path().join("spool.offset"), 3u64.to_be_bytes()).unwrap();
+
+        let mut spool = new_spool(&dir, 1024);
+        assert_eq!(
+            entries(&mut spool).await,
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+    }
+}
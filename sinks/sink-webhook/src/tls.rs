@@ -0,0 +1,107 @@
+//! Connect to endpoints with certificates that are not in the system trust store.
+//!
+//! Additional root certificates are trusted on top of the system ones, so
+//! public endpoints keep working. Endpoints that require mutual TLS receive
+//! the client certificate.
+
+use std::{fs, path::Path};
+
+use apibara_sink_common::{SinkError, SinkErrorResultExt};
+use error_stack::Result;
+use reqwest::{Certificate, ClientBuilder, Identity};
+use tracing::warn;
+
+const BEGIN_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----";
+const END_CERTIFICATE: &str = "-----END CERTIFICATE-----";
+
+#[derive(Debug, Default)]
+pub struct TlsConfiguration {
+    /// Certificates trusted in addition to the system trust store.
+    pub root_certificates: Vec<Certificate>,
+    /// Client certificate and private key, for endpoints that require mutual TLS.
+    pub identity: Option<Identity>,
+    /// Accept any certificate, including self-signed and expired ones.
+    ///
+    /// Only use this in development.
+    pub danger_accept_invalid_certificates: bool,
+}
+
+impl TlsConfiguration {
+    /// Loads all certificates in the PEM bundle at `path`.
+    pub fn load_root_certificates(path: &Path) -> Result<Vec<Certificate>, SinkError> {
+        let pem = fs::read_to_string(path).configuration("failed to read ca certificate file")?;
+
+        let certificates = pem_certificates(&pem)
+            .into_iter()
+            .map(|pem| Certificate::from_pem(pem.as_bytes()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .configuration("malformed ca certificate")?;
+
+        if certificates.is_empty() {
+            return Err(SinkError::configuration(
+                "ca certificate file doesn't contain any certificate",
+            ));
+        }
+
+        Ok(certificates)
+    }
+
+    /// Loads the client certificate and its private key, both PEM-encoded.
+    pub fn load_identity(certificate: &Path, key: &Path) -> Result<Identity, SinkError> {
+        let mut pem = fs::read(key).configuration("failed to read client key file")?;
+        pem.push(b'\n');
+        pem.extend(fs::read(certificate).configuration("failed to read client certificate file")?);
+        Identity::from_pem(&pem).configuration("malformed client certificate or key")
+    }
+
+    /// Configures the client to use these certificates.
+    pub fn apply(self, mut builder: ClientBuilder) -> ClientBuilder {
+        for certificate in self.root_certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        if let Some(identity) = self.identity {
+            builder = builder.identity(identity);
+        }
+
+        if self.danger_accept_invalid_certificates {
+            warn!("webhook sink accepts invalid TLS certificates");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        builder
+    }
+}
+
+/// Splits a PEM bundle into its certificates.
+fn pem_certificates(pem: &str) -> Vec<&str> {
+    pem.split_inclusive(END_CERTIFICATE)
+        .filter_map(|block| {
+            let start = block.find(BEGIN_CERTIFICATE)?;
+            let block = &block[start..];
+            block.ends_with(END_CERTIFICATE).then_some(block)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pem_certificates;
+
+    #[test]
+    fn test_pem_certificates() {
+        let bundle = "# internal ca\n\
+            -----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n\
+            -----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----\n";
+        let certificates = pem_certificates(bundle);
+        assert_eq!(certificates.len(), 2);
+        assert_eq!(
+            certificates[0],
+            "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----"
+        );
+        assert!(certificates[1].contains("BBBB"));
+
+        assert!(pem_certificates("-----BEGIN CERTIFICATE-----\nAAAA\n").is_empty());
+        assert!(pem_certificates("").is_empty());
+    }
+}
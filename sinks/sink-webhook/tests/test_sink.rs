@@ -1,8 +1,20 @@
+use std::{io::Read, time::Duration};
+
 use apibara_core::node::v1alpha2::{Cursor, DataFinality};
-use apibara_sink_common::{Context, Sink, SinkError};
-use apibara_sink_webhook::{SinkWebhookConfiguration, WebhookSink};
+use apibara_sink_common::{
+    Context, CursorAction, DeliveredBatch, InvalidatedRange, RetryAfter, Sink, SinkError,
+};
+use apibara_sink_webhook::{
+    BodyFormat, CircuitBreakerConfiguration, CircuitProbe, Compression, ContextHeaders,
+    EnrichmentConfiguration, Envelope, FanOutMode, InvalidateFailureMode, OAuthConfiguration,
+    PayloadSampleConfiguration, QueryApiKeyConfiguration, RawFailureMode, RetryConfiguration,
+    SignatureConfiguration, SignatureScheme, SinkWebhookConfiguration, SpoolConfiguration,
+    StatusCodeMatcher, TargetCredentials, TlsConfiguration, WebhookSink, WebhookTarget,
+    DEFAULT_CONNECT_TIMEOUT, DEFAULT_MAX_RESPONSE_BODY_BYTES, DEFAULT_REQUEST_TIMEOUT,
+    DEFAULT_USER_AGENT,
+};
 use error_stack::{Result, ResultExt};
-use http::{HeaderMap, Uri};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Uri};
 use serde_json::{json, Value};
 
 fn new_batch(start_cursor: &Option<Cursor>, end_cursor: &Cursor) -> Value {
@@ -31,19 +43,91 @@ fn new_cursor(order_key: u64) -> Cursor {
     }
 }
 
+/// Starts a mock server that accepts all requests.
+async fn start_server() -> wiremock::MockServer {
+    let server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+    server
+}
+
+fn new_config(
+    server: &wiremock::MockServer,
+    raw: bool,
+) -> Result<SinkWebhookConfiguration, SinkError> {
+    let target_url = server
+        .uri()
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    Ok(new_config_with_url(target_url, raw))
+}
+
+/// Returns the default configuration of a sink that sends data to `target_url`.
+fn new_config_with_url(target_url: Uri, raw: bool) -> SinkWebhookConfiguration {
+    SinkWebhookConfiguration {
+        target_url,
+        method: Method::POST,
+        headers: HeaderMap::new(),
+        fan_out_targets: Vec::default(),
+        fan_out_mode: FanOutMode::AllMustSucceed,
+        raw,
+        raw_failure_mode: RawFailureMode::FailBatch,
+        raw_item_max_retries: 0,
+        raw_concurrency: 1,
+        max_in_flight_batches: 1,
+        raw_invalidate: false,
+        invalidate_failure_mode: InvalidateFailureMode::Halt,
+        invalidate_max_retries: 0,
+        raw_batch: false,
+        context_headers: ContextHeaders::default(),
+        compression: None,
+        compression_threshold_bytes: 0,
+        max_body_bytes: None,
+        split_oversized_batches: false,
+        sample: None,
+        body_format: BodyFormat::Json,
+        envelope: Envelope::Nested,
+        pretty_json: false,
+        enrichment: None,
+        skip_empty: false,
+        circuit_breaker: None,
+        spool: None,
+        retry: None,
+        retryable_status: vec![StatusCodeMatcher::Class(5), StatusCodeMatcher::Code(429)],
+        skip_status: Vec::default(),
+        require_ack: false,
+        signature: None,
+        idempotency_key_header: None,
+        request_id_header: None,
+        user_agent: HeaderValue::from_static(DEFAULT_USER_AGENT),
+        oauth: None,
+        basic_auth: None,
+        query_api_key: None,
+        dead_letter_url: None,
+        preflight_method: None,
+        request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        max_response_body_bytes: DEFAULT_MAX_RESPONSE_BODY_BYTES,
+        tls: TlsConfiguration::default(),
+    }
+}
+
+fn header_value(request: &wiremock::Request, name: &str) -> Option<String> {
+    request
+        .headers
+        .iter()
+        .find(|(key, _)| key.as_str() == name)
+        .map(|(_, values)| values.last().as_str().to_string())
+}
+
 #[tokio::test]
 #[ignore]
 async fn test_handle_data() -> Result<(), SinkError> {
-    let server = wiremock::MockServer::start().await;
+    let server = start_server().await;
 
-    let config = SinkWebhookConfiguration {
-        target_url: server
-            .uri()
-            .parse::<Uri>()
-            .change_context(SinkError::Runtime)?,
-        headers: HeaderMap::new(),
-        raw: false,
-    };
+    let config = new_config(&server, false)?;
 
     let mut sink = WebhookSink::new(config);
 
@@ -88,17 +172,36 @@ async fn test_handle_data() -> Result<(), SinkError> {
 
 #[tokio::test]
 #[ignore]
-async fn test_handle_invalidate() -> Result<(), SinkError> {
-    let server = wiremock::MockServer::start().await;
+async fn test_handle_data_put() -> Result<(), SinkError> {
+    let server = start_server().await;
 
-    let config = SinkWebhookConfiguration {
-        target_url: server
-            .uri()
-            .parse::<Uri>()
-            .change_context(SinkError::Runtime)?,
-        headers: HeaderMap::new(),
-        raw: false,
+    let mut config = new_config(&server, false)?;
+    config.method = Method::PUT;
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
     };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method.to_string(), "PUT");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_invalidate() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let config = new_config(&server, false)?;
 
     let mut sink = WebhookSink::new(config);
 
@@ -128,17 +231,45 @@ async fn test_handle_invalidate() -> Result<(), SinkError> {
 
 #[tokio::test]
 #[ignore]
-async fn test_handle_data_raw() -> Result<(), SinkError> {
-    let server = wiremock::MockServer::start().await;
+async fn test_handle_invalidate_range() -> Result<(), SinkError> {
+    let server = start_server().await;
 
-    let config = SinkWebhookConfiguration {
-        target_url: server
-            .uri()
-            .parse::<Uri>()
+    let config = new_config(&server, false)?;
+
+    let mut sink = WebhookSink::new(config);
+
+    let cursor = Some(new_cursor(10));
+    let invalidated = InvalidatedRange::new(&cursor, Some(new_cursor(13)));
+
+    sink.handle_invalidate_range(&cursor, invalidated.as_ref())
+        .await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0]
+            .body_json::<Value>()
             .change_context(SinkError::Runtime)?,
-        headers: HeaderMap::new(),
-        raw: true,
-    };
+        json!({
+            "invalidate": {
+                "cursor": &cursor,
+                "invalidated": {
+                    "start_order_key": 11,
+                    "end_cursor": new_cursor(13),
+                },
+            }
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let config = new_config(&server, true)?;
 
     let mut sink = WebhookSink::new(config);
 
@@ -178,17 +309,168 @@ async fn test_handle_data_raw() -> Result<(), SinkError> {
 
 #[tokio::test]
 #[ignore]
-async fn test_handle_invalidate_raw() -> Result<(), SinkError> {
-    let server = wiremock::MockServer::start().await;
+async fn test_handle_data_raw_batch() -> Result<(), SinkError> {
+    let server = start_server().await;
 
-    let config = SinkWebhookConfiguration {
-        target_url: server
-            .uri()
-            .parse::<Uri>()
+    let mut config = new_config(&server, true)?;
+    config.raw_batch = true;
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(5),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0]
+            .body_json::<Value>()
             .change_context(SinkError::Runtime)?,
-        headers: HeaderMap::new(),
-        raw: true,
+        batch
+    );
+    assert_eq!(header_value(&requests[0], "x-dna-cursor"), None);
+    assert_eq!(
+        header_value(&requests[0], "x-dna-end-cursor").as_deref(),
+        Some("5")
+    );
+    assert_eq!(
+        header_value(&requests[0], "x-dna-finality").as_deref(),
+        Some("finalized")
+    );
+    assert_eq!(header_value(&requests[0], "x-dna-item-index"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw_context_headers() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, true)?;
+    config.context_headers =
+        ContextHeaders::with_prefix("x-chain").change_context(SinkError::Runtime)?;
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: Some(new_cursor(3)),
+        end_cursor: new_cursor(5),
+        finality: DataFinality::DataStatusAccepted,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), batch.as_array().unwrap().len());
+    for (index, request) in requests.iter().enumerate() {
+        assert_eq!(
+            header_value(request, "x-chain-cursor").as_deref(),
+            Some("3")
+        );
+        assert_eq!(
+            header_value(request, "x-chain-end-cursor").as_deref(),
+            Some("5")
+        );
+        assert_eq!(
+            header_value(request, "x-chain-finality").as_deref(),
+            Some("accepted")
+        );
+        assert_eq!(
+            header_value(request, "x-chain-item-index"),
+            Some(index.to_string())
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw_concurrency() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, true)?;
+    config.raw_concurrency = 4;
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(10),
+        finality: DataFinality::DataStatusFinalized,
     };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    let action = sink.handle_data(&ctx, &batch).await?;
+    assert_eq!(action, CursorAction::Persist);
+
+    // all items are sent, in any order.
+    let requests = server.received_requests().await.unwrap();
+    let mut block_nums = requests
+        .iter()
+        .map(|request| {
+            let body = request.body_json::<Value>().unwrap();
+            body["block_num"].as_u64().unwrap()
+        })
+        .collect::<Vec<_>>();
+    block_nums.sort();
+    assert_eq!(block_nums, (0..10).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_invalidate_failure_mode() -> Result<(), SinkError> {
+    let server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(503))
+        .up_to_n_times(3)
+        .mount(&server)
+        .await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let cursor = Some(new_cursor(1));
+
+    let config = new_config(&server, false)?;
+    let mut sink = WebhookSink::new(config);
+    assert!(sink.handle_invalidate(&cursor).await.is_err());
+
+    let mut config = new_config(&server, false)?;
+    config.invalidate_failure_mode = InvalidateFailureMode::Continue;
+    let mut sink = WebhookSink::new(config);
+    sink.handle_invalidate(&cursor).await?;
+
+    // fails once more, then succeeds.
+    let mut config = new_config(&server, false)?;
+    config.invalidate_failure_mode = InvalidateFailureMode::RetryThenContinue;
+    config.invalidate_max_retries = 2;
+    let mut sink = WebhookSink::new(config);
+    sink.handle_invalidate(&cursor).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 4);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_invalidate_raw() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let config = new_config(&server, true)?;
 
     let mut sink = WebhookSink::new(config);
 
@@ -203,3 +485,2006 @@ async fn test_handle_invalidate_raw() -> Result<(), SinkError> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_invalidate_raw_enabled() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, true)?;
+    config.raw_invalidate = true;
+
+    let mut sink = WebhookSink::new(config);
+
+    let cursor = Some(new_cursor(3));
+    sink.handle_invalidate(&cursor).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0]
+            .body_json::<Value>()
+            .change_context(SinkError::Runtime)?,
+        json!({
+            "invalidate": {
+                "cursor": &cursor,
+            },
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_zstd() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, false)?;
+    config.compression = Some(Compression::Zstd);
+    config.compression_threshold_bytes = 256;
+
+    let mut sink = WebhookSink::new(config);
+
+    let finality = DataFinality::DataStatusFinalized;
+
+    // Small batch: below threshold, sent uncompressed.
+    let end_cursor = new_cursor(1);
+    let batch = new_batch(&None, &end_cursor);
+    let ctx = Context {
+        cursor: None,
+        end_cursor: end_cursor.clone(),
+        finality,
+    };
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    let request = requests.last().unwrap();
+    assert!(header_value(request, "content-encoding").is_none());
+    assert_eq!(
+        request
+            .body_json::<Value>()
+            .change_context(SinkError::Runtime)?["data"]["batch"],
+        batch
+    );
+
+    // Large batch: above threshold, sent compressed.
+    let end_cursor = new_cursor(100);
+    let batch = new_batch(&None, &end_cursor);
+    let ctx = Context {
+        cursor: None,
+        end_cursor: end_cursor.clone(),
+        finality,
+    };
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    let request = requests.last().unwrap();
+    assert_eq!(
+        header_value(request, "content-encoding"),
+        Some("zstd".to_string())
+    );
+    let body = zstd::decode_all(request.body.as_slice()).change_context(SinkError::Runtime)?;
+    let body: Value = serde_json::from_slice(&body).change_context(SinkError::Runtime)?;
+    assert_eq!(
+        body,
+        json!({
+            "data": {
+                "cursor": None::<Cursor>,
+                "end_cursor": &end_cursor,
+                "finality": &finality,
+                "batch": &batch,
+            },
+        })
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw_gzip_signature() -> Result<(), SinkError> {
+    use std::io::Read;
+
+    let server = start_server().await;
+
+    let signature = SignatureConfiguration {
+        secret: b"secret".to_vec(),
+        scheme: SignatureScheme::HmacSha256,
+        header: HeaderName::from_static("x-signature"),
+    };
+    let mut config = new_config(&server, true)?;
+    config.raw_batch = true;
+    config.compression = Some(Compression::Gzip);
+    config.signature = Some(signature.clone());
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(10),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    let request = requests.last().unwrap();
+    assert_eq!(
+        header_value(request, "content-encoding").as_deref(),
+        Some("gzip")
+    );
+    // the signature is computed over the compressed body.
+    assert_eq!(
+        header_value(request, "x-signature").as_deref(),
+        signature.sign(&request.body).to_str().ok()
+    );
+
+    let mut body = Vec::new();
+    flate2::read::GzDecoder::new(request.body.as_slice())
+        .read_to_end(&mut body)
+        .change_context(SinkError::Runtime)?;
+    let body: Value = serde_json::from_slice(&body).change_context(SinkError::Runtime)?;
+    assert_eq!(body, batch);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_max_body_bytes() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, false)?;
+    config.max_body_bytes = Some(256);
+
+    let mut sink = WebhookSink::new(config);
+
+    let cursor = Some(new_cursor(0));
+    let end_cursor = new_cursor(10);
+    let batch = new_batch(&cursor, &end_cursor);
+    let ctx = Context {
+        cursor,
+        end_cursor,
+        finality: DataFinality::DataStatusFinalized,
+    };
+
+    assert!(sink.handle_data(&ctx, &batch).await.is_err());
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_split_oversized_batches() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, false)?;
+    config.max_body_bytes = Some(256);
+    config.split_oversized_batches = true;
+
+    let mut sink = WebhookSink::new(config);
+
+    let cursor = Some(new_cursor(0));
+    let end_cursor = new_cursor(10);
+    let batch = new_batch(&cursor, &end_cursor);
+    let ctx = Context {
+        cursor,
+        end_cursor,
+        finality: DataFinality::DataStatusFinalized,
+    };
+
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert!(requests.len() > 1);
+
+    let mut received = Vec::new();
+    for request in &requests {
+        assert!(request.body.len() <= 256);
+        let body = request
+            .body_json::<Value>()
+            .change_context(SinkError::Runtime)?;
+        received.extend(body["data"]["batch"].as_array().unwrap().clone());
+    }
+    assert_eq!(Value::from(received), batch);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_pretty_json() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, false)?;
+    config.pretty_json = true;
+
+    let mut sink = WebhookSink::new(config);
+
+    let cursor = Some(new_cursor(0));
+    let end_cursor = new_cursor(2);
+    let batch = new_batch(&cursor, &end_cursor);
+    let ctx = Context {
+        cursor,
+        end_cursor,
+        finality: DataFinality::DataStatusFinalized,
+    };
+
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    let body = String::from_utf8(requests[0].body.clone()).change_context(SinkError::Runtime)?;
+    let expected = serde_json::to_string_pretty(&json!({
+        "data": {
+            "cursor": &ctx.cursor,
+            "end_cursor": &ctx.end_cursor,
+            "finality": &ctx.finality,
+            "batch": &batch,
+        },
+    }))
+    .change_context(SinkError::Runtime)?;
+    assert_eq!(body, expected);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_ndjson() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, false)?;
+    config.body_format = BodyFormat::Ndjson;
+
+    let mut sink = WebhookSink::new(config);
+
+    let cursor = Some(new_cursor(0));
+    let end_cursor = new_cursor(3);
+    let batch = new_batch(&cursor, &end_cursor);
+    let ctx = Context {
+        cursor,
+        end_cursor,
+        finality: DataFinality::DataStatusFinalized,
+    };
+
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        header_value(&requests[0], "content-type").as_deref(),
+        Some("application/x-ndjson")
+    );
+    let body = String::from_utf8(requests[0].body.clone()).change_context(SinkError::Runtime)?;
+    assert!(body.ends_with('\n'));
+    let lines = body
+        .lines()
+        .map(serde_json::from_str::<Value>)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .change_context(SinkError::Runtime)?;
+    assert_eq!(lines.len(), 3);
+    for (line, item) in lines.iter().zip(batch.as_array().unwrap()) {
+        assert_eq!(line["data"]["end_cursor"], json!(&ctx.end_cursor));
+        assert_eq!(line["data"]["batch"], json!([item]));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_envelope() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let ctx = Context {
+        cursor: Some(new_cursor(0)),
+        end_cursor: new_cursor(3),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    for envelope in [Envelope::Nested, Envelope::Flat, Envelope::Meta] {
+        let mut config = new_config(&server, false)?;
+        config.envelope = envelope;
+        let mut sink = WebhookSink::new(config);
+        sink.handle_data(&ctx, &batch).await?;
+    }
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 3);
+    let bodies = requests
+        .iter()
+        .map(|request| serde_json::from_slice::<Value>(&request.body))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .change_context(SinkError::Runtime)?;
+
+    // the default envelope is unchanged.
+    let metadata = json!({
+        "cursor": &ctx.cursor,
+        "end_cursor": &ctx.end_cursor,
+        "finality": &ctx.finality,
+    });
+    let mut data = metadata.clone();
+    data["batch"] = batch.clone();
+    assert_eq!(bodies[0], json!({ "data": data }));
+    assert_eq!(bodies[1], data);
+    assert_eq!(bodies[2], json!({ "batch": batch, "meta": metadata }));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_skip_empty() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, false)?;
+    config.skip_empty = true;
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: Some(new_cursor(0)),
+        end_cursor: new_cursor(3),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    for batch in [json!([]), Value::Null] {
+        let action = sink.handle_data(&ctx, &batch).await?;
+        assert_eq!(action, CursorAction::Persist);
+    }
+    assert!(server.received_requests().await.unwrap().is_empty());
+
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+    sink.handle_data(&ctx, &batch).await?;
+    assert_eq!(server.received_requests().await.unwrap().len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_msgpack() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, false)?;
+    config.body_format = BodyFormat::MessagePack;
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: Some(new_cursor(0)),
+        end_cursor: new_cursor(3),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        header_value(&requests[0], "content-type").as_deref(),
+        Some("application/msgpack")
+    );
+    let body: Value =
+        rmp_serde::from_slice(&requests[0].body).change_context(SinkError::Runtime)?;
+    assert_eq!(body["data"]["end_cursor"], json!(&ctx.end_cursor));
+    assert_eq!(body["data"]["batch"], batch);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw_batch_msgpack_gzip() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, true)?;
+    config.raw_batch = true;
+    config.body_format = BodyFormat::MessagePack;
+    config.compression = Some(Compression::Gzip);
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(10),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    let request = requests.last().unwrap();
+    assert_eq!(
+        header_value(request, "content-type").as_deref(),
+        Some("application/msgpack")
+    );
+    assert_eq!(
+        header_value(request, "content-encoding").as_deref(),
+        Some("gzip")
+    );
+
+    // compression applies to the encoded body.
+    let mut body = Vec::new();
+    flate2::read::GzDecoder::new(request.body.as_slice())
+        .read_to_end(&mut body)
+        .change_context(SinkError::Runtime)?;
+    let body: Value = rmp_serde::from_slice(&body).change_context(SinkError::Runtime)?;
+    assert_eq!(body, batch);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw_batch_ndjson() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let mut config = new_config(&server, true)?;
+    config.raw_batch = true;
+    config.body_format = BodyFormat::Ndjson;
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(2),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    let body = String::from_utf8(requests[0].body.clone()).change_context(SinkError::Runtime)?;
+    assert_eq!(
+        body,
+        "{\"block_num\":0,\"block_str\":\"block_0\"}\n{\"block_num\":1,\"block_str\":\"block_1\"}\n"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_sample() -> Result<(), SinkError> {
+    let server = start_server().await;
+    let dir = tempdir::TempDir::new("webhook-sample").change_context(SinkError::Runtime)?;
+    let path = dir.path().join("samples.jsonl");
+
+    let mut config = new_config(&server, false)?;
+    config.sample = Some(PayloadSampleConfiguration {
+        path: path.clone(),
+        rate: 0.5,
+        max_file_bytes: 1024 * 1024,
+    });
+
+    let mut sink = WebhookSink::new(config);
+
+    let mut bodies = Vec::new();
+    for order_key in 0..4 {
+        let cursor = Some(new_cursor(order_key * 2));
+        let end_cursor = new_cursor((order_key + 1) * 2);
+        let batch = new_batch(&cursor, &end_cursor);
+        let ctx = Context {
+            cursor,
+            end_cursor,
+            finality: DataFinality::DataStatusFinalized,
+        };
+
+        sink.handle_data(&ctx, &batch).await?;
+    }
+
+    for request in server.received_requests().await.unwrap() {
+        bodies.push(
+            request
+                .body_json::<Value>()
+                .change_context(SinkError::Runtime)?,
+        );
+    }
+    assert_eq!(bodies.len(), 4);
+
+    let samples = std::fs::read_to_string(&path).change_context(SinkError::Runtime)?;
+    let samples = samples
+        .lines()
+        .map(serde_json::from_str::<Value>)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .change_context(SinkError::Runtime)?;
+    assert_eq!(samples, vec![bodies[1].clone(), bodies[3].clone()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_connection_closed_is_temporary() -> Result<(), SinkError> {
+    // accept connections and close them without reading the body.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .change_context(SinkError::Runtime)?;
+    let address = listener.local_addr().change_context(SinkError::Runtime)?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            drop(stream);
+        }
+    });
+
+    let target_url = format!("http://{address}")
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    let config = new_config_with_url(target_url, true);
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = json!([{ "data": "x".repeat(16 * 1024 * 1024) }]);
+
+    let err = sink.handle_data(&ctx, &batch).await.unwrap_err();
+    assert!(matches!(err.current_context(), SinkError::Temporary));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw_skip_item() -> Result<(), SinkError> {
+    // accept connections and close them without reading the body.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .change_context(SinkError::Runtime)?;
+    let address = listener.local_addr().change_context(SinkError::Runtime)?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            drop(stream);
+        }
+    });
+
+    let server = start_server().await;
+    let mut config = new_config(&server, true)?;
+    config.target_url = format!("http://{address}")
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    config.raw_failure_mode = RawFailureMode::SkipItem;
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = json!([{ "data": "x".repeat(16 * 1024 * 1024) }]);
+
+    let action = sink.handle_data(&ctx, &batch).await?;
+    assert_eq!(action, CursorAction::Persist);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw_retry_item() -> Result<(), SinkError> {
+    use wiremock::{
+        matchers::{body_json, method},
+        Mock, ResponseTemplate,
+    };
+
+    let server = wiremock::MockServer::start().await;
+    // the second item fails twice, then succeeds.
+    Mock::given(method("POST"))
+        .and(body_json(json!({ "item": 1 })))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(2)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .with_priority(2)
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, true)?;
+    config.raw_failure_mode = RawFailureMode::RetryItem;
+    config.raw_item_max_retries = 2;
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = json!([{ "item": 0 }, { "item": 1 }, { "item": 2 }]);
+
+    let action = sink.handle_data(&ctx, &batch).await?;
+    assert_eq!(action, CursorAction::Persist);
+
+    // only the failed item is sent again.
+    let items = server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| serde_json::from_slice::<Value>(&request.body).unwrap()["item"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        items,
+        vec![json!(0), json!(1), json!(1), json!(1), json!(2)]
+    );
+
+    // the batch fails once the item runs out of retries.
+    let server = wiremock::MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_json(json!({ "item": 1 })))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+    let mut config = new_config(&server, true)?;
+    config.raw_failure_mode = RawFailureMode::RetryItem;
+    config.raw_item_max_retries = 2;
+    let mut sink = WebhookSink::new(config);
+    assert!(sink.handle_data(&ctx, &batch).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_spool() -> Result<(), SinkError> {
+    let dir = tempdir::TempDir::new("webhook-spool").change_context(SinkError::Runtime)?;
+    let spool = SpoolConfiguration {
+        path: dir.path().join("spool"),
+        max_bytes: 1024 * 1024,
+    };
+
+    // accept connections and close them without reading the body.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .change_context(SinkError::Runtime)?;
+    let address = listener.local_addr().change_context(SinkError::Runtime)?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            drop(stream);
+        }
+    });
+
+    let server = start_server().await;
+    let mut config = new_config(&server, false)?;
+    config.target_url = format!("http://{address}")
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    config.spool = Some(spool.clone());
+
+    let mut sink = WebhookSink::new(config);
+
+    let mut batches = Vec::default();
+    for order_key in 0..2 {
+        let ctx = Context {
+            cursor: Some(new_cursor(order_key)),
+            end_cursor: new_cursor(order_key + 1),
+            finality: DataFinality::DataStatusFinalized,
+        };
+        let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+        let action = sink.handle_data(&ctx, &batch).await?;
+        assert_eq!(action, CursorAction::Persist);
+        batches.push(batch);
+    }
+
+    // the endpoint is back, spooled data is sent in order.
+    let mut config = new_config(&server, false)?;
+    config.spool = Some(spool);
+    let mut sink = WebhookSink::new(config);
+    sink.handle_heartbeat().await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), batches.len());
+    for (request, batch) in requests.iter().zip(batches.iter()) {
+        let body = request
+            .body_json::<Value>()
+            .change_context(SinkError::Runtime)?;
+        assert_eq!(&body["data"]["batch"], batch);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_retry() -> Result<(), SinkError> {
+    let server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .mount(&server)
+        .await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.retry = Some(RetryConfiguration {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+        jitter: 0.0,
+    });
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    let action = sink.handle_data(&ctx, &batch).await?;
+    assert_eq!(action, CursorAction::Persist);
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_user_agent_and_request_id() -> Result<(), SinkError> {
+    let server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.request_id_header = Some(HeaderName::from_static("x-request-id"));
+    config.user_agent = HeaderValue::from_static("my-indexer/1.0");
+    config.retry = Some(RetryConfiguration {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+        jitter: 0.0,
+    });
+
+    let mut sink = WebhookSink::new(config);
+
+    for order_key in [1, 2] {
+        let ctx = Context {
+            cursor: None,
+            end_cursor: new_cursor(order_key),
+            finality: DataFinality::DataStatusFinalized,
+        };
+        let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+        sink.handle_data(&ctx, &batch).await?;
+    }
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 3);
+    for request in &requests {
+        assert_eq!(
+            header_value(request, "user-agent"),
+            Some("my-indexer/1.0".to_string())
+        );
+    }
+
+    let ids = requests
+        .iter()
+        .map(|request| header_value(request, "x-request-id").unwrap())
+        .collect::<Vec<_>>();
+    // the retry has the same id as the failed request.
+    assert_eq!(ids[0], ids[1]);
+    assert_ne!(ids[1], ids[2]);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_request_id_is_kept_when_batch_is_sent_again() -> Result<(), SinkError> {
+    let server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.request_id_header = Some(HeaderName::from_static("x-request-id"));
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    // the sink doesn't retry, the batch is sent again by the caller.
+    assert!(sink.handle_data(&ctx, &batch).await.is_err());
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(
+        header_value(&requests[0], "x-request-id"),
+        header_value(&requests[1], "x-request-id")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_identical_raw_items_have_different_request_ids() -> Result<(), SinkError>
+{
+    let server = wiremock::MockServer::start().await;
+    // the second item fails once.
+    wiremock::Mock::given(wiremock::matchers::header("x-dna-item-index", "1"))
+        .respond_with(wiremock::ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(200))
+        .with_priority(2)
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, true)?;
+    config.request_id_header = Some(HeaderName::from_static("x-request-id"));
+    config.retry = Some(RetryConfiguration {
+        max_attempts: 2,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+        jitter: 0.0,
+    });
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = json!([{ "item": 0 }, { "item": 0 }]);
+    sink.handle_data(&ctx, &batch).await?;
+
+    let ids = server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|request| header_value(request, "x-request-id").unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ids.len(), 3);
+    // each item has its own id, kept by the retry.
+    assert_ne!(ids[0], ids[1]);
+    assert_eq!(ids[1], ids[2]);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_client_error_is_fatal() -> Result<(), SinkError> {
+    let server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(400))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.retry = Some(RetryConfiguration {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+        jitter: 0.0,
+    });
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    let err = sink.handle_data(&ctx, &batch).await.unwrap_err();
+    assert!(matches!(err.current_context(), SinkError::Fatal));
+
+    // fatal errors are not retried.
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_timeout_is_temporary() -> Result<(), SinkError> {
+    let server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.request_timeout = Duration::from_millis(100);
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    let err = sink.handle_data(&ctx, &batch).await.unwrap_err();
+    assert!(matches!(err.current_context(), SinkError::Temporary));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw_signature() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let signature = SignatureConfiguration {
+        secret: b"shared-secret".to_vec(),
+        scheme: SignatureScheme::HmacSha256,
+        header: HeaderName::from_static("x-signature"),
+    };
+
+    let mut config = new_config(&server, true)?;
+    config.signature = Some(signature.clone());
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(2),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    sink.handle_data(&ctx, &batch).await?;
+
+    // every item is signed on its own.
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    for request in &requests {
+        let expected = signature.sign(&request.body);
+        assert_eq!(
+            header_value(request, "x-signature").as_deref(),
+            expected.to_str().ok()
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_oauth_refresh_on_unauthorized() -> Result<(), SinkError> {
+    use wiremock::{
+        matchers::{body_string_contains, header, method, path},
+        Mock, ResponseTemplate,
+    };
+
+    let server = wiremock::MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/token"))
+        .and(body_string_contains("grant_type=client_credentials"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "access_token": "token",
+            "token_type": "Bearer",
+            "expires_in": 3600,
+        })))
+        .mount(&server)
+        .await;
+    // reject the first token, as if it was revoked.
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(401))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(header("authorization", "Bearer token"))
+        .respond_with(ResponseTemplate::new(200))
+        .with_priority(2)
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.target_url = format!("{}/hook", server.uri())
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    config.oauth = Some(OAuthConfiguration {
+        token_url: format!("{}/token", server.uri()),
+        client_id: "client".to_string(),
+        client_secret: "secret".to_string(),
+        scope: Some("webhook".to_string()),
+    });
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&None, &new_cursor(1));
+
+    let action = sink.handle_data(&ctx, &batch).await?;
+    assert_eq!(action, CursorAction::Persist);
+
+    // the token is requested again after the 401, then cached.
+    let ctx = Context {
+        cursor: Some(new_cursor(1)),
+        end_cursor: new_cursor(2),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    let paths = requests
+        .iter()
+        .map(|request| request.url.path())
+        .collect::<Vec<_>>();
+    assert_eq!(paths, vec!["/token", "/hook", "/token", "/hook", "/hook"]);
+    for request in requests.iter().filter(|r| r.url.path() == "/hook") {
+        assert_eq!(
+            header_value(request, "authorization").as_deref(),
+            Some("Bearer token")
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_response_cursor_action() -> Result<(), SinkError> {
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, ResponseTemplate,
+    };
+
+    let server = wiremock::MockServer::start().await;
+    // the endpoint is busy with the first batch, and rate limits the second.
+    Mock::given(method("POST"))
+        .and(body_string_contains("block_2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "ok": true })))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("block_1"))
+        .respond_with(ResponseTemplate::new(418).insert_header("retry-after", "7"))
+        .with_priority(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "cursor_action": "skip" })))
+        .with_priority(3)
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.skip_status = vec![StatusCodeMatcher::Code(418)];
+
+    let mut sink = WebhookSink::new(config);
+
+    for (order_key, expected_retry_after) in
+        [(1, None), (2, Some(RetryAfter(Duration::from_secs(7))))]
+    {
+        let ctx = Context {
+            cursor: None,
+            end_cursor: new_cursor(order_key),
+            finality: DataFinality::DataStatusFinalized,
+        };
+        let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+        // the data is sent again later.
+        let err = sink.handle_data(&ctx, &batch).await.unwrap_err();
+        assert!(matches!(err.current_context(), SinkError::Temporary));
+        assert_eq!(
+            err.downcast_ref::<RetryAfter>(),
+            expected_retry_after.as_ref()
+        );
+    }
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(3),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+    assert_eq!(sink.handle_data(&ctx, &batch).await?, CursorAction::Persist);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_retry_after() -> Result<(), SinkError> {
+    let server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(429).insert_header("retry-after", "1"))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.retry = Some(RetryConfiguration {
+        max_attempts: 2,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+        jitter: 0.0,
+    });
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    let started_at = std::time::Instant::now();
+    let action = sink.handle_data(&ctx, &batch).await?;
+    assert_eq!(action, CursorAction::Persist);
+    // the retry waits for the delay asked by the endpoint, not the retry delay.
+    assert!(started_at.elapsed() >= Duration::from_secs(1));
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_concurrently() -> Result<(), SinkError> {
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, ResponseTemplate,
+    };
+
+    let server = wiremock::MockServer::start().await;
+    // the first batch is delivered last, the second asks to be sent again later.
+    Mock::given(method("POST"))
+        .and(body_string_contains("block_5"))
+        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_millis(200)))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("block_6"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "cursor_action": "skip" })))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("block_7"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.max_in_flight_batches = 3;
+
+    let sink = WebhookSink::new(config);
+    let sink = sink.concurrent().unwrap();
+    assert_eq!(sink.max_in_flight_batches(), 3);
+
+    let contexts = (5..8)
+        .map(|order_key| Context {
+            cursor: Some(new_cursor(order_key)),
+            end_cursor: new_cursor(order_key + 1),
+            finality: DataFinality::DataStatusFinalized,
+        })
+        .collect::<Vec<_>>();
+    let mut results = futures::future::join_all(contexts.iter().map(|ctx| {
+        let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+        async move { sink.handle_data_concurrently(ctx, &batch).await }
+    }))
+    .await
+    .into_iter();
+
+    assert_eq!(
+        results.next().unwrap()?,
+        DeliveredBatch {
+            action: CursorAction::Persist,
+            delivered_cursor: Some(new_cursor(6)),
+        }
+    );
+    let err = results.next().unwrap().unwrap_err();
+    assert!(matches!(err.current_context(), SinkError::Temporary));
+    assert_eq!(
+        results.next().unwrap()?,
+        DeliveredBatch {
+            action: CursorAction::Persist,
+            delivered_cursor: Some(new_cursor(8)),
+        }
+    );
+    assert_eq!(server.received_requests().await.unwrap().len(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_max_response_body_bytes() -> Result<(), SinkError> {
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, ResponseTemplate,
+    };
+
+    let server = wiremock::MockServer::start().await;
+    // a large error page for batches that contain block 5.
+    Mock::given(method("POST"))
+        .and(body_string_contains("block_5"))
+        .respond_with(ResponseTemplate::new(500).set_body_string("x".repeat(1024 * 1024)))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "cursor_action": "skip" })))
+        .with_priority(2)
+        .mount(&server)
+        .await;
+
+    let send = |max_response_body_bytes: usize, order_key: u64| {
+        let server = &server;
+        async move {
+            let mut config = new_config(server, false)?;
+            config.max_response_body_bytes = max_response_body_bytes;
+            let mut sink = WebhookSink::new(config);
+            let ctx = Context {
+                cursor: None,
+                end_cursor: new_cursor(order_key),
+                finality: DataFinality::DataStatusFinalized,
+            };
+            let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+            sink.handle_data(&ctx, &batch).await
+        }
+    };
+
+    // the status is handled whatever the size of the body.
+    assert!(send(16, 6).await.is_err());
+    // the directive is truncated, so it's ignored.
+    assert_eq!(send(16, 2).await?, CursorAction::Persist);
+    assert!(send(DEFAULT_MAX_RESPONSE_BODY_BYTES, 2).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_require_ack() -> Result<(), SinkError> {
+    use wiremock::{
+        matchers::{body_string_contains, method},
+        Mock, ResponseTemplate,
+    };
+
+    let server = wiremock::MockServer::start().await;
+    // the endpoint stored the data up to block 2.
+    Mock::given(method("POST"))
+        .and(body_string_contains("block_1"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({ "ack_cursor": new_cursor(2) })),
+        )
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "ok": true })))
+        .with_priority(2)
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.require_ack = true;
+
+    let mut sink = WebhookSink::new(config);
+
+    for (order_key, acknowledged) in [
+        // not acknowledged.
+        (1, false),
+        (2, true),
+        // acknowledged with the wrong cursor.
+        (3, false),
+    ] {
+        let ctx = Context {
+            cursor: None,
+            end_cursor: new_cursor(order_key),
+            finality: DataFinality::DataStatusFinalized,
+        };
+        let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+        let result = sink.handle_data(&ctx, &batch).await;
+        if acknowledged {
+            assert_eq!(result.unwrap(), CursorAction::Persist);
+        } else {
+            // data that isn't acknowledged is retried.
+            let err = result.unwrap_err();
+            assert!(matches!(err.current_context(), SinkError::Temporary));
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_delivered_cursor() -> Result<(), SinkError> {
+    let server = start_server().await;
+    let config = new_config(&server, false)?;
+
+    let mut sink = WebhookSink::new(config);
+    // a previous run delivered data up to block 4, but only persisted block 2.
+    sink.restore_delivered_cursor(Some(new_cursor(4))).await?;
+
+    for (order_key, finality, expected_requests) in [
+        (3, DataFinality::DataStatusFinalized, 0),
+        (4, DataFinality::DataStatusFinalized, 0),
+        (5, DataFinality::DataStatusFinalized, 1),
+        (6, DataFinality::DataStatusAccepted, 2),
+    ] {
+        let ctx = Context {
+            cursor: Some(new_cursor(order_key - 1)),
+            end_cursor: new_cursor(order_key),
+            finality,
+        };
+        let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+        let action = sink.handle_data(&ctx, &batch).await?;
+        assert_eq!(action, CursorAction::Persist);
+
+        let requests = server.received_requests().await.unwrap();
+        assert_eq!(requests.len(), expected_requests);
+    }
+
+    // only finalized data counts as delivered.
+    assert_eq!(sink.delivered_cursor(), Some(new_cursor(5)));
+
+    sink.handle_invalidate(&Some(new_cursor(3))).await?;
+    assert_eq!(sink.delivered_cursor(), Some(new_cursor(3)));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_dead_letter() -> Result<(), SinkError> {
+    use wiremock::{
+        matchers::{method, path},
+        Mock, ResponseTemplate,
+    };
+
+    let server = wiremock::MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(400))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/dead-letter"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.target_url = format!("{}/hook", server.uri())
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    config.dead_letter_url = Some(
+        format!("{}/dead-letter", server.uri())
+            .parse::<Uri>()
+            .change_context(SinkError::Runtime)?,
+    );
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: Some(new_cursor(1)),
+        end_cursor: new_cursor(2),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    // the sink continues with the next batch.
+    let action = sink.handle_data(&ctx, &batch).await?;
+    assert_eq!(action, CursorAction::Persist);
+
+    let requests = server.received_requests().await.unwrap();
+    let dead_letter = requests
+        .iter()
+        .find(|request| request.url.path() == "/dead-letter")
+        .expect("dead-letter request");
+    let body = dead_letter
+        .body_json::<Value>()
+        .change_context(SinkError::Runtime)?;
+    assert_eq!(body["dead_letter"]["cursor"], json!(&ctx.cursor));
+    assert_eq!(body["dead_letter"]["end_cursor"], json!(&ctx.end_cursor));
+    assert_eq!(body["dead_letter"]["batch"], batch);
+    assert!(body["dead_letter"]["error"].is_string());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_dead_letter_after_retries() -> Result<(), SinkError> {
+    use wiremock::{
+        matchers::{body_string_contains, method, path},
+        Mock, ResponseTemplate,
+    };
+
+    let server = wiremock::MockServer::start().await;
+    // the first batch fails once, the second batch always fails.
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(body_string_contains("block_1"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/dead-letter"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.target_url = format!("{}/hook", server.uri())
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    config.dead_letter_url = Some(
+        format!("{}/dead-letter", server.uri())
+            .parse::<Uri>()
+            .change_context(SinkError::Runtime)?,
+    );
+    config.retry = Some(RetryConfiguration {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+        jitter: 0.0,
+    });
+
+    let mut sink = WebhookSink::new(config);
+
+    let paths = |requests: Vec<wiremock::Request>| {
+        requests
+            .iter()
+            .map(|request| request.url.path().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // the retry succeeds, so the batch is not dead-lettered.
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+    assert_eq!(sink.handle_data(&ctx, &batch).await?, CursorAction::Persist);
+    assert_eq!(
+        paths(server.received_requests().await.unwrap()),
+        vec!["/hook", "/hook"]
+    );
+
+    // the batch is dead-lettered after all attempts failed.
+    let ctx = Context {
+        cursor: Some(new_cursor(1)),
+        end_cursor: new_cursor(2),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+    assert_eq!(sink.handle_data(&ctx, &batch).await?, CursorAction::Persist);
+    assert_eq!(
+        paths(server.received_requests().await.unwrap())[2..],
+        vec!["/hook", "/hook", "/hook", "/dead-letter"]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_dead_letter_failure() -> Result<(), SinkError> {
+    use wiremock::{
+        matchers::{method, path},
+        Mock, ResponseTemplate,
+    };
+
+    let server = wiremock::MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(400))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/dead-letter"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.target_url = format!("{}/hook", server.uri())
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    config.dead_letter_url = Some(
+        format!("{}/dead-letter", server.uri())
+            .parse::<Uri>()
+            .change_context(SinkError::Runtime)?,
+    );
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&None, &new_cursor(1));
+
+    let err = sink.handle_data(&ctx, &batch).await.unwrap_err();
+    assert!(matches!(err.current_context(), SinkError::Fatal));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_enrichment() -> Result<(), SinkError> {
+    let enrichment = EnrichmentConfiguration {
+        fields: serde_json::from_value(json!({"env": "staging"})).unwrap(),
+        redact: vec!["/block_str".to_string()],
+    };
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(2),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    let server = start_server().await;
+    let mut config = new_config(&server, false)?;
+    config.enrichment = Some(enrichment.clone());
+    let mut sink = WebhookSink::new(config);
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    let body: Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(body["env"], json!("staging"));
+    assert_eq!(
+        body["data"]["batch"],
+        json!([{"block_num": 0}, {"block_num": 1}])
+    );
+
+    // in raw mode, each item gets the fields.
+    let server = start_server().await;
+    let mut config = new_config(&server, true)?;
+    config.enrichment = Some(enrichment);
+    let mut sink = WebhookSink::new(config);
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    for (index, request) in requests.iter().enumerate() {
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(body, json!({"block_num": index, "env": "staging"}));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_raw_idempotency_key() -> Result<(), SinkError> {
+    let server = start_server().await;
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(5),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    // send the same batch twice, as if the sink restarted before persisting the cursor.
+    for _ in 0..2 {
+        let mut config = new_config(&server, true)?;
+        config.idempotency_key_header = Some(HeaderName::from_static("idempotency-key"));
+        let mut sink = WebhookSink::new(config);
+        sink.handle_data(&ctx, &batch).await?;
+    }
+
+    let requests = server.received_requests().await.unwrap();
+    let keys = requests
+        .iter()
+        .map(|request| header_value(request, "idempotency-key").unwrap())
+        .collect::<Vec<_>>();
+    let (first, second) = keys.split_at(keys.len() / 2);
+    assert_eq!(first, second);
+    for (index, key) in first.iter().enumerate() {
+        assert!(!first[index + 1..].contains(key));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_static_auth() -> Result<(), SinkError> {
+    use apibara_sink_webhook::BasicAuthConfiguration;
+
+    let server = start_server().await;
+
+    let mut config = new_config(&server, false)?;
+    config.target_url = format!("{}/hook?source=apibara", server.uri())
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    config.headers.insert("x-static", "static".parse().unwrap());
+    config.basic_auth = Some(BasicAuthConfiguration {
+        username: "user".to_string(),
+        password: Some("pass".to_string()),
+    });
+    config.query_api_key = Some(QueryApiKeyConfiguration {
+        param: "api_key".to_string(),
+        key: "key".to_string(),
+    });
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+    sink.handle_data(&ctx, &batch).await?;
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].url.path(), "/hook");
+    assert_eq!(requests[0].url.query(), Some("source=apibara&api_key=key"));
+    // base64 of `user:pass`.
+    assert_eq!(
+        header_value(&requests[0], "authorization").as_deref(),
+        Some("Basic dXNlcjpwYXNz")
+    );
+    assert_eq!(
+        header_value(&requests[0], "x-static").as_deref(),
+        Some("static")
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_fan_out() -> Result<(), SinkError> {
+    use apibara_sink_webhook::BasicAuthConfiguration;
+
+    let server = start_server().await;
+    let failing_server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(503))
+        .mount(&failing_server)
+        .await;
+
+    let other_server = start_server().await;
+    let mut headers = HeaderMap::new();
+    headers.insert("x-target", "other".parse().unwrap());
+
+    let mut config = new_config(&server, false)?;
+    config.signature = Some(SignatureConfiguration {
+        secret: b"secret".to_vec(),
+        scheme: SignatureScheme::HmacSha256,
+        header: HeaderName::from_static("x-signature"),
+    });
+    config.basic_auth = Some(BasicAuthConfiguration {
+        username: "user".to_string(),
+        password: Some("pass".to_string()),
+    });
+    config.fan_out_targets = vec![
+        WebhookTarget {
+            url: other_server
+                .uri()
+                .parse::<Uri>()
+                .change_context(SinkError::Runtime)?,
+            method: Method::PUT,
+            headers,
+            credentials: TargetCredentials {
+                basic_auth: Some(BasicAuthConfiguration {
+                    username: "other".to_string(),
+                    password: None,
+                }),
+                ..TargetCredentials::default()
+            },
+        },
+        WebhookTarget {
+            url: failing_server
+                .uri()
+                .parse::<Uri>()
+                .change_context(SinkError::Runtime)?,
+            method: Method::POST,
+            headers: HeaderMap::new(),
+            credentials: TargetCredentials::default(),
+        },
+    ];
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(2),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+
+    // all targets must succeed by default.
+    let err = sink.handle_data(&ctx, &batch).await.unwrap_err();
+    assert!(matches!(err.current_context(), SinkError::Temporary));
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    let other_requests = other_server.received_requests().await.unwrap();
+    assert_eq!(other_requests.len(), 1);
+    assert_eq!(other_requests[0].method.to_string(), "PUT");
+    assert_eq!(
+        header_value(&other_requests[0], "x-target").as_deref(),
+        Some("other")
+    );
+    // each target only receives its own credentials.
+    assert!(header_value(&requests[0], "x-signature").is_some());
+    assert!(header_value(&other_requests[0], "x-signature").is_none());
+    assert_eq!(
+        header_value(&other_requests[0], "authorization").as_deref(),
+        // base64 of `other:`.
+        Some("Basic b3RoZXI6")
+    );
+    let failing_requests = failing_server.received_requests().await.unwrap();
+    assert!(header_value(&failing_requests[0], "authorization").is_none());
+    assert_eq!(
+        other_requests[0]
+            .body_json::<Value>()
+            .change_context(SinkError::Runtime)?,
+        requests[0]
+            .body_json::<Value>()
+            .change_context(SinkError::Runtime)?
+    );
+
+    // the batch is only sent again to the target that failed.
+    let err = sink.handle_data(&ctx, &batch).await.unwrap_err();
+    assert!(matches!(err.current_context(), SinkError::Temporary));
+    assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    assert_eq!(other_server.received_requests().await.unwrap().len(), 1);
+    assert_eq!(failing_server.received_requests().await.unwrap().len(), 2);
+
+    let mut config = new_config(&server, false)?;
+    config.fan_out_mode = FanOutMode::BestEffort;
+    config.fan_out_targets = vec![WebhookTarget {
+        url: failing_server
+            .uri()
+            .parse::<Uri>()
+            .change_context(SinkError::Runtime)?,
+        method: Method::POST,
+        headers: HeaderMap::new(),
+        credentials: TargetCredentials::default(),
+    }];
+
+    let mut sink = WebhookSink::new(config);
+    let action = sink.handle_data(&ctx, &batch).await?;
+    assert_eq!(action, CursorAction::Persist);
+
+    // invalidate requests are sent to all targets too.
+    sink.handle_invalidate(&Some(new_cursor(1))).await?;
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 3);
+    let failing_requests = failing_server.received_requests().await.unwrap();
+    assert_eq!(failing_requests.len(), 4);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_fan_out_identical_raw_items() -> Result<(), SinkError> {
+    let server = start_server().await;
+    let other_server = start_server().await;
+
+    let mut config = new_config(&server, true)?;
+    config.fan_out_targets = vec![WebhookTarget {
+        url: other_server
+            .uri()
+            .parse::<Uri>()
+            .change_context(SinkError::Runtime)?,
+        method: Method::POST,
+        headers: HeaderMap::new(),
+        credentials: TargetCredentials::default(),
+    }];
+
+    let mut sink = WebhookSink::new(config);
+
+    let ctx = Context {
+        cursor: None,
+        end_cursor: new_cursor(1),
+        finality: DataFinality::DataStatusFinalized,
+    };
+    let batch = json!([{ "item": 0 }, { "item": 0 }]);
+    sink.handle_data(&ctx, &batch).await?;
+
+    // items with the same body are different requests.
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    assert_eq!(other_server.received_requests().await.unwrap().len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_handle_data_fan_out_circuit_per_target() -> Result<(), SinkError> {
+    let server = start_server().await;
+    let failing_server = wiremock::MockServer::start().await;
+    wiremock::Mock::given(wiremock::matchers::any())
+        .respond_with(wiremock::ResponseTemplate::new(503))
+        .mount(&failing_server)
+        .await;
+
+    let mut config = new_config(&server, false)?;
+    config.fan_out_mode = FanOutMode::BestEffort;
+    config.fan_out_targets = vec![WebhookTarget {
+        url: failing_server
+            .uri()
+            .parse::<Uri>()
+            .change_context(SinkError::Runtime)?,
+        method: Method::POST,
+        headers: HeaderMap::new(),
+        credentials: TargetCredentials::default(),
+    }];
+    config.circuit_breaker = Some(CircuitBreakerConfiguration {
+        failure_threshold: 2,
+        cooldown: Duration::from_secs(60),
+        probe: CircuitProbe::Request,
+    });
+
+    let mut sink = WebhookSink::new(config);
+
+    for end in 1..=4 {
+        let ctx = Context {
+            cursor: Some(new_cursor(end - 1)),
+            end_cursor: new_cursor(end),
+            finality: DataFinality::DataStatusFinalized,
+        };
+        let batch = new_batch(&ctx.cursor, &ctx.end_cursor);
+        let action = sink.handle_data(&ctx, &batch).await?;
+        assert_eq!(action, CursorAction::Persist);
+    }
+
+    // the circuit of the failing target opened, the other target receives all batches.
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 4);
+    let failing_requests = failing_server.received_requests().await.unwrap();
+    assert_eq!(failing_requests.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_preflight() -> Result<(), SinkError> {
+    use apibara_sink_webhook::SinkWebhookOptions;
+
+    let server = start_server().await;
+    let options = serde_json::from_value::<SinkWebhookOptions>(json!({
+        "sinkType": "webhook",
+        "sinkOptions": {
+            "targetUrl": server.uri(),
+            "preflightMethod": "head",
+        },
+    }))
+    .change_context(SinkError::Runtime)?;
+
+    WebhookSink::from_options(options).await?;
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method.to_string(), "HEAD");
+
+    // nothing listens on the discard port.
+    let options = serde_json::from_value::<SinkWebhookOptions>(json!({
+        "sinkType": "webhook",
+        "sinkOptions": {
+            "targetUrl": "http://127.0.0.1:9",
+            "preflightMethod": "HEAD",
+        },
+    }))
+    .change_context(SinkError::Runtime)?;
+
+    let Err(err) = WebhookSink::from_options(options).await else {
+        panic!("expected the preflight check to fail");
+    };
+    assert!(matches!(err.current_context(), SinkError::Configuration));
+
+    Ok(())
+}
+
+#[test]
+fn test_metadata_has_no_secrets() -> Result<(), SinkError> {
+    let mut headers = HeaderMap::new();
+    headers.insert("authorization", "Bearer secret".parse().unwrap());
+
+    let target_url = "https://example.com/hook/secret?token=secret"
+        .parse::<Uri>()
+        .change_context(SinkError::Runtime)?;
+    let mut config = new_config_with_url(target_url, false);
+    config.headers = headers;
+    config.compression = Some(Compression::Zstd);
+    config.compression_threshold_bytes = 1024;
+    config.signature = Some(SignatureConfiguration {
+        secret: b"secret".to_vec(),
+        scheme: SignatureScheme::HmacSha256,
+        header: HeaderName::from_static("x-signature"),
+    });
+    config.oauth = Some(OAuthConfiguration {
+        token_url: "https://example.com/token".to_string(),
+        client_id: "client".to_string(),
+        client_secret: "secret".to_string(),
+        scope: None,
+    });
+    config.query_api_key = Some(QueryApiKeyConfiguration {
+        param: "api_key".to_string(),
+        key: "secret".to_string(),
+    });
+
+    let sink = WebhookSink::new(config);
+    let metadata = sink.metadata();
+
+    assert_eq!(metadata.sink_type, "webhook");
+    assert_eq!(metadata.target.as_deref(), Some("https://example.com"));
+    assert!(!metadata.to_string().contains("secret"));
+    assert!(metadata
+        .summary
+        .contains(&("raw_failure_mode".to_string(), "fail-batch".to_string())));
+
+    Ok(())
+}
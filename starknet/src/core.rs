@@ -68,6 +68,11 @@ impl GlobalBlockId {
         Self::new(number, BlockHash::zero())
     }
 
+    /// Creates a block id from a stream cursor.
+    ///
+    /// Cursors without a unique key (or with an all-zeros key) are hashless:
+    /// they refer to whichever block is canonical at that height. Cursors with
+    /// a unique key only match the block with that exact hash.
     pub fn from_cursor(cursor: &Cursor) -> Result<Self, InvalidBlockHashSize> {
         let hash = if cursor.unique_key.is_empty() {
             BlockHash::zero()
@@ -108,6 +113,11 @@ impl GlobalBlockId {
         &self.1
     }
 
+    /// Returns true if the block id doesn't specify a block hash.
+    pub fn is_hashless(&self) -> bool {
+        self.1.is_zero()
+    }
+
     /// Returns a cursor corresponding to the block id.
     pub fn to_cursor(&self) -> Cursor {
         Cursor {
@@ -171,3 +181,33 @@ impl apibara_node::core::Cursor for GlobalBlockId {
         self.to_cursor()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::node::v1alpha2::Cursor;
+
+    use super::GlobalBlockId;
+
+    #[test]
+    fn test_hashless_cursor() {
+        let cursor = Cursor {
+            order_key: 8,
+            unique_key: Vec::default(),
+        };
+        let id = GlobalBlockId::from_cursor(&cursor).unwrap();
+        assert_eq!(id.number(), 8);
+        assert!(id.is_hashless());
+
+        let cursor = Cursor {
+            order_key: 8,
+            unique_key: vec![0; 32],
+        };
+        assert!(GlobalBlockId::from_cursor(&cursor).unwrap().is_hashless());
+
+        let cursor = Cursor {
+            order_key: 8,
+            unique_key: vec![0; 4],
+        };
+        assert!(GlobalBlockId::from_cursor(&cursor).is_err());
+    }
+}
@@ -46,6 +46,10 @@ pub struct BlockStatus {
 #[derive(Debug, Clone, Copy, Default)]
 pub struct BlockHeaderTable {}
 
+/// Store the time each block was ingested.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockIngestionTimeTable {}
+
 impl TableKey for BlockHash {
     type Encoded = [u8; 32];
 
@@ -106,6 +110,15 @@ impl Table for BlockHeaderTable {
     }
 }
 
+impl Table for BlockIngestionTimeTable {
+    type Key = GlobalBlockId;
+    type Value = pbjson_types::Timestamp;
+
+    fn db_name() -> &'static str {
+        "BlockIngestionTime"
+    }
+}
+
 impl TableKey for ContractAtBlockId {
     type Encoded = [u8; 72];
 
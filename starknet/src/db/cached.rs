@@ -0,0 +1,438 @@
+//! Read-through cache over another [StorageReader].
+
+use std::{num::NonZeroUsize, sync::Mutex};
+
+use apibara_core::starknet::v1alpha2;
+use apibara_node::o11y::{self, Counter};
+use lru::LruCache;
+
+use crate::core::{BlockHash, GlobalBlockId};
+
+use super::StorageReader;
+
+/// Default number of blocks kept in the cache.
+pub const DEFAULT_CACHED_BLOCKS: usize = 128;
+
+/// A [StorageReader] that caches block data read from another [StorageReader].
+///
+/// Only data that never changes for a given block id (header, transactions,
+/// receipts, events and state update) is cached. Pending blocks are never
+/// cached. Methods that depend on the
+/// chain state, like [StorageReader::highest_accepted_block] or
+/// [StorageReader::read_status], always go to the inner storage.
+///
+/// When the cache is full, the least recently used block is evicted.
+/// Share the same reader between streams (using an `Arc`) so that streams
+/// following the chain head read recent blocks from storage only once.
+///
+/// Blocks are spread over several shards by number, each with its own lock,
+/// so that streams reading different blocks don't wait for each other.
+pub struct CachedStorageReader<R: StorageReader> {
+    inner: R,
+    /// Empty if the cache is disabled.
+    shards: Vec<Mutex<LruCache<CacheKey, CachedBlock>>>,
+    metrics: CacheMetrics,
+}
+
+/// Number of shards of the cache, at most.
+const MAX_CACHE_SHARDS: usize = 16;
+
+/// Number of blocks in each shard, at least.
+const MIN_SHARD_CAPACITY: usize = 8;
+
+type CacheKey = (u64, [u8; 32]);
+
+#[derive(Default)]
+struct CachedBlock {
+    header: Option<v1alpha2::BlockHeader>,
+    body: Option<Vec<v1alpha2::Transaction>>,
+    receipts: Option<Vec<v1alpha2::TransactionReceipt>>,
+    events: Option<Vec<v1alpha2::Event>>,
+    state_update: Option<v1alpha2::StateUpdate>,
+}
+
+struct CacheMetrics {
+    hit: Counter<u64>,
+    miss: Counter<u64>,
+}
+
+impl<R: StorageReader> CachedStorageReader<R> {
+    /// Creates a new cache with the default capacity.
+    pub fn new(inner: R) -> Self {
+        Self::with_capacity(inner, DEFAULT_CACHED_BLOCKS)
+    }
+
+    /// Creates a new cache that holds data for at most `capacity` blocks.
+    pub fn with_capacity(inner: R, capacity: usize) -> Self {
+        // consecutive blocks go to different shards, so each shard holds an
+        // even part of the recent blocks.
+        let shard_count = (capacity / MIN_SHARD_CAPACITY).clamp(1, MAX_CACHE_SHARDS);
+        let shards = (0..shard_count)
+            .filter_map(|index| {
+                let shard_capacity =
+                    capacity / shard_count + usize::from(index < capacity % shard_count);
+                NonZeroUsize::new(shard_capacity)
+                    .map(|capacity| Mutex::new(LruCache::new(capacity)))
+            })
+            .collect();
+        CachedStorageReader {
+            inner,
+            shards,
+            metrics: CacheMetrics::new(),
+        }
+    }
+
+    /// Returns the inner storage.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    fn read_through<T: Clone>(
+        &self,
+        id: &GlobalBlockId,
+        field: fn(&mut CachedBlock) -> &mut Option<T>,
+        read: impl FnOnce(&R) -> Result<Option<T>, R::Error>,
+    ) -> Result<Option<T>, R::Error> {
+        // pending blocks are stored without a hash and overwritten as they change.
+        if id.is_hashless() || self.shards.is_empty() {
+            return read(&self.inner);
+        }
+
+        let key = (id.number(), id.hash().into_bytes());
+        let shard = &self.shards[id.number() as usize % self.shards.len()];
+
+        {
+            let mut shard = shard.lock().expect("block cache lock poisoned");
+            if let Some(value) = shard.get_mut(&key).and_then(|block| field(block).clone()) {
+                self.metrics.hit.add(&o11y::Context::current(), 1, &[]);
+                return Ok(Some(value));
+            }
+        }
+
+        self.metrics.miss.add(&o11y::Context::current(), 1, &[]);
+        let value = read(&self.inner)?;
+
+        // Don't cache missing data since it may be written later.
+        if let Some(value) = &value {
+            let mut shard = shard.lock().expect("block cache lock poisoned");
+            let block = shard.get_or_insert_mut(key, CachedBlock::default);
+            *field(block) = Some(value.clone());
+        }
+
+        Ok(value)
+    }
+}
+
+impl CacheMetrics {
+    fn new() -> Self {
+        let meter = o11y::meter("starknet_storage");
+        CacheMetrics {
+            hit: meter.u64_counter("block_cache_hit").init(),
+            miss: meter.u64_counter("block_cache_miss").init(),
+        }
+    }
+}
+
+/// Returns `None` for empty lists, so that they are not cached.
+fn non_empty<T>(values: Vec<T>) -> Option<Vec<T>> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values)
+    }
+}
+
+impl<R: StorageReader> StorageReader for CachedStorageReader<R> {
+    type Error = R::Error;
+
+    fn highest_accepted_block(&self) -> Result<Option<GlobalBlockId>, Self::Error> {
+        self.inner.highest_accepted_block()
+    }
+
+    fn highest_finalized_block(&self) -> Result<Option<GlobalBlockId>, Self::Error> {
+        self.inner.highest_finalized_block()
+    }
+
+    fn canonical_block_id(&self, number: u64) -> Result<Option<GlobalBlockId>, Self::Error> {
+        self.inner.canonical_block_id(number)
+    }
+
+    fn read_block_range(&self, from: u64, to: u64) -> Result<Vec<GlobalBlockId>, Self::Error> {
+        self.inner.read_block_range(from, to)
+    }
+
+    fn canonical_block_id_by_hash(
+        &self,
+        hash: &BlockHash,
+    ) -> Result<Option<GlobalBlockId>, Self::Error> {
+        self.inner.canonical_block_id_by_hash(hash)
+    }
+
+    fn contains_block(&self, id: &GlobalBlockId) -> Result<bool, Self::Error> {
+        self.inner.contains_block(id)
+    }
+
+    fn read_status(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Option<v1alpha2::BlockStatus>, Self::Error> {
+        self.inner.read_status(id)
+    }
+
+    fn read_header(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Option<v1alpha2::BlockHeader>, Self::Error> {
+        self.read_through(id, |block| &mut block.header, |inner| inner.read_header(id))
+    }
+
+    fn read_ingestion_time(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Option<pbjson_types::Timestamp>, Self::Error> {
+        self.inner.read_ingestion_time(id)
+    }
+
+    fn read_body(&self, id: &GlobalBlockId) -> Result<Vec<v1alpha2::Transaction>, Self::Error> {
+        let body = self.read_through(
+            id,
+            |block| &mut block.body,
+            |inner| inner.read_body(id).map(non_empty),
+        )?;
+        Ok(body.unwrap_or_default())
+    }
+
+    fn read_receipts(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Vec<v1alpha2::TransactionReceipt>, Self::Error> {
+        let receipts = self.read_through(
+            id,
+            |block| &mut block.receipts,
+            |inner| inner.read_receipts(id).map(non_empty),
+        )?;
+        Ok(receipts.unwrap_or_default())
+    }
+
+    fn read_events(
+        &self,
+        id: &GlobalBlockId,
+        contract_address: &v1alpha2::FieldElement,
+    ) -> Result<Vec<v1alpha2::Event>, Self::Error> {
+        self.inner.read_events(id, contract_address)
+    }
+
+    fn read_all_events(&self, id: &GlobalBlockId) -> Result<Vec<v1alpha2::Event>, Self::Error> {
+        let events = self.read_through(
+            id,
+            |block| &mut block.events,
+            |inner| inner.read_all_events(id).map(non_empty),
+        )?;
+        Ok(events.unwrap_or_default())
+    }
+
+    fn read_state_update(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Option<v1alpha2::StateUpdate>, Self::Error> {
+        self.read_through(
+            id,
+            |block| &mut block.state_update,
+            |inner| inner.read_state_update(id),
+        )
+    }
+
+    fn read_storage_diff(
+        &self,
+        id: &GlobalBlockId,
+        contract_address: &v1alpha2::FieldElement,
+    ) -> Result<Option<v1alpha2::StorageDiff>, Self::Error> {
+        self.inner.read_storage_diff(id, contract_address)
+    }
+
+    fn read_all_storage_diff(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Vec<v1alpha2::StorageDiff>, Self::Error> {
+        self.inner.read_all_storage_diff(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use apibara_core::starknet::v1alpha2::{BlockHeader, BlockStatus, Transaction};
+    use mockall::predicate::{eq, function};
+
+    use crate::{
+        core::{BlockHash, GlobalBlockId},
+        db::{MockStorageReader, StorageReader},
+    };
+
+    use super::CachedStorageReader;
+
+    fn new_block_id(num: u64) -> GlobalBlockId {
+        let mut b = [0; 32];
+        b[24..].copy_from_slice(&num.to_be_bytes());
+        GlobalBlockId::new(num, BlockHash::from_slice(&b).unwrap())
+    }
+
+    fn new_block_header(num: u64) -> BlockHeader {
+        BlockHeader {
+            block_number: num,
+            ..BlockHeader::default()
+        }
+    }
+
+    #[test]
+    fn test_block_data_is_read_once() {
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_header()
+            .with(eq(new_block_id(1)))
+            .times(1)
+            .returning(|_| Ok(Some(new_block_header(1))));
+        storage
+            .expect_read_body()
+            .with(eq(new_block_id(1)))
+            .times(1)
+            .returning(|_| Ok(vec![Transaction::default()]));
+
+        let storage = CachedStorageReader::new(storage);
+        for _ in 0..3 {
+            let header = storage.read_header(&new_block_id(1)).unwrap();
+            assert_eq!(header, Some(new_block_header(1)));
+            let body = storage.read_body(&new_block_id(1)).unwrap();
+            assert_eq!(body.len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_missing_data_is_not_cached() {
+        let calls = AtomicUsize::new(0);
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_header()
+            .with(eq(new_block_id(1)))
+            .times(2)
+            .returning(move |_| {
+                // the block is not ingested on the first call.
+                if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    Ok(None)
+                } else {
+                    Ok(Some(new_block_header(1)))
+                }
+            });
+
+        let storage = CachedStorageReader::new(storage);
+        assert_eq!(storage.read_header(&new_block_id(1)).unwrap(), None);
+        assert_eq!(
+            storage.read_header(&new_block_id(1)).unwrap(),
+            Some(new_block_header(1))
+        );
+        assert_eq!(
+            storage.read_header(&new_block_id(1)).unwrap(),
+            Some(new_block_header(1))
+        );
+    }
+
+    #[test]
+    fn test_chain_state_is_not_cached() {
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_highest_accepted_block()
+            .times(3)
+            .returning(|| Ok(Some(new_block_id(10))));
+        storage
+            .expect_canonical_block_id()
+            .with(eq(5))
+            .times(3)
+            .returning(|i| Ok(Some(new_block_id(i))));
+        storage
+            .expect_read_status()
+            .times(3)
+            .returning(|_| Ok(Some(BlockStatus::AcceptedOnL2)));
+
+        let storage = CachedStorageReader::new(storage);
+        for _ in 0..3 {
+            storage.highest_accepted_block().unwrap();
+            storage.canonical_block_id(5).unwrap();
+            storage.read_status(&new_block_id(5)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_block() {
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_header()
+            .with(eq(new_block_id(1)))
+            .times(1)
+            .returning(|_| Ok(Some(new_block_header(1))));
+        storage
+            .expect_read_header()
+            .with(eq(new_block_id(2)))
+            .times(2)
+            .returning(|_| Ok(Some(new_block_header(2))));
+        storage
+            .expect_read_header()
+            .with(function(|id: &GlobalBlockId| id.number() > 2))
+            .returning(|id| Ok(Some(new_block_header(id.number()))));
+
+        let storage = CachedStorageReader::with_capacity(storage, 2);
+        storage.read_header(&new_block_id(1)).unwrap();
+        storage.read_header(&new_block_id(2)).unwrap();
+        storage.read_header(&new_block_id(1)).unwrap();
+        // evicts block 2, since block 1 was used more recently.
+        storage.read_header(&new_block_id(3)).unwrap();
+        storage.read_header(&new_block_id(1)).unwrap();
+        storage.read_header(&new_block_id(2)).unwrap();
+    }
+
+    #[test]
+    fn test_recent_blocks_are_cached_in_all_shards() {
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_header()
+            .times(64)
+            .returning(|id| Ok(Some(new_block_header(id.number()))));
+
+        let storage = CachedStorageReader::with_capacity(storage, 64);
+        for _ in 0..3 {
+            for number in 1..=64 {
+                let header = storage.read_header(&new_block_id(number)).unwrap();
+                assert_eq!(header, Some(new_block_header(number)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_capacity_disables_cache() {
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_header()
+            .times(3)
+            .returning(|_| Ok(Some(new_block_header(1))));
+
+        let storage = CachedStorageReader::with_capacity(storage, 0);
+        for _ in 0..3 {
+            storage.read_header(&new_block_id(1)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_pending_block_is_not_cached() {
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_header()
+            .with(eq(GlobalBlockId::from_u64(1)))
+            .times(3)
+            .returning(|_| Ok(Some(new_block_header(1))));
+
+        let storage = CachedStorageReader::new(storage);
+        for _ in 0..3 {
+            storage.read_header(&GlobalBlockId::from_u64(1)).unwrap();
+        }
+    }
+}
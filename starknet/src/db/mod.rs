@@ -1,19 +1,22 @@
 mod block;
+mod cached;
 mod chain;
 mod state;
 mod storage;
 mod transaction;
 
 pub use self::block::{BlockBody, BlockReceipts, BlockStatus};
+pub use self::cached::{CachedStorageReader, DEFAULT_CACHED_BLOCKS};
 pub use self::storage::{
     DatabaseStorage, DatabaseStorageWriter, MockStorageReader, StorageReader, StorageWriter,
+    CANONICAL_BLOCK_BY_HASH_WINDOW,
 };
 
 pub mod tables {
     use apibara_node::db::libmdbx::{EnvironmentKind, Error as MdbxError, Transaction, RW};
     use apibara_node::db::MdbxRWTransactionExt;
 
-    pub use super::block::{BlockHeaderTable, BlockStatusTable};
+    pub use super::block::{BlockHeaderTable, BlockIngestionTimeTable, BlockStatusTable};
     pub use super::chain::CanonicalChainTable;
     pub use super::state::{StateUpdateTable, StorageDiffTable};
     pub use super::transaction::{BlockBodyTable, BlockEventsTable, BlockReceiptsTable};
@@ -22,6 +25,7 @@ pub mod tables {
     pub fn ensure<E: EnvironmentKind>(txn: &Transaction<RW, E>) -> Result<(), MdbxError> {
         txn.ensure_table::<self::BlockBodyTable>(None)?;
         txn.ensure_table::<self::BlockHeaderTable>(None)?;
+        txn.ensure_table::<self::BlockIngestionTimeTable>(None)?;
         txn.ensure_table::<self::BlockStatusTable>(None)?;
         txn.ensure_table::<self::CanonicalChainTable>(None)?;
         txn.ensure_table::<self::BlockReceiptsTable>(None)?;
@@ -7,9 +7,10 @@ use apibara_node::db::{
     libmdbx::{self, Environment, EnvironmentKind, Transaction, RW},
     MdbxErrorExt, MdbxTransactionExt, TableCursor,
 };
+use apibara_node::stream::{IntoStreamError, StreamError};
 use mockall::automock;
 
-use crate::core::GlobalBlockId;
+use crate::core::{BlockHash, GlobalBlockId};
 
 use super::{
     block::{BlockBody, BlockReceipts, ContractAtBlockId},
@@ -20,10 +21,33 @@ use super::{
 #[derive(Debug, thiserror::Error)]
 pub enum MockStorageReaderError {}
 
+impl IntoStreamError for MockStorageReaderError {
+    fn into_stream_error(self) -> StreamError {
+        match self {}
+    }
+}
+
+/// Number of blocks, from the chain head, searched by [StorageReader::canonical_block_id_by_hash].
+pub const CANONICAL_BLOCK_BY_HASH_WINDOW: u64 = 1_000;
+
 /// An object to read chain data from storage.
+///
+/// Implementations must uphold the following contract, which the stream
+/// producers and [CachedStorageReader](super::CachedStorageReader) rely on:
+///
+///  - Data read by block id (header, body, receipts, events, state update and
+///    storage diffs) never changes once it is written. A block id includes the
+///    block hash, so data from a reorged block is never returned for the new
+///    block at the same height.
+///  - Missing data is not an error: methods return `None` or an empty list.
+///  - The highest accepted and finalized blocks, the canonical chain and the
+///    block status change as the chain progresses and must be read from the
+///    most recent state.
+///  - Readers are shared between streams and called from many tasks
+///    concurrently.
 #[automock(type Error=MockStorageReaderError;)]
 pub trait StorageReader {
-    type Error: std::error::Error + Send + Sync + 'static;
+    type Error: std::error::Error + IntoStreamError + Send + Sync + 'static;
 
     /// Returns the highest accepted block that was indexed.
     fn highest_accepted_block(&self) -> Result<Option<GlobalBlockId>, Self::Error>;
@@ -35,6 +59,66 @@ pub trait StorageReader {
     /// canonical chain is shorter.
     fn canonical_block_id(&self, number: u64) -> Result<Option<GlobalBlockId>, Self::Error>;
 
+    /// Returns the block ids of the canonical chain from `from` to `to`, inclusive.
+    ///
+    /// Stops at the first missing block, so the returned ids are contiguous and
+    /// in order. The default implementation reads one block at a time,
+    /// implementations should override it to read the range in a single pass.
+    fn read_block_range(&self, from: u64, to: u64) -> Result<Vec<GlobalBlockId>, Self::Error> {
+        let mut block_ids = Vec::new();
+        for number in from..=to {
+            match self.canonical_block_id(number)? {
+                Some(block_id) => block_ids.push(block_id),
+                None => break,
+            }
+        }
+        Ok(block_ids)
+    }
+
+    /// Returns the id of the canonical block with the given hash, or `None` if
+    /// no recent block in the canonical chain has that hash.
+    ///
+    /// Only the genesis block and the last [CANONICAL_BLOCK_BY_HASH_WINDOW]
+    /// blocks are searched, so that clients can't make the server read the
+    /// whole chain. The default implementation reads one block at a time,
+    /// implementations should override it to read the chain in a single pass.
+    fn canonical_block_id_by_hash(
+        &self,
+        hash: &BlockHash,
+    ) -> Result<Option<GlobalBlockId>, Self::Error> {
+        match self.canonical_block_id(0)? {
+            Some(genesis) if genesis.hash() == hash => return Ok(Some(genesis)),
+            Some(_) => {}
+            None => return Ok(None),
+        }
+        let Some(head) = self.highest_accepted_block()? else {
+            return Ok(None);
+        };
+        let oldest = head
+            .number()
+            .saturating_sub(CANONICAL_BLOCK_BY_HASH_WINDOW - 1);
+        for number in (oldest..=head.number()).rev() {
+            match self.canonical_block_id(number)? {
+                Some(block_id) if block_id.hash() == hash => return Ok(Some(block_id)),
+                Some(_) => {}
+                None => break,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns whether the given block is part of the canonical chain.
+    ///
+    /// Returns `false` if the canonical block at the same height has a different
+    /// hash, for example because the block was reorged, and for hashless ids.
+    fn contains_block(&self, id: &GlobalBlockId) -> Result<bool, Self::Error> {
+        if id.is_hashless() {
+            return Ok(false);
+        }
+        let canonical = self.canonical_block_id(id.number())?;
+        Ok(canonical.as_ref() == Some(id))
+    }
+
     /// Returns the block status for the given block.
     fn read_status(&self, id: &GlobalBlockId)
         -> Result<Option<v1alpha2::BlockStatus>, Self::Error>;
@@ -43,6 +127,12 @@ pub trait StorageReader {
     fn read_header(&self, id: &GlobalBlockId)
         -> Result<Option<v1alpha2::BlockHeader>, Self::Error>;
 
+    /// Returns the time the given block was ingested.
+    fn read_ingestion_time(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Option<pbjson_types::Timestamp>, Self::Error>;
+
     /// Returns all transactions in the given block.
     fn read_body(&self, id: &GlobalBlockId) -> Result<Vec<v1alpha2::Transaction>, Self::Error>;
 
@@ -109,6 +199,13 @@ pub trait StorageWriter {
         header: v1alpha2::BlockHeader,
     ) -> Result<(), Self::Error>;
 
+    /// Writes the time the block was ingested.
+    fn write_ingestion_time(
+        &mut self,
+        id: &GlobalBlockId,
+        time: pbjson_types::Timestamp,
+    ) -> Result<(), Self::Error>;
+
     /// Writes the transactions in a block.
     fn write_body(&mut self, id: &GlobalBlockId, body: BlockBody) -> Result<(), Self::Error>;
 
@@ -136,6 +233,7 @@ pub struct DatabaseStorageWriter<'env, 'txn, E: EnvironmentKind> {
     txn: Transaction<'env, RW, E>,
     status_cursor: TableCursor<'txn, tables::BlockStatusTable, RW>,
     header_cursor: TableCursor<'txn, tables::BlockHeaderTable, RW>,
+    ingestion_time_cursor: TableCursor<'txn, tables::BlockIngestionTimeTable, RW>,
     body_cursor: TableCursor<'txn, tables::BlockBodyTable, RW>,
     receipts_cursor: TableCursor<'txn, tables::BlockReceiptsTable, RW>,
     state_update_cursor: TableCursor<'txn, tables::StateUpdateTable, RW>,
@@ -152,6 +250,7 @@ impl<E: EnvironmentKind> DatabaseStorage<E> {
         let txn = self.db.begin_rw_txn()?;
         let status_cursor = txn.open_cursor::<tables::BlockStatusTable>()?;
         let header_cursor = txn.open_cursor::<tables::BlockHeaderTable>()?;
+        let ingestion_time_cursor = txn.open_cursor::<tables::BlockIngestionTimeTable>()?;
         let body_cursor = txn.open_cursor::<tables::BlockBodyTable>()?;
         let receipts_cursor = txn.open_cursor::<tables::BlockReceiptsTable>()?;
         let state_update_cursor = txn.open_cursor::<tables::StateUpdateTable>()?;
@@ -161,6 +260,7 @@ impl<E: EnvironmentKind> DatabaseStorage<E> {
             txn,
             status_cursor,
             header_cursor,
+            ingestion_time_cursor,
             body_cursor,
             receipts_cursor,
             state_update_cursor,
@@ -235,6 +335,85 @@ impl<E: EnvironmentKind> StorageReader for DatabaseStorage<E> {
         }
     }
 
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn canonical_block_id_by_hash(
+        &self,
+        hash: &BlockHash,
+    ) -> Result<Option<GlobalBlockId>, Self::Error> {
+        let txn = self.db.begin_ro_txn()?;
+        let mut cursor = txn.open_cursor::<tables::CanonicalChainTable>()?;
+        // the genesis block is a common starting point, check it first.
+        if let Some((0, block_hash)) = cursor.first()? {
+            let block_hash: BlockHash = (&block_hash)
+                .try_into()
+                .map_err(libmdbx::Error::decode_error)?;
+            if block_hash == *hash {
+                txn.commit()?;
+                return Ok(Some(GlobalBlockId::new(0, block_hash)));
+            }
+        }
+
+        let mut entry = cursor.last()?;
+        let mut remaining = CANONICAL_BLOCK_BY_HASH_WINDOW;
+        while let Some((number, block_hash)) = entry {
+            if remaining == 0 || number == 0 {
+                break;
+            }
+            let block_hash: BlockHash = (&block_hash)
+                .try_into()
+                .map_err(libmdbx::Error::decode_error)?;
+            if block_hash == *hash {
+                txn.commit()?;
+                return Ok(Some(GlobalBlockId::new(number, block_hash)));
+            }
+            remaining -= 1;
+            entry = cursor.prev()?;
+        }
+        txn.commit()?;
+        Ok(None)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn read_block_range(&self, from: u64, to: u64) -> Result<Vec<GlobalBlockId>, Self::Error> {
+        let txn = self.db.begin_ro_txn()?;
+        let mut cursor = txn.open_cursor::<tables::CanonicalChainTable>()?;
+        let mut block_ids = Vec::new();
+        let mut entry = cursor.seek_exact(&from)?;
+        while let Some((number, block_hash)) = entry {
+            // the canonical chain has no gaps, but check anyway to uphold the contract.
+            if number > to || number != from + block_ids.len() as u64 {
+                break;
+            }
+            let block_hash = (&block_hash)
+                .try_into()
+                .map_err(libmdbx::Error::decode_error)?;
+            block_ids.push(GlobalBlockId::new(number, block_hash));
+            entry = cursor.next()?;
+        }
+        txn.commit()?;
+        Ok(block_ids)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn contains_block(&self, id: &GlobalBlockId) -> Result<bool, Self::Error> {
+        if id.is_hashless() {
+            return Ok(false);
+        }
+        let txn = self.db.begin_ro_txn()?;
+        let mut cursor = txn.open_cursor::<tables::CanonicalChainTable>()?;
+        let contains = match cursor.seek_exact(&id.number())? {
+            None => false,
+            Some((_, block_hash)) => {
+                let block_hash = (&block_hash)
+                    .try_into()
+                    .map_err(libmdbx::Error::decode_error)?;
+                GlobalBlockId::new(id.number(), block_hash) == *id
+            }
+        };
+        txn.commit()?;
+        Ok(contains)
+    }
+
     #[tracing::instrument(level = "debug", skip(self))]
     fn read_status(
         &self,
@@ -259,6 +438,18 @@ impl<E: EnvironmentKind> StorageReader for DatabaseStorage<E> {
         Ok(header)
     }
 
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn read_ingestion_time(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Option<pbjson_types::Timestamp>, Self::Error> {
+        let txn = self.db.begin_ro_txn()?;
+        let mut cursor = txn.open_cursor::<tables::BlockIngestionTimeTable>()?;
+        let time = cursor.seek_exact(id)?.map(|t| t.1);
+        txn.commit()?;
+        Ok(time)
+    }
+
     #[tracing::instrument(level = "debug", skip(self))]
     fn read_body(&self, id: &GlobalBlockId) -> Result<Vec<v1alpha2::Transaction>, Self::Error> {
         let txn = self.db.begin_ro_txn()?;
@@ -408,6 +599,17 @@ impl<'env, 'txn, E: EnvironmentKind> StorageWriter for DatabaseStorageWriter<'en
         Ok(())
     }
 
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn write_ingestion_time(
+        &mut self,
+        id: &GlobalBlockId,
+        time: pbjson_types::Timestamp,
+    ) -> Result<(), Self::Error> {
+        self.ingestion_time_cursor.seek_exact(id)?;
+        self.ingestion_time_cursor.put(id, &time)?;
+        Ok(())
+    }
+
     #[tracing::instrument(level = "debug", skip(self, body))]
     fn write_body(&mut self, id: &GlobalBlockId, body: BlockBody) -> Result<(), Self::Error> {
         self.body_cursor.seek_exact(id)?;
@@ -452,3 +654,130 @@ impl<'env, 'txn, E: EnvironmentKind> StorageWriter for DatabaseStorageWriter<'en
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use apibara_node::db::{
+        libmdbx::{Environment, NoWriteMap},
+        MdbxEnvironmentExt,
+    };
+
+    use crate::core::{BlockHash, GlobalBlockId};
+
+    use super::{
+        tables, DatabaseStorage, StorageReader, StorageWriter, CANONICAL_BLOCK_BY_HASH_WINDOW,
+    };
+
+    fn new_block_id(num: u64) -> GlobalBlockId {
+        let mut b = [0; 32];
+        b[24..].copy_from_slice(&num.to_be_bytes());
+        GlobalBlockId::new(num, BlockHash::from_slice(&b).unwrap())
+    }
+
+    #[test]
+    fn test_canonical_block_id_by_hash() {
+        let datadir = tempfile::tempdir().unwrap();
+        let db = Environment::<NoWriteMap>::open(datadir.path()).unwrap();
+        let txn = db.begin_rw_txn().unwrap();
+        tables::ensure(&txn).unwrap();
+        txn.commit().unwrap();
+
+        let storage = DatabaseStorage::new(Arc::new(db));
+        let mut writer = storage.begin_txn().unwrap();
+        for num in 0..5 {
+            writer.extend_canonical_chain(&new_block_id(num)).unwrap();
+        }
+        writer.commit().unwrap();
+
+        for num in [1, 3, 4] {
+            let block_id = new_block_id(num);
+            let found = storage.canonical_block_id_by_hash(block_id.hash()).unwrap();
+            assert_eq!(found, Some(block_id));
+        }
+
+        let missing = BlockHash::from_slice(&[1; 32]).unwrap();
+        assert_eq!(storage.canonical_block_id_by_hash(&missing).unwrap(), None);
+    }
+
+    #[test]
+    fn test_canonical_block_id_by_hash_only_searches_recent_blocks() {
+        let datadir = tempfile::tempdir().unwrap();
+        let db = Environment::<NoWriteMap>::open(datadir.path()).unwrap();
+        let txn = db.begin_rw_txn().unwrap();
+        tables::ensure(&txn).unwrap();
+        txn.commit().unwrap();
+
+        let storage = DatabaseStorage::new(Arc::new(db));
+        let head = CANONICAL_BLOCK_BY_HASH_WINDOW + 10;
+        let mut writer = storage.begin_txn().unwrap();
+        for num in 0..=head {
+            writer.extend_canonical_chain(&new_block_id(num)).unwrap();
+        }
+        writer.commit().unwrap();
+
+        let oldest = head - CANONICAL_BLOCK_BY_HASH_WINDOW + 1;
+        for num in [0, oldest, head] {
+            let block_id = new_block_id(num);
+            let found = storage.canonical_block_id_by_hash(block_id.hash()).unwrap();
+            assert_eq!(found, Some(block_id));
+        }
+
+        for num in [1, oldest - 1] {
+            let block_id = new_block_id(num);
+            let found = storage.canonical_block_id_by_hash(block_id.hash()).unwrap();
+            assert_eq!(found, None);
+        }
+    }
+
+    #[test]
+    fn test_read_block_range() {
+        let datadir = tempfile::tempdir().unwrap();
+        let db = Environment::<NoWriteMap>::open(datadir.path()).unwrap();
+        let txn = db.begin_rw_txn().unwrap();
+        tables::ensure(&txn).unwrap();
+        txn.commit().unwrap();
+
+        let storage = DatabaseStorage::new(Arc::new(db));
+        let mut writer = storage.begin_txn().unwrap();
+        for num in 0..5 {
+            writer.extend_canonical_chain(&new_block_id(num)).unwrap();
+        }
+        writer.commit().unwrap();
+
+        let expected = (1..=3).map(new_block_id).collect::<Vec<_>>();
+        assert_eq!(storage.read_block_range(1, 3).unwrap(), expected);
+        // stops at the chain head.
+        let expected = (3..5).map(new_block_id).collect::<Vec<_>>();
+        assert_eq!(storage.read_block_range(3, 10).unwrap(), expected);
+        assert!(storage.read_block_range(7, 9).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ingestion_time() {
+        let datadir = tempfile::tempdir().unwrap();
+        let db = Environment::<NoWriteMap>::open(datadir.path()).unwrap();
+        let txn = db.begin_rw_txn().unwrap();
+        tables::ensure(&txn).unwrap();
+        txn.commit().unwrap();
+
+        let storage = DatabaseStorage::new(Arc::new(db));
+        let time = pbjson_types::Timestamp {
+            seconds: 1_700_000_000,
+            nanos: 42,
+        };
+        let mut writer = storage.begin_txn().unwrap();
+        writer
+            .write_ingestion_time(&new_block_id(1), time.clone())
+            .unwrap();
+        writer.commit().unwrap();
+
+        assert_eq!(
+            storage.read_ingestion_time(&new_block_id(1)).unwrap(),
+            Some(time)
+        );
+        // blocks ingested before the time was recorded don't have one.
+        assert_eq!(storage.read_ingestion_time(&new_block_id(2)).unwrap(), None);
+    }
+}
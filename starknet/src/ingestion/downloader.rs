@@ -1,6 +1,9 @@
 //! Download and store block data.
 
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use apibara_core::starknet::v1alpha2;
 use futures::{stream, StreamExt};
@@ -100,6 +103,7 @@ where
         // write block status, header, body, receipts and state update to storage
         writer.write_status(global_id, status)?;
         writer.write_header(global_id, header)?;
+        writer.write_ingestion_time(global_id, ingestion_time())?;
         writer.write_body(global_id, body)?;
         writer.write_receipts(global_id, receipts)?;
 
@@ -110,3 +114,14 @@ where
         Ok(())
     }
 }
+
+/// Returns the current time, used as the block ingestion time.
+fn ingestion_time() -> pbjson_types::Timestamp {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    pbjson_types::Timestamp {
+        seconds: now.as_secs() as i64,
+        nanos: now.subsec_nanos() as i32,
+    }
+}
@@ -15,12 +15,14 @@ use tracing::error;
 
 use crate::{db::DatabaseStorage, provider::Provider};
 
-use self::{started::StartedBlockIngestion, subscription::IngestionStreamPublisher};
+use self::started::StartedBlockIngestion;
 
 pub use self::{
     config::BlockIngestionConfig,
     error::BlockIngestionError,
-    subscription::{IngestionStream, IngestionStreamClient},
+    subscription::{
+        IngestionStream, IngestionStreamClient, IngestionStreamPublisher, INGESTION_STREAM_CAPACITY,
+    },
 };
 
 /// Block ingestion service.
@@ -10,6 +10,11 @@ use super::error::BlockIngestionError;
 
 pub type IngestionStream = BroadcastStream<IngestionMessage>;
 
+/// Number of messages queued for each subscriber.
+///
+/// Subscribers that fall further behind miss messages and receive an error instead.
+pub const INGESTION_STREAM_CAPACITY: usize = 128;
+
 #[derive(Clone)]
 pub struct IngestionStreamPublisher {
     tx: Arc<broadcast::Sender<IngestionMessage>>,
@@ -22,7 +27,7 @@ pub struct IngestionStreamClient {
 
 impl IngestionStreamPublisher {
     pub fn new() -> (IngestionStreamClient, IngestionStreamPublisher) {
-        let (tx, _rx) = broadcast::channel(128);
+        let (tx, _rx) = broadcast::channel(INGESTION_STREAM_CAPACITY);
         let tx = Arc::new(tx);
 
         let manager = IngestionStreamPublisher { tx: tx.clone() };
@@ -7,6 +7,8 @@ pub mod provider;
 pub mod server;
 pub mod status;
 pub mod stream;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod websocket;
 
 pub use crate::node::StarkNetNode;
@@ -18,17 +20,24 @@ pub use apibara_node::{
 };
 use apibara_sdk::Uri;
 use ingestion::BlockIngestionConfig;
+use server::StreamServiceConfig;
 
-use std::{fmt, path::PathBuf, time::Duration};
+use std::{fmt, num::NonZeroU32, path::PathBuf, sync::Arc, time::Duration};
 
-use apibara_node::{db::default_data_dir, server::QuotaConfiguration};
+use apibara_node::{
+    db::default_data_dir,
+    server::{ApiKeyAuth, QuotaConfiguration},
+    stream::{
+        BatchSizeLimits, ProgressTokenSigner, MAX_HEARTBEAT_INTERVAL, MIN_HEARTBEAT_INTERVAL,
+    },
+};
 use clap::Args;
 use error_stack::{Result, ResultExt};
 use tempdir::TempDir;
 use tokio_util::sync::CancellationToken;
 use tracing::info;
 
-#[derive(Clone, Debug, Args)]
+#[derive(Default, Clone, Debug, Args)]
 pub struct StartArgs {
     /// StarkNet RPC address.
     #[arg(long, env)]
@@ -52,6 +61,9 @@ pub struct StartArgs {
     #[arg(long, env)]
     pub devnet: bool,
     /// Use the specified metadata key for tracing and metering.
+    ///
+    /// Values are attached as labels to the stream's span and metrics. At most 8 keys are
+    /// used and values longer than 64 bytes are truncated.
     #[arg(long, env)]
     pub use_metadata: Vec<String>,
     #[command(flatten)]
@@ -67,6 +79,116 @@ pub struct StartArgs {
     /// This should be used only for testing and never in production.
     #[arg(long, env)]
     pub dangerously_override_ingestion_start_block: Option<u64>,
+    /// Secret used to sign progress tokens.
+    ///
+    /// If set, the server periodically sends a signed progress token that clients can use to
+    /// resume the stream.
+    #[arg(long, env)]
+    pub progress_token_secret: Option<String>,
+    /// How long progress tokens are valid for (in seconds), defaults to 24 hours.
+    #[arg(long, env)]
+    pub progress_token_ttl_secs: Option<u64>,
+    /// Smallest batch size clients can request, defaults to 1.
+    #[arg(long, env)]
+    pub min_batch_size: Option<usize>,
+    /// Largest batch size clients can request, defaults to 50.
+    #[arg(long, env)]
+    pub max_batch_size: Option<usize>,
+    /// Batch size of streams that don't request one, defaults to 20 or the
+    /// closest value within the min and max batch size.
+    ///
+    /// Must be between the min and max batch size.
+    #[arg(long, env)]
+    pub default_batch_size: Option<usize>,
+    /// Number of messages buffered for each stream, defaults to 16.
+    ///
+    /// Streams produce data ahead of the client until the buffer is full, then
+    /// wait for the client.
+    #[arg(long, env)]
+    pub stream_buffer_size: Option<usize>,
+    /// Close streams if the client doesn't consume data for this long (in seconds).
+    ///
+    /// If not set, streams are never closed because of slow clients.
+    #[arg(long, env)]
+    pub backpressure_timeout_secs: Option<u64>,
+    /// Close streams if the client doesn't read any message waiting for it for this long
+    /// (in seconds), defaults to 10 minutes.
+    ///
+    /// Clients that read heartbeats are never closed. Set to 0 to never close stalled streams.
+    #[arg(long, env)]
+    pub stream_stall_timeout_secs: Option<u64>,
+    /// Split batches into multiple messages of at most this many bytes.
+    ///
+    /// Tonic clients reject messages larger than 4 MiB by default. If not set,
+    /// batches are never split.
+    #[arg(long, env)]
+    pub max_message_size_bytes: Option<usize>,
+    /// Number of recent blocks cached in memory and shared between streams.
+    ///
+    /// If not set, blocks are always read from the database.
+    #[arg(long, env)]
+    pub block_cache_size: Option<usize>,
+    /// Number of recent filtered blocks shared between streams with the same filter.
+    ///
+    /// Use this when many clients stream the same data, for example replicas of
+    /// the same service. If not set, each stream filters its own data.
+    #[arg(long, env)]
+    pub filtered_block_cache_size: Option<usize>,
+    /// Number of recent ingestion notifications used to drop duplicates.
+    ///
+    /// If not set, duplicate notifications are not dropped.
+    #[arg(long, env)]
+    pub ingestion_dedupe_window: Option<usize>,
+    /// How long keepalive requests can extend a stream past its `grpc-timeout` (in seconds),
+    /// defaults to 1 hour.
+    ///
+    /// Set to 0 to disable extensions.
+    #[arg(long, env)]
+    pub max_deadline_extension_secs: Option<u64>,
+    /// Close streams after they run for this long (in seconds), so that clients reconnect.
+    ///
+    /// Streams end cleanly after the message they are sending. If not set,
+    /// streams run until the client disconnects.
+    #[arg(long, env)]
+    pub max_stream_duration_secs: Option<u64>,
+    /// How long streams wait without sending data before their first heartbeat (in seconds),
+    /// defaults to the heartbeat interval.
+    #[arg(long, env)]
+    pub initial_heartbeat_delay_secs: Option<u64>,
+    /// Interval between heartbeats when streams don't send data (in milliseconds),
+    /// defaults to 30 seconds.
+    ///
+    /// The value is clamped between 1 second and 30 seconds, so that heartbeats
+    /// keep streams open for clients that time out after 45 seconds without
+    /// messages, like the SDK.
+    #[arg(long, env)]
+    pub heartbeat_interval_ms: Option<u64>,
+    /// Limit the number of messages each stream sends per second.
+    ///
+    /// Streams over the limit are slowed down. If not set, the number of messages is not limited.
+    /// Must be greater than zero.
+    #[arg(long, env)]
+    pub stream_messages_per_second_limit: Option<NonZeroU32>,
+    /// Limit the number of bytes each stream sends per second.
+    ///
+    /// Streams over the limit are slowed down. If not set, the bandwidth is not limited.
+    /// Must be greater than zero.
+    #[arg(long, env)]
+    pub stream_bytes_per_second_limit: Option<NonZeroU32>,
+    /// Compress stream responses with gzip for clients that support it.
+    #[arg(long, env)]
+    pub enable_gzip_compression: bool,
+    /// Reject stream filters that have no constraints or match all events,
+    /// transactions or messages.
+    #[arg(long, env)]
+    pub reject_broad_filters: bool,
+    /// Require clients to authenticate with one of these API keys (comma-separated).
+    ///
+    /// Clients send the key as a bearer token in the `authorization` header, or in the
+    /// `x-api-key` header. Applies to both the gRPC and the websocket stream. If not set,
+    /// all clients can stream.
+    #[arg(long, env, value_delimiter = ',')]
+    pub api_keys: Vec<String>,
 }
 
 #[derive(Default, Clone, Debug, Args)]
@@ -156,6 +278,96 @@ pub async fn start_node(args: StartArgs, cts: CancellationToken) -> Result<(), S
         node.with_blocks_per_second_limit(limit);
     }
 
+    if let Some(secret) = args.progress_token_secret {
+        let mut signer = ProgressTokenSigner::new(secret);
+        if let Some(ttl) = args.progress_token_ttl_secs {
+            signer = signer.with_ttl(Duration::from_secs(ttl));
+        }
+        node.with_progress_token_signer(signer);
+    }
+
+    let mut stream_service_config = StreamServiceConfig::default();
+
+    if args.min_batch_size.is_some()
+        || args.max_batch_size.is_some()
+        || args.default_batch_size.is_some()
+    {
+        let defaults = BatchSizeLimits::default();
+        let min = args.min_batch_size.unwrap_or(defaults.min());
+        let max = args.max_batch_size.unwrap_or(defaults.max());
+        // only the built-in default follows the configured limits, an explicit
+        // default outside of them is rejected.
+        let default = args
+            .default_batch_size
+            .unwrap_or_else(|| defaults.default_batch_size().clamp(min, max.max(min)));
+        stream_service_config.batch_size_limits = BatchSizeLimits::new(min, max, default)
+            .ok_or(StarknetError)
+            .attach_printable_lazy(|| {
+                format!("invalid batch size limits: min {min}, max {max}, default {default}")
+            })?;
+    }
+
+    if let Some(buffer_size) = args.stream_buffer_size {
+        stream_service_config.buffer_size = buffer_size;
+    }
+
+    if let Some(timeout) = args.backpressure_timeout_secs {
+        stream_service_config.backpressure_timeout = Some(Duration::from_secs(timeout));
+    }
+
+    if let Some(timeout) = args.stream_stall_timeout_secs {
+        stream_service_config.stall_timeout = if timeout == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(timeout))
+        };
+    }
+
+    stream_service_config.max_message_size = args.max_message_size_bytes;
+
+    if let Some(block_cache_size) = args.block_cache_size {
+        stream_service_config.block_cache_size = block_cache_size;
+    }
+
+    if let Some(filtered_block_cache_size) = args.filtered_block_cache_size {
+        stream_service_config.filtered_block_cache_size = filtered_block_cache_size;
+    }
+
+    if let Some(ingestion_dedupe_window) = args.ingestion_dedupe_window {
+        stream_service_config.ingestion_dedupe_window = ingestion_dedupe_window;
+    }
+
+    if let Some(extension) = args.max_deadline_extension_secs {
+        stream_service_config.max_deadline_extension = Duration::from_secs(extension);
+    }
+
+    if let Some(duration) = args.max_stream_duration_secs {
+        stream_service_config.max_stream_duration = Some(Duration::from_secs(duration));
+    }
+
+    if let Some(delay) = args.initial_heartbeat_delay_secs {
+        stream_service_config.initial_heartbeat_delay = Some(Duration::from_secs(delay));
+    }
+
+    if let Some(interval) = args.heartbeat_interval_ms {
+        // Adjust to some value that makes sense.
+        let interval = Duration::from_millis(interval);
+        stream_service_config.heartbeat_interval =
+            interval.clamp(MIN_HEARTBEAT_INTERVAL, MAX_HEARTBEAT_INTERVAL);
+    }
+
+    stream_service_config.rate_limit.messages_per_second = args.stream_messages_per_second_limit;
+    stream_service_config.rate_limit.bytes_per_second = args.stream_bytes_per_second_limit;
+
+    stream_service_config.gzip_compression = args.enable_gzip_compression;
+    stream_service_config.reject_broad_filters = args.reject_broad_filters;
+
+    if !args.api_keys.is_empty() {
+        stream_service_config.auth = Some(Arc::new(ApiKeyAuth::new(args.api_keys)));
+    }
+
+    node.with_stream_service_config(stream_service_config);
+
     let mut block_ingestion_config = BlockIngestionConfig::default();
 
     if let Some(head_refresh_interval_free) = args.head_refresh_interval_ms {
@@ -14,6 +14,7 @@ use apibara_node::{
         MdbxEnvironmentExt,
     },
     server::{QuotaConfiguration, RequestObserver, SimpleRequestObserver},
+    stream::ProgressTokenSigner,
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
@@ -22,7 +23,7 @@ use crate::{
     db::{tables, DatabaseStorage},
     ingestion::{BlockIngestion, BlockIngestionConfig, BlockIngestionError},
     provider::{HttpProviderError, Provider},
-    server::{Server, ServerError},
+    server::{Server, ServerError, StreamServiceConfig},
     status::{StatusService, StatusServiceError},
     websocket::WebsocketStreamServer,
     HttpProvider,
@@ -42,6 +43,8 @@ where
     block_ingestion_config: BlockIngestionConfig,
     blocks_per_second_quota: u32,
     quota_configuration: QuotaConfiguration,
+    progress_token_signer: Option<ProgressTokenSigner>,
+    stream_service_config: StreamServiceConfig,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -81,6 +84,8 @@ where
         block_ingestion_config: BlockIngestionConfig,
         blocks_per_second_quota: Option<u32>,
         quota_configuration: QuotaConfiguration,
+        progress_token_signer: Option<ProgressTokenSigner>,
+        stream_service_config: StreamServiceConfig,
     ) -> Self {
         let db = Arc::new(db);
         let sequencer_provider = Arc::new(sequencer_provider);
@@ -93,6 +98,8 @@ where
             block_ingestion_config,
             blocks_per_second_quota: blocks_per_second_quota.unwrap_or(10_000),
             quota_configuration,
+            progress_token_signer,
+            stream_service_config,
         }
     }
 
@@ -144,6 +151,7 @@ where
             .address
             .unwrap_or_else(|| "0.0.0.0:7171".to_string())
             .parse()?;
+        let websocket_auth = self.stream_service_config.auth.clone();
         let server = Server::<E, O>::new(
             self.db.clone(),
             block_ingestion_client.clone(),
@@ -151,7 +159,9 @@ where
             self.blocks_per_second_quota,
         )
         .with_request_observer(self.request_span)
-        .with_quota_configuration(self.quota_configuration);
+        .with_quota_configuration(self.quota_configuration)
+        .with_progress_token_signer(self.progress_token_signer)
+        .with_stream_service_config(self.stream_service_config);
 
         let mut server_handle = tokio::spawn({
             let ct = ct.clone();
@@ -173,7 +183,8 @@ where
                     storage,
                     block_ingestion_client.clone(),
                     self.blocks_per_second_quota,
-                );
+                )
+                .with_auth(websocket_auth);
                 tokio::spawn(Arc::new(websocket_server).start())
             }
             None => tokio::spawn(future::pending()),
@@ -238,6 +249,8 @@ pub struct StarkNetNodeBuilder<O: RequestObserver, E: EnvironmentKind> {
     websocket_address: Option<String>,
     blocks_per_second_quota: Option<u32>,
     quota_configuration: QuotaConfiguration,
+    progress_token_signer: Option<ProgressTokenSigner>,
+    stream_service_config: StreamServiceConfig,
     block_ingestion_config: BlockIngestionConfig,
     _phantom: PhantomData<E>,
 }
@@ -274,6 +287,8 @@ where
             request_observer,
             block_ingestion_config: BlockIngestionConfig::default(),
             quota_configuration: QuotaConfiguration::NoQuota,
+            progress_token_signer: None,
+            stream_service_config: StreamServiceConfig::default(),
             blocks_per_second_quota: None,
             address: None,
             websocket_address: None,
@@ -298,6 +313,8 @@ where
             websocket_address: self.websocket_address,
             blocks_per_second_quota: self.blocks_per_second_quota,
             quota_configuration: self.quota_configuration,
+            progress_token_signer: self.progress_token_signer,
+            stream_service_config: self.stream_service_config,
             block_ingestion_config: self.block_ingestion_config,
             _phantom: self._phantom,
         }
@@ -311,6 +328,14 @@ where
         self.quota_configuration = configuration;
     }
 
+    pub fn with_progress_token_signer(&mut self, signer: ProgressTokenSigner) {
+        self.progress_token_signer = Some(signer);
+    }
+
+    pub fn with_stream_service_config(&mut self, config: StreamServiceConfig) {
+        self.stream_service_config = config;
+    }
+
     pub fn build(self) -> Result<StarkNetNode<HttpProvider, O, E>, StarkNetNodeBuilderError> {
         fs::create_dir_all(&self.datadir).map_err(StarkNetNodeBuilderError::CreateDatadir)?;
 
@@ -329,6 +354,8 @@ where
             self.block_ingestion_config,
             self.blocks_per_second_quota,
             self.quota_configuration,
+            self.progress_token_signer,
+            self.stream_service_config,
         ))
     }
 
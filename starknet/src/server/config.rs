@@ -0,0 +1,111 @@
+use std::{sync::Arc, time::Duration};
+
+use apibara_node::{
+    server::StreamAuth,
+    stream::{BatchSizeLimits, StreamRateLimit, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_STALL_TIMEOUT},
+};
+
+/// Default number of messages buffered for each stream.
+pub const DEFAULT_STREAM_BUFFER_SIZE: usize = 16;
+
+/// Default limit to stream deadline extensions, one hour.
+pub const DEFAULT_MAX_DEADLINE_EXTENSION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct StreamServiceConfig {
+    /// Batch sizes that clients can request, and the batch size of streams
+    /// that don't request one.
+    pub batch_size_limits: BatchSizeLimits,
+    /// Number of messages buffered for each stream.
+    ///
+    /// Streams produce data ahead of the client until the buffer is full, then
+    /// wait for the client to catch up.
+    pub buffer_size: usize,
+    /// Close streams whose buffer is full for longer than this.
+    ///
+    /// If `None`, streams wait for slow clients and are never closed.
+    pub backpressure_timeout: Option<Duration>,
+    /// Close streams whose client doesn't read any of the messages waiting
+    /// for it for longer than this.
+    ///
+    /// Clients that read heartbeats, and streams without data to send, are
+    /// never closed. If `None`, stalled streams are never closed.
+    pub stall_timeout: Option<Duration>,
+    /// Split batches into multiple data messages so that each message is at
+    /// most this many bytes.
+    ///
+    /// Clients reject messages larger than their own limit, 4 MiB by default
+    /// for tonic clients. If `None`, the default, batches are never split.
+    pub max_message_size: Option<usize>,
+    /// Number of blocks kept in the shared block cache.
+    ///
+    /// A value of `0` disables the cache.
+    pub block_cache_size: usize,
+    /// Number of filtered blocks shared between streams with the same filter.
+    ///
+    /// Streams at the chain head with the same filter read and filter each
+    /// block only once. A value of `0` disables sharing.
+    pub filtered_block_cache_size: usize,
+    /// Number of ingestion notifications remembered to drop duplicates.
+    ///
+    /// A value of `0` disables deduplication, the default.
+    pub ingestion_dedupe_window: usize,
+    /// How far past the client's `grpc-timeout` keepalive requests can extend
+    /// a stream deadline.
+    ///
+    /// A value of `0` disables extensions.
+    pub max_deadline_extension: Duration,
+    /// Close streams after they run for this long, whatever their finality.
+    ///
+    /// Streams end cleanly between two messages, and clients reconnect from
+    /// the last cursor they received. If `None`, streams run until the
+    /// client disconnects.
+    pub max_stream_duration: Option<Duration>,
+    /// How long a stream waits without sending data before its first heartbeat.
+    ///
+    /// If `None`, the regular heartbeat interval is used.
+    pub initial_heartbeat_delay: Option<Duration>,
+    /// Interval between heartbeats when a stream doesn't send data.
+    pub heartbeat_interval: Duration,
+    /// Limits on the messages and bytes sent by each stream.
+    ///
+    /// Streams over the limit are slowed down, not closed.
+    pub rate_limit: StreamRateLimit,
+    /// Compress responses with gzip, for clients that accept it.
+    ///
+    /// Clients that don't send `grpc-accept-encoding: gzip` receive
+    /// uncompressed responses.
+    pub gzip_compression: bool,
+    /// Reject stream filters that have no constraints or match all data of a kind.
+    ///
+    /// Public nodes should enable this, so that a single client can't stream
+    /// the whole chain. Private nodes can leave it disabled.
+    pub reject_broad_filters: bool,
+    /// Check that clients are allowed to stream before setting up the stream.
+    ///
+    /// If `None`, all clients are allowed.
+    pub auth: Option<Arc<dyn StreamAuth>>,
+}
+
+impl Default for StreamServiceConfig {
+    fn default() -> Self {
+        StreamServiceConfig {
+            batch_size_limits: BatchSizeLimits::default(),
+            buffer_size: DEFAULT_STREAM_BUFFER_SIZE,
+            backpressure_timeout: None,
+            stall_timeout: Some(DEFAULT_STALL_TIMEOUT),
+            max_message_size: None,
+            block_cache_size: 0,
+            filtered_block_cache_size: 0,
+            ingestion_dedupe_window: 0,
+            max_deadline_extension: DEFAULT_MAX_DEADLINE_EXTENSION,
+            max_stream_duration: None,
+            initial_heartbeat_delay: None,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            rate_limit: StreamRateLimit::default(),
+            gzip_compression: false,
+            reject_broad_filters: false,
+            auth: None,
+        }
+    }
+}
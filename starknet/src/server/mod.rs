@@ -1,3 +1,4 @@
+mod config;
 mod health;
 pub mod stream;
 
@@ -7,6 +8,7 @@ use apibara_core::node as node_pb;
 use apibara_node::{
     db::libmdbx::{Environment, EnvironmentKind},
     server::{QuotaClientFactory, QuotaConfiguration, RequestObserver, SimpleRequestObserver},
+    stream::ProgressTokenSigner,
 };
 use tokio::task::JoinError;
 use tokio_util::sync::CancellationToken;
@@ -14,10 +16,14 @@ use tonic::transport::Server as TonicServer;
 use tracing::{debug_span, error, info};
 
 use crate::{
-    db::DatabaseStorage, ingestion::IngestionStreamClient, server::stream::StreamService,
+    db::{CachedStorageReader, DatabaseStorage},
+    ingestion::IngestionStreamClient,
+    server::stream::StreamService,
     status::StatusClient,
 };
 
+pub use self::config::StreamServiceConfig;
+
 use self::health::HealthReporter;
 
 pub struct Server<E: EnvironmentKind, O: RequestObserver> {
@@ -27,6 +33,8 @@ pub struct Server<E: EnvironmentKind, O: RequestObserver> {
     blocks_per_second_quota: u32,
     request_observer: O,
     quota_configuration: QuotaConfiguration,
+    progress_token_signer: Option<ProgressTokenSigner>,
+    stream_service_config: StreamServiceConfig,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -60,6 +68,8 @@ where
             request_observer,
             blocks_per_second_quota,
             quota_configuration,
+            progress_token_signer: None,
+            stream_service_config: StreamServiceConfig::default(),
         }
     }
 
@@ -72,6 +82,8 @@ where
             request_observer,
             blocks_per_second_quota: self.blocks_per_second_quota,
             quota_configuration: self.quota_configuration,
+            progress_token_signer: self.progress_token_signer,
+            stream_service_config: self.stream_service_config,
         }
     }
 
@@ -80,6 +92,16 @@ where
         self
     }
 
+    pub fn with_progress_token_signer(mut self, signer: Option<ProgressTokenSigner>) -> Self {
+        self.progress_token_signer = signer;
+        self
+    }
+
+    pub fn with_stream_service_config(mut self, config: StreamServiceConfig) -> Self {
+        self.stream_service_config = config;
+        self
+    }
+
     pub async fn start(self, addr: SocketAddr, ct: CancellationToken) -> Result<(), ServerError> {
         let (mut health_reporter, health_service) = HealthReporter::new(self.db.clone());
 
@@ -93,7 +115,10 @@ where
             .build()?;
 
         let quota_client_factory = QuotaClientFactory::new(self.quota_configuration);
-        let storage = DatabaseStorage::new(self.db);
+        let storage = CachedStorageReader::with_capacity(
+            DatabaseStorage::new(self.db),
+            self.stream_service_config.block_cache_size,
+        );
 
         let stream_service = StreamService::new(
             self.ingestion,
@@ -103,6 +128,9 @@ where
             self.blocks_per_second_quota,
             quota_client_factory,
         )
+        .with_progress_token_signer(self.progress_token_signer)
+        .with_config(self.stream_service_config)
+        .with_shutdown_token(ct.clone())
         .into_service();
 
         info!(addr = %addr, "starting server");
@@ -1,30 +1,43 @@
 //! Implements the node stream service.
 
 use std::{
+    collections::VecDeque,
     pin::Pin,
     sync::Arc,
     task::{self, Poll},
 };
 
-use apibara_core::node::v1alpha2::{
-    stream_server, StatusRequest, StatusResponse, StreamDataRequest, StreamDataResponse,
+use apibara_core::{
+    node::v1alpha2::{
+        stream_data_response, stream_server, Cursor, StatusRequest, StatusResponse,
+        StreamDataRequest, StreamDataResponse,
+    },
+    starknet::v1alpha2,
 };
 use apibara_node::{
+    o11y::{self, Counter},
     server::{QuotaClientFactory, RequestObserver},
-    stream::{new_data_stream, ResponseStream, StreamConfigurationStream, StreamError},
+    stream::{
+        new_data_stream, BackpressureStream, DeadlineStream, IntoStreamError, KeepaliveStream,
+        LatestCursor, ProgressTokenSigner, RateLimitedStream, RequestedHeartbeat, ResponseStream,
+        ShutdownStream, StreamConfiguration, StreamConfigurationStream, StreamDeadline,
+        StreamError, StreamRegistry,
+    },
 };
-use futures::Stream;
+use futures::{stream::Peekable, Stream, StreamExt};
 use pin_project::pin_project;
-use tonic::{metadata::MetadataMap, Request, Response, Streaming};
-use tracing::warn;
+use tokio_util::sync::CancellationToken;
+use tonic::{codec::CompressionEncoding, metadata::MetadataMap, Request, Response, Streaming};
+use tracing::{debug, enabled, field, trace, Level, Span};
 use tracing_futures::Instrument;
 
+use super::StreamServiceConfig;
 use crate::{
-    core::IngestionMessage,
+    core::{GlobalBlockId, IngestionMessage},
     db::StorageReader,
     ingestion::IngestionStreamClient,
     status::StatusClient,
-    stream::{DbBatchProducer, SequentialCursorProducer},
+    stream::{DbBatchProducer, FilteredBlockCache, SequentialCursorProducer},
 };
 
 pub struct StreamService<R: StorageReader, O: RequestObserver> {
@@ -34,8 +47,16 @@ pub struct StreamService<R: StorageReader, O: RequestObserver> {
     storage: Arc<R>,
     request_observer: O,
     quota_client_factory: QuotaClientFactory,
+    progress_token_signer: Option<ProgressTokenSigner>,
+    config: StreamServiceConfig,
+    filtered_block_cache: Option<Arc<FilteredBlockCache>>,
+    shutdown: CancellationToken,
+    streams: StreamRegistry,
 }
 
+type StreamDataResponseStream =
+    Pin<Box<dyn Stream<Item = Result<StreamDataResponse, tonic::Status>> + Send + 'static>>;
+
 impl<R, O> StreamService<R, O>
 where
     R: StorageReader + Send + Sync + 'static,
@@ -57,22 +78,78 @@ where
             request_observer,
             blocks_per_second_quota,
             quota_client_factory,
+            progress_token_signer: None,
+            config: StreamServiceConfig::default(),
+            filtered_block_cache: None,
+            shutdown: CancellationToken::new(),
+            streams: StreamRegistry::default(),
         }
     }
 
+    /// Enables progress tokens, signed with the given signer.
+    pub fn with_progress_token_signer(mut self, signer: Option<ProgressTokenSigner>) -> Self {
+        self.progress_token_signer = signer;
+        self
+    }
+
+    pub fn with_config(mut self, config: StreamServiceConfig) -> Self {
+        self.filtered_block_cache = if config.filtered_block_cache_size > 0 {
+            let cache = FilteredBlockCache::with_capacity(config.filtered_block_cache_size);
+            Some(Arc::new(cache))
+        } else {
+            None
+        };
+        self.config = config;
+        self
+    }
+
+    /// Ends all streams, after the message they are sending, once the token is cancelled.
+    pub fn with_shutdown_token(mut self, ct: CancellationToken) -> Self {
+        self.shutdown = ct;
+        self
+    }
+
+    /// Returns the registry of running streams.
+    ///
+    /// Use it to cancel streams by their `stream_id` after the service is started.
+    pub fn stream_registry(&self) -> StreamRegistry {
+        self.streams.clone()
+    }
+
+    /// Ends all streams with the given `stream_id`, after the message they
+    /// are sending. Returns the number of streams cancelled.
+    pub fn cancel_stream(&self, stream_id: u64) -> usize {
+        self.streams.cancel(stream_id)
+    }
+
     pub fn into_service(self) -> stream_server::StreamServer<Self> {
-        stream_server::StreamServer::new(self)
+        let gzip_compression = self.config.gzip_compression;
+        let server = stream_server::StreamServer::new(self);
+        if gzip_compression {
+            server
+                .accept_compressed(CompressionEncoding::Gzip)
+                .send_compressed(CompressionEncoding::Gzip)
+        } else {
+            server
+        }
     }
 
     async fn stream_data_with_configuration<S, E>(
         &self,
         metadata: MetadataMap,
         configuration: S,
-    ) -> Result<impl Stream<Item = Result<StreamDataResponse, tonic::Status>>, tonic::Status>
+    ) -> Result<StreamDataResponseStream, tonic::Status>
     where
-        S: Stream<Item = Result<StreamDataRequest, E>> + Unpin,
+        S: Stream<Item = Result<StreamDataRequest, E>> + Unpin + Send + 'static,
         E: std::error::Error + Send + Sync + 'static,
     {
+        if let Some(auth) = &self.config.auth {
+            auth.authorize(&metadata).map_err(|status| {
+                debug!(code = ?status.code(), "client not authorized");
+                status
+            })?;
+        }
+
         let stream_span = self.request_observer.stream_data_span(&metadata);
         let stream_meter = self.request_observer.stream_data_meter(&metadata);
 
@@ -81,18 +158,39 @@ where
             .client_with_metadata(&metadata)
             .await
             .map_err(|err| {
-                warn!(error = %err, "failed to create quota client");
-                tonic::Status::internal(format!(
+                StreamError::unavailable(format!(
                     "failed to create quota client: {}",
                     err.human_readable()
                 ))
             })?;
 
-        let configuration_stream = StreamConfigurationStream::new(configuration);
+        // cancelled on shutdown, or on its own with the stream registry.
+        let registration = Arc::new(self.streams.register(self.shutdown.child_token()));
+
+        let deadline = StreamDeadline::from_metadata(&metadata, self.config.max_deadline_extension);
+        let configuration = KeepaliveStream::new(configuration, deadline.clone());
+        let configuration_stream = StreamConfigurationStream::new(configuration)
+            .with_progress_token_signer(self.progress_token_signer.clone())
+            .with_batch_size_limits(self.config.batch_size_limits)
+            .inspect({
+                let stream_span = stream_span.clone();
+                let registration = registration.clone();
+                move |configuration| {
+                    if let Ok(configuration) = configuration {
+                        record_configuration(&stream_span, configuration);
+                        registration.set_stream_id(configuration.stream_id);
+                    }
+                }
+            });
         let ingestion_stream = self.ingestion.subscribe().await;
-        let ingestion_stream = IngestionStream::new(ingestion_stream);
-        let batch_producer = DbBatchProducer::new(self.storage.clone());
+        let ingestion_stream = IngestionStream::new(ingestion_stream)
+            .with_dedupe_window(self.config.ingestion_dedupe_window);
+        let batch_producer = DbBatchProducer::new(self.storage.clone())
+            .with_reject_broad_filters(self.config.reject_broad_filters)
+            .with_filtered_block_cache(self.filtered_block_cache.clone());
         let cursor_producer = SequentialCursorProducer::new(self.storage.clone());
+        let latest_cursor = LatestCursor::default();
+        let requested_heartbeat = RequestedHeartbeat::default();
 
         let data_stream = new_data_stream(
             configuration_stream,
@@ -102,9 +200,54 @@ where
             self.blocks_per_second_quota,
             stream_meter,
             quota_client,
+            self.progress_token_signer.clone(),
+            latest_cursor.clone(),
+            requested_heartbeat.clone(),
+            self.config.max_message_size,
         );
+        // end the data stream before the response stream, so that buffered
+        // messages are still sent.
+        let data_stream = ShutdownStream::new(data_stream, registration.token())
+            .with_max_duration(self.config.max_stream_duration);
+
+        let heartbeat_interval = self.config.heartbeat_interval;
+        let initial_heartbeat_delay = self
+            .config
+            .initial_heartbeat_delay
+            .unwrap_or(heartbeat_interval);
+        let response = ResponseStream::with_heartbeat(
+            data_stream,
+            initial_heartbeat_delay,
+            heartbeat_interval,
+        )
+        .with_latest_cursor(latest_cursor)
+        .with_requested_heartbeat(requested_heartbeat)
+        .inspect(move |response| {
+            // the stream stays in the registry until the response is dropped.
+            let _registration = &registration;
+            if let Ok(response) = response {
+                trace_batch_size(response);
+            }
+        })
+        .instrument(stream_span);
+
+        let response: StreamDataResponseStream = if self.config.rate_limit.is_unlimited() {
+            Box::pin(response)
+        } else {
+            Box::pin(RateLimitedStream::new(response, self.config.rate_limit))
+        };
+
+        let response: StreamDataResponseStream = Box::pin(BackpressureStream::new(
+            response,
+            self.config.buffer_size,
+            self.config.backpressure_timeout,
+            self.config.stall_timeout,
+        ));
 
-        Ok(ResponseStream::new(data_stream).instrument(stream_span))
+        match deadline {
+            None => Ok(response),
+            Some(deadline) => Ok(Box::pin(DeadlineStream::new(response, deadline))),
+        }
     }
 }
 
@@ -114,11 +257,9 @@ where
     R: StorageReader + Send + Sync + 'static,
     O: RequestObserver,
 {
-    type StreamDataStream =
-        Pin<Box<dyn Stream<Item = Result<StreamDataResponse, tonic::Status>> + Send + 'static>>;
+    type StreamDataStream = StreamDataResponseStream;
 
-    type StreamDataImmutableStream =
-        Pin<Box<dyn Stream<Item = Result<StreamDataResponse, tonic::Status>> + Send + 'static>>;
+    type StreamDataImmutableStream = StreamDataResponseStream;
 
     async fn stream_data(
         &self,
@@ -128,7 +269,7 @@ where
         let response = self
             .stream_data_with_configuration(metadata, request.into_inner())
             .await?;
-        Ok(Response::new(Box::pin(response)))
+        Ok(Response::new(response))
     }
 
     async fn stream_data_immutable(
@@ -142,21 +283,91 @@ where
         let response = self
             .stream_data_with_configuration(metadata, configuration_stream)
             .await?;
-        Ok(Response::new(Box::pin(response)))
+        Ok(Response::new(response))
     }
 
     async fn status(
         &self,
         _request: Request<StatusRequest>,
     ) -> Result<Response<StatusResponse>, tonic::Status> {
-        self.status_client
-            .get_status()
-            .await
-            .map(Response::new)
-            .map_err(|e| tonic::Status::internal(format!("Failed to get status: {}", e)))
+        let mut status =
+            self.status_client.get_status().await.map_err(|err| {
+                StreamError::unavailable(format!("failed to get status: {}", err))
+            })?;
+
+        let last_finalized = self
+            .storage
+            .highest_finalized_block()
+            .map_err(|err| err.into_stream_error())?;
+        status.last_finalized = last_finalized.map(|cursor| cursor.to_cursor());
+        status.ingestion_lag = blocks_between(&status.current_head, &status.last_ingested);
+        status.finality_lag = blocks_between(&status.last_ingested, &status.last_finalized);
+
+        Ok(Response::new(status))
     }
 }
 
+/// Returns how many blocks `to` is behind `from`, if both are known.
+fn blocks_between(from: &Option<Cursor>, to: &Option<Cursor>) -> Option<u64> {
+    match (from, to) {
+        (Some(from), Some(to)) => Some(from.order_key.saturating_sub(to.order_key)),
+        _ => None,
+    }
+}
+
+/// Records the stream configuration on the stream span.
+fn record_configuration(
+    span: &Span,
+    configuration: &StreamConfiguration<GlobalBlockId, v1alpha2::Filter>,
+) {
+    span.record("stream_id", configuration.stream_id);
+    span.record("batch_size", configuration.batch_size);
+    span.record("finality", field::debug(configuration.finality));
+    span.record("filter", filter_summary(&configuration.filter).as_str());
+}
+
+/// Emits the number of blocks and bytes of a data message, as a trace event
+/// of the stream span.
+///
+/// Nothing is computed unless trace events are enabled.
+fn trace_batch_size(response: &StreamDataResponse) {
+    if !enabled!(Level::TRACE) {
+        return;
+    }
+    if let Some((items, bytes)) = batch_size(response) {
+        trace!(items, bytes, "send batch");
+    }
+}
+
+/// Returns the number of items and serialized bytes in a data message.
+fn batch_size(response: &StreamDataResponse) -> Option<(usize, usize)> {
+    match &response.message {
+        Some(stream_data_response::Message::Data(data)) => {
+            Some((data.data.len(), data.data.iter().map(Vec::len).sum()))
+        }
+        _ => None,
+    }
+}
+
+/// Summarizes the filters by what they include, without the addresses or keys
+/// they match.
+fn filter_summary(filters: &[v1alpha2::Filter]) -> String {
+    let header = filters.iter().any(|filter| filter.header.is_some());
+    let transactions: usize = filters.iter().map(|filter| filter.transactions.len()).sum();
+    let events: usize = filters.iter().map(|filter| filter.events.len()).sum();
+    let messages: usize = filters.iter().map(|filter| filter.messages.len()).sum();
+    let state_update = filters.iter().any(|filter| filter.state_update.is_some());
+    format!(
+        "filters={} header={} transactions={} events={} messages={} state_update={}",
+        filters.len(),
+        header,
+        transactions,
+        events,
+        messages,
+        state_update
+    )
+}
+
 /// A stream that yields the configuration once, and is pending forever after that.
 struct ImmutableRequestStream {
     request: Option<StreamDataRequest>,
@@ -177,6 +388,19 @@ impl Stream for ImmutableRequestStream {
 }
 
 /// A simple adapter from a generic ingestion stream to the one used by the server/stream module.
+///
+/// If enabled with [IngestionStream::with_dedupe_window], finalized and accepted
+/// notifications for blocks that were recently seen are dropped.
+///
+/// The data stream only reads notifications when it's not waiting for the
+/// client, so ingestion is not consumed while the client is slow and
+/// notifications queue up in the subscription instead. The queue is bounded
+/// by [INGESTION_STREAM_CAPACITY](crate::ingestion::INGESTION_STREAM_CAPACITY):
+/// a stream that falls further behind fails, and the client reconnects from
+/// its last cursor. Queued finalized (or accepted) notifications are
+/// superseded by the most recent one, so they are merged and a burst of
+/// notifications is handled, and reads storage, only once. This is enough to
+/// keep the work done by slow streams proportional to the data they send.
 #[pin_project]
 pub struct IngestionStream<L, E>
 where
@@ -184,15 +408,102 @@ where
     E: std::error::Error + Send + Sync + 'static,
 {
     #[pin]
-    inner: L,
+    inner: Peekable<L>,
+    recently_seen: RecentlySeen,
+    deduped_counter: Counter<u64>,
+    coalesced_counter: Counter<u64>,
+}
+
+/// The most recent finalized and accepted notifications.
+struct RecentlySeen {
+    size: usize,
+    messages: VecDeque<IngestionMessage>,
 }
+
 impl<L, E> IngestionStream<L, E>
 where
     L: Stream<Item = Result<IngestionMessage, E>>,
     E: std::error::Error + Send + Sync + 'static,
 {
     pub fn new(inner: L) -> Self {
-        IngestionStream { inner }
+        let meter = o11y::meter("starknet_stream");
+        IngestionStream {
+            inner: inner.peekable(),
+            recently_seen: RecentlySeen::new(0),
+            deduped_counter: meter.u64_counter("ingestion_notifications_deduped").init(),
+            coalesced_counter: meter
+                .u64_counter("ingestion_notifications_coalesced")
+                .init(),
+        }
+    }
+
+    /// Remember the last `size` notifications to detect duplicates.
+    ///
+    /// A value of `0`, the default, disables deduplication.
+    pub fn with_dedupe_window(mut self, size: usize) -> Self {
+        self.recently_seen = RecentlySeen::new(size);
+        self
+    }
+}
+
+impl RecentlySeen {
+    fn new(size: usize) -> Self {
+        RecentlySeen {
+            size,
+            messages: VecDeque::with_capacity(size),
+        }
+    }
+
+    /// Returns true if the message was already seen, otherwise remembers it.
+    fn check_duplicate(&mut self, message: &IngestionMessage) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+
+        match message {
+            IngestionMessage::Finalized(_) | IngestionMessage::Accepted(_) => {}
+            // pending blocks are updated in place, so the same id is sent many times.
+            IngestionMessage::Pending(_) => return false,
+            // blocks after the invalidated one can be ingested again.
+            IngestionMessage::Invalidate(_) => {
+                self.messages.clear();
+                return false;
+            }
+        }
+
+        if self
+            .messages
+            .iter()
+            .any(|seen| is_same_message(seen, message))
+        {
+            return true;
+        }
+
+        if self.messages.len() >= self.size {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message.clone());
+        false
+    }
+}
+
+/// Returns true if `next` makes `message` obsolete, because it moves the same
+/// chain head to the same block or a later one.
+fn is_superseded_by(message: &IngestionMessage, next: &IngestionMessage) -> bool {
+    match (message, next) {
+        (IngestionMessage::Finalized(a), IngestionMessage::Finalized(b)) => {
+            a.number() <= b.number()
+        }
+        (IngestionMessage::Accepted(a), IngestionMessage::Accepted(b)) => a.number() <= b.number(),
+        _ => false,
+    }
+}
+
+fn is_same_message(a: &IngestionMessage, b: &IngestionMessage) -> bool {
+    match (a, b) {
+        (IngestionMessage::Finalized(a), IngestionMessage::Finalized(b)) => a == b,
+        (IngestionMessage::Accepted(a), IngestionMessage::Accepted(b)) => a == b,
+        _ => false,
     }
 }
 
@@ -204,14 +515,36 @@ where
     type Item = Result<IngestionMessage, StreamError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = self.project();
-        match this.inner.poll_next(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(Ok(value))) => Poll::Ready(Some(Ok(value))),
-            Poll::Ready(Some(Err(err))) => {
-                let err = StreamError::internal(err);
-                Poll::Ready(Some(Err(err)))
+        let mut this = self.project();
+        loop {
+            match this.inner.as_mut().poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Ready(Some(Ok(mut value))) => {
+                    // only merge notifications that are already queued, never wait for more.
+                    while let Poll::Ready(Some(Ok(next))) = this.inner.as_mut().poll_peek(cx) {
+                        if !is_superseded_by(&value, next) {
+                            break;
+                        }
+                        this.coalesced_counter
+                            .add(&o11y::Context::current(), 1, &[]);
+                        if let Poll::Ready(Some(Ok(next))) = this.inner.as_mut().poll_next(cx) {
+                            value = next;
+                        }
+                    }
+
+                    if this.recently_seen.check_duplicate(&value) {
+                        debug!(message = ?value, "skip duplicate ingestion message");
+                        this.deduped_counter.add(&o11y::Context::current(), 1, &[]);
+                        continue;
+                    }
+                    return Poll::Ready(Some(Ok(value)));
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    // the node can't follow the chain head without ingestion messages.
+                    let err = StreamError::unavailable(err);
+                    return Poll::Ready(Some(Err(err)));
+                }
             }
         }
     }
@@ -220,3 +553,452 @@ where
         self.inner.size_hint()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures::{stream, FutureExt, StreamExt};
+
+    use apibara_core::{
+        node::v1alpha2::{
+            stream_data_response, stream_server::Stream as _, Data, DataFinality, Heartbeat,
+            StatusResponse, StreamDataRequest, StreamDataResponse,
+        },
+        starknet::v1alpha2::{
+            Block, BlockStatus, Event, EventFilter, FieldElement, Filter, HeaderFilter,
+            Transaction, TransactionReceipt, TransactionWithReceipt,
+        },
+    };
+    use apibara_node::{
+        core::Cursor,
+        server::{QuotaClientFactory, QuotaConfiguration, SimpleRequestObserver},
+    };
+    use prost::Message;
+    use tonic::{metadata::MetadataMap, Request};
+
+    use crate::{
+        core::{BlockHash, GlobalBlockId, IngestionMessage},
+        ingestion::{IngestionStreamPublisher, INGESTION_STREAM_CAPACITY},
+        status::StatusClient,
+        testing::{new_block, InMemoryStorage, MockIngestionStream},
+    };
+
+    use super::{batch_size, blocks_between, filter_summary, IngestionStream, StreamService};
+
+    fn new_block_id(num: u64) -> GlobalBlockId {
+        let mut b = [0; 32];
+        b[24..].copy_from_slice(&num.to_be_bytes());
+        GlobalBlockId::new(num, BlockHash::from_slice(&b).unwrap())
+    }
+
+    async fn collect_ingestion_stream(
+        messages: Vec<IngestionMessage>,
+        window: usize,
+    ) -> Vec<IngestionMessage> {
+        let inner = stream::iter(messages.into_iter().map(Ok::<_, std::io::Error>));
+        IngestionStream::new(inner)
+            .with_dedupe_window(window)
+            .map(|message| message.unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_ingestion_messages() {
+        let messages = vec![
+            IngestionMessage::Accepted(new_block_id(1)),
+            IngestionMessage::Accepted(new_block_id(1)),
+            IngestionMessage::Finalized(new_block_id(1)),
+            IngestionMessage::Pending(new_block_id(2)),
+            IngestionMessage::Pending(new_block_id(2)),
+            IngestionMessage::Accepted(new_block_id(2)),
+            IngestionMessage::Invalidate(new_block_id(1)),
+            IngestionMessage::Accepted(new_block_id(2)),
+        ];
+
+        let received = collect_ingestion_stream(messages.clone(), 8).await;
+        assert_eq!(received.len(), 7);
+        assert!(matches!(received[1], IngestionMessage::Finalized(_)));
+        assert!(matches!(received[6], IngestionMessage::Accepted(_)));
+
+        // queued duplicates are still merged.
+        let received = collect_ingestion_stream(messages, 0).await;
+        assert_eq!(received.len(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_is_disabled_by_default() {
+        let messages = vec![
+            IngestionMessage::Accepted(new_block_id(1)),
+            IngestionMessage::Finalized(new_block_id(1)),
+            IngestionMessage::Accepted(new_block_id(1)),
+        ];
+
+        let inner = stream::iter(messages.into_iter().map(Ok::<_, std::io::Error>));
+        let received = IngestionStream::new(inner)
+            .map(|message| message.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(received.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_burst_with_stalled_consumer() {
+        let (client, publisher) = IngestionStreamPublisher::new();
+        let mut stream = Box::pin(IngestionStream::new(client.subscribe().await));
+
+        // the consumer doesn't read messages while the burst is published.
+        let burst = INGESTION_STREAM_CAPACITY as u64;
+        for number in 1..=burst {
+            publisher.publish_finalized(new_block_id(number)).unwrap();
+        }
+
+        // the whole burst is handled as a single message, then the stream waits.
+        let message = stream.next().await.unwrap().unwrap();
+        assert!(matches!(message, IngestionMessage::Finalized(id) if id.number() == burst));
+        assert!(stream.next().now_or_never().is_none());
+
+        // a consumer that falls behind more than the capacity fails.
+        for number in 1..=burst + 1 {
+            publisher
+                .publish_finalized(new_block_id(burst + number))
+                .unwrap();
+        }
+        assert!(stream.next().await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_ingestion_messages() {
+        let messages = vec![
+            IngestionMessage::Finalized(new_block_id(1)),
+            IngestionMessage::Finalized(new_block_id(2)),
+            IngestionMessage::Finalized(new_block_id(3)),
+            IngestionMessage::Accepted(new_block_id(4)),
+            IngestionMessage::Accepted(new_block_id(5)),
+            IngestionMessage::Invalidate(new_block_id(4)),
+            IngestionMessage::Accepted(new_block_id(5)),
+            IngestionMessage::Finalized(new_block_id(4)),
+            // an older block doesn't supersede the previous message.
+            IngestionMessage::Finalized(new_block_id(3)),
+        ];
+
+        let received = collect_ingestion_stream(messages, 0).await;
+        let received = received
+            .iter()
+            .map(|message| match message {
+                IngestionMessage::Finalized(id) => ('f', id.number()),
+                IngestionMessage::Accepted(id) => ('a', id.number()),
+                IngestionMessage::Pending(id) => ('p', id.number()),
+                IngestionMessage::Invalidate(id) => ('i', id.number()),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            vec![('f', 3), ('a', 5), ('i', 4), ('a', 5), ('f', 4), ('f', 3)]
+        );
+    }
+
+    /// Returns the end cursor of the next data message.
+    async fn next_data_end_cursor<S>(stream: &mut S) -> u64
+    where
+        S: futures::Stream<Item = Result<StreamDataResponse, tonic::Status>> + Unpin,
+    {
+        loop {
+            let response = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .expect("no data message")
+                .unwrap()
+                .unwrap();
+            if let Some(stream_data_response::Message::Data(data)) = response.message {
+                assert!(!data.data.is_empty());
+                return data.end_cursor.unwrap().order_key;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_finalized_data() {
+        let storage = InMemoryStorage::with_chain(Some(4), 8);
+        let (ingestion_client, ingestion) = MockIngestionStream::new(storage.clone());
+        let service = StreamService::new(
+            Arc::new(ingestion_client),
+            StatusClient::with_static_status(StatusResponse::default()),
+            storage,
+            SimpleRequestObserver::default(),
+            10_000,
+            QuotaClientFactory::new(QuotaConfiguration::NoQuota),
+        );
+
+        let filter = Filter {
+            header: Some(HeaderFilter { weak: false }),
+            ..Filter::default()
+        };
+        let request = StreamDataRequest {
+            batch_size: Some(2),
+            finality: Some(DataFinality::DataStatusFinalized as i32),
+            filter: filter.encode_to_vec(),
+            ..StreamDataRequest::default()
+        };
+        let mut stream = service
+            .stream_data_immutable(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+
+        for end_cursor in [1, 3, 4] {
+            assert_eq!(next_data_end_cursor(&mut stream).await, end_cursor);
+        }
+
+        ingestion.finalize(6);
+        assert_eq!(next_data_end_cursor(&mut stream).await, 6);
+    }
+
+    fn events_request(
+        stream_id: u64,
+        from_address: u64,
+        starting_block: Option<u64>,
+    ) -> Result<StreamDataRequest, std::io::Error> {
+        let filter = Filter {
+            events: vec![EventFilter {
+                from_address: Some(FieldElement::from_u64(from_address)),
+                ..EventFilter::default()
+            }],
+            ..Filter::default()
+        };
+        Ok(StreamDataRequest {
+            stream_id: Some(stream_id),
+            batch_size: Some(2),
+            finality: Some(DataFinality::DataStatusFinalized as i32),
+            starting_cursor: starting_block.map(|number| new_block_id(number).to_proto()),
+            filter: filter.encode_to_vec(),
+            ..StreamDataRequest::default()
+        })
+    }
+
+    /// Returns the stream id, the end cursor and the address of the events of
+    /// the next data message.
+    async fn next_events<S>(stream: &mut S) -> (u64, u64, Vec<FieldElement>)
+    where
+        S: futures::Stream<Item = Result<StreamDataResponse, tonic::Status>> + Unpin,
+    {
+        loop {
+            let response = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .expect("no data message")
+                .unwrap()
+                .unwrap();
+            if let Some(stream_data_response::Message::Data(data)) = response.message {
+                let addresses = data
+                    .data
+                    .iter()
+                    .flat_map(|block| Block::decode(block.as_slice()).unwrap().events)
+                    .filter_map(|event| event.event?.from_address)
+                    .collect();
+                let end_cursor = data.end_cursor.unwrap().order_key;
+                return (response.stream_id, end_cursor, addresses);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_change_filter_mid_stream() {
+        // every block has an event from address 1 and one from address 2.
+        let storage = InMemoryStorage::new((0..=8).map(|number| {
+            let events = [1, 2]
+                .map(|from_address| Event {
+                    from_address: Some(FieldElement::from_u64(from_address)),
+                    ..Event::default()
+                })
+                .to_vec();
+            Block {
+                transactions: vec![TransactionWithReceipt {
+                    transaction: Some(Transaction::default()),
+                    receipt: Some(TransactionReceipt {
+                        events,
+                        ..TransactionReceipt::default()
+                    }),
+                }],
+                ..new_block(new_block_id(number), BlockStatus::AcceptedOnL1)
+            }
+        }));
+        let (ingestion_client, _ingestion) = MockIngestionStream::new(storage.clone());
+        let service = StreamService::new(
+            Arc::new(ingestion_client),
+            StatusClient::with_static_status(StatusResponse::default()),
+            storage,
+            SimpleRequestObserver::default(),
+            10_000,
+            QuotaClientFactory::new(QuotaConfiguration::NoQuota),
+        );
+
+        let (requests, configuration) = futures::channel::mpsc::unbounded();
+        requests.unbounded_send(events_request(1, 1, None)).unwrap();
+        let mut stream = service
+            .stream_data_with_configuration(MetadataMap::new(), configuration)
+            .await
+            .unwrap();
+
+        let (stream_id, end_cursor, addresses) = next_events(&mut stream).await;
+        assert_eq!((stream_id, end_cursor), (1, 1));
+        assert_eq!(addresses, vec![FieldElement::from_u64(1); 2]);
+
+        // the new filter applies from the new starting cursor.
+        requests
+            .unbounded_send(events_request(2, 2, Some(4)))
+            .unwrap();
+        let (end_cursor, addresses) = loop {
+            let (stream_id, end_cursor, addresses) = next_events(&mut stream).await;
+            if stream_id == 2 {
+                break (end_cursor, addresses);
+            }
+            // messages already in flight use the old filter.
+            assert!(addresses.iter().all(|a| *a == FieldElement::from_u64(1)));
+        };
+        assert_eq!(end_cursor, 6);
+        assert_eq!(addresses, vec![FieldElement::from_u64(2); 2]);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_stream() {
+        let storage = InMemoryStorage::with_chain(Some(4), 8);
+        let (ingestion_client, _ingestion) = MockIngestionStream::new(storage.clone());
+        let service = StreamService::new(
+            Arc::new(ingestion_client),
+            StatusClient::with_static_status(StatusResponse::default()),
+            storage,
+            SimpleRequestObserver::default(),
+            10_000,
+            QuotaClientFactory::new(QuotaConfiguration::NoQuota),
+        );
+
+        let filter = Filter {
+            header: Some(HeaderFilter { weak: false }),
+            ..Filter::default()
+        };
+        let request = StreamDataRequest {
+            stream_id: Some(7),
+            batch_size: Some(2),
+            finality: Some(DataFinality::DataStatusFinalized as i32),
+            filter: filter.encode_to_vec(),
+            ..StreamDataRequest::default()
+        };
+        let mut stream = service
+            .stream_data_immutable(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(next_data_end_cursor(&mut stream).await, 1);
+
+        // the default stream id doesn't match streams with other ids.
+        assert_eq!(service.cancel_stream(0), 0);
+        assert_eq!(service.cancel_stream(7), 1);
+
+        // the stream ends cleanly after the messages that were already produced.
+        let ended = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(response) = stream.next().await {
+                assert!(response.is_ok());
+            }
+        })
+        .await;
+        assert!(ended.is_ok());
+        assert!(service.stream_registry().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_notify_finalized_head() {
+        let storage = InMemoryStorage::with_chain(Some(4), 8);
+        let (ingestion_client, ingestion) = MockIngestionStream::new(storage.clone());
+        let service = StreamService::new(
+            Arc::new(ingestion_client),
+            StatusClient::with_static_status(StatusResponse::default()),
+            storage,
+            SimpleRequestObserver::default(),
+            10_000,
+            QuotaClientFactory::new(QuotaConfiguration::NoQuota),
+        );
+
+        let filter = Filter {
+            header: Some(HeaderFilter { weak: false }),
+            ..Filter::default()
+        };
+        let request = StreamDataRequest {
+            batch_size: Some(2),
+            finality: Some(DataFinality::DataStatusFinalized as i32),
+            filter: filter.encode_to_vec(),
+            notify_finalized: true,
+            ..StreamDataRequest::default()
+        };
+        let mut stream = service
+            .stream_data_immutable(Request::new(request))
+            .await
+            .unwrap()
+            .into_inner();
+
+        for end_cursor in [1, 3, 4] {
+            assert_eq!(next_data_end_cursor(&mut stream).await, end_cursor);
+        }
+
+        // the notification is sent before the data of the new finalized block.
+        ingestion.finalize(6);
+        let response = loop {
+            let response = tokio::time::timeout(Duration::from_secs(5), stream.next())
+                .await
+                .expect("no finalized head message")
+                .unwrap()
+                .unwrap();
+            match response.message {
+                Some(stream_data_response::Message::Heartbeat(_)) => {}
+                message => break message,
+            }
+        };
+        let Some(stream_data_response::Message::Finalized(finalized)) = response else {
+            panic!("expected finalized head, got {:?}", response);
+        };
+        assert_eq!(finalized.cursor.unwrap().order_key, 6);
+        assert_eq!(next_data_end_cursor(&mut stream).await, 6);
+    }
+
+    #[test]
+    fn test_blocks_between() {
+        let head = Some(new_block_id(10).to_cursor());
+        let finalized = Some(new_block_id(4).to_cursor());
+        assert_eq!(blocks_between(&head, &finalized), Some(6));
+        assert_eq!(blocks_between(&finalized, &head), Some(0));
+        assert_eq!(blocks_between(&head, &None), None);
+    }
+
+    #[test]
+    fn test_batch_size() {
+        let data = StreamDataResponse {
+            message: Some(stream_data_response::Message::Data(Data {
+                data: vec![vec![0; 10], vec![0; 5]],
+                ..Data::default()
+            })),
+            ..StreamDataResponse::default()
+        };
+        assert_eq!(batch_size(&data), Some((2, 15)));
+
+        let heartbeat = StreamDataResponse {
+            message: Some(stream_data_response::Message::Heartbeat(
+                Heartbeat::default(),
+            )),
+            ..StreamDataResponse::default()
+        };
+        assert_eq!(batch_size(&heartbeat), None);
+    }
+
+    #[test]
+    fn test_filter_summary() {
+        let filter = Filter {
+            header: Some(HeaderFilter { weak: false }),
+            events: vec![EventFilter::default(), EventFilter::default()],
+            ..Filter::default()
+        };
+        let summary = filter_summary(&[filter, Filter::default()]);
+        assert_eq!(
+            summary,
+            "filters=2 header=true transactions=0 events=2 messages=0 state_update=false"
+        );
+    }
+}
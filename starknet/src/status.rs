@@ -69,6 +69,7 @@ impl<G: Provider> StatusService<G> {
                             let response = StatusResponse {
                                 current_head: current_head.map(|c| c.to_cursor()),
                                 last_ingested: last_ingested.map(|c| c.to_cursor()),
+                                ..StatusResponse::default()
                             };
                             let _ = tx.send(response);
                         }
@@ -124,4 +125,18 @@ impl StatusClient {
         let response = rx.await?;
         Ok(response)
     }
+
+    /// Creates a client that always returns the given status, without a status service.
+    ///
+    /// Must be called from a tokio runtime.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_static_status(status: StatusResponse) -> StatusClient {
+        let (tx, mut rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while let Some(Message::GetStatus(tx)) = rx.recv().await {
+                let _ = tx.send(status.clone());
+            }
+        });
+        StatusClient { tx }
+    }
 }
@@ -1,22 +1,48 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use apibara_core::starknet::v1alpha2;
+use apibara_core::{
+    filter::Filter as FilterTrait, node::v1alpha2::DataFinality, starknet::v1alpha2,
+};
 use apibara_node::{
     async_trait,
     server::RequestMeter,
-    stream::{BatchProducer, StreamConfiguration, StreamError},
+    stream::{BatchProducer, IntoStreamError, StreamConfiguration, StreamError},
 };
+use prost::Message;
 use tracing::debug_span;
 
 use crate::{core::GlobalBlockId, db::StorageReader};
 
+use super::filtered_cache::{FilteredBlock, FilteredBlockCache};
+
+/// Number of blocking tasks used to read a batch of blocks when streaming from genesis.
+const BULK_READ_TASKS: usize = 4;
+
 /// A [BatchProducer] that reads data from the database.
+///
+/// Streams of finalized data starting from genesis are initial full syncs,
+/// where every batch is full and the client is far behind the chain head. For
+/// these streams the producer reads the blocks of each batch in parallel on
+/// the runtime's blocking thread pool, so that throughput scales with the number
+/// of concurrent reads instead of being
+/// bound by the latency of sequential reads. Once the stream reaches the chain
+/// head, batches contain a single block and are read sequentially as usual.
+///
+/// With a [FilteredBlockCache], streams at the chain head share the filtered
+/// data with the other streams that use the same filter.
 pub struct DbBatchProducer<R>
 where
     R: StorageReader + Send + Sync + 'static,
 {
     storage: Arc<R>,
-    inner: Vec<InnerProducer<R>>,
+    /// Shared with the bulk read tasks.
+    inner: Arc<Vec<InnerProducer<R>>>,
+    bulk: bool,
+    reject_broad_filters: bool,
+    filtered_cache: Option<Arc<FilteredBlockCache>>,
 }
 
 struct InnerProducer<R>
@@ -25,6 +51,8 @@ where
 {
     storage: Arc<R>,
     filter: v1alpha2::Filter,
+    /// The encoded filter, used as key in the filtered block cache.
+    filter_key: Arc<[u8]>,
 }
 
 impl<R> DbBatchProducer<R>
@@ -33,39 +61,124 @@ where
 {
     pub fn new(storage: Arc<R>) -> Self {
         DbBatchProducer {
-            inner: Vec::default(),
+            inner: Arc::default(),
+            bulk: false,
+            reject_broad_filters: false,
+            filtered_cache: None,
             storage,
         }
     }
 
+    /// Share filtered blocks with the other streams that use this cache.
+    pub fn with_filtered_block_cache(mut self, cache: Option<Arc<FilteredBlockCache>>) -> Self {
+        self.filtered_cache = cache;
+        self
+    }
+
+    /// Reject filters that have no constraints or match all data of a kind.
+    pub fn with_reject_broad_filters(mut self, reject_broad_filters: bool) -> Self {
+        self.reject_broad_filters = reject_broad_filters;
+        self
+    }
+
     fn block_data<M: RequestMeter>(
         &self,
         block_id: &GlobalBlockId,
         meter: &M,
     ) -> Result<Vec<v1alpha2::Block>, R::Error> {
         let mut blocks = Vec::default();
+        for inner in self.inner.iter() {
+            blocks.push(inner.block_data(block_id, meter)?);
+        }
+        Ok(self.merge_blocks(blocks))
+    }
+
+    /// Same as [Self::block_data], but reads the filtered data from `cache` if possible.
+    async fn cached_block_data<M: RequestMeter>(
+        &self,
+        block_id: &GlobalBlockId,
+        cache: &FilteredBlockCache,
+        meter: &M,
+    ) -> Result<Vec<v1alpha2::Block>, R::Error> {
+        let mut blocks = Vec::default();
+        for inner in self.inner.iter() {
+            blocks.push(inner.cached_block_data(cache, block_id, meter).await?);
+        }
+        Ok(self.merge_blocks(blocks))
+    }
+
+    /// Returns the blocks of each filter, in order.
+    fn merge_blocks(&self, blocks: Vec<Option<v1alpha2::Block>>) -> Vec<v1alpha2::Block> {
+        // If all blocks are empty, return an empty vector to skip this batch.
+        if blocks.iter().all(Option::is_none) {
+            return Vec::default();
+        }
+
         let is_multi_filter = self.inner.len() > 1;
-        let mut all_empty = true;
-        for inner in &self.inner {
-            if let Some(block) = inner.block_data(block_id, meter)? {
-                all_empty = false;
-                blocks.push(block);
-            } else if is_multi_filter {
+        blocks
+            .into_iter()
+            .filter_map(|block| match block {
+                Some(block) => Some(block),
                 // push an empty block to keep the order of the blocks.
-                let empty = v1alpha2::Block {
+                None if is_multi_filter => Some(v1alpha2::Block {
                     empty: true,
                     ..Default::default()
-                };
-                blocks.push(empty);
-            }
-        }
+                }),
+                None => None,
+            })
+            .collect()
+    }
 
-        // If all blocks are empty, return an empty vector to skip this batch.
-        if all_empty {
-            Ok(Vec::default())
-        } else {
-            Ok(blocks)
+    /// Reads the data of the given blocks in parallel.
+    ///
+    /// Blocks are split into contiguous chunks, each read by a task on the
+    /// blocking thread pool, and the results are concatenated in order.
+    async fn bulk_block_data<M: RequestMeter>(
+        &self,
+        cursors: &[GlobalBlockId],
+        meter: &M,
+    ) -> Result<Vec<Vec<v1alpha2::Block>>, StreamError> {
+        let chunk_size = cursors.len().div_ceil(BULK_READ_TASKS);
+        let tasks = cursors
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let inner = self.inner.clone();
+                let chunk = chunk.to_vec();
+                tokio::task::spawn_blocking(move || {
+                    chunk
+                        .iter()
+                        .map(|cursor| {
+                            inner
+                                .iter()
+                                .map(|inner| inner.filter_block(cursor))
+                                .collect::<Result<Vec<_>, _>>()
+                        })
+                        .collect::<Result<Vec<_>, R::Error>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut batch = Vec::default();
+        for task in tasks {
+            let filtered = task
+                .await
+                .map_err(StreamError::internal)?
+                .map_err(IntoStreamError::into_stream_error)?;
+            // the meter is only available here, so blocks are metered after they're read.
+            for blocks in filtered {
+                let blocks = blocks
+                    .into_iter()
+                    .map(|FilteredBlock { block, counter }| {
+                        if block.is_some() {
+                            counter.update_meter(meter);
+                        }
+                        block
+                    })
+                    .collect();
+                batch.push(self.merge_blocks(blocks));
+            }
         }
+        Ok(batch)
     }
 }
 
@@ -73,12 +186,41 @@ impl<R> InnerProducer<R>
 where
     R: StorageReader + Send + Sync + 'static,
 {
-    #[tracing::instrument(skip(self, meter), level = "debug")]
     fn block_data<M: RequestMeter>(
         &self,
         block_id: &GlobalBlockId,
         meter: &M,
     ) -> Result<Option<v1alpha2::Block>, R::Error> {
+        let FilteredBlock { block, counter } = self.filter_block(block_id)?;
+        if block.is_some() {
+            counter.update_meter(meter);
+        }
+        Ok(block)
+    }
+
+    /// Same as [Self::block_data], but shares the filtered data through the cache.
+    ///
+    /// Each stream is still metered for the data it receives.
+    async fn cached_block_data<M: RequestMeter>(
+        &self,
+        cache: &FilteredBlockCache,
+        block_id: &GlobalBlockId,
+        meter: &M,
+    ) -> Result<Option<v1alpha2::Block>, R::Error> {
+        let (FilteredBlock { mut block, counter }, cached) = cache
+            .get_or_filter(&self.filter_key, block_id, || self.filter_block(block_id))
+            .await?;
+        if let Some(block) = block.as_mut() {
+            if cached {
+                block.status = self.status(block_id)? as i32;
+            }
+            counter.update_meter(meter);
+        }
+        Ok(block)
+    }
+
+    #[tracing::instrument(skip(self), level = "debug")]
+    fn filter_block(&self, block_id: &GlobalBlockId) -> Result<FilteredBlock, R::Error> {
         let mut has_data = false;
 
         let mut data_counter = DataCounter::default();
@@ -89,18 +231,70 @@ where
             has_data |= header.is_some();
         }
 
-        let transactions = self.transactions(block_id, &mut data_counter)?;
-        has_data |= !transactions.is_empty();
+        let (transactions, mut events, l2_to_l1_messages, mut transaction_groups) =
+            if self.filter.group_by_transaction {
+                let transaction_groups = self.transaction_groups(block_id, &mut data_counter)?;
+                has_data |= !transaction_groups.is_empty();
+                (
+                    Vec::default(),
+                    Vec::default(),
+                    Vec::default(),
+                    transaction_groups,
+                )
+            } else {
+                let transactions = self.transactions(block_id, &mut data_counter)?;
+                has_data |= !transactions.is_empty();
+
+                let events = self.events(block_id, &mut data_counter)?;
+                has_data |= !events.is_empty();
+
+                let l2_to_l1_messages = self.l2_to_l1_messages(block_id, &mut data_counter)?;
+                has_data |= !l2_to_l1_messages.is_empty();
+
+                (transactions, events, l2_to_l1_messages, Vec::default())
+            };
+
+        let aggregates = if self.filter.group_by_transaction {
+            self.aggregates(
+                transaction_groups
+                    .iter()
+                    .flat_map(|group| group.events.iter()),
+            )
+        } else {
+            self.aggregates(events.iter().filter_map(|event| event.event.as_ref()))
+        };
 
-        let events = self.events(block_id, &mut data_counter)?;
-        has_data |= !events.is_empty();
+        // events are projected last, since sorting and aggregates can use any field.
+        if !self.filter.event_fields.is_empty() {
+            let grouped_events = transaction_groups
+                .iter_mut()
+                .flat_map(|group| group.events.iter_mut());
+            let events = events.iter_mut().filter_map(|event| event.event.as_mut());
+            for event in grouped_events.chain(events) {
+                event.project(&self.filter.event_fields);
+            }
+        }
 
-        let l2_to_l1_messages = self.l2_to_l1_messages(block_id, &mut data_counter)?;
-        has_data |= !l2_to_l1_messages.is_empty();
+        let (events, event_rows) = if self.is_flat_rows() {
+            let block_hash: v1alpha2::FieldElement = block_id.hash().into();
+            let rows = events
+                .iter()
+                .map(|event| event.to_row(block_id.number(), block_hash.clone()))
+                .collect();
+            (Vec::default(), rows)
+        } else {
+            (events, Vec::default())
+        };
 
         let state_update = self.state_update(block_id, &mut data_counter)?;
         has_data |= state_update.is_some();
 
+        let ingestion_timestamp = if self.filter.include_ingestion_timestamp {
+            self.storage.read_ingestion_time(block_id)?
+        } else {
+            None
+        };
+
         let data = v1alpha2::Block {
             status: status as i32,
             header,
@@ -109,16 +303,18 @@ where
             events,
             l2_to_l1_messages,
             empty: false,
+            transaction_groups,
+            ingestion_timestamp,
+            event_rows,
+            aggregates,
         };
 
-        if has_data {
-            // emit here so that weak headers are not counted
-            data_counter.update_meter(meter);
-
-            Ok(Some(data))
-        } else {
-            Ok(None)
-        }
+        // the counter is only used if there's data, so that weak headers are not counted.
+        let block = if has_data { Some(data) } else { None };
+        Ok(FilteredBlock {
+            block,
+            counter: data_counter,
+        })
     }
 
     #[tracing::instrument(skip(self), level = "debug")]
@@ -130,6 +326,22 @@ where
         Ok(status)
     }
 
+    /// Computes the aggregates requested by the filter over the matched events.
+    fn aggregates<'a>(
+        &self,
+        events: impl Iterator<Item = &'a v1alpha2::Event> + Clone,
+    ) -> Vec<v1alpha2::AggregateValue> {
+        self.filter
+            .aggregations
+            .iter()
+            .map(|aggregation| aggregation.aggregate(events.clone()))
+            .collect()
+    }
+
+    fn is_flat_rows(&self) -> bool {
+        self.filter.format() == v1alpha2::ResponseFormat::FlatRows
+    }
+
     fn has_weak_header(&self) -> bool {
         // No header is the same as a weak header.
         self.filter.header.as_ref().map(|h| h.weak).unwrap_or(true)
@@ -227,22 +439,23 @@ where
                             None
                         };
 
-                        let receipt = if filter.include_receipt.unwrap_or(true) {
-                            Some(receipt.clone())
-                        } else {
-                            None
-                        };
-
-                        let event = event.clone();
+                        // flat rows read the transaction hash from the receipt.
+                        let receipt =
+                            if filter.include_receipt.unwrap_or(true) || self.is_flat_rows() {
+                                Some(receipt.clone())
+                            } else {
+                                None
+                            };
 
                         events.push(v1alpha2::EventWithTransaction {
-                            event: Some(event),
+                            event: Some(event.clone()),
                             transaction,
                             receipt,
                         });
                     }
                 }
             }
+            self.filter.sort_events_by().sort(&mut events);
             events
         });
 
@@ -294,6 +507,83 @@ where
         Ok(messages)
     }
 
+    /// Returns the matched transactions, events and messages grouped by transaction.
+    ///
+    /// Matching follows the same rules as [Self::transactions], [Self::events] and
+    /// [Self::l2_to_l1_messages].
+    #[tracing::instrument(skip(self, meter), level = "debug")]
+    fn transaction_groups(
+        &self,
+        block_id: &GlobalBlockId,
+        meter: &mut DataCounter,
+    ) -> Result<Vec<v1alpha2::TransactionGroup>, R::Error> {
+        if self.filter.transactions.is_empty()
+            && self.filter.events.is_empty()
+            && self.filter.messages.is_empty()
+        {
+            return Ok(Vec::default());
+        }
+
+        let transactions = self.storage.read_body(block_id)?;
+        let mut receipts = self.storage.read_receipts(block_id)?;
+
+        assert!(transactions.len() == receipts.len());
+        receipts.sort_by(|a, b| a.transaction_index.cmp(&b.transaction_index));
+
+        let mut groups = Vec::default();
+        for (transaction, receipt) in transactions.into_iter().zip(receipts.into_iter()) {
+            // TODO: use filter flag.
+            if receipt.execution_status == v1alpha2::ExecutionStatus::Reverted as i32 {
+                continue;
+            }
+
+            let transaction_matches = self.filter_transaction(&transaction);
+            let mut include_transaction = transaction_matches;
+            let mut include_receipt = transaction_matches;
+
+            let mut events = Vec::default();
+            for event in &receipt.events {
+                if let Some(filter) = self.filter_event(event) {
+                    include_transaction |= filter.include_transaction.unwrap_or(true);
+                    include_receipt |= filter.include_receipt.unwrap_or(true);
+                    events.push(event.clone());
+                }
+            }
+
+            let l2_to_l1_messages: Vec<_> = receipt
+                .l2_to_l1_messages
+                .iter()
+                .filter(|message| self.filter_l2_to_l1_message(message))
+                .cloned()
+                .collect();
+            if !l2_to_l1_messages.is_empty() {
+                include_transaction = true;
+                include_receipt = true;
+            }
+
+            if !transaction_matches && events.is_empty() && l2_to_l1_messages.is_empty() {
+                continue;
+            }
+
+            if transaction_matches {
+                meter.transaction += 1;
+            }
+            meter.event += events.len();
+            meter.message += l2_to_l1_messages.len();
+
+            groups.push(v1alpha2::TransactionGroup {
+                transaction_hash: receipt.transaction_hash.clone(),
+                transaction_index: receipt.transaction_index,
+                transaction: include_transaction.then_some(transaction),
+                receipt: include_receipt.then_some(receipt),
+                events,
+                l2_to_l1_messages,
+            });
+        }
+
+        Ok(groups)
+    }
+
     #[tracing::instrument(skip(self, meter), level = "debug")]
     fn state_update(
         &self,
@@ -482,8 +772,8 @@ where
     }
 }
 
-#[derive(Debug, Default)]
-struct DataCounter {
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DataCounter {
     pub header: usize,
     pub transaction: usize,
     pub event: usize,
@@ -526,13 +816,26 @@ where
     ) -> Result<(), StreamError> {
         let mut new_inner = Vec::default();
         for filter in &configuration.filter {
+            filter.validate().map_err(StreamError::invalid_request)?;
+            if self.reject_broad_filters {
+                if let Some(reason) = filter.broad_filter_reason() {
+                    return Err(StreamError::invalid_request(format!(
+                        "{}, add constraints to the filter",
+                        reason
+                    )));
+                }
+            }
             let inner = InnerProducer {
                 storage: self.storage.clone(),
                 filter: filter.clone(),
+                filter_key: filter.encode_to_vec().into(),
             };
             new_inner.push(inner);
         }
-        self.inner = new_inner;
+        self.inner = Arc::new(new_inner);
+        self.bulk = configuration.starting_cursor.is_none()
+            && !configuration.start_from_latest
+            && configuration.finality == DataFinality::DataStatusFinalized;
         Ok(())
     }
 
@@ -540,14 +843,374 @@ where
         &mut self,
         cursors: impl Iterator<Item = Self::Cursor> + Send + Sync,
         meter: &M,
-    ) -> Result<Vec<Self::Block>, StreamError> {
+    ) -> Result<Vec<Vec<Self::Block>>, StreamError> {
+        let cursors = cursors.collect::<Vec<_>>();
+        if self.bulk && cursors.len() > 1 {
+            return self.bulk_block_data(&cursors, meter).await;
+        }
+
+        // only streams at the chain head, with a single block per batch, share data.
+        if let (Some(cache), [cursor]) = (self.filtered_cache.as_deref(), cursors.as_slice()) {
+            let blocks = self
+                .cached_block_data(cursor, cache, meter)
+                .await
+                .map_err(IntoStreamError::into_stream_error)?;
+            return Ok(vec![blocks]);
+        }
+
         let mut batch = Vec::default();
         for cursor in cursors {
             let blocks = self
                 .block_data(&cursor, meter)
-                .map_err(StreamError::internal)?;
-            batch.extend(blocks);
+                .map_err(IntoStreamError::into_stream_error)?;
+            batch.push(blocks);
         }
         Ok(batch)
     }
+
+    fn block_timestamp(&self, cursor: &Self::Cursor) -> Result<Option<SystemTime>, StreamError> {
+        let header = self
+            .storage
+            .read_header(cursor)
+            .map_err(IntoStreamError::into_stream_error)?;
+        let timestamp = header.and_then(|header| header.timestamp).and_then(|ts| {
+            let seconds = u64::try_from(ts.seconds).ok()?;
+            Some(UNIX_EPOCH + Duration::new(seconds, ts.nanos.max(0) as u32))
+        });
+        Ok(timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use apibara_core::{
+        node::v1alpha2::DataFinality,
+        starknet::v1alpha2::{
+            AggregateFunction, Aggregation, BlockHeader, BlockStatus, Event, EventOrder,
+            FieldElement, Filter, HeaderFilter, Transaction, TransactionReceipt,
+        },
+    };
+    use apibara_node::{
+        server::{RequestMeter, SimpleMeter},
+        stream::{BatchProducer, StreamConfiguration},
+    };
+
+    use crate::{
+        core::{BlockHash, GlobalBlockId},
+        db::MockStorageReader,
+        stream::FilteredBlockCache,
+    };
+
+    use super::DbBatchProducer;
+
+    fn new_block_id(num: u64) -> GlobalBlockId {
+        let mut b = [0; 32];
+        b[24..].copy_from_slice(&num.to_be_bytes());
+        GlobalBlockId::new(num, BlockHash::from_slice(&b).unwrap())
+    }
+
+    fn new_configuration(filter: Filter) -> StreamConfiguration<GlobalBlockId, Filter> {
+        StreamConfiguration {
+            batch_size: 1,
+            stream_id: 0,
+            finality: DataFinality::DataStatusAccepted,
+            starting_cursor: None,
+            filter: vec![filter],
+            max_data_age: None,
+            notify_caught_up: false,
+            end_cursor: None,
+            heartbeat_interval: None,
+            min_batch_size: None,
+            max_batch_wait: None,
+            invalidations_only: false,
+            start_from_latest: false,
+            notify_chain_head: false,
+            notify_finalized: false,
+            block_ranges: Vec::default(),
+        }
+    }
+
+    fn new_event(from_address: u64) -> Event {
+        Event {
+            from_address: Some(FieldElement::from_u64(from_address)),
+            keys: vec![FieldElement::from_u64(from_address + 100)],
+            ..Event::default()
+        }
+    }
+
+    /// Returns a storage with a block with a transaction per list of events.
+    fn new_storage_with_events(events: Vec<Vec<Event>>) -> MockStorageReader {
+        let transactions = events
+            .iter()
+            .map(|_| Transaction::default())
+            .collect::<Vec<_>>();
+        let receipts = events
+            .into_iter()
+            .enumerate()
+            .map(|(index, events)| TransactionReceipt {
+                transaction_index: index as u64,
+                transaction_hash: Some(FieldElement::from_u64(index as u64)),
+                events,
+                ..TransactionReceipt::default()
+            })
+            .collect::<Vec<_>>();
+
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_status()
+            .returning(|_| Ok(Some(BlockStatus::AcceptedOnL2)));
+        storage
+            .expect_read_header()
+            .returning(|_| Ok(Some(BlockHeader::default())));
+        storage
+            .expect_read_body()
+            .returning(move |_| Ok(transactions.clone()));
+        storage
+            .expect_read_receipts()
+            .returning(move |_| Ok(receipts.clone()));
+        storage
+    }
+
+    #[tokio::test]
+    async fn test_group_by_transaction() {
+        let storage = new_storage_with_events(vec![
+            vec![new_event(1), new_event(2), new_event(1)],
+            vec![new_event(2)],
+            vec![new_event(1)],
+        ]);
+        let configuration = new_configuration(
+            Filter::default()
+                .add_event(|event| event.with_from_address(FieldElement::from_u64(1)))
+                .with_group_by_transaction(true)
+                .build(),
+        );
+        let meter = SimpleMeter::default();
+
+        let mut producer = DbBatchProducer::new(Arc::new(storage));
+        producer.reconfigure(&configuration).unwrap();
+        let batch = producer
+            .next_batch([new_block_id(1)].into_iter(), &meter)
+            .await
+            .unwrap()
+            .concat();
+
+        assert_eq!(batch.len(), 1);
+        let block = &batch[0];
+        assert!(block.events.is_empty());
+        assert!(block.transactions.is_empty());
+        // the second transaction has no matching events.
+        let groups = &block.transaction_groups;
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].transaction_index, 0);
+        assert_eq!(groups[0].events, vec![new_event(1), new_event(1)]);
+        assert!(groups[0].transaction.is_some());
+        assert!(groups[0].receipt.is_some());
+        assert_eq!(groups[1].transaction_index, 2);
+        assert_eq!(groups[1].events, vec![new_event(1)]);
+        assert_eq!(groups[1].transaction_hash, Some(FieldElement::from_u64(2)));
+    }
+
+    #[tokio::test]
+    async fn test_project_events_after_sort_and_aggregates() {
+        let with_data = |from_address: u64, value: u64| Event {
+            data: vec![FieldElement::from_u64(value)],
+            ..new_event(from_address)
+        };
+        let meter = SimpleMeter::default();
+
+        for group_by_transaction in [false, true] {
+            let storage = new_storage_with_events(vec![vec![with_data(2, 10), with_data(1, 20)]]);
+            let configuration = new_configuration(
+                Filter::default()
+                    .add_event(|event| event.with_from_address(FieldElement::from_u64(1)))
+                    .add_event(|event| event.with_from_address(FieldElement::from_u64(2)))
+                    .with_sort_events_by(EventOrder::FromAddress)
+                    .with_group_by_transaction(group_by_transaction)
+                    .with_event_fields(["keys"])
+                    .add_aggregation(Aggregation {
+                        function: AggregateFunction::Sum as i32,
+                        data_index: 0,
+                    })
+                    .build(),
+            );
+
+            let mut producer = DbBatchProducer::new(Arc::new(storage));
+            producer.reconfigure(&configuration).unwrap();
+            let batch = producer
+                .next_batch([new_block_id(1)].into_iter(), &meter)
+                .await
+                .unwrap()
+                .concat();
+
+            let block = &batch[0];
+            assert_eq!(block.aggregates[0].sum, Some(FieldElement::from_u64(30)));
+            let events = if group_by_transaction {
+                block.transaction_groups[0].events.clone()
+            } else {
+                block
+                    .events
+                    .iter()
+                    .filter_map(|event| event.event.clone())
+                    .collect()
+            };
+            let keys = |from_address: u64| Event {
+                keys: vec![FieldElement::from_u64(from_address + 100)],
+                ..Event::default()
+            };
+            if group_by_transaction {
+                // grouped events keep the order of the transaction.
+                assert_eq!(events, vec![keys(2), keys(1)]);
+            } else {
+                assert_eq!(events, vec![keys(1), keys(2)]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_include_ingestion_timestamp() {
+        let time = pbjson_types::Timestamp {
+            seconds: 1_700_000_000,
+            nanos: 0,
+        };
+        let meter = SimpleMeter::default();
+
+        for include in [false, true] {
+            let mut storage = new_storage_with_events(vec![vec![new_event(1)]]);
+            // the time is only read if requested.
+            let expected_reads = if include { 1 } else { 0 };
+            let stored = time.clone();
+            storage
+                .expect_read_ingestion_time()
+                .times(expected_reads)
+                .returning(move |_| Ok(Some(stored.clone())));
+            let configuration = new_configuration(
+                Filter::default()
+                    .add_event(|event| event.with_from_address(FieldElement::from_u64(1)))
+                    .with_include_ingestion_timestamp(include)
+                    .build(),
+            );
+
+            let mut producer = DbBatchProducer::new(Arc::new(storage));
+            producer.reconfigure(&configuration).unwrap();
+            let batch = producer
+                .next_batch([new_block_id(1)].into_iter(), &meter)
+                .await
+                .unwrap()
+                .concat();
+
+            let expected = if include { Some(time.clone()) } else { None };
+            assert_eq!(batch[0].ingestion_timestamp, expected);
+        }
+    }
+
+    #[test]
+    fn test_reject_broad_filters() {
+        let broad = new_configuration(Filter::default().build());
+        let narrow = new_configuration(
+            Filter::default()
+                .add_event(|event| event.with_from_address(FieldElement::from_u64(1)))
+                .build(),
+        );
+
+        let mut producer = DbBatchProducer::new(Arc::new(MockStorageReader::new()));
+        assert!(producer.reconfigure(&broad).is_ok());
+
+        let mut producer = producer.with_reject_broad_filters(true);
+        assert!(producer.reconfigure(&broad).is_err());
+        assert!(producer.reconfigure(&narrow).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_share_filtered_blocks() {
+        let mut storage = MockStorageReader::new();
+        storage.expect_read_header().times(1).returning(|id| {
+            Ok(Some(BlockHeader {
+                block_number: id.number(),
+                ..BlockHeader::default()
+            }))
+        });
+        // the status can change, so it's read by every stream.
+        storage
+            .expect_read_status()
+            .times(3)
+            .returning(|_| Ok(Some(BlockStatus::AcceptedOnL2)));
+        let storage = Arc::new(storage);
+
+        let cache = Arc::new(FilteredBlockCache::with_capacity(8));
+        let configuration =
+            new_configuration(Filter::default().with_header(HeaderFilter::new()).build());
+        let meter = SimpleMeter::default();
+
+        for _ in 0..3 {
+            let mut producer = DbBatchProducer::new(storage.clone())
+                .with_filtered_block_cache(Some(cache.clone()));
+            producer.reconfigure(&configuration).unwrap();
+            let batch = producer
+                .next_batch([new_block_id(1)].into_iter(), &meter)
+                .await
+                .unwrap()
+                .concat();
+            assert_eq!(batch.len(), 1);
+            assert_eq!(batch[0].header.as_ref().unwrap().block_number, 1);
+            assert_eq!(batch[0].status, BlockStatus::AcceptedOnL2 as i32);
+        }
+    }
+
+    /// Counts the headers sent to the client.
+    #[derive(Default)]
+    struct HeaderMeter(AtomicU64);
+
+    impl RequestMeter for HeaderMeter {
+        fn increment_counter(&self, name: &'static str, amount: u64) {
+            if name == "header" {
+                self.0.fetch_add(amount, Ordering::Relaxed);
+            }
+        }
+
+        fn increment_bytes_sent_counter(&self, _amount: u64) {}
+    }
+
+    #[tokio::test]
+    async fn test_bulk_read_keeps_block_order() {
+        let mut storage = MockStorageReader::new();
+        storage.expect_read_header().returning(|id| {
+            Ok(Some(BlockHeader {
+                block_number: id.number(),
+                ..BlockHeader::default()
+            }))
+        });
+        storage
+            .expect_read_status()
+            .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
+
+        // a finalized stream from genesis reads blocks in bulk.
+        let configuration = StreamConfiguration {
+            batch_size: 10,
+            finality: DataFinality::DataStatusFinalized,
+            ..new_configuration(Filter::default().with_header(HeaderFilter::new()).build())
+        };
+        let meter = HeaderMeter::default();
+
+        let mut producer = DbBatchProducer::new(Arc::new(storage));
+        producer.reconfigure(&configuration).unwrap();
+        let batch = producer
+            .next_batch((1..=10).map(new_block_id), &meter)
+            .await
+            .unwrap();
+
+        // one block for each cursor.
+        assert!(batch.iter().all(|blocks| blocks.len() == 1));
+        let numbers = batch
+            .iter()
+            .flatten()
+            .map(|block| block.header.as_ref().unwrap().block_number)
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, (1..=10).collect::<Vec<_>>());
+        assert_eq!(meter.0.load(Ordering::Relaxed), 10);
+    }
 }
@@ -1,4 +1,5 @@
 use std::{
+    ops::RangeInclusive,
     pin::Pin,
     sync::Arc,
     task::{self, Poll, Waker},
@@ -8,14 +9,17 @@ use apibara_core::{node::v1alpha2::DataFinality, starknet::v1alpha2};
 use apibara_node::{
     async_trait,
     stream::{
-        BatchCursor, CursorProducer, IngestionMessage, IngestionResponse, ReconfigureResponse,
-        StreamConfiguration, StreamError,
+        BatchCursor, ChainHead, CursorProducer, IngestionMessage, IngestionResponse,
+        IntoStreamError, ReconfigureResponse, StreamConfiguration, StreamError,
     },
 };
 use futures::{stream::FusedStream, Stream};
 use tracing::{debug, instrument, trace};
 
-use crate::{core::GlobalBlockId, db::StorageReader};
+use crate::{
+    core::GlobalBlockId,
+    db::{StorageReader, CANONICAL_BLOCK_BY_HASH_WINDOW},
+};
 
 /// A [CursorProducer] that produces sequential cursors.
 pub struct SequentialCursorProducer<R: StorageReader + Send + Sync + 'static> {
@@ -30,6 +34,10 @@ struct BatchConfiguration {
     pending_sent: bool,
     data_finality: DataFinality,
     batch_size: usize,
+    /// Don't produce cursors after this block number.
+    end_block_number: Option<u64>,
+    /// Only produce cursors for the blocks in these ranges, if not empty.
+    block_ranges: Vec<RangeInclusive<u64>>,
 }
 
 #[derive(Default, Debug)]
@@ -76,6 +84,25 @@ where
         let starting_cursor = configuration.current;
 
         let next_block_number = configuration.current.map(|c| c.number() + 1).unwrap_or(0);
+        // seek to the next block requested, without reading the blocks in between.
+        let next_block_number = if configuration.block_ranges.is_empty() {
+            next_block_number
+        } else {
+            match configuration
+                .block_ranges
+                .iter()
+                .find(|range| *range.end() >= next_block_number)
+            {
+                None => return Ok(None),
+                Some(range) => u64::max(next_block_number, *range.start()),
+            }
+        };
+
+        if let Some(end_block_number) = configuration.end_block_number {
+            if next_block_number > end_block_number {
+                return Ok(None);
+            }
+        }
 
         trace!(
             next_block_number = %next_block_number,
@@ -115,19 +142,24 @@ where
     ) -> Result<Option<BatchCursor<GlobalBlockId>>, R::Error> {
         // always send finalized data.
         let configuration = self.configuration.as_mut().expect("configuration");
-        let mut cursors = Vec::with_capacity(configuration.batch_size);
         let final_block_number = u64::min(
             finalized.number(),
             next_block_number + (configuration.batch_size as u64) - 1,
         );
-        for block_number in next_block_number..=final_block_number {
-            match self.storage.canonical_block_id(block_number)? {
-                Some(cursor) => {
-                    cursors.push(cursor);
-                }
-                None => break,
-            }
-        }
+        let final_block_number = configuration
+            .end_block_number
+            .map(|end| u64::min(end, final_block_number))
+            .unwrap_or(final_block_number);
+        // batches don't span multiple block ranges.
+        let final_block_number = configuration
+            .block_ranges
+            .iter()
+            .find(|range| range.contains(&next_block_number))
+            .map(|range| u64::min(*range.end(), final_block_number))
+            .unwrap_or(final_block_number);
+        let cursors = self
+            .storage
+            .read_block_range(next_block_number, final_block_number)?;
 
         if cursors.is_empty() {
             return Ok(None);
@@ -235,35 +267,134 @@ where
         &mut self,
         configuration: &StreamConfiguration<Self::Cursor, Self::Filter>,
     ) -> Result<ReconfigureResponse<Self::Cursor>, StreamError> {
-        let (current, response) = match configuration.starting_cursor {
+        if let Some(last_range) = configuration.block_ranges.last() {
+            let finalized = self
+                .get_ingestion_state()
+                .map_err(IntoStreamError::into_stream_error)?
+                .finalized;
+            if let Some(finalized) = finalized {
+                if *last_range.end() > finalized.number() {
+                    return Err(StreamError::cursor_out_of_range(format!(
+                        "requested block {} is not finalized yet, the finalized block is {}",
+                        last_range.end(),
+                        finalized.number()
+                    )));
+                }
+            }
+        }
+
+        if let Some(end_cursor) = configuration.end_cursor {
+            if configuration.finality == DataFinality::DataStatusFinalized {
+                let finalized = self
+                    .get_ingestion_state()
+                    .map_err(IntoStreamError::into_stream_error)?
+                    .finalized;
+                if let Some(finalized) = finalized {
+                    if end_cursor.number() > finalized.number() {
+                        return Err(StreamError::cursor_out_of_range(format!(
+                            "end cursor {} is after the finalized block {}",
+                            end_cursor.number(),
+                            finalized.number()
+                        )));
+                    }
+                }
+            }
+        }
+
+        let starting_cursor = if configuration.start_from_latest {
+            let state = self
+                .get_ingestion_state()
+                .map_err(IntoStreamError::into_stream_error)?;
+            // an empty chain has no head, start from the genesis block.
+            let latest = if configuration.finality == DataFinality::DataStatusFinalized {
+                state.finalized
+            } else {
+                state.accepted.or(state.finalized)
+            };
+
+            if let (Some(latest), Some(end_cursor)) = (latest, configuration.end_cursor) {
+                if end_cursor.number() <= latest.number() {
+                    return Err(StreamError::invalid_request(format!(
+                        "end cursor {} must be after the latest block {}",
+                        end_cursor.number(),
+                        latest.number()
+                    )));
+                }
+            }
+
+            debug!(latest = ?latest, "reconfigure stream from latest block");
+            latest
+        } else {
+            configuration.starting_cursor
+        };
+
+        let (current, response) = match starting_cursor {
             None => (None, ReconfigureResponse::Ok),
             Some(starting_cursor) => {
-                let starting_cursor = if starting_cursor.hash().is_zero() {
+                let starting_cursor = if starting_cursor.is_hashless() {
                     // the user specified a block number but not a hash. Find the hash
                     // corresponding to the block number.
                     match self
                         .storage
                         .canonical_block_id(starting_cursor.number())
-                        .map_err(StreamError::internal)?
+                        .map_err(IntoStreamError::into_stream_error)?
                     {
                         Some(starting_cursor) => starting_cursor,
                         None => return Ok(ReconfigureResponse::MissingStartingCursor),
                     }
+                } else if starting_cursor.number() == 0 {
+                    // the user specified a block hash but not a number. Find the block
+                    // number in the canonical chain, the genesis block resolves to itself.
+                    match self
+                        .storage
+                        .canonical_block_id_by_hash(starting_cursor.hash())
+                        .map_err(IntoStreamError::into_stream_error)?
+                    {
+                        Some(starting_cursor) => starting_cursor,
+                        None => {
+                            return Err(StreamError::not_found(format!(
+                                "block with hash 0x{} is not in the canonical chain, or older than the last {} blocks",
+                                hex::encode(starting_cursor.hash().as_bytes()),
+                                CANONICAL_BLOCK_BY_HASH_WINDOW
+                            )))
+                        }
+                    }
                 } else {
                     starting_cursor
                 };
 
                 debug!(starting_cursor = ?starting_cursor, "reconfigure stream with starting cursor");
-                let starting_status = match self
+                if configuration.finality == DataFinality::DataStatusFinalized {
+                    let finalized = self
+                        .get_ingestion_state()
+                        .map_err(IntoStreamError::into_stream_error)?
+                        .finalized;
+                    if let Some(finalized) = finalized {
+                        // a cursor equal to the finalized head waits for new finalized blocks.
+                        if starting_cursor.number() > finalized.number() {
+                            return Err(StreamError::cursor_out_of_range(format!(
+                                "starting cursor {} is after the finalized block {}",
+                                starting_cursor.number(),
+                                finalized.number()
+                            )));
+                        }
+                    }
+                }
+
+                if self
                     .storage
                     .read_status(&starting_cursor)
-                    .map_err(StreamError::internal)?
+                    .map_err(IntoStreamError::into_stream_error)?
+                    .is_none()
                 {
-                    None => return Ok(ReconfigureResponse::MissingStartingCursor),
-                    Some(starting_status) => starting_status,
-                };
+                    return Ok(ReconfigureResponse::MissingStartingCursor);
+                }
 
-                if starting_status.is_accepted() || starting_status.is_finalized() {
+                if self
+                    .storage
+                    .contains_block(&starting_cursor)
+                    .map_err(IntoStreamError::into_stream_error)?
+                {
                     (Some(starting_cursor), ReconfigureResponse::Ok)
                 } else {
                     // the user-specified cursor is not part of the canonical chain anymore.
@@ -271,23 +402,27 @@ where
                     // cursor.
                     let mut new_root = starting_cursor;
                     loop {
-                        let status = match self
+                        if self
                             .storage
                             .read_status(&new_root)
-                            .map_err(StreamError::internal)?
+                            .map_err(IntoStreamError::into_stream_error)?
+                            .is_none()
                         {
-                            None => return Ok(ReconfigureResponse::MissingStartingCursor),
-                            Some(status) => status,
-                        };
+                            return Ok(ReconfigureResponse::MissingStartingCursor);
+                        }
 
-                        if status.is_accepted() || status.is_finalized() {
+                        if self
+                            .storage
+                            .contains_block(&new_root)
+                            .map_err(IntoStreamError::into_stream_error)?
+                        {
                             break;
                         }
 
                         let header = match self
                             .storage
                             .read_header(&new_root)
-                            .map_err(StreamError::internal)?
+                            .map_err(IntoStreamError::into_stream_error)?
                         {
                             None => return Ok(ReconfigureResponse::MissingStartingCursor),
                             Some(header) => header,
@@ -307,6 +442,8 @@ where
             pending_sent: false,
             current,
             batch_size: configuration.batch_size,
+            end_block_number: configuration.end_cursor.map(|cursor| cursor.number()),
+            block_ranges: configuration.block_ranges.clone(),
         };
         self.configuration = Some(configuration);
 
@@ -321,7 +458,7 @@ where
     ) -> Result<IngestionResponse<Self::Cursor>, StreamError> {
         let state = self
             .get_ingestion_state_mut()
-            .map_err(StreamError::internal)?;
+            .map_err(IntoStreamError::into_stream_error)?;
         let response = match message {
             IngestionMessage::Pending(cursor) => {
                 state.pending = Some(*cursor);
@@ -368,6 +505,32 @@ where
 
         Ok(response)
     }
+
+    fn is_at_head(&self, cursor: &Self::Cursor) -> bool {
+        let (Some(configuration), Some(state)) = (&self.configuration, &self.ingestion_state)
+        else {
+            return false;
+        };
+
+        let head = if configuration.data_finality == DataFinality::DataStatusFinalized {
+            state.finalized
+        } else {
+            state.accepted
+        };
+
+        head.map(|head| cursor.number() >= head.number())
+            .unwrap_or(false)
+    }
+    fn chain_head(&mut self) -> Result<ChainHead<Self::Cursor>, StreamError> {
+        let state = self
+            .get_ingestion_state()
+            .map_err(IntoStreamError::into_stream_error)?;
+        Ok(ChainHead {
+            finalized: state.finalized,
+            accepted: state.accepted,
+            pending: state.pending,
+        })
+    }
 }
 
 impl<R> Stream for SequentialCursorProducer<R>
@@ -382,7 +545,7 @@ where
     ) -> task::Poll<Option<Self::Item>> {
         match self.next_cursor() {
             Err(err) => {
-                let err = StreamError::internal(err);
+                let err = err.into_stream_error();
                 Poll::Ready(Some(Err(err)))
             }
             Ok(None) => {
@@ -420,24 +583,13 @@ mod tests {
     use mockall::predicate::eq;
 
     use crate::{
-        core::{BlockHash, GlobalBlockId},
+        core::GlobalBlockId,
         db::{MockStorageReader, StorageReader},
+        testing::{fork_block_id, new_block_id, InMemoryStorage},
     };
 
     use super::SequentialCursorProducer;
 
-    fn new_block_hash(n: u64, c: u8) -> BlockHash {
-        let mut b = [0; 32];
-        b[24..].copy_from_slice(&n.to_be_bytes());
-        b[0] = c;
-        BlockHash::from_slice(&b).unwrap()
-    }
-
-    fn new_block_id(num: u64) -> GlobalBlockId {
-        let hash = new_block_hash(num, 0);
-        GlobalBlockId::new(num, hash)
-    }
-
     fn new_block_header(
         number: u64,
         hash: GlobalBlockId,
@@ -461,9 +613,33 @@ mod tests {
             finality,
             starting_cursor,
             filter: vec![Filter::default()],
+            max_data_age: None,
+            notify_caught_up: false,
+            end_cursor: None,
+            heartbeat_interval: None,
+            min_batch_size: None,
+            max_batch_wait: None,
+            invalidations_only: false,
+            start_from_latest: false,
+            notify_chain_head: false,
+            notify_finalized: false,
+            block_ranges: Vec::default(),
         }
     }
 
+    /// Mocks a canonical chain that contains all blocks of the main chain.
+    fn expect_canonical_chain(storage: &mut MockStorageReader) {
+        storage
+            .expect_canonical_block_id()
+            .returning(|i| Ok(Some(new_block_id(i))));
+        storage
+            .expect_contains_block()
+            .returning(|id| Ok(*id == new_block_id(id.number())));
+        storage
+            .expect_read_block_range()
+            .returning(|from, to| Ok((from..=to).map(new_block_id).collect()));
+    }
+
     async fn new_producer<R>(
         cursor: Option<GlobalBlockId>,
         finality: DataFinality,
@@ -486,16 +662,7 @@ mod tests {
     /// Finality: FINALIZED
     #[tokio::test]
     async fn test_produce_full_batch_finalized() {
-        let mut storage = MockStorageReader::new();
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
-        storage
-            .expect_highest_accepted_block()
-            .returning(|| Ok(Some(new_block_id(100))));
-        storage
-            .expect_highest_finalized_block()
-            .returning(|| Ok(Some(new_block_id(90))));
+        let storage = InMemoryStorage::with_chain(Some(90), 100);
 
         let producer =
             new_producer(None, DataFinality::DataStatusFinalized, Arc::new(storage)).await;
@@ -512,25 +679,85 @@ mod tests {
         }
     }
 
-    /// This test checks that the producer doesn't produce any cursor if the requested block is
-    /// after the most recent finalized block.
-    ///
-    /// Finality: FINALIZED
     #[tokio::test]
-    async fn test_produce_nothing_if_after_finalized_as_finalized() {
+    async fn test_stop_at_end_cursor_finalized() {
+        let storage = InMemoryStorage::with_chain(Some(10), 20);
+
+        let mut configuration = new_configuration(None, DataFinality::DataStatusFinalized);
+        configuration.end_cursor = Some(GlobalBlockId::from_u64(4));
+        let mut producer = SequentialCursorProducer::new(Arc::new(storage));
+        producer.reconfigure(&configuration).await.unwrap();
+
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert_eq!(batch.end_cursor().number(), 2);
+        // the last batch is smaller than the batch size.
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert_eq!(batch.as_finalized().unwrap().len(), 2);
+        assert_eq!(batch.end_cursor().number(), 4);
+        let batch = producer.try_next().now_or_never();
+        assert!(batch.is_none());
+
+        configuration.end_cursor = Some(GlobalBlockId::from_u64(11));
+        let err = producer.reconfigure(&configuration).await.unwrap_err();
+        assert!(err.to_string().contains("after the finalized block"));
+    }
+
+    #[tokio::test]
+    async fn test_block_ranges_finalized() {
         let mut storage = MockStorageReader::new();
-        storage
-            .expect_read_status()
-            .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        storage.expect_read_block_range().returning(|from, to| {
+            // the blocks between ranges are never read.
+            assert!(matches!(from, 100 | 500 | 9000) && to - from < 2);
+            Ok((from..=to).map(new_block_id).collect())
+        });
         storage
             .expect_highest_accepted_block()
-            .returning(|| Ok(Some(new_block_id(100))));
+            .returning(|| Ok(Some(new_block_id(10_000))));
         storage
             .expect_highest_finalized_block()
-            .returning(|| Ok(Some(new_block_id(90))));
+            .returning(|| Ok(Some(new_block_id(9_500))));
+
+        let mut configuration = new_configuration(None, DataFinality::DataStatusFinalized);
+        configuration.block_ranges = vec![100..=100, 500..=501, 9000..=9000];
+        configuration.end_cursor = Some(GlobalBlockId::from_u64(9000));
+        let mut producer = SequentialCursorProducer::new(Arc::new(storage));
+        producer.reconfigure(&configuration).await.unwrap();
+
+        let mut batches = Vec::default();
+        while let Some(batch) = producer.try_next().now_or_never() {
+            let batch = batch.unwrap().unwrap();
+            let cursors = batch.as_finalized().unwrap();
+            batches.push(cursors.iter().map(|c| c.number()).collect::<Vec<_>>());
+        }
+        assert_eq!(batches, vec![vec![100], vec![500, 501], vec![9000]]);
+
+        configuration.block_ranges = vec![9000..=9600];
+        configuration.end_cursor = Some(GlobalBlockId::from_u64(9600));
+        let err = producer.reconfigure(&configuration).await.unwrap_err();
+        assert!(err.to_string().contains("not finalized yet"));
+    }
+
+    #[tokio::test]
+    async fn test_is_at_head_finalized() {
+        let storage = InMemoryStorage::with_chain(Some(5), 10);
+
+        let mut producer =
+            new_producer(None, DataFinality::DataStatusFinalized, Arc::new(storage)).await;
+
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert!(!producer.is_at_head(batch.end_cursor()));
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert_eq!(batch.end_cursor().number(), 5);
+        assert!(producer.is_at_head(batch.end_cursor()));
+    }
+
+    /// This test checks that the producer doesn't produce any cursor if the requested block is
+    /// after the most recent finalized block.
+    ///
+    /// Finality: FINALIZED
+    #[tokio::test]
+    async fn test_produce_nothing_if_after_finalized_as_finalized() {
+        let storage = InMemoryStorage::with_chain(Some(90), 100);
 
         let mut producer = new_producer(
             Some(new_block_id(90)),
@@ -549,16 +776,7 @@ mod tests {
     /// Finality: FINALIZED
     #[tokio::test]
     async fn test_reach_accepted_as_finalized() {
-        let mut storage = MockStorageReader::new();
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
-        storage
-            .expect_highest_accepted_block()
-            .returning(|| Ok(Some(new_block_id(15))));
-        storage
-            .expect_highest_finalized_block()
-            .returning(|| Ok(Some(new_block_id(10))));
+        let storage = InMemoryStorage::with_chain(Some(10), 15);
 
         let producer =
             new_producer(None, DataFinality::DataStatusFinalized, Arc::new(storage)).await;
@@ -587,16 +805,7 @@ mod tests {
     /// Finality: FINALIZED
     #[tokio::test]
     async fn test_handle_finalized_message_as_finalized() {
-        let mut storage = MockStorageReader::new();
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
-        storage
-            .expect_highest_accepted_block()
-            .returning(|| Ok(Some(new_block_id(15))));
-        storage
-            .expect_highest_finalized_block()
-            .returning(|| Ok(Some(new_block_id(10))));
+        let storage = InMemoryStorage::with_chain(Some(10), 15);
 
         let mut producer =
             new_producer(None, DataFinality::DataStatusFinalized, Arc::new(storage)).await;
@@ -628,24 +837,96 @@ mod tests {
         assert!(batch.is_none());
     }
 
+    /// This test checks that a stream that starts from the latest block only
+    /// produces blocks finalized after it started.
+    ///
+    /// Finality: FINALIZED
+    #[tokio::test]
+    async fn test_start_from_latest_as_finalized() {
+        let storage = InMemoryStorage::with_chain(Some(10), 15);
+
+        let mut configuration = new_configuration(None, DataFinality::DataStatusFinalized);
+        configuration.start_from_latest = true;
+        let mut producer = SequentialCursorProducer::new(Arc::new(storage));
+        producer.reconfigure(&configuration).await.unwrap();
+
+        let batch = producer.try_next().now_or_never();
+        assert!(batch.is_none());
+
+        producer
+            .handle_ingestion_message(&IngestionMessage::Finalized(new_block_id(12)))
+            .await
+            .unwrap();
+
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert_eq!(batch.start_cursor().map(|c| c.number()), Some(10));
+        let cursors = batch.as_finalized().unwrap();
+        assert_eq!(
+            cursors.iter().map(|c| c.number()).collect::<Vec<_>>(),
+            vec![11, 12]
+        );
+
+        configuration.end_cursor = Some(GlobalBlockId::from_u64(12));
+        let err = producer.reconfigure(&configuration).await.unwrap_err();
+        assert!(err.to_string().contains("after the latest block"));
+    }
+
+    /// This test checks that a starting cursor with only a block hash starts
+    /// after the canonical block with that hash.
+    ///
+    /// Finality: FINALIZED
+    #[tokio::test]
+    async fn test_starting_cursor_by_hash_as_finalized() {
+        let storage = InMemoryStorage::with_chain(Some(10), 15);
+        let hash_only = |block_id: GlobalBlockId| GlobalBlockId::new(0, *block_id.hash());
+
+        let mut producer = new_producer(
+            Some(hash_only(new_block_id(6))),
+            DataFinality::DataStatusFinalized,
+            Arc::new(storage.clone()),
+        )
+        .await;
+
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert_eq!(batch.start_cursor().map(|c| c.number()), Some(6));
+        assert_eq!(batch.end_cursor().number(), 9);
+
+        // the block was reorged.
+        storage.invalidate(5);
+        let configuration = new_configuration(
+            Some(hash_only(new_block_id(6))),
+            DataFinality::DataStatusFinalized,
+        );
+        let err = producer.reconfigure(&configuration).await.unwrap_err();
+        assert!(err.to_string().contains("not in the canonical chain"));
+    }
+
+    #[tokio::test]
+    async fn test_chain_head() {
+        let storage = InMemoryStorage::with_chain(Some(10), 15);
+
+        let mut producer =
+            new_producer(None, DataFinality::DataStatusFinalized, Arc::new(storage)).await;
+
+        let chain_head = producer.chain_head().unwrap();
+        assert_eq!(chain_head.finalized, Some(new_block_id(10)));
+        assert_eq!(chain_head.accepted, Some(new_block_id(15)));
+        assert_eq!(chain_head.pending, None);
+
+        producer
+            .handle_ingestion_message(&IngestionMessage::Finalized(new_block_id(12)))
+            .await
+            .unwrap();
+        let chain_head = producer.chain_head().unwrap();
+        assert_eq!(chain_head.finalized, Some(new_block_id(12)));
+    }
+
     /// This test checks that the producer produces messages after the invalidated cursor.
     ///
     /// Finality: FINALIZED
     #[tokio::test]
     async fn test_handle_invalidate_message_as_finalized() {
-        let mut storage = MockStorageReader::new();
-        storage
-            .expect_read_status()
-            .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
-        storage
-            .expect_highest_accepted_block()
-            .returning(|| Ok(Some(new_block_id(15))));
-        storage
-            .expect_highest_finalized_block()
-            .returning(|| Ok(Some(new_block_id(10))));
+        let storage = InMemoryStorage::with_chain(Some(10), 15);
 
         let mut producer = new_producer(
             Some(new_block_id(8)),
@@ -694,16 +975,7 @@ mod tests {
     /// Finality: FINALIZED
     #[tokio::test]
     async fn test_no_finalized_as_finalized() {
-        let mut storage = MockStorageReader::new();
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
-        storage
-            .expect_highest_accepted_block()
-            .returning(|| Ok(Some(new_block_id(14))));
-        storage
-            .expect_highest_finalized_block()
-            .returning(|| Ok(None));
+        let storage = InMemoryStorage::with_chain(None, 14);
 
         let mut producer =
             new_producer(None, DataFinality::DataStatusFinalized, Arc::new(storage)).await;
@@ -727,9 +999,7 @@ mod tests {
     #[tokio::test]
     async fn test_no_accepted_as_finalized() {
         let mut storage = MockStorageReader::new();
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(None));
@@ -754,9 +1024,7 @@ mod tests {
         storage
             .expect_read_status()
             .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(Some(new_block_id(15))));
@@ -797,9 +1065,7 @@ mod tests {
         storage
             .expect_read_status()
             .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(Some(new_block_id(15))));
@@ -850,9 +1116,7 @@ mod tests {
         storage
             .expect_read_status()
             .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(Some(new_block_id(15))));
@@ -902,9 +1166,7 @@ mod tests {
         storage
             .expect_read_status()
             .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(Some(new_block_id(15))));
@@ -959,9 +1221,7 @@ mod tests {
     #[tokio::test]
     async fn test_no_finalized_as_accepted() {
         let mut storage = MockStorageReader::new();
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(Some(new_block_id(14))));
@@ -983,9 +1243,7 @@ mod tests {
     #[tokio::test]
     async fn test_no_accepted_as_accepted() {
         let mut storage = MockStorageReader::new();
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(None));
@@ -1010,9 +1268,7 @@ mod tests {
         storage
             .expect_read_status()
             .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(Some(new_block_id(15))));
@@ -1070,9 +1326,7 @@ mod tests {
         storage
             .expect_read_status()
             .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(Some(new_block_id(15))));
@@ -1095,13 +1349,14 @@ mod tests {
     #[tokio::test]
     async fn test_configure_with_invalidated_starting_cursor() {
         let mut storage = MockStorageReader::new();
+        // blocks 7 and 8 are on a fork that was reorged.
         storage
             .expect_read_status()
-            .with(eq(new_block_id(8)))
+            .with(eq(fork_block_id(8, 1)))
             .returning(|_| Ok(Some(BlockStatus::Rejected)));
         storage
             .expect_read_status()
-            .with(eq(new_block_id(7)))
+            .with(eq(fork_block_id(7, 1)))
             .returning(|_| Ok(Some(BlockStatus::Rejected)));
         storage
             .expect_read_status()
@@ -1109,15 +1364,25 @@ mod tests {
             .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
         storage
             .expect_read_header()
-            .with(eq(new_block_id(8)))
-            .returning(|_| Ok(Some(new_block_header(8, new_block_id(8), new_block_id(7)))));
+            .with(eq(fork_block_id(8, 1)))
+            .returning(|_| {
+                Ok(Some(new_block_header(
+                    8,
+                    fork_block_id(8, 1),
+                    fork_block_id(7, 1),
+                )))
+            });
         storage
             .expect_read_header()
-            .with(eq(new_block_id(7)))
-            .returning(|_| Ok(Some(new_block_header(7, new_block_id(7), new_block_id(6)))));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+            .with(eq(fork_block_id(7, 1)))
+            .returning(|_| {
+                Ok(Some(new_block_header(
+                    7,
+                    fork_block_id(7, 1),
+                    new_block_id(6),
+                )))
+            });
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(Some(new_block_id(15))));
@@ -1125,7 +1390,7 @@ mod tests {
             .expect_highest_finalized_block()
             .returning(|| Ok(Some(new_block_id(10))));
 
-        let cursor = new_block_id(8);
+        let cursor = fork_block_id(8, 1);
         let mut producer = SequentialCursorProducer::new(Arc::new(storage));
         let response = producer
             .reconfigure(&new_configuration(
@@ -1134,16 +1399,20 @@ mod tests {
             ))
             .await
             .unwrap();
-        assert_matches!(response, ReconfigureResponse::Invalidate(_));
+        // the client is sent back to the common ancestor.
+        assert_matches!(response, ReconfigureResponse::Invalidate(root) if root == new_block_id(6));
+
+        // then the stream continues from the canonical chain.
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert_eq!(batch.start_cursor(), Some(&new_block_id(6)));
+        assert_eq!(batch.as_finalized().unwrap()[0], new_block_id(7));
     }
 
     #[tokio::test]
     async fn test_configure_with_non_existing_starting_cursor() {
         let mut storage = MockStorageReader::new();
         storage.expect_read_status().returning(|_| Ok(None));
-        storage
-            .expect_canonical_block_id()
-            .returning(|i| Ok(Some(new_block_id(i))));
+        expect_canonical_chain(&mut storage);
         storage
             .expect_highest_accepted_block()
             .returning(|| Ok(Some(new_block_id(15))));
@@ -1162,4 +1431,135 @@ mod tests {
             .unwrap();
         assert_matches!(response, ReconfigureResponse::MissingStartingCursor);
     }
+
+    #[tokio::test]
+    async fn test_configure_with_hashless_starting_cursor() {
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_status()
+            .with(eq(new_block_id(8)))
+            .returning(|_| Ok(Some(BlockStatus::AcceptedOnL2)));
+        expect_canonical_chain(&mut storage);
+        storage
+            .expect_highest_accepted_block()
+            .returning(|| Ok(Some(new_block_id(15))));
+        storage
+            .expect_highest_finalized_block()
+            .returning(|| Ok(Some(new_block_id(10))));
+
+        // the hashless cursor resolves to the canonical block at that height.
+        let cursor = GlobalBlockId::from_u64(8);
+        let mut producer = SequentialCursorProducer::new(Arc::new(storage));
+        let response = producer
+            .reconfigure(&new_configuration(
+                Some(cursor),
+                DataFinality::DataStatusAccepted,
+            ))
+            .await
+            .unwrap();
+        assert_matches!(response, ReconfigureResponse::Ok);
+
+        // the batch starts from the resolved block, with its hash.
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert_eq!(batch.start_cursor(), Some(&new_block_id(8)));
+        assert_eq!(batch.as_finalized().unwrap()[0], new_block_id(9));
+    }
+
+    #[tokio::test]
+    async fn test_configure_with_hashless_starting_cursor_after_head() {
+        let mut storage = MockStorageReader::new();
+        storage.expect_canonical_block_id().returning(|_| Ok(None));
+        storage
+            .expect_read_block_range()
+            .returning(|_, _| Ok(Vec::new()));
+        storage
+            .expect_highest_accepted_block()
+            .returning(|| Ok(Some(new_block_id(15))));
+        storage
+            .expect_highest_finalized_block()
+            .returning(|| Ok(Some(new_block_id(10))));
+
+        let cursor = GlobalBlockId::from_u64(20);
+        let mut producer = SequentialCursorProducer::new(Arc::new(storage));
+        let response = producer
+            .reconfigure(&new_configuration(
+                Some(cursor),
+                DataFinality::DataStatusAccepted,
+            ))
+            .await
+            .unwrap();
+        assert_matches!(response, ReconfigureResponse::MissingStartingCursor);
+    }
+
+    /// This test checks that a new configuration sent mid-stream restarts the
+    /// stream from its starting cursor.
+    #[tokio::test]
+    async fn test_reconfigure_mid_stream() {
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_status()
+            .returning(|_| Ok(Some(BlockStatus::AcceptedOnL1)));
+        expect_canonical_chain(&mut storage);
+        storage
+            .expect_highest_accepted_block()
+            .returning(|| Ok(Some(new_block_id(100))));
+        storage
+            .expect_highest_finalized_block()
+            .returning(|| Ok(Some(new_block_id(90))));
+
+        let mut producer =
+            new_producer(None, DataFinality::DataStatusFinalized, Arc::new(storage)).await;
+
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert_eq!(batch.as_finalized().unwrap()[0].number(), 0);
+
+        let response = producer
+            .reconfigure(&new_configuration(
+                Some(new_block_id(50)),
+                DataFinality::DataStatusFinalized,
+            ))
+            .await
+            .unwrap();
+        assert_matches!(response, ReconfigureResponse::Ok);
+
+        let batch = producer.try_next().await.unwrap().unwrap();
+        assert_eq!(batch.start_cursor(), Some(&new_block_id(50)));
+        assert_eq!(batch.as_finalized().unwrap()[0].number(), 51);
+    }
+
+    #[tokio::test]
+    async fn test_configure_finalized_with_starting_cursor_after_finalized() {
+        let mut storage = MockStorageReader::new();
+        storage
+            .expect_read_status()
+            .returning(|_| Ok(Some(BlockStatus::AcceptedOnL2)));
+        expect_canonical_chain(&mut storage);
+        storage
+            .expect_highest_accepted_block()
+            .returning(|| Ok(Some(new_block_id(100))));
+        storage
+            .expect_highest_finalized_block()
+            .returning(|| Ok(Some(new_block_id(90))));
+
+        let mut producer = SequentialCursorProducer::new(Arc::new(storage));
+        let err = producer
+            .reconfigure(&new_configuration(
+                Some(new_block_id(95)),
+                DataFinality::DataStatusFinalized,
+            ))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("after the finalized block 90"));
+        assert_eq!(err.into_status().code(), tonic::Code::OutOfRange);
+
+        // accepted streams can start from accepted blocks.
+        let response = producer
+            .reconfigure(&new_configuration(
+                Some(new_block_id(95)),
+                DataFinality::DataStatusAccepted,
+            ))
+            .await
+            .unwrap();
+        assert_matches!(response, ReconfigureResponse::Ok);
+    }
 }
@@ -0,0 +1,259 @@
+//! Share filtered block data between streams with the same filter.
+
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use apibara_core::starknet::v1alpha2;
+use apibara_node::o11y::{self, Counter};
+use lru::LruCache;
+use tokio::sync::OnceCell;
+
+use crate::core::GlobalBlockId;
+
+use super::batch_producer::DataCounter;
+
+/// A block filtered by a stream filter, with the data used to meter it.
+#[derive(Debug, Clone)]
+pub(crate) struct FilteredBlock {
+    /// The block data, `None` if the filter doesn't match any data.
+    pub block: Option<v1alpha2::Block>,
+    pub counter: DataCounter,
+}
+
+/// Caches the result of filtering recent blocks, shared by all streams.
+///
+/// Clients that run replicas of the same service subscribe with the same
+/// filter and follow the chain head together. Each block is then read and
+/// filtered once, by the first stream that needs it, and the other streams
+/// receive a copy of the filtered data. Streams that request the same block
+/// at the same time wait for the first one to filter it, without blocking
+/// their thread.
+///
+/// Only streams at the chain head, whose batches contain a single block,
+/// use the cache. Streams that are catching up read their own data, so that
+/// they don't evict the blocks shared by the streams at the head.
+///
+/// Entries are keyed by the encoded filter and the block id, so that blocks
+/// removed by a chain reorganization are never returned. Pending blocks are
+/// never cached. The block status changes over time, so it's not cached either.
+pub struct FilteredBlockCache {
+    /// `None` if the cache is disabled.
+    cache: Option<Mutex<LruCache<CacheKey, CacheSlot>>>,
+    metrics: CacheMetrics,
+}
+
+type CacheKey = (Arc<[u8]>, u64, [u8; 32]);
+
+type CacheSlot = Arc<OnceCell<FilteredBlock>>;
+
+struct CacheMetrics {
+    hit: Counter<u64>,
+    miss: Counter<u64>,
+}
+
+impl FilteredBlockCache {
+    /// Creates a new cache that holds at most `capacity` filtered blocks.
+    pub fn with_capacity(capacity: usize) -> Self {
+        FilteredBlockCache {
+            cache: NonZeroUsize::new(capacity).map(|capacity| Mutex::new(LruCache::new(capacity))),
+            metrics: CacheMetrics::new(),
+        }
+    }
+
+    /// Returns the block filtered with `filter`, calling `filter_block` if
+    /// it's not cached.
+    ///
+    /// The second value is true if the block was cached.
+    pub(crate) async fn get_or_filter<E>(
+        &self,
+        filter: &Arc<[u8]>,
+        id: &GlobalBlockId,
+        filter_block: impl FnOnce() -> Result<FilteredBlock, E>,
+    ) -> Result<(FilteredBlock, bool), E> {
+        // pending blocks are stored without a hash and overwritten as they change.
+        let Some(cache) = self.cache.as_ref().filter(|_| !id.is_hashless()) else {
+            return filter_block().map(|block| (block, false));
+        };
+
+        let key = (filter.clone(), id.number(), id.hash().into_bytes());
+        let slot = cache
+            .lock()
+            .expect("filtered block cache poisoned")
+            .get_or_insert(key, CacheSlot::default)
+            .clone();
+
+        // concurrent streams wait for the stream that filters the block.
+        // errors are not cached, the next stream tries again.
+        let mut filtered = false;
+        let block = slot
+            .get_or_try_init(|| async {
+                filtered = true;
+                filter_block()
+            })
+            .await?;
+
+        if filtered {
+            self.metrics.miss.add(&o11y::Context::current(), 1, &[]);
+        } else {
+            self.metrics.hit.add(&o11y::Context::current(), 1, &[]);
+        }
+        Ok((block.clone(), !filtered))
+    }
+}
+
+impl CacheMetrics {
+    fn new() -> Self {
+        let meter = o11y::meter("starknet_stream");
+        CacheMetrics {
+            hit: meter.u64_counter("filtered_block_cache_hit").init(),
+            miss: meter.u64_counter("filtered_block_cache_miss").init(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use apibara_core::starknet::v1alpha2;
+
+    use crate::core::{BlockHash, GlobalBlockId};
+
+    use super::{FilteredBlock, FilteredBlockCache};
+
+    fn new_block_id(num: u64) -> GlobalBlockId {
+        let mut b = [0; 32];
+        b[24..].copy_from_slice(&num.to_be_bytes());
+        GlobalBlockId::new(num, BlockHash::from_slice(&b).unwrap())
+    }
+
+    fn filtered(num: u64) -> Result<FilteredBlock, ()> {
+        let block = v1alpha2::Block {
+            header: Some(v1alpha2::BlockHeader {
+                block_number: num,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        Ok(FilteredBlock {
+            block: Some(block),
+            counter: Default::default(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_filter_once_per_filter() {
+        let cache = FilteredBlockCache::with_capacity(8);
+        let filter_a: Arc<[u8]> = Arc::from(vec![1u8]);
+        let filter_b: Arc<[u8]> = Arc::from(vec![2u8]);
+
+        let (_, cached) = cache
+            .get_or_filter(&filter_a, &new_block_id(1), || filtered(1))
+            .await
+            .unwrap();
+        assert!(!cached);
+        let (block, cached) = cache
+            .get_or_filter(&filter_a, &new_block_id(1), || -> Result<_, ()> {
+                panic!("filtered twice")
+            })
+            .await
+            .unwrap();
+        assert!(cached);
+        assert_eq!(block.block.unwrap().header.unwrap().block_number, 1);
+
+        let (_, cached) = cache
+            .get_or_filter(&filter_b, &new_block_id(1), || filtered(1))
+            .await
+            .unwrap();
+        assert!(!cached);
+    }
+
+    #[tokio::test]
+    async fn test_errors_and_pending_blocks_are_not_cached() {
+        let cache = FilteredBlockCache::with_capacity(8);
+        let filter: Arc<[u8]> = Arc::from(vec![1u8]);
+
+        assert!(cache
+            .get_or_filter(&filter, &new_block_id(1), || Err(()))
+            .await
+            .is_err());
+        let (_, cached) = cache
+            .get_or_filter(&filter, &new_block_id(1), || filtered(1))
+            .await
+            .unwrap();
+        assert!(!cached);
+
+        let pending = GlobalBlockId::from_u64(2);
+        for _ in 0..2 {
+            let (_, cached) = cache
+                .get_or_filter(&filter, &pending, || filtered(2))
+                .await
+                .unwrap();
+            assert!(!cached);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used_block() {
+        let cache = FilteredBlockCache::with_capacity(2);
+        let filter: Arc<[u8]> = Arc::from(vec![1u8]);
+        let get = |num| {
+            let cache = &cache;
+            let filter = &filter;
+            async move {
+                cache
+                    .get_or_filter(filter, &new_block_id(num), || filtered(num))
+                    .await
+                    .unwrap()
+                    .1
+            }
+        };
+
+        get(1).await;
+        get(2).await;
+        assert!(get(1).await);
+        // evicts block 2, since block 1 was used more recently.
+        get(3).await;
+        assert!(get(1).await);
+        assert!(!get(2).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_streams_filter_once() {
+        let cache = Arc::new(FilteredBlockCache::with_capacity(8));
+        let filter: Arc<[u8]> = Arc::from(vec![1u8]);
+        let filter_count = Arc::new(AtomicUsize::new(0));
+
+        let streams = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                let filter = filter.clone();
+                let filter_count = filter_count.clone();
+                tokio::spawn(async move {
+                    cache
+                        .get_or_filter(&filter, &new_block_id(1), || {
+                            filter_count.fetch_add(1, Ordering::SeqCst);
+                            std::thread::sleep(Duration::from_millis(50));
+                            filtered(1)
+                        })
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for stream in streams {
+            let (block, _) = stream.await.unwrap();
+            assert_eq!(block.block.unwrap().header.unwrap().block_number, 1);
+        }
+        assert_eq!(filter_count.load(Ordering::SeqCst), 1);
+    }
+}
@@ -2,6 +2,8 @@
 mod batch_producer;
 mod cursor_producer;
 mod data;
+mod filtered_cache;
 
 pub use self::batch_producer::DbBatchProducer;
 pub use self::cursor_producer::SequentialCursorProducer;
+pub use self::filtered_cache::FilteredBlockCache;
@@ -0,0 +1,416 @@
+//! Test helpers to run streams without a node.
+//!
+//! Enabled with the `testing` feature. [InMemoryStorage] is a [StorageReader]
+//! seeded with blocks, and [MockIngestionStream] updates it and notifies the
+//! streams, like block ingestion does. Together with
+//! [StatusClient::with_static_status](crate::status::StatusClient::with_static_status)
+//! they are enough to create a [StreamService](crate::server::stream::StreamService)
+//! in a test.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use apibara_core::starknet::v1alpha2;
+use apibara_node::stream::{IntoStreamError, StreamError};
+
+use crate::{
+    core::{BlockHash, GlobalBlockId},
+    db::StorageReader,
+    ingestion::{IngestionStreamClient, IngestionStreamPublisher},
+};
+
+/// Returns the id of the block at the given height on the main chain.
+pub fn new_block_id(number: u64) -> GlobalBlockId {
+    fork_block_id(number, 0)
+}
+
+/// Returns the id of the block at the given height on a fork of the main chain.
+///
+/// Blocks on different forks have different hashes.
+pub fn fork_block_id(number: u64, fork: u8) -> GlobalBlockId {
+    let mut hash = [0; 32];
+    hash[24..].copy_from_slice(&number.to_be_bytes());
+    hash[0] = fork;
+    let hash = BlockHash::from_slice(&hash).expect("block hash is 32 bytes");
+    GlobalBlockId::new(number, hash)
+}
+
+/// Returns an empty block with the given id and status.
+pub fn new_block(id: GlobalBlockId, status: v1alpha2::BlockStatus) -> v1alpha2::Block {
+    v1alpha2::Block {
+        status: status as i32,
+        header: Some(v1alpha2::BlockHeader {
+            block_number: id.number(),
+            block_hash: Some(id.hash().into()),
+            ..v1alpha2::BlockHeader::default()
+        }),
+        ..v1alpha2::Block::default()
+    }
+}
+
+/// An empty error type. Use by [InMemoryStorage].
+#[derive(Debug, thiserror::Error)]
+pub enum InMemoryStorageError {}
+
+impl IntoStreamError for InMemoryStorageError {
+    fn into_stream_error(self) -> StreamError {
+        match self {}
+    }
+}
+
+/// A [StorageReader] that keeps blocks in memory.
+///
+/// Clones share the same blocks, so tests can keep a clone to change the
+/// chain after the storage is given to a stream. As with the database, the
+/// highest accepted block is the last block of the canonical chain and the
+/// highest finalized block is the last block accepted on L1.
+#[derive(Clone, Default)]
+pub struct InMemoryStorage {
+    inner: Arc<RwLock<ChainState>>,
+}
+
+type BlockKey = (u64, [u8; 32]);
+
+#[derive(Default)]
+struct ChainState {
+    blocks: HashMap<BlockKey, v1alpha2::Block>,
+    canonical: BTreeMap<u64, GlobalBlockId>,
+}
+
+impl InMemoryStorage {
+    /// Creates a storage with the given blocks, added in order to the canonical chain.
+    ///
+    /// Panics if a block doesn't have a header with the block hash.
+    pub fn new(blocks: impl IntoIterator<Item = v1alpha2::Block>) -> Self {
+        let storage = InMemoryStorage::default();
+        for block in blocks {
+            storage.push_block(block);
+        }
+        storage
+    }
+
+    /// Creates a storage with empty blocks from genesis to `accepted`, on the main chain.
+    ///
+    /// The blocks up to `finalized` are finalized.
+    pub fn with_chain(finalized: Option<u64>, accepted: u64) -> Self {
+        InMemoryStorage::new((0..=accepted).map(|number| {
+            let status = if matches!(finalized, Some(finalized) if number <= finalized) {
+                v1alpha2::BlockStatus::AcceptedOnL1
+            } else {
+                v1alpha2::BlockStatus::AcceptedOnL2
+            };
+            new_block(new_block_id(number), status)
+        }))
+    }
+
+    /// Adds the block to the canonical chain, replacing the blocks at the same
+    /// height or after it.
+    ///
+    /// Panics if the block doesn't have a header with the block hash.
+    pub fn push_block(&self, block: v1alpha2::Block) -> GlobalBlockId {
+        let id = GlobalBlockId::from_block(&block).expect("block without header or hash");
+        let mut state = self.write();
+        state.truncate(id.number());
+        state.canonical.insert(id.number(), id);
+        state.blocks.insert(block_key(&id), block);
+        id
+    }
+
+    /// Marks the canonical blocks up to `number` as finalized.
+    ///
+    /// Returns the id of the new highest finalized block.
+    pub fn finalize(&self, number: u64) -> Option<GlobalBlockId> {
+        let mut state = self.write();
+        let ids = state
+            .canonical
+            .range(..=number)
+            .map(|(_, id)| *id)
+            .collect::<Vec<_>>();
+        for id in &ids {
+            if let Some(block) = state.blocks.get_mut(&block_key(id)) {
+                block.status = v1alpha2::BlockStatus::AcceptedOnL1 as i32;
+            }
+        }
+        ids.last().copied()
+    }
+
+    /// Removes the canonical blocks after `number`, like a chain reorganization.
+    ///
+    /// Returns the id of the new chain head.
+    pub fn invalidate(&self, number: u64) -> Option<GlobalBlockId> {
+        let mut state = self.write();
+        state.truncate(number + 1);
+        state.canonical.values().next_back().copied()
+    }
+
+    fn read_block_data<T>(
+        &self,
+        id: &GlobalBlockId,
+        f: impl FnOnce(&v1alpha2::Block) -> T,
+    ) -> Option<T> {
+        self.read().blocks.get(&block_key(id)).map(f)
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, ChainState> {
+        self.inner.read().expect("in-memory storage poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, ChainState> {
+        self.inner.write().expect("in-memory storage poisoned")
+    }
+}
+
+impl ChainState {
+    /// Removes the canonical blocks from `number` onwards, marking them as rejected.
+    fn truncate(&mut self, number: u64) {
+        for (_, id) in self.canonical.split_off(&number) {
+            if let Some(block) = self.blocks.get_mut(&block_key(&id)) {
+                block.status = v1alpha2::BlockStatus::Rejected as i32;
+            }
+        }
+    }
+}
+
+fn block_key(id: &GlobalBlockId) -> BlockKey {
+    (id.number(), id.hash().into_bytes())
+}
+
+fn transaction_receipts(block: &v1alpha2::Block) -> Vec<v1alpha2::TransactionReceipt> {
+    block
+        .transactions
+        .iter()
+        .filter_map(|transaction| transaction.receipt.clone())
+        .collect()
+}
+
+fn storage_diffs(block: &v1alpha2::Block) -> Vec<v1alpha2::StorageDiff> {
+    block
+        .state_update
+        .as_ref()
+        .and_then(|state_update| state_update.state_diff.as_ref())
+        .map(|state_diff| state_diff.storage_diffs.clone())
+        .unwrap_or_default()
+}
+
+impl StorageReader for InMemoryStorage {
+    type Error = InMemoryStorageError;
+
+    fn highest_accepted_block(&self) -> Result<Option<GlobalBlockId>, Self::Error> {
+        Ok(self.read().canonical.values().next_back().copied())
+    }
+
+    fn highest_finalized_block(&self) -> Result<Option<GlobalBlockId>, Self::Error> {
+        let state = self.read();
+        let finalized = state.canonical.values().rev().find(|id| {
+            state
+                .blocks
+                .get(&block_key(id))
+                .map(|block| block.status() == v1alpha2::BlockStatus::AcceptedOnL1)
+                .unwrap_or(false)
+        });
+        Ok(finalized.copied())
+    }
+
+    fn canonical_block_id(&self, number: u64) -> Result<Option<GlobalBlockId>, Self::Error> {
+        Ok(self.read().canonical.get(&number).copied())
+    }
+
+    fn read_status(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Option<v1alpha2::BlockStatus>, Self::Error> {
+        Ok(self.read_block_data(id, |block| block.status()))
+    }
+
+    fn read_header(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Option<v1alpha2::BlockHeader>, Self::Error> {
+        Ok(self
+            .read_block_data(id, |block| block.header.clone())
+            .flatten())
+    }
+
+    fn read_ingestion_time(
+        &self,
+        _id: &GlobalBlockId,
+    ) -> Result<Option<pbjson_types::Timestamp>, Self::Error> {
+        Ok(None)
+    }
+
+    fn read_body(&self, id: &GlobalBlockId) -> Result<Vec<v1alpha2::Transaction>, Self::Error> {
+        let transactions = self.read_block_data(id, |block| {
+            block
+                .transactions
+                .iter()
+                .filter_map(|transaction| transaction.transaction.clone())
+                .collect()
+        });
+        Ok(transactions.unwrap_or_default())
+    }
+
+    fn read_receipts(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Vec<v1alpha2::TransactionReceipt>, Self::Error> {
+        Ok(self
+            .read_block_data(id, transaction_receipts)
+            .unwrap_or_default())
+    }
+
+    fn read_events(
+        &self,
+        id: &GlobalBlockId,
+        contract_address: &v1alpha2::FieldElement,
+    ) -> Result<Vec<v1alpha2::Event>, Self::Error> {
+        let events = self
+            .read_all_events(id)?
+            .into_iter()
+            .filter(|event| event.from_address.as_ref() == Some(contract_address))
+            .collect();
+        Ok(events)
+    }
+
+    fn read_all_events(&self, id: &GlobalBlockId) -> Result<Vec<v1alpha2::Event>, Self::Error> {
+        let events = self
+            .read_receipts(id)?
+            .into_iter()
+            .flat_map(|receipt| receipt.events)
+            .collect();
+        Ok(events)
+    }
+
+    fn read_state_update(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Option<v1alpha2::StateUpdate>, Self::Error> {
+        let state_update = self.read_block_data(id, |block| {
+            // storage diffs are read separately, like in the database.
+            let mut state_update = block.state_update.clone()?;
+            if let Some(state_diff) = state_update.state_diff.as_mut() {
+                state_diff.storage_diffs.clear();
+            }
+            Some(state_update)
+        });
+        Ok(state_update.flatten())
+    }
+
+    fn read_storage_diff(
+        &self,
+        id: &GlobalBlockId,
+        contract_address: &v1alpha2::FieldElement,
+    ) -> Result<Option<v1alpha2::StorageDiff>, Self::Error> {
+        let storage_diff = self
+            .read_all_storage_diff(id)?
+            .into_iter()
+            .find(|diff| diff.contract_address.as_ref() == Some(contract_address));
+        Ok(storage_diff)
+    }
+
+    fn read_all_storage_diff(
+        &self,
+        id: &GlobalBlockId,
+    ) -> Result<Vec<v1alpha2::StorageDiff>, Self::Error> {
+        Ok(self.read_block_data(id, storage_diffs).unwrap_or_default())
+    }
+}
+
+/// Changes an [InMemoryStorage] and notifies the streams following it.
+///
+/// Streams only receive the messages sent after they subscribe to the
+/// ingestion stream, that is after the stream is created.
+pub struct MockIngestionStream {
+    storage: InMemoryStorage,
+    publisher: IngestionStreamPublisher,
+}
+
+impl MockIngestionStream {
+    /// Creates a new ingestion stream over the storage.
+    ///
+    /// Returns the client used by the stream service to subscribe to the stream.
+    pub fn new(storage: InMemoryStorage) -> (IngestionStreamClient, Self) {
+        let (client, publisher) = IngestionStreamPublisher::new();
+        let ingestion = MockIngestionStream { storage, publisher };
+        (client, ingestion)
+    }
+
+    /// Returns the storage updated by the ingestion stream.
+    pub fn storage(&self) -> &InMemoryStorage {
+        &self.storage
+    }
+
+    /// Adds the block to the canonical chain and sends an accepted message.
+    pub fn accept(&self, block: v1alpha2::Block) -> GlobalBlockId {
+        let id = self.storage.push_block(block);
+        self.publisher
+            .publish_accepted(id)
+            .expect("failed to publish accepted block");
+        id
+    }
+
+    /// Finalizes the blocks up to `number` and sends a finalized message.
+    pub fn finalize(&self, number: u64) -> Option<GlobalBlockId> {
+        let id = self.storage.finalize(number)?;
+        self.publisher
+            .publish_finalized(id)
+            .expect("failed to publish finalized block");
+        Some(id)
+    }
+
+    /// Removes the blocks after `number` and sends an invalidate message.
+    ///
+    /// Add the blocks of the new chain with [MockIngestionStream::accept].
+    pub fn reorg(&self, number: u64) -> Option<GlobalBlockId> {
+        let id = self.storage.invalidate(number)?;
+        self.publisher
+            .publish_invalidate(id)
+            .expect("failed to publish invalidated block");
+        Some(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apibara_core::starknet::v1alpha2::BlockStatus;
+
+    use crate::db::StorageReader;
+
+    use super::{fork_block_id, new_block, new_block_id, InMemoryStorage, MockIngestionStream};
+
+    #[tokio::test]
+    async fn test_mock_ingestion_stream() {
+        let storage = InMemoryStorage::with_chain(Some(5), 10);
+        assert_eq!(
+            storage.highest_finalized_block().unwrap(),
+            Some(new_block_id(5))
+        );
+        assert_eq!(
+            storage.highest_accepted_block().unwrap(),
+            Some(new_block_id(10))
+        );
+
+        let (_client, ingestion) = MockIngestionStream::new(storage.clone());
+        ingestion.finalize(7);
+        assert_eq!(
+            storage.highest_finalized_block().unwrap(),
+            Some(new_block_id(7))
+        );
+
+        ingestion.reorg(8);
+        assert_eq!(
+            storage.highest_accepted_block().unwrap(),
+            Some(new_block_id(8))
+        );
+        assert_eq!(
+            storage.read_status(&new_block_id(9)).unwrap(),
+            Some(BlockStatus::Rejected)
+        );
+
+        let fork = fork_block_id(9, 1);
+        ingestion.accept(new_block(fork, BlockStatus::AcceptedOnL2));
+        assert_eq!(storage.canonical_block_id(9).unwrap(), Some(fork));
+        assert!(!storage.contains_block(&new_block_id(9)).unwrap());
+        assert!(storage.read_header(&fork).unwrap().is_some());
+    }
+}
@@ -2,16 +2,22 @@ use crate::db::StorageReader;
 use crate::ingestion::IngestionStreamClient;
 use crate::server::stream::IngestionStream;
 use crate::stream::{DbBatchProducer, SequentialCursorProducer};
+use apibara_core::node::v1alpha2::stream_data_response;
 use apibara_core::starknet::v1alpha2::Block;
 use apibara_core::starknet::v1alpha2::Filter;
-use apibara_node::server::QuotaClient;
-use apibara_node::stream::{new_data_stream, StreamConfigurationStream, StreamError};
+use apibara_node::server::{QuotaClient, StreamAuth};
+use apibara_node::stream::{
+    new_data_stream, LatestCursor, RequestedHeartbeat, StreamConfigurationStream, StreamError,
+};
 use apibara_sdk::{Configuration, DataMessage};
 use futures::future;
 use futures::{SinkExt, StreamExt, TryStreamExt};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tracing::info;
+use tonic::metadata::MetadataMap;
+use tracing::{debug, info};
+use warp::http::{HeaderMap, StatusCode};
+use warp::reply::Reply;
 use warp::ws::{Message, WebSocket};
 use warp::Filter as WarpFilter;
 
@@ -21,6 +27,7 @@ pub struct WebsocketStreamServer<R: StorageReader + Send + Sync + 'static> {
     blocks_per_second_quota: u32,
     ingestion: Arc<IngestionStreamClient>,
     storage: Arc<R>,
+    auth: Option<Arc<dyn StreamAuth>>,
 }
 
 impl<R: StorageReader + Send + Sync + 'static> WebsocketStreamServer<R> {
@@ -36,17 +43,34 @@ impl<R: StorageReader + Send + Sync + 'static> WebsocketStreamServer<R> {
             ingestion,
             storage: db,
             blocks_per_second_quota,
+            auth: None,
         }
     }
 
+    /// Checks that clients are allowed to stream before upgrading the connection.
+    pub fn with_auth(mut self, auth: Option<Arc<dyn StreamAuth>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
     pub async fn start(self: Arc<Self>) {
         let socket_address: SocketAddr = self.address.parse().expect("valid socket Address");
 
         let ws = warp::path("ws")
             .and(warp::ws())
-            .map(move |ws: warp::ws::Ws| {
+            .and(warp::header::headers_cloned())
+            .map(move |ws: warp::ws::Ws, headers: HeaderMap| {
+                if let Err(status) = self.authorize(headers) {
+                    return warp::reply::with_status(
+                        status.message().to_string(),
+                        StatusCode::UNAUTHORIZED,
+                    )
+                    .into_response();
+                }
+
                 let self_ = self.clone();
                 ws.on_upgrade(move |websocket| self_.connect(websocket))
+                    .into_response()
             });
 
         let server = warp::serve(ws).try_bind(socket_address);
@@ -56,6 +80,19 @@ impl<R: StorageReader + Send + Sync + 'static> WebsocketStreamServer<R> {
         server.await
     }
 
+    /// Checks the upgrade request headers, like the stream service does with the metadata.
+    fn authorize(&self, headers: HeaderMap) -> Result<(), tonic::Status> {
+        let Some(auth) = &self.auth else {
+            return Ok(());
+        };
+
+        auth.authorize(&MetadataMap::from_headers(headers))
+            .map_err(|status| {
+                debug!(code = ?status.code(), "websocket client not authorized");
+                status
+            })
+    }
+
     async fn connect(self: Arc<Self>, ws: WebSocket) {
         // Establishing a connection
         let (user_tx, user_rx) = ws.split();
@@ -97,10 +134,21 @@ impl<R: StorageReader + Send + Sync + 'static> WebsocketStreamServer<R> {
             self.blocks_per_second_quota,
             meter,
             quota_client,
+            None,
+            LatestCursor::default(),
+            RequestedHeartbeat::default(),
+            None,
         );
 
         // TODO: send the first decoding error downstream
         data_stream
+            // websocket messages have no representation for the end-of-stream summary.
+            .try_filter(|message| {
+                future::ready(!matches!(
+                    message.message,
+                    Some(stream_data_response::Message::Summary(_))
+                ))
+            })
             .and_then(|message| async {
                 let message = DataMessage::<Block>::from_stream_data_response(message).ok_or(
                     StreamError::internal("Cannot convert StreamDataResponse to DataMessage"),
@@ -35,7 +35,6 @@ async fn test_starknet_reorgs() {
 
     let node_args = StartArgs {
         rpc: format!("http://localhost:{}/rpc", rpc_port),
-        data: None,
         name: Some(
             tempdir
                 .path()
@@ -45,14 +44,7 @@ async fn test_starknet_reorgs() {
                 .unwrap(),
         ),
         wait_for_rpc: true,
-        devnet: false,
-        head_refresh_interval_ms: None,
-        use_metadata: Vec::default(),
-        blocks_per_second_limit: None,
-        address: None,
-        websocket_address: None,
-        quota_server: None,
-        dangerously_override_ingestion_start_block: None,
+        ..Default::default()
     };
 
     let configuration = Configuration::<Filter>::default()
@@ -35,17 +35,9 @@ async fn test_reorg_from_client_pov() {
         async move {
             let args = StartArgs {
                 rpc: format!("http://localhost:{}/rpc", rpc_port),
-                data: None,
-                name: None,
                 wait_for_rpc: true,
                 devnet: true,
-                use_metadata: Vec::default(),
-                blocks_per_second_limit: None,
-                head_refresh_interval_ms: None,
-                address: None,
-                websocket_address: None,
-                quota_server: None,
-                dangerously_override_ingestion_start_block: None,
+                ..Default::default()
             };
             start_node(args, cts).await.unwrap();
         }
@@ -142,7 +134,7 @@ async fn test_reorg_from_client_pov() {
             .unwrap();
         devnet_client.mint().await.unwrap();
         match data_stream.try_next().await.unwrap().unwrap() {
-            DataMessage::Invalidate { cursor } => {
+            DataMessage::Invalidate { cursor, .. } => {
                 // block 5 is reorged too
                 assert_eq!(cursor.unwrap().order_key, 4);
             }
@@ -188,7 +180,7 @@ async fn test_reorg_from_client_pov() {
         info!("re-connected. tests starting");
         // first message should be warning of reorg
         match data_stream.try_next().await.unwrap().unwrap() {
-            DataMessage::Invalidate { cursor } => {
+            DataMessage::Invalidate { cursor, .. } => {
                 // block 5 is reorged too
                 assert_eq!(cursor.unwrap().order_key, 4);
             }
@@ -38,17 +38,10 @@ async fn test_reorg_from_client_pov_websockets() {
         async move {
             let args = StartArgs {
                 rpc: format!("http://localhost:{}/rpc", rpc_port),
-                data: None,
-                name: None,
                 wait_for_rpc: true,
                 devnet: true,
-                use_metadata: Vec::default(),
-                head_refresh_interval_ms: None,
-                address: None,
                 websocket_address: Some("127.0.0.1:8080".into()),
-                blocks_per_second_limit: None,
-                quota_server: None,
-                dangerously_override_ingestion_start_block: None,
+                ..Default::default()
             };
             start_node(args, cts).await.unwrap();
         }
@@ -161,7 +154,7 @@ async fn test_reorg_from_client_pov_websockets() {
         let message: DataMessage<Block> = serde_json::from_slice(&message.into_data()).unwrap();
 
         match message {
-            DataMessage::Invalidate { cursor } => {
+            DataMessage::Invalidate { cursor, .. } => {
                 // block 5 is reorged too
                 assert_eq!(cursor.unwrap().order_key, 4);
             }
@@ -214,7 +207,7 @@ async fn test_reorg_from_client_pov_websockets() {
         let message: DataMessage<Block> = serde_json::from_slice(&message.into_data()).unwrap();
 
         match message {
-            DataMessage::Invalidate { cursor } => {
+            DataMessage::Invalidate { cursor, .. } => {
                 // block 5 is reorged too
                 assert_eq!(cursor.unwrap().order_key, 4);
             }